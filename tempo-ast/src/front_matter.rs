@@ -0,0 +1,6 @@
+//! The values of the fields of front matter.
+//!
+//! Fields are dynamically typed values, but only strings, numbers and
+//! booleans can be written in front matter.
+
+pub use value::Value;
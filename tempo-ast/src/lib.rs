@@ -0,0 +1,1292 @@
+//! The syntax tree of tempo templates.
+//!
+//! These types are kept apart from the parser and translator, so that
+//! linters, formatters and converters can build on them alone. tempo
+//! re-exports them as `tempo::ast`, and they only change in ways which
+//! break code with a new major version.
+
+pub mod front_matter;
+pub mod value;
+
+use std::{fmt, mem};
+use std::ops::Range;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ast
+{
+    pub items: Vec<Item>,
+}
+
+/// An item of a template.
+///
+/// Items are compared by kind alone, so that ASTs built by hand
+/// equal the ASTs parsed from the same template.
+#[derive(Clone, Debug)]
+pub struct Item
+{
+    pub kind: ItemKind,
+    /// The byte range of the source the item was parsed from.
+    ///
+    /// Blocks span from the start of their opening tag to the end of
+    /// their `end` tag. Items which were not parsed have an empty span.
+    pub span: Range<usize>,
+    /// The template the item came from, such as its name or path.
+    ///
+    /// This is kept when the item is included into another template.
+    pub origin: Option<String>,
+}
+
+/// The kind of an item.
+///
+/// New kinds of items may be added in future versions, so
+/// code outside of tempo must handle unknown kinds.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum ItemKind
+{
+    /// A normal piece of text.
+    Text(String),
+    /// A comment, which produces no output.
+    Comment(String),
+    /// A block of code, which is run but produces no output.
+    Code(String),
+    /// An expression whose result is printed.
+    Print(String),
+    /// A directive to tempo itself, written as a
+    /// `<%# @tempo: name = "value" %>` comment.
+    Directive {
+        /// The name of the directive.
+        name: String,
+        /// The value of the directive, without quotes.
+        value: String,
+    },
+    /// A default value for a variable, used if the variable
+    /// is not otherwise given to the template.
+    Default {
+        /// The name of the variable.
+        name: String,
+        /// The Rust expression giving the default value.
+        value: String,
+    },
+    /// A variable bound to the value of an expression, for the rest of
+    /// the enclosing block.
+    Set {
+        /// The name of the variable.
+        name: String,
+        /// The Rust expression giving the value.
+        value: String,
+    },
+    /// A reusable snippet which can be called like a function.
+    Macro {
+        /// The name of the macro.
+        name: String,
+        /// The parameters of the macro, each optionally with a type.
+        parameters: Vec<String>,
+        /// The contents of the macro.
+        body: Vec<Item>,
+    },
+    /// A block whose rendered output is stored in a variable
+    /// rather than being output.
+    Capture {
+        /// The name of the variable.
+        name: String,
+        /// The contents of the block.
+        body: Vec<Item>,
+    },
+    /// A block which is only rendered if a condition holds.
+    If {
+        /// The Rust expression giving the condition.
+        condition: String,
+        /// The contents rendered if the condition holds.
+        body: Vec<Item>,
+        /// The contents rendered otherwise.
+        else_body: Vec<Item>,
+    },
+    /// A block which is rendered once for each element of an iterator.
+    For {
+        /// The pattern bound to each element.
+        pattern: String,
+        /// The Rust expression giving the iterator.
+        iterator: String,
+        /// The contents of the block.
+        body: Vec<Item>,
+    },
+    /// A block whose rendered output is written to a file of its
+    /// own rather than being output.
+    File {
+        /// The Rust expression giving the path of the file.
+        path: String,
+        /// The contents of the file.
+        body: Vec<Item>,
+    },
+    /// A block whose rendered output is stored in a cache, and reused
+    /// until it is invalidated.
+    Cache {
+        /// The Rust expression giving the key the output is stored under.
+        key: String,
+        /// The contents of the block.
+        body: Vec<Item>,
+    },
+    /// A block with a variable bound to the value of an expression.
+    ///
+    /// `include "name" with binding` is parsed as a block around the
+    /// include, with the same span.
+    With {
+        /// The name of the variable.
+        name: String,
+        /// The Rust expression whose value the variable refers to.
+        value: String,
+        /// The contents of the block.
+        body: Vec<Item>,
+    },
+    /// A named block, whose output may be limited to a number of bytes.
+    Block {
+        /// The name of the block.
+        name: String,
+        /// The most bytes of output the block may render, if limited.
+        limit: Option<u64>,
+        /// What happens to output over the limit.
+        truncation: Truncation,
+        /// The contents of the block.
+        body: Vec<Item>,
+    },
+    /// A block tag defined by the application, with a `tag::TagHandler`.
+    Tag {
+        /// The name of the tag.
+        name: String,
+        /// The code after the name in the opening tag.
+        arguments: String,
+        /// The contents of the block.
+        body: Vec<Item>,
+    },
+    /// The contents of another template, by name.
+    Include {
+        /// The name of the included template.
+        name: String,
+        /// The templates included instead if it does not exist, in order,
+        /// written as `include "a" or "b"`.
+        fallbacks: Vec<String>,
+    },
+    /// Metadata at the top of a template, between `---` or `+++` lines.
+    ///
+    /// Each field is a variable in the template, unless it is
+    /// otherwise given.
+    FrontMatter {
+        /// The source of the front matter, including its delimiters.
+        source: String,
+        /// The fields, in order.
+        fields: Vec<(String, front_matter::Value)>,
+    },
+    /// A condition which must hold, or rendering fails.
+    Assert {
+        /// The Rust expression giving the condition.
+        condition: String,
+        /// The message of the error given if the condition does not
+        /// hold, as written between the quotes of its string literal.
+        message: Option<String>,
+    },
+    /// Source which could not be parsed, as it was written.
+    ///
+    /// These are only produced by `tempo::parse::parse_with_recovery`.
+    Error(String),
+}
+
+/// Walks an AST without modifying it.
+///
+/// Each method defaults to visiting the children of its node, so
+/// implementations only need to override the nodes they care about,
+/// calling the matching `walk_` function to continue into children.
+pub trait Visitor
+{
+    fn visit_ast(&mut self, ast: &Ast) {
+        walk_ast(self, ast)
+    }
+
+    fn visit_item(&mut self, item: &Item) {
+        walk_item(self, item)
+    }
+}
+
+/// Walks an AST, possibly modifying it.
+///
+/// Items can be rewritten in place with `visit_item_mut`, or added
+/// and removed by overriding `visit_items_mut`.
+pub trait VisitorMut
+{
+    fn visit_ast_mut(&mut self, ast: &mut Ast) {
+        walk_ast_mut(self, ast)
+    }
+
+    fn visit_items_mut(&mut self, items: &mut Vec<Item>) {
+        walk_items_mut(self, items)
+    }
+
+    fn visit_item_mut(&mut self, item: &mut Item) {
+        walk_item_mut(self, item)
+    }
+}
+
+/// Visits each top-level item of an AST.
+pub fn walk_ast<V: Visitor + ?Sized>(visitor: &mut V, ast: &Ast) {
+    for item in ast.items.iter() {
+        visitor.visit_item(item);
+    }
+}
+
+/// Visits each item nested inside an item.
+pub fn walk_item<V: Visitor + ?Sized>(visitor: &mut V, item: &Item) {
+    for child in item.kind.children() {
+        visitor.visit_item(child);
+    }
+}
+
+/// Visits the top-level items of an AST.
+pub fn walk_ast_mut<V: VisitorMut + ?Sized>(visitor: &mut V, ast: &mut Ast) {
+    visitor.visit_items_mut(&mut ast.items);
+}
+
+/// Visits each item in a list.
+pub fn walk_items_mut<V: VisitorMut + ?Sized>(visitor: &mut V, items: &mut [Item]) {
+    for item in items.iter_mut() {
+        visitor.visit_item_mut(item);
+    }
+}
+
+/// Visits the lists of items nested inside an item.
+pub fn walk_item_mut<V: VisitorMut + ?Sized>(visitor: &mut V, item: &mut Item) {
+    match item.kind {
+        ItemKind::Macro { ref mut body, .. } |
+        ItemKind::Capture { ref mut body, .. } |
+        ItemKind::For { ref mut body, .. } |
+        ItemKind::File { ref mut body, .. } |
+        ItemKind::Cache { ref mut body, .. } |
+        ItemKind::With { ref mut body, .. } |
+        ItemKind::Block { ref mut body, .. } |
+        ItemKind::Tag { ref mut body, .. } => visitor.visit_items_mut(body),
+        ItemKind::If { ref mut body, ref mut else_body, .. } => {
+            visitor.visit_items_mut(body);
+            visitor.visit_items_mut(else_body);
+        },
+        _ => (),
+    }
+}
+
+/// Moves the spans of items and their children by an offset.
+pub fn shift_spans(items: &mut [Item], offset: isize) {
+    struct Shift(isize);
+
+    impl VisitorMut for Shift {
+        fn visit_item_mut(&mut self, item: &mut Item) {
+            item.span.start = (item.span.start as isize + self.0) as usize;
+            item.span.end = (item.span.end as isize + self.0) as usize;
+            walk_item_mut(self, item);
+        }
+    }
+
+    for item in items.iter_mut() {
+        Shift(offset).visit_item_mut(item);
+    }
+}
+
+/// Moves the spans of the items which are not from a named template.
+fn shift_unnamed_spans(items: &mut [Item], offset: isize) {
+    for item in items.iter_mut().filter(|item| item.origin.is_none()) {
+        shift_spans(::std::slice::from_mut(item), offset);
+    }
+}
+
+impl Item
+{
+    /// Creates an item which was not parsed from source.
+    pub fn new(kind: ItemKind) -> Self {
+        Item::with_span(kind, 0..0)
+    }
+
+    /// Creates an item parsed from the given range of the source.
+    pub fn with_span(kind: ItemKind, span: Range<usize>) -> Self {
+        Item { kind, span, origin: None }
+    }
+
+    /// Gets the include in a `with` block which was written as
+    /// `include "name" with binding`.
+    pub fn included_with(&self) -> Option<&Item> {
+        match self.kind {
+            ItemKind::With { ref body, .. } if body.len() == 1 && body[0].span == self.span => {
+                match body[0].kind {
+                    ItemKind::Include { .. } => Some(&body[0]),
+                    _ => None,
+                }
+            },
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for Item
+{
+    fn eq(&self, other: &Item) -> bool {
+        self.kind == other.kind
+    }
+}
+
+/// What happens to the output of a block which is over its limit.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Truncation
+{
+    /// The output is cut off at the limit.
+    Cut,
+    /// The output is cut off, ending with `…` within the limit.
+    Ellipsis,
+    /// Rendering fails.
+    Error,
+}
+
+impl Truncation
+{
+    /// Gets the truncation from how it is written in a `block` tag.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "cut" => Some(Truncation::Cut),
+            "ellipsis" => Some(Truncation::Ellipsis),
+            "error" => Some(Truncation::Error),
+            _ => None,
+        }
+    }
+
+    /// Gets how the truncation is written in a `block` tag.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Truncation::Cut => "cut",
+            Truncation::Ellipsis => "ellipsis",
+            Truncation::Error => "error",
+        }
+    }
+}
+
+/// A feature of the template language, which platforms hosting
+/// templates may choose to allow.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Feature
+{
+    /// Code which is run, such as `<% let x = 1; %>`.
+    Code,
+    /// Printed expressions. These are written as they are, without
+    /// escaping.
+    Print,
+    /// `if` blocks.
+    Condition,
+    /// `for` loops.
+    Loop,
+    /// Macros.
+    Macro,
+    /// `capture` blocks.
+    Capture,
+    /// `set` tags.
+    Set,
+    /// `default` directives.
+    Default,
+    /// `file` blocks, which write files.
+    File,
+    /// `cache` blocks.
+    Cache,
+    /// Named `block`s.
+    Block,
+    /// Includes of other templates.
+    Include,
+    /// `with` blocks.
+    With,
+    /// Custom tags.
+    Tag,
+    /// `assert` tags.
+    Assert,
+    /// `@tempo` directives.
+    Directive,
+    /// Front matter.
+    FrontMatter,
+}
+
+impl Feature
+{
+    /// Gets the name of the feature, such as `loop`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Feature::Code => "code",
+            Feature::Print => "print",
+            Feature::Condition => "condition",
+            Feature::Loop => "loop",
+            Feature::Macro => "macro",
+            Feature::Capture => "capture",
+            Feature::Set => "set",
+            Feature::Default => "default",
+            Feature::File => "file",
+            Feature::Cache => "cache",
+            Feature::Block => "block",
+            Feature::Include => "include",
+            Feature::With => "with",
+            Feature::Tag => "tag",
+            Feature::Assert => "assert",
+            Feature::Directive => "directive",
+            Feature::FrontMatter => "front-matter",
+        }
+    }
+}
+
+impl ItemKind
+{
+    /// Gets the language feature the item uses, if any.
+    ///
+    /// Text, comments and errors use no feature.
+    pub fn feature(&self) -> Option<Feature> {
+        match *self {
+            ItemKind::Text(..) | ItemKind::Comment(..) | ItemKind::Error(..) => None,
+            ItemKind::Code(..) => Some(Feature::Code),
+            ItemKind::Print(..) => Some(Feature::Print),
+            ItemKind::Directive { .. } => Some(Feature::Directive),
+            ItemKind::Default { .. } => Some(Feature::Default),
+            ItemKind::Set { .. } => Some(Feature::Set),
+            ItemKind::Macro { .. } => Some(Feature::Macro),
+            ItemKind::Capture { .. } => Some(Feature::Capture),
+            ItemKind::If { .. } => Some(Feature::Condition),
+            ItemKind::For { .. } => Some(Feature::Loop),
+            ItemKind::File { .. } => Some(Feature::File),
+            ItemKind::Cache { .. } => Some(Feature::Cache),
+            ItemKind::Block { .. } => Some(Feature::Block),
+            ItemKind::Include { .. } => Some(Feature::Include),
+            ItemKind::With { .. } => Some(Feature::With),
+            ItemKind::Tag { .. } => Some(Feature::Tag),
+            ItemKind::Assert { .. } => Some(Feature::Assert),
+            ItemKind::FrontMatter { .. } => Some(Feature::FrontMatter),
+        }
+    }
+
+    /// Gets the items nested inside this item, in order.
+    pub fn children(&self) -> Vec<&Item> {
+        match *self {
+            ItemKind::Macro { ref body, .. } |
+            ItemKind::Capture { ref body, .. } |
+            ItemKind::For { ref body, .. } |
+            ItemKind::File { ref body, .. } |
+            ItemKind::Cache { ref body, .. } |
+            ItemKind::With { ref body, .. } |
+            ItemKind::Block { ref body, .. } |
+            ItemKind::Tag { ref body, .. } => body.iter().collect(),
+            ItemKind::If { ref body, ref else_body, .. } => body.iter().chain(else_body.iter()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Gets the Rust code of the item, such as the condition of an `if`
+    /// block or the parameters of a macro, so that it can be rewritten.
+    pub fn code_mut(&mut self) -> Vec<&mut String> {
+        match *self {
+            ItemKind::Code(ref mut code) |
+                ItemKind::Print(ref mut code) |
+                ItemKind::Default { value: ref mut code, .. } |
+                ItemKind::Set { value: ref mut code, .. } |
+                ItemKind::With { value: ref mut code, .. } |
+                ItemKind::If { condition: ref mut code, .. } |
+                ItemKind::File { path: ref mut code, .. } |
+                ItemKind::Cache { key: ref mut code, .. } |
+                ItemKind::Tag { arguments: ref mut code, .. } |
+                ItemKind::Assert { condition: ref mut code, .. } => vec![code],
+            ItemKind::For { ref mut pattern, ref mut iterator, .. } => vec![pattern, iterator],
+            ItemKind::Macro { ref mut parameters, .. } => parameters.iter_mut().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Gets the name of the tag that opens a block item.
+    pub fn block_name(&self) -> &'static str {
+        match *self {
+            ItemKind::Macro { .. } => "macro",
+            ItemKind::Capture { .. } => "capture",
+            ItemKind::If { .. } => "if",
+            ItemKind::For { .. } => "for",
+            ItemKind::File { .. } => "file",
+            ItemKind::Cache { .. } => "cache",
+            ItemKind::With { .. } => "with",
+            ItemKind::Block { .. } => "block",
+            _ => "",
+        }
+    }
+}
+
+impl Ast
+{
+    /// Gets the fields of the template's front matter, if it has any.
+    pub fn front_matter(&self) -> &[(String, front_matter::Value)] {
+        match self.items.first().map(|item| &item.kind) {
+            Some(ItemKind::FrontMatter { fields, .. }) => fields,
+            _ => &[],
+        }
+    }
+
+    /// Checks whether the template is a draft, with a truthy `draft` field
+    /// in its front matter.
+    pub fn is_draft(&self) -> bool {
+        self.front_matter().iter().any(|(name, value)| name == "draft" && value.is_truthy())
+    }
+
+    /// Gets the keys of the data the template declares it needs, in the
+    /// `required_data` field of its front matter.
+    ///
+    /// The field lists keys separated by commas, such as
+    /// `required_data: user, recent_posts`. What each key means is up to
+    /// the application, which resolves them before rendering.
+    pub fn required_data(&self) -> Vec<String> {
+        self.data_keys("required_data")
+    }
+
+    /// Gets the keys of the data the template can render without, in the
+    /// `optional_data` field of its front matter.
+    ///
+    /// Optional data is given as an `Option`. Named blocks which use it
+    /// are only rendered when it is given. See `tempo::trans::Config::placeholder`.
+    pub fn optional_data(&self) -> Vec<String> {
+        self.data_keys("optional_data")
+    }
+
+    /// Gets the keys listed in a field of the front matter.
+    fn data_keys(&self, field: &str) -> Vec<String> {
+        let keys = self.front_matter().iter().filter_map(|(name, value)| match *value {
+            front_matter::Value::String(ref keys) if name == field => Some(keys),
+            _ => None,
+        }).next();
+
+        keys.map_or(Vec::new(), |keys| {
+            keys.split(',').map(str::trim).filter(|key| !key.is_empty()).map(str::to_owned).collect()
+        })
+    }
+
+    /// Gets the names of the templates this template includes directly,
+    /// in the order they first appear.
+    pub fn dependencies(&self) -> Vec<String> {
+        struct Includes(Vec<String>);
+
+        impl Visitor for Includes {
+            fn visit_item(&mut self, item: &Item) {
+                if let ItemKind::Include { ref name, ref fallbacks } = item.kind {
+                    for name in ::std::iter::once(name).chain(fallbacks) {
+                        if !self.0.contains(name) {
+                            self.0.push(name.clone());
+                        }
+                    }
+                }
+                walk_item(self, item);
+            }
+        }
+
+        let mut includes = Includes(Vec::new());
+        includes.visit_ast(self);
+        includes.0
+    }
+
+    /// Gets the names of the named blocks in the template, in the order
+    /// they appear.
+    pub fn block_names(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        let mut items: Vec<&Item> = self.items.iter().rev().collect();
+
+        while let Some(item) = items.pop() {
+            if let ItemKind::Block { ref name, .. } = item.kind {
+                names.push(&name[..]);
+            }
+            items.extend(item.kind.children().into_iter().rev());
+        }
+        names
+    }
+
+    /// Finds the first named block with a name, including blocks nested
+    /// inside others.
+    pub fn block(&self, name: &str) -> Option<&Item> {
+        let mut items: Vec<&Item> = self.items.iter().rev().collect();
+
+        while let Some(item) = items.pop() {
+            match item.kind {
+                ItemKind::Block { name: ref block_name, .. } if block_name == name => return Some(item),
+                _ => items.extend(item.kind.children().into_iter().rev()),
+            }
+        }
+        None
+    }
+
+    /// Gets a template rendering only a named block, such as to render
+    /// part of a page again after it has changed.
+    ///
+    /// The template's front matter, defaults and top-level macros are
+    /// kept. Variables bound around the block, such as by an enclosing
+    /// `for` loop or a `set` tag, are not.
+    pub fn block_ast(&self, name: &str) -> Option<Ast> {
+        let block = self.block(name)?.clone();
+
+        let mut items: Vec<_> = self.items.iter().filter(|item| {
+            matches!(item.kind, ItemKind::FrontMatter { .. } | ItemKind::Default { .. } | ItemKind::Macro { .. })
+        }).cloned().collect();
+        items.push(block);
+
+        Some(Ast { items })
+    }
+
+    /// Gets the language features the template uses, in order and
+    /// without duplicates.
+    ///
+    /// This is useful to reject templates using features which are not
+    /// allowed, such as when they are uploaded, rather than when they
+    /// are rendered.
+    pub fn features(&self) -> Vec<Feature> {
+        struct Features(Vec<Feature>);
+
+        impl Visitor for Features {
+            fn visit_item(&mut self, item: &Item) {
+                self.0.extend(item.kind.feature());
+                walk_item(self, item);
+            }
+        }
+
+        let mut features = Features(Vec::new());
+        features.visit_ast(self);
+        features.0.sort();
+        features.0.dedup();
+        features.0
+    }
+
+    /// Counts the items in the template, including those in blocks.
+    pub fn item_count(&self) -> usize {
+        struct Count(usize);
+
+        impl Visitor for Count {
+            fn visit_item(&mut self, item: &Item) {
+                self.0 += 1;
+                walk_item(self, item);
+            }
+        }
+
+        let mut count = Count(0);
+        count.visit_ast(self);
+        count.0
+    }
+
+    /// Counts the bytes of text in the template, which is output as
+    /// it is written.
+    pub fn text_len(&self) -> usize {
+        struct TextLength(usize);
+
+        impl Visitor for TextLength {
+            fn visit_item(&mut self, item: &Item) {
+                if let ItemKind::Text(ref text) = item.kind {
+                    self.0 += text.len();
+                }
+                walk_item(self, item);
+            }
+        }
+
+        let mut length = TextLength(0);
+        length.visit_ast(self);
+        length.0
+    }
+
+    /// Estimates the bytes of memory the AST takes up, such as while
+    /// an engine holds it.
+    pub fn memory_size(&self) -> usize {
+        fn items_size(items: &Vec<Item>) -> usize {
+            items.capacity() * mem::size_of::<Item>() + items.iter().map(item_size).sum::<usize>()
+        }
+
+        fn item_size(item: &Item) -> usize {
+            let origin = item.origin.as_ref().map_or(0, |o| o.capacity());
+
+            origin + match item.kind {
+                ItemKind::Text(ref s) | ItemKind::Comment(ref s) | ItemKind::Code(ref s) |
+                    ItemKind::Print(ref s) | ItemKind::Error(ref s) => s.capacity(),
+                ItemKind::Include { ref name, ref fallbacks } => {
+                    name.capacity() + fallbacks.capacity() * mem::size_of::<String>() +
+                        fallbacks.iter().map(|f| f.capacity()).sum::<usize>()
+                },
+                ItemKind::Directive { ref name, ref value } |
+                    ItemKind::Default { ref name, ref value } |
+                    ItemKind::Set { ref name, ref value } => name.capacity() + value.capacity(),
+                ItemKind::With { ref name, ref value, ref body } => {
+                    name.capacity() + value.capacity() + items_size(body)
+                },
+                ItemKind::Macro { ref name, ref parameters, ref body } => {
+                    name.capacity() + parameters.capacity() * mem::size_of::<String>() +
+                        parameters.iter().map(|p| p.capacity()).sum::<usize>() + items_size(body)
+                },
+                ItemKind::Capture { name: ref s, ref body } |
+                    ItemKind::File { path: ref s, ref body } |
+                    ItemKind::Cache { key: ref s, ref body } |
+                    ItemKind::Block { name: ref s, ref body, .. } => s.capacity() + items_size(body),
+                ItemKind::Tag { ref name, ref arguments, ref body } => {
+                    name.capacity() + arguments.capacity() + items_size(body)
+                },
+                ItemKind::If { ref condition, ref body, ref else_body } => {
+                    condition.capacity() + items_size(body) + items_size(else_body)
+                },
+                ItemKind::For { ref pattern, ref iterator, ref body } => {
+                    pattern.capacity() + iterator.capacity() + items_size(body)
+                },
+                ItemKind::Assert { ref condition, ref message } => {
+                    condition.capacity() + message.as_ref().map_or(0, |m| m.capacity())
+                },
+                ItemKind::FrontMatter { ref source, ref fields } => {
+                    source.capacity() + fields.capacity() * mem::size_of::<(String, front_matter::Value)>() +
+                        fields.iter().map(|(key, value)| key.capacity() + match *value {
+                            front_matter::Value::String(ref s) => s.capacity(),
+                            _ => 0,
+                        }).sum::<usize>()
+                },
+            }
+        }
+
+        mem::size_of::<Ast>() + items_size(&self.items)
+    }
+
+    /// Sets the template every item came from.
+    pub fn set_origin(&mut self, origin: &str) {
+        struct SetOrigin<'a>(&'a str);
+
+        impl<'a> VisitorMut for SetOrigin<'a> {
+            fn visit_item_mut(&mut self, item: &mut Item) {
+                item.origin = Some(self.0.to_owned());
+                walk_item_mut(self, item);
+            }
+        }
+
+        SetOrigin(origin).visit_ast_mut(self);
+    }
+
+    /// Adds the items of another AST to the end of this one.
+    ///
+    /// Spans are moved as if the other template's source followed this
+    /// one's. Items from a named template keep their spans, which are
+    /// in the source of that template.
+    pub fn append(&mut self, mut other: Ast) {
+        shift_unnamed_spans(&mut other.items, self.source_len() as isize);
+        self.items.append(&mut other.items);
+    }
+
+    /// Replaces a range of top-level items with the items of another AST,
+    /// giving back the items which were removed.
+    ///
+    /// Spans are moved as if the other template's source was written in
+    /// place of the source of the removed items.
+    pub fn splice(&mut self, range: Range<usize>, mut other: Ast) -> Vec<Item> {
+        let start = match self.items[..range.start].iter().rev().find(|item| item.origin.is_none()) {
+            Some(item) => item.span.end,
+            None => 0,
+        };
+        let removed_len = self.items[range.clone()].iter()
+            .filter(|item| item.origin.is_none())
+            .map(|item| item.span.end)
+            .max().map_or(0, |end| end - start);
+        let added_len = other.source_len();
+
+        shift_unnamed_spans(&mut other.items, start as isize);
+        shift_unnamed_spans(&mut self.items[range.end..], added_len as isize - removed_len as isize);
+        self.items.splice(range, other.items).collect()
+    }
+
+    /// Gets the length of the source the unnamed items were parsed from.
+    fn source_len(&self) -> usize {
+        self.items.iter().filter(|item| item.origin.is_none()).map(|item| item.span.end).max().unwrap_or(0)
+    }
+
+    /// Converts the AST back into template source.
+    ///
+    /// Code, printed expressions and comments keep their original
+    /// whitespace, but block tags and directives are written in a
+    /// canonical form, and raw blocks are only used where text
+    /// contains a tag. Parsing the source gives back the same AST.
+    pub fn to_source(&self) -> String {
+        self.to_string()
+    }
+
+    /// Hashes the meaning of the template, to find whether it has changed.
+    ///
+    /// Comments and the whitespace around code in tags are ignored, so
+    /// reformatting a template keeps its hash, but any change to text
+    /// does not. The hash is the same across runs, platforms and
+    /// versions of Rust, so it can be stored.
+    pub fn content_hash(&self) -> u64 {
+        let mut ast = self.clone();
+        Normalize.visit_ast_mut(&mut ast);
+
+        // FNV-1a, as the standard library's hasher may change.
+        ast.to_source().bytes().fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+}
+
+/// Gets the code of an `include "name"` tag, with its fallbacks.
+pub fn include_tag(name: &str, fallbacks: &[String]) -> String {
+    let mut tag = format!("include \"{}\"", name);
+    for fallback in fallbacks {
+        tag.push_str(&format!(" or \"{}\"", fallback));
+    }
+    tag
+}
+
+/// Gets the code of an `include "name" with binding` tag.
+pub fn include_with_tag(include: &Item, name: &str, value: &str) -> String {
+    let include = match include.kind {
+        ItemKind::Include { ref name, ref fallbacks } => include_tag(name, fallbacks),
+        _ => unreachable!(),
+    };
+
+    if name == value {
+        format!("{} with {}", include, name)
+    } else {
+        format!("{} with {} = {}", include, name, value)
+    }
+}
+
+/// Gets the code of the tag which opens a named block.
+pub fn block_tag(name: &str, limit: Option<u64>, truncation: Truncation) -> String {
+    let mut tag = format!("block {}", name);
+    if let Some(limit) = limit {
+        tag.push_str(&format!(" limit={}", limit));
+    }
+    if truncation != Truncation::Cut {
+        tag.push_str(&format!(" truncate={}", truncation.name()));
+    }
+    tag
+}
+
+/// Removes the parts of an AST which do not change its meaning.
+struct Normalize;
+
+impl VisitorMut for Normalize
+{
+    fn visit_items_mut(&mut self, items: &mut Vec<Item>) {
+        let mut normalized: Vec<Item> = Vec::new();
+
+        for item in items.drain(..) {
+            match item.kind {
+                ItemKind::Comment(..) => continue,
+                // Text either side of a comment is joined.
+                ItemKind::Text(ref text) => {
+                    if let Some(&mut Item { kind: ItemKind::Text(ref mut previous), .. }) = normalized.last_mut() {
+                        previous.push_str(text);
+                        continue;
+                    }
+                },
+                _ => (),
+            }
+            normalized.push(item);
+        }
+
+        *items = normalized;
+        walk_items_mut(self, items)
+    }
+
+    fn visit_item_mut(&mut self, item: &mut Item) {
+        for code in item.kind.code_mut() {
+            *code = normalize_code(code);
+        }
+
+        walk_item_mut(self, item)
+    }
+}
+
+/// Trims code, and replaces each run of whitespace in it with a
+/// single space, except inside string and character literals.
+fn normalize_code(code: &str) -> String {
+    let chars: Vec<char> = code.trim().chars().collect();
+    let mut normalized = String::new();
+    let (mut index, mut is_space) = (0, false);
+
+    while index < chars.len() {
+        if chars[index].is_whitespace() {
+            is_space = true;
+            index += 1;
+            continue;
+        }
+
+        if is_space {
+            normalized.push(' ');
+            is_space = false;
+        }
+
+        let end = literal_end(&chars, index).unwrap_or(index + 1);
+        normalized.extend(&chars[index..end]);
+        index = end;
+    }
+
+    normalized
+}
+
+/// Finds the index just past a string or character literal starting at
+/// an index of code, if one starts there.
+///
+/// Raw strings, such as `r#"a"b"#`, start at their `r`, and byte
+/// literals at their quotes. A `'` only starts a character literal
+/// if it is not a lifetime.
+fn literal_end(chars: &[char], start: usize) -> Option<usize> {
+    // Finds the end of a literal closed by a quote, with escapes.
+    let quoted_end = |quote: char| {
+        let mut index = start + 1;
+        while index < chars.len() && chars[index] != quote {
+            index += if chars[index] == '\\' { 2 } else { 1 };
+        }
+        Some(::std::cmp::min(index + 1, chars.len()))
+    };
+
+    match chars[start] {
+        '"' => quoted_end('"'),
+        '\'' if chars.get(start + 1) == Some(&'\\') || chars.get(start + 2) == Some(&'\'') => quoted_end('\''),
+        'r' => {
+            // The `r` of a raw string may follow a `b`, but not other
+            // characters of an identifier.
+            let prefix = if start > 0 && chars[start - 1] == 'b' { start - 1 } else { start };
+            if prefix > 0 && (chars[prefix - 1].is_alphanumeric() || chars[prefix - 1] == '_') {
+                return None;
+            }
+
+            let hashes = chars[start + 1..].iter().take_while(|&&c| c == '#').count();
+            if chars.get(start + 1 + hashes) != Some(&'"') {
+                return None;
+            }
+
+            let closing: Vec<char> = format!("\"{}", "#".repeat(hashes)).chars().collect();
+            let end = (start + 2 + hashes..chars.len()).find(|&index| chars[index..].starts_with(&closing));
+            Some(end.map_or(chars.len(), |end| end + closing.len()))
+        },
+        _ => None,
+    }
+}
+
+impl fmt::Display for Ast
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for item in self.items.iter() {
+            write!(fmt, "{}", item)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Item
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fn write_body(fmt: &mut fmt::Formatter, items: &[Item]) -> fmt::Result {
+            for item in items {
+                write!(fmt, "{}", item)?;
+            }
+            Ok(())
+        }
+
+        match self.kind {
+            ItemKind::Text(ref text) if text.contains("<%") => {
+                write!(fmt, "<% raw %>{}<% endraw %>", text)
+            },
+            ItemKind::Text(ref text) => write!(fmt, "{}", text),
+            ItemKind::Comment(ref text) => write!(fmt, "<%#{}%>", text),
+            ItemKind::Code(ref source) => write!(fmt, "<%{}%>", source),
+            ItemKind::Print(ref source) => write!(fmt, "<%={}%>", source),
+            ItemKind::Directive { ref name, ref value } => {
+                write!(fmt, "<%# @tempo: {} = \"{}\" %>", name, value)
+            },
+            ItemKind::Default { ref name, ref value } => {
+                write!(fmt, "<% default {} = {} %>", name, value)
+            },
+            ItemKind::Set { ref name, ref value } => {
+                write!(fmt, "<% set {} = {} %>", name, value)
+            },
+            ItemKind::Macro { ref name, ref parameters, ref body } => {
+                write!(fmt, "<% macro {}({}) %>", name, parameters.join(", "))?;
+                write_body(fmt, body)?;
+                write!(fmt, "<% end %>")
+            },
+            ItemKind::Capture { ref name, ref body } => {
+                write!(fmt, "<% capture {} %>", name)?;
+                write_body(fmt, body)?;
+                write!(fmt, "<% end %>")
+            },
+            ItemKind::If { ref condition, ref body, ref else_body } => {
+                write!(fmt, "<% if {} %>", condition)?;
+                write_body(fmt, body)?;
+                if !else_body.is_empty() {
+                    write!(fmt, "<% else %>")?;
+                    write_body(fmt, else_body)?;
+                }
+                write!(fmt, "<% end %>")
+            },
+            ItemKind::For { ref pattern, ref iterator, ref body } => {
+                write!(fmt, "<% for {} in {} %>", pattern, iterator)?;
+                write_body(fmt, body)?;
+                write!(fmt, "<% end %>")
+            },
+            ItemKind::File { ref path, ref body } => {
+                write!(fmt, "<% file {} %>", path)?;
+                write_body(fmt, body)?;
+                write!(fmt, "<% end %>")
+            },
+            ItemKind::Cache { ref key, ref body } => {
+                write!(fmt, "<% cache {} %>", key)?;
+                write_body(fmt, body)?;
+                write!(fmt, "<% end %>")
+            },
+            ItemKind::With { ref name, ref value, .. } if self.included_with().is_some() => {
+                write!(fmt, "<% {} %>", include_with_tag(self.included_with().unwrap(), name, value))
+            },
+            ItemKind::With { ref name, ref value, ref body } => {
+                write!(fmt, "<% with {} = {} %>", name, value)?;
+                write_body(fmt, body)?;
+                write!(fmt, "<% end %>")
+            },
+            ItemKind::Block { ref name, limit, truncation, ref body } => {
+                write!(fmt, "<% {} %>", block_tag(name, limit, truncation))?;
+                write_body(fmt, body)?;
+                write!(fmt, "<% end %>")
+            },
+            ItemKind::Tag { ref name, ref arguments, ref body } => {
+                if arguments.is_empty() {
+                    write!(fmt, "<% {} %>", name)?;
+                } else {
+                    write!(fmt, "<% {} {} %>", name, arguments)?;
+                }
+                write_body(fmt, body)?;
+                write!(fmt, "<% end %>")
+            },
+            ItemKind::Include { ref name, ref fallbacks } => write!(fmt, "<% {} %>", include_tag(name, fallbacks)),
+            ItemKind::Assert { ref condition, message: Some(ref message) } => {
+                write!(fmt, "<% assert {}, \"{}\" %>", condition, message)
+            },
+            ItemKind::Assert { ref condition, message: None } => {
+                write!(fmt, "<% assert {} %>", condition)
+            },
+            ItemKind::FrontMatter { ref source, .. } |
+                ItemKind::Error(ref source) => write!(fmt, "{}", source),
+        }
+    }
+}
+
+/// Builds an AST item by item, for tools which generate templates.
+///
+/// ```
+/// # use tempo_ast::AstBuilder;
+/// let ast = AstBuilder::new()
+///     .text("<ul>")
+///     .for_block("item", "items.iter()", |b| b.text("<li>").print_expr("item").text("</li>"))
+///     .text("</ul>")
+///     .build();
+///
+/// assert_eq!(ast.to_string(), "<ul><% for item in items.iter() %><li><%= item %></li><% end %></ul>");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct AstBuilder
+{
+    items: Vec<Item>,
+}
+
+impl AstBuilder
+{
+    /// Creates a builder with no items.
+    pub fn new() -> Self {
+        AstBuilder { items: Vec::new() }
+    }
+
+    /// Adds an item.
+    pub fn item(mut self, kind: ItemKind) -> Self {
+        self.items.push(Item::new(kind));
+        self
+    }
+
+    /// Adds text, which is output as it is written.
+    pub fn text(self, text: &str) -> Self {
+        self.item(ItemKind::Text(text.to_owned()))
+    }
+
+    /// Adds an expression whose result is printed.
+    pub fn print_expr(self, expression: &str) -> Self {
+        self.item(ItemKind::Print(format!(" {} ", expression)))
+    }
+
+    /// Adds code which is run but produces no output.
+    pub fn code(self, code: &str) -> Self {
+        self.item(ItemKind::Code(format!(" {} ", code)))
+    }
+
+    /// Adds a comment.
+    pub fn comment(self, text: &str) -> Self {
+        self.item(ItemKind::Comment(format!(" {} ", text)))
+    }
+
+    /// Adds a default value for a variable.
+    pub fn default_value(self, name: &str, value: &str) -> Self {
+        self.item(ItemKind::Default { name: name.to_owned(), value: value.to_owned() })
+    }
+
+    /// Adds a variable bound to the value of an expression.
+    pub fn set(self, name: &str, value: &str) -> Self {
+        self.item(ItemKind::Set { name: name.to_owned(), value: value.to_owned() })
+    }
+
+    /// Adds the contents of another template.
+    pub fn include(self, name: &str) -> Self {
+        self.item(ItemKind::Include { name: name.to_owned(), fallbacks: Vec::new() })
+    }
+
+    /// Adds a condition which must hold.
+    pub fn assert(self, condition: &str, message: Option<&str>) -> Self {
+        self.item(ItemKind::Assert {
+            condition: condition.to_owned(),
+            message: message.map(|m| m.to_owned()),
+        })
+    }
+
+    /// Adds a block which is only rendered if a condition holds.
+    pub fn if_block<F>(self, condition: &str, body: F) -> Self
+        where F: FnOnce(AstBuilder) -> AstBuilder {
+        self.if_else_block(condition, body, |b| b)
+    }
+
+    /// Adds a block with contents rendered if a condition holds, and
+    /// contents rendered otherwise.
+    pub fn if_else_block<F, G>(self, condition: &str, body: F, else_body: G) -> Self
+        where F: FnOnce(AstBuilder) -> AstBuilder, G: FnOnce(AstBuilder) -> AstBuilder {
+        self.item(ItemKind::If {
+            condition: condition.to_owned(),
+            body: body(AstBuilder::new()).items,
+            else_body: else_body(AstBuilder::new()).items,
+        })
+    }
+
+    /// Adds a block which is rendered for each element of an iterator.
+    pub fn for_block<F>(self, pattern: &str, iterator: &str, body: F) -> Self
+        where F: FnOnce(AstBuilder) -> AstBuilder {
+        self.item(ItemKind::For {
+            pattern: pattern.to_owned(),
+            iterator: iterator.to_owned(),
+            body: body(AstBuilder::new()).items,
+        })
+    }
+
+    /// Adds a block whose output is stored in a variable.
+    pub fn capture_block<F>(self, name: &str, body: F) -> Self
+        where F: FnOnce(AstBuilder) -> AstBuilder {
+        self.item(ItemKind::Capture { name: name.to_owned(), body: body(AstBuilder::new()).items })
+    }
+
+    /// Adds a reusable snippet which can be called like a function.
+    pub fn macro_block<F>(self, name: &str, parameters: &[&str], body: F) -> Self
+        where F: FnOnce(AstBuilder) -> AstBuilder {
+        self.item(ItemKind::Macro {
+            name: name.to_owned(),
+            parameters: parameters.iter().map(|p| p.to_string()).collect(),
+            body: body(AstBuilder::new()).items,
+        })
+    }
+
+    /// Adds a block whose output is written to a file, at the path
+    /// given by a Rust expression.
+    pub fn file_block<F>(self, path: &str, body: F) -> Self
+        where F: FnOnce(AstBuilder) -> AstBuilder {
+        self.item(ItemKind::File { path: path.to_owned(), body: body(AstBuilder::new()).items })
+    }
+
+    /// Finishes the AST.
+    pub fn build(self) -> Ast {
+        Ast { items: self.items }
+    }
+}
+
+impl Extend<Item> for Ast
+{
+    /// Adds items to the end of the AST, moving their spans as `append` does.
+    fn extend<I: IntoIterator<Item=Item>>(&mut self, items: I) {
+        self.append(Ast { items: items.into_iter().collect() });
+    }
+}
+
+impl From<Vec<Item>> for Ast
+{
+    fn from(items: Vec<Item>) -> Ast {
+        Ast { items }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn text(text: &str) -> Item {
+        Item::new(ItemKind::Text(text.to_owned()))
+    }
+
+    fn sample() -> Ast {
+        vec![
+            text("a"),
+            Item::new(ItemKind::If {
+                condition: "x".to_owned(),
+                body: vec![text("b")],
+                else_body: vec![text("c")],
+            }),
+        ].into()
+    }
+
+    #[test]
+    fn measures_size() {
+        let ast = sample();
+
+        assert_eq!(ast.item_count(), 4);
+        assert_eq!(ast.text_len(), 3);
+        assert!(ast.memory_size() >= 4 * mem::size_of::<Item>() + 4);
+    }
+
+    #[test]
+    fn finds_dependencies() {
+        let include = |name: &str| Item::new(ItemKind::Include { name: name.to_owned(), fallbacks: Vec::new() });
+        let ast: Ast = vec![
+            include("a"),
+            Item::new(ItemKind::For {
+                pattern: "x".to_owned(),
+                iterator: "xs".to_owned(),
+                body: vec![include("b"), include("a")],
+            }),
+        ].into();
+
+        assert_eq!(ast.dependencies(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn visits_nested_items() {
+        struct Texts(Vec<String>);
+
+        impl Visitor for Texts {
+            fn visit_item(&mut self, item: &Item) {
+                if let ItemKind::Text(ref text) = item.kind {
+                    self.0.push(text.clone());
+                }
+                walk_item(self, item);
+            }
+        }
+
+        let mut texts = Texts(Vec::new());
+        texts.visit_ast(&sample());
+        assert_eq!(texts.0, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn rewrites_nested_items() {
+        struct RemoveTexts;
+
+        impl VisitorMut for RemoveTexts {
+            fn visit_items_mut(&mut self, items: &mut Vec<Item>) {
+                items.retain(|item| !matches!(item.kind, ItemKind::Text(..)));
+                walk_items_mut(self, items);
+            }
+        }
+
+        let mut ast = sample();
+        RemoveTexts.visit_ast_mut(&mut ast);
+        assert_eq!(ast, vec![
+            Item::new(ItemKind::If {
+                condition: "x".to_owned(),
+                body: Vec::new(),
+                else_body: Vec::new(),
+            }),
+        ].into());
+    }
+}
@@ -0,0 +1,388 @@
+//! Dynamically typed values, for data whose shape is not known when
+//! templates are translated, such as fields read from JSON or front
+//! matter.
+//!
+//! tempo re-exports these as `tempo::value`, which describes how they
+//! are coerced.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::{error, fmt};
+
+/// A dynamically typed value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value
+{
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    List(Vec<Value>),
+    /// Entries in order of their keys.
+    Map(BTreeMap<String, Value>),
+}
+
+impl Value
+{
+    /// Gets the name of the type of the value, as used in errors.
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Value::Null => "null",
+            Value::Bool(..) => "bool",
+            Value::Int(..) => "int",
+            Value::Float(..) => "float",
+            Value::String(..) => "string",
+            Value::List(..) => "list",
+            Value::Map(..) => "map",
+        }
+    }
+
+    /// Checks whether the value is true when used as a condition.
+    pub fn is_truthy(&self) -> bool {
+        match *self {
+            Value::Null => false,
+            Value::Bool(b) => b,
+            Value::Int(i) => i != 0,
+            Value::Float(f) => f != 0.0 && !f.is_nan(),
+            Value::String(ref s) => !s.is_empty(),
+            Value::List(ref list) => !list.is_empty(),
+            Value::Map(ref map) => !map.is_empty(),
+        }
+    }
+
+    /// Gets the value as a float, promoting integers.
+    pub fn as_float(&self) -> Option<f64> {
+        match *self {
+            Value::Int(i) => Some(i as f64),
+            Value::Float(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    /// Gets the value as an integer.
+    pub fn as_int(&self) -> Option<i64> {
+        match *self {
+            Value::Int(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    /// Gets the value as a string, without stringifying it.
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Gets the element of a list at an index, or the entry of a map
+    /// with a key, giving `Null` if there is none.
+    pub fn get(&self, key: &str) -> &Value {
+        const NULL: &Value = &Value::Null;
+
+        match *self {
+            Value::List(ref list) => key.parse::<usize>().ok().and_then(|i| list.get(i)).unwrap_or(NULL),
+            Value::Map(ref map) => map.get(key).unwrap_or(NULL),
+            _ => NULL,
+        }
+    }
+
+    /// Writes the value as JSON, such as for the body of a request.
+    ///
+    /// Floats which are not finite are written as `null`.
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        self.write_json(&mut json);
+        json
+    }
+
+    fn write_json(&self, json: &mut String) {
+        match *self {
+            Value::Null => json.push_str("null"),
+            Value::Bool(b) => json.push_str(if b { "true" } else { "false" }),
+            Value::Int(i) => json.push_str(&i.to_string()),
+            Value::Float(f) if f.is_finite() => json.push_str(&format!("{:?}", f)),
+            Value::Float(..) => json.push_str("null"),
+            Value::String(ref s) => json.push_str(&json_string(s)),
+            Value::List(ref list) => {
+                json.push('[');
+                for (i, element) in list.iter().enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+                    element.write_json(json);
+                }
+                json.push(']');
+            },
+            Value::Map(ref map) => {
+                json.push('{');
+                for (i, (key, value)) in map.iter().enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+                    json.push_str(&json_string(key));
+                    json.push(':');
+                    value.write_json(json);
+                }
+                json.push('}');
+            },
+        }
+    }
+
+    /// Gets the Rust type of the value, as used for a field of front
+    /// matter, if it has a literal.
+    ///
+    /// Only strings, numbers and booleans have literals.
+    pub fn rust_type(&self) -> Option<&'static str> {
+        match *self {
+            Value::String(..) => Some("&'static str"),
+            Value::Int(..) => Some("i64"),
+            Value::Float(..) => Some("f64"),
+            Value::Bool(..) => Some("bool"),
+            _ => None,
+        }
+    }
+
+    /// Writes the value as a Rust literal of its `rust_type`.
+    pub fn rust_literal(&self) -> Option<String> {
+        match *self {
+            Value::String(ref string) => Some(format!("{:?}", string)),
+            Value::Int(integer) => Some(integer.to_string()),
+            Value::Float(float) => Some(format!("{:?}", float)),
+            Value::Bool(boolean) => Some(boolean.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Creates the error given when a value is not of the expected type.
+    fn type_error(&self, expected: &'static str) -> UnexpectedType {
+        UnexpectedType { expected, found: self.type_name() }
+    }
+}
+
+/// The error given when a value is converted to a Rust type it does not hold.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnexpectedType
+{
+    /// The name of the type the value was converted to.
+    pub expected: &'static str,
+    /// The name of the type of the value, from `Value::type_name`.
+    pub found: &'static str,
+}
+
+impl fmt::Display for UnexpectedType
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "expected a value of type '{}', found '{}'", self.expected, self.found)
+    }
+}
+
+impl error::Error for UnexpectedType { }
+
+impl fmt::Display for Value
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Null => Ok(()),
+            Value::Bool(b) => write!(fmt, "{}", b),
+            Value::Int(i) => write!(fmt, "{}", i),
+            Value::Float(f) => write!(fmt, "{}", f),
+            Value::String(ref s) => write!(fmt, "{}", s),
+            Value::List(ref list) => {
+                for (i, element) in list.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, ", ")?;
+                    }
+                    write!(fmt, "{}", element)?;
+                }
+                Ok(())
+            },
+            Value::Map(ref map) => {
+                for (i, (key, value)) in map.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, ", ")?;
+                    }
+                    write!(fmt, "{}: {}", key, value)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl From<bool> for Value
+{
+    fn from(b: bool) -> Self { Value::Bool(b) }
+}
+
+impl From<i32> for Value
+{
+    fn from(i: i32) -> Self { Value::Int(i as i64) }
+}
+
+impl From<i64> for Value
+{
+    fn from(i: i64) -> Self { Value::Int(i) }
+}
+
+impl From<f64> for Value
+{
+    fn from(f: f64) -> Self { Value::Float(f) }
+}
+
+impl<'a> From<&'a str> for Value
+{
+    fn from(s: &'a str) -> Self { Value::String(s.to_owned()) }
+}
+
+impl From<String> for Value
+{
+    fn from(s: String) -> Self { Value::String(s) }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value
+{
+    fn from(option: Option<T>) -> Self {
+        option.map_or(Value::Null, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value
+{
+    fn from(list: Vec<T>) -> Self {
+        Value::List(list.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<Value>> From<BTreeMap<String, T>> for Value
+{
+    fn from(map: BTreeMap<String, T>) -> Self {
+        Value::Map(map.into_iter().map(|(key, value)| (key, value.into())).collect())
+    }
+}
+
+impl TryFrom<Value> for bool
+{
+    type Error = UnexpectedType;
+
+    fn try_from(value: Value) -> Result<Self, UnexpectedType> {
+        match value {
+            Value::Bool(b) => Ok(b),
+            value => Err(value.type_error("bool")),
+        }
+    }
+}
+
+impl TryFrom<Value> for i64
+{
+    type Error = UnexpectedType;
+
+    fn try_from(value: Value) -> Result<Self, UnexpectedType> {
+        value.as_int().ok_or_else(|| value.type_error("int"))
+    }
+}
+
+impl TryFrom<Value> for f64
+{
+    type Error = UnexpectedType;
+
+    fn try_from(value: Value) -> Result<Self, UnexpectedType> {
+        value.as_float().ok_or_else(|| value.type_error("float"))
+    }
+}
+
+impl TryFrom<Value> for String
+{
+    type Error = UnexpectedType;
+
+    fn try_from(value: Value) -> Result<Self, UnexpectedType> {
+        match value {
+            Value::String(s) => Ok(s),
+            value => Err(value.type_error("string")),
+        }
+    }
+}
+
+impl TryFrom<Value> for Vec<Value>
+{
+    type Error = UnexpectedType;
+
+    fn try_from(value: Value) -> Result<Self, UnexpectedType> {
+        match value {
+            Value::List(list) => Ok(list),
+            value => Err(value.type_error("list")),
+        }
+    }
+}
+
+impl TryFrom<Value> for BTreeMap<String, Value>
+{
+    type Error = UnexpectedType;
+
+    fn try_from(value: Value) -> Result<Self, UnexpectedType> {
+        match value {
+            Value::Map(map) => Ok(map),
+            value => Err(value.type_error("map")),
+        }
+    }
+}
+
+/// Writes text as a JSON string, with quotes.
+pub fn json_string(text: &str) -> String {
+    let mut json = String::with_capacity(text.len() + 2);
+    json.push('"');
+
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn coerces_values() {
+        assert!(!Value::from("").is_truthy());
+        assert!(!Value::Float(f64::NAN).is_truthy());
+        assert!(!Value::from(None::<i64>).is_truthy());
+        assert!(Value::from(vec![0]).is_truthy());
+
+        assert_eq!(Value::from(3).as_float(), Some(3.0));
+        assert_eq!(Value::from(2.5).as_int(), None);
+        assert_eq!(f64::try_from(Value::from(3)).unwrap(), 3.0);
+
+        let mut map = BTreeMap::new();
+        map.insert("b".to_owned(), Value::from(vec![1, 2]));
+        map.insert("a".to_owned(), Value::Null);
+        let map = Value::from(map);
+        assert_eq!(map.to_string(), "a: , b: 1, 2");
+        assert_eq!(map.get("b").get("1"), &Value::Int(2));
+        assert_eq!(map.get("c"), &Value::Null);
+    }
+
+    #[test]
+    fn writes_json() {
+        assert_eq!(json_string("a \"b\"\n\u{1}"), "\"a \\\"b\\\"\\n\\u0001\"");
+        assert_eq!(Value::from(vec![Value::from("x"), Value::Float(f64::NAN)]).to_json(), "[\"x\",null]");
+    }
+
+    #[test]
+    fn writes_rust_literals() {
+        assert_eq!(Value::from("a \"b\"").rust_literal().unwrap(), "\"a \\\"b\\\"\"");
+        assert_eq!(Value::Float(1.0).rust_literal().unwrap(), "1.0");
+        assert_eq!((Value::Int(2).rust_type(), Value::Null.rust_type()), (Some("i64"), None));
+    }
+}
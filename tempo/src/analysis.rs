@@ -0,0 +1,540 @@
+//! Static analysis of templates.
+//!
+//! The code in templates is Rust, which tempo does not fully parse, so
+//! the analysis here is a close approximation. It understands literals,
+//! paths, field accesses, macro calls, and the `let`, `for` and closure
+//! bindings made by the code itself.
+
+use {ast, parse};
+
+use std::ops::Range;
+
+/// Words which are never variables.
+const KEYWORDS: &'static [&'static str] = &[
+    "as", "box", "break", "const", "continue", "crate", "dyn", "else", "enum",
+    "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match",
+    "mod", "move", "mut", "pub", "ref", "return", "self", "static", "struct",
+    "super", "trait", "true", "type", "unsafe", "use", "where", "while",
+];
+
+/// The keywords allowed by `disallowed_construct`.
+const EXPRESSION_KEYWORDS: &'static [&'static str] = &["as", "false", "true"];
+
+/// The punctuation allowed by `disallowed_construct`.
+const EXPRESSION_PUNCTUATION: &'static [&'static str] = &[
+    ".", ",", "(", ")", "[", "]", "+", "-", "*", "/", "%", "=", "<", ">", "!", "&", "|", "^",
+];
+
+/// The punctuation allowed by `disallowed_binding` in patterns.
+const PATTERN_PUNCTUATION: &'static [&'static str] = &["(", ")", ","];
+
+/// The punctuation allowed by `disallowed_binding` in types.
+const TYPE_PUNCTUATION: &'static [&'static str] = &["::", "(", ")", "[", "]", "<", ">", ",", "&"];
+
+/// A use of a variable which the template does not define itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VariableRef
+{
+    /// The name of the variable.
+    pub name: String,
+    /// The byte range of the name in the template source.
+    ///
+    /// For code inside block tags, such as the condition of an `if`
+    /// block, this is the span of the whole block instead.
+    pub span: Range<usize>,
+}
+
+/// A token of Rust code.
+#[derive(Debug, PartialEq)]
+enum Token<'a>
+{
+    Identifier(&'a str),
+    Punctuation(&'a str),
+    Literal,
+}
+
+/// Finds the variables referenced by a template but not defined by it.
+///
+/// Names bound by macros, captures, `for` blocks and the code itself
+/// are not references. Variables with a `default` are references,
+/// because they may still be given to the template.
+pub fn referenced_variables(ast: &ast::Ast) -> Vec<VariableRef> {
+    let mut finder = Finder { bound: Vec::new(), references: Vec::new() };
+    finder.items(&ast.items);
+    finder.references
+}
+
+struct Finder
+{
+    /// The names bound at this point in the template.
+    bound: Vec<String>,
+    references: Vec<VariableRef>,
+}
+
+impl Finder
+{
+    fn items(&mut self, items: &[ast::Item]) {
+        let bound_count = self.bound.len();
+
+        for item in items {
+            self.item(item);
+        }
+
+        // Names bound by code only last until the end of the block.
+        self.bound.truncate(bound_count);
+    }
+
+    fn item(&mut self, item: &ast::Item) {
+        let span = item.span.clone();
+
+        match item.kind {
+            ast::ItemKind::Code(ref source) => self.code(source, span.start + parse::OPEN_DELIMITER.len()),
+            ast::ItemKind::Print(ref source) => self.code(source, span.start + parse::OPEN_DELIMITER.len() + "=".len()),
+            ast::ItemKind::Default { ref value, .. } => self.code_in(value, span),
+            ast::ItemKind::Set { ref name, ref value } => {
+                self.code_in(value, span);
+                self.bound.push(name.clone());
+            },
+            ast::ItemKind::Assert { ref condition, .. } => self.code_in(condition, span),
+            ast::ItemKind::FrontMatter { ref fields, .. } => {
+                self.bound.extend(fields.iter().map(|&(ref key, _)| key.clone()));
+            },
+            ast::ItemKind::Macro { ref name, ref parameters, ref body } => {
+                self.bound.push(name.clone());
+
+                let bound_count = self.bound.len();
+                for parameter in parameters {
+                    let pattern = parameter.split(':').next().unwrap();
+                    let names = identifiers(pattern).into_iter().map(|(_, name)| name.to_owned());
+                    self.bound.extend(names);
+                }
+
+                self.items(body);
+                self.bound.truncate(bound_count);
+            },
+            ast::ItemKind::Capture { ref name, ref body } => {
+                self.items(body);
+                self.bound.push(name.clone());
+            },
+            ast::ItemKind::With { ref name, ref value, ref body } => {
+                self.code_in(value, span);
+                self.bound.push(name.clone());
+                self.items(body);
+                self.bound.pop();
+            },
+            ast::ItemKind::If { ref condition, ref body, ref else_body } => {
+                self.code_in(condition, span);
+                self.items(body);
+                self.items(else_body);
+            },
+            ast::ItemKind::File { path: ref code, ref body } |
+            ast::ItemKind::Cache { key: ref code, ref body } => {
+                self.code_in(code, span);
+                self.items(body);
+            },
+            // The arguments of tags are only known to their handlers.
+            ast::ItemKind::Tag { ref body, .. } |
+            ast::ItemKind::Block { ref body, .. } => self.items(body),
+            ast::ItemKind::For { ref pattern, ref iterator, ref body } => {
+                self.code_in(iterator, span);
+
+                let bound_count = self.bound.len();
+                self.bound.extend(identifiers(pattern).into_iter().map(|(_, name)| name.to_owned()));
+                self.items(body);
+                self.bound.truncate(bound_count);
+            },
+            ast::ItemKind::Text(..) |
+            ast::ItemKind::Comment(..) |
+            ast::ItemKind::Directive { .. } |
+            ast::ItemKind::Include { .. } |
+            ast::ItemKind::Error(..) => (),
+            // Kinds this version does not know of are not analyzed.
+            _ => (),
+        }
+    }
+
+    /// Finds the references in code whose exact position is unknown.
+    fn code_in(&mut self, code: &str, span: Range<usize>) {
+        let count = self.references.len();
+        self.code(code, 0);
+
+        for reference in self.references[count..].iter_mut() {
+            reference.span = span.clone();
+        }
+    }
+
+    /// Finds the references in code starting at the given index of the source.
+    fn code(&mut self, code: &str, start: usize) {
+        let tokens = tokenize(code);
+        let mut index = 0;
+
+        while index < tokens.len() {
+            let (offset, ref token) = tokens[index];
+            let previous = if index > 0 { Some(&tokens[index - 1].1) } else { None };
+            let next = tokens.get(index + 1).map(|&(_, ref t)| t);
+
+            match *token {
+                Token::Identifier("let") | Token::Identifier("for") => {
+                    // Bind the names in the pattern.
+                    let end = tokens[index..].iter().position(|&(_, ref t)| match *t {
+                        Token::Punctuation("=") | Token::Punctuation(";") |
+                        Token::Punctuation(":") | Token::Identifier("in") => true,
+                        _ => false,
+                    }).map(|i| index + i).unwrap_or(tokens.len());
+
+                    self.bind(&tokens[index + 1..end]);
+                    index = end;
+                    continue;
+                },
+                Token::Punctuation("|") if is_closure_start(previous) => {
+                    let end = tokens[index + 1..].iter()
+                        .position(|&(_, ref t)| *t == Token::Punctuation("|"))
+                        .map(|i| index + 1 + i).unwrap_or(tokens.len());
+
+                    self.bind(&tokens[index + 1..end]);
+                    index = end + 1;
+                    continue;
+                },
+                Token::Identifier(name) => {
+                    let is_member = match previous {
+                        Some(&Token::Punctuation(".")) | Some(&Token::Punctuation("::")) => true,
+                        _ => false,
+                    };
+                    let is_path_or_macro = match next {
+                        Some(&Token::Punctuation("!")) | Some(&Token::Punctuation("::")) |
+                        Some(&Token::Punctuation(":")) => true,
+                        _ => false,
+                    };
+
+                    if !is_member && !is_path_or_macro && is_variable_name(name) &&
+                        !self.bound.iter().any(|b| b == name) {
+                        self.references.push(VariableRef {
+                            name: name.to_owned(),
+                            span: (start + offset)..(start + offset + name.len()),
+                        });
+                    }
+                },
+                _ => (),
+            }
+
+            index += 1;
+        }
+    }
+
+    fn bind(&mut self, tokens: &[(usize, Token)]) {
+        for &(_, ref token) in tokens {
+            if let Token::Identifier(name) = *token {
+                if is_variable_name(name) {
+                    self.bound.push(name.to_owned());
+                }
+            }
+        }
+    }
+}
+
+/// Finds the first construct in an expression which is outside of a
+/// simple grammar, describing it, such as `a path`.
+///
+/// The grammar has variables, field accesses, indexing, literals, casts
+/// and operators. Only the given functions, such as the macros of the
+/// template, and methods can be called. Paths, blocks, macros, closures
+/// and other keywords, such as `unsafe`, are outside of it.
+pub fn disallowed_construct(code: &str, functions: &[String], methods: &[String]) -> Option<String> {
+    let tokens = tokenize(code);
+    let token_at = |index: usize| tokens.get(index).map(|&(_, ref token)| token);
+
+    let mut index = 0;
+    while index < tokens.len() {
+        let previous = if index > 0 { token_at(index - 1) } else { None };
+        let is_called = token_at(index + 1) == Some(&Token::Punctuation("("));
+
+        match tokens[index].1 {
+            Token::Identifier(word) if KEYWORDS.contains(&word) && !EXPRESSION_KEYWORDS.contains(&word) => {
+                return Some(format!("'{}'", word));
+            },
+            Token::Identifier(..) if token_at(index + 1) == Some(&Token::Punctuation("!")) &&
+                token_at(index + 2).map_or(false, |t| *t == Token::Punctuation("(") ||
+                                           *t == Token::Punctuation("[") || *t == Token::Punctuation("{")) => {
+                return Some("a macro".to_owned());
+            },
+            Token::Identifier(word) if is_called && previous == Some(&Token::Punctuation(".")) => {
+                if !methods.iter().any(|m| m == word) {
+                    return Some(format!("the method '{}'", word));
+                }
+            },
+            Token::Identifier(word) if is_called && !functions.iter().any(|f| f == word) => {
+                return Some(format!("calling '{}'", word));
+            },
+            Token::Punctuation("::") => return Some("a path".to_owned()),
+            Token::Punctuation("{") | Token::Punctuation("}") => return Some("a block".to_owned()),
+            Token::Punctuation("|") if is_closure_start(previous) => return Some("a closure".to_owned()),
+            // The second half of `||`.
+            Token::Punctuation("|") if token_at(index + 1) == Some(&Token::Punctuation("|")) => index += 1,
+            Token::Punctuation(")") | Token::Punctuation("]") if is_called => {
+                return Some("calling the result of an expression".to_owned());
+            },
+            Token::Punctuation(p) if !EXPRESSION_PUNCTUATION.contains(&p) => return Some(format!("'{}'", p)),
+            _ => (),
+        }
+        index += 1;
+    }
+
+    None
+}
+
+/// Finds the first construct in a binding which is outside of a simple
+/// grammar, describing it, such as `'|'`.
+///
+/// A binding is the pattern of a `for` block, or a macro parameter
+/// with an optional type. Patterns are names and tuples of them, and
+/// types are paths with generic arguments, references, slices and tuples.
+pub fn disallowed_binding(binding: &str) -> Option<String> {
+    let tokens = tokenize(binding);
+    let colon = tokens.iter().position(|&(_, ref token)| *token == Token::Punctuation(":")).unwrap_or(tokens.len());
+
+    disallowed_token(&tokens[..colon], PATTERN_PUNCTUATION, &["mut", "ref"]).or_else(|| {
+        tokens.get(colon + 1..).and_then(|tokens| disallowed_token(tokens, TYPE_PUNCTUATION, &["mut", "dyn"]))
+    })
+}
+
+/// Finds the first token which is not a name, one of the given keywords
+/// or one of the given punctuation, describing it.
+fn disallowed_token(tokens: &[(usize, Token)], punctuation: &[&str], keywords: &[&str]) -> Option<String> {
+    tokens.iter().filter_map(|&(_, ref token)| match *token {
+        Token::Identifier(word) if KEYWORDS.contains(&word) && !keywords.contains(&word) => {
+            Some(format!("'{}'", word))
+        },
+        Token::Identifier(..) => None,
+        Token::Punctuation(p) if punctuation.contains(&p) => None,
+        Token::Punctuation(p) => Some(format!("'{}'", p)),
+        Token::Literal => Some("a literal".to_owned()),
+    }).next()
+}
+
+/// Checks whether a `|` after the given token begins a closure.
+fn is_closure_start(previous: Option<&Token>) -> bool {
+    match previous {
+        None => true,
+        Some(&Token::Punctuation(p)) => p != ")" && p != "]" && p != "}",
+        Some(&Token::Identifier(word)) => KEYWORDS.contains(&word),
+        Some(&Token::Literal) => false,
+    }
+}
+
+/// Checks whether code awaits a value, with `.await`.
+pub fn awaits(code: &str) -> bool {
+    let tokens = tokenize(code);
+    tokens.windows(2).any(|pair| pair[0].1 == Token::Punctuation(".") && pair[1].1 == Token::Identifier("await"))
+}
+
+/// Checks whether a word is a Rust keyword.
+pub fn is_keyword(word: &str) -> bool {
+    KEYWORDS.contains(&word)
+}
+
+/// Checks whether an identifier could be a variable.
+fn is_variable_name(name: &str) -> bool {
+    let first = name.chars().next().unwrap();
+    (first.is_lowercase() || first == '_') && name != "_" && !KEYWORDS.contains(&name)
+}
+
+/// Gets the identifiers in code, with their byte offsets.
+fn identifiers(code: &str) -> Vec<(usize, &str)> {
+    tokenize(code).into_iter().filter_map(|(offset, token)| match token {
+        Token::Identifier(name) if is_variable_name(name) => Some((offset, name)),
+        _ => None,
+    }).collect()
+}
+
+/// Finds the calls of a function in code, giving the byte range of the
+/// path called, such as `client::fetch` in `client::fetch(url)`.
+///
+/// Method calls, calls with turbofish and macros with the same name
+/// are not found.
+pub fn function_calls(code: &str, name: &str) -> Vec<Range<usize>> {
+    let tokens = tokenize(code);
+    let mut calls = Vec::new();
+
+    for (index, &(offset, ref token)) in tokens.iter().enumerate() {
+        let is_call = *token == Token::Identifier(name) &&
+            tokens.get(index + 1).map_or(false, |&(_, ref next)| *next == Token::Punctuation("("));
+        if !is_call {
+            continue;
+        }
+
+        // Walk back over the rest of the path.
+        let mut start = index;
+        while start >= 2 && tokens[start - 1].1 == Token::Punctuation("::") {
+            match tokens[start - 2].1 {
+                Token::Identifier(..) => start -= 2,
+                _ => break,
+            }
+        }
+        if start >= 1 && tokens[start - 1].1 == Token::Punctuation("::") {
+            start -= 1;
+        }
+
+        let is_excluded = start >= 1 && match tokens[start - 1].1 {
+            Token::Punctuation(".") | Token::Identifier("fn") => true,
+            _ => false,
+        };
+        if !is_excluded {
+            calls.push(tokens[start].0..(offset + name.len()));
+        }
+    }
+
+    calls
+}
+
+/// Splits code into tokens, with their byte offsets.
+fn tokenize(code: &str) -> Vec<(usize, Token<'_>)> {
+    let bytes = code.as_bytes();
+    let mut tokens = Vec::new();
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let c = bytes[index];
+        let start = index;
+
+        if c.is_ascii_whitespace() {
+            index += 1;
+        } else if c == b'"' || (c == b'r' && bytes.get(index + 1).map_or(false, |&n| n == b'"' || n == b'#')) {
+            index = end_of_string(bytes, index);
+            tokens.push((start, Token::Literal));
+        } else if c == b'\'' {
+            // Either a character literal or a lifetime.
+            let is_char = bytes.get(index + 1) == Some(&b'\\') ||
+                code[index + 1..].chars().nth(1) == Some('\'');
+            if is_char {
+                index += 1;
+                while index < bytes.len() && bytes[index] != b'\'' {
+                    index += if bytes[index] == b'\\' { 2 } else { 1 };
+                }
+                index += 1;
+                tokens.push((start, Token::Literal));
+            } else {
+                index += 1;
+                while index < bytes.len() && is_identifier_byte(bytes[index]) {
+                    index += 1;
+                }
+            }
+        } else if c.is_ascii_digit() {
+            while index < bytes.len() && is_identifier_byte(bytes[index]) {
+                index += 1;
+            }
+            tokens.push((start, Token::Literal));
+        } else if is_identifier_byte(c) {
+            while index < bytes.len() && is_identifier_byte(bytes[index]) {
+                index += 1;
+            }
+            tokens.push((start, Token::Identifier(&code[start..index])));
+        } else if code[index..].starts_with("::") {
+            index += 2;
+            tokens.push((start, Token::Punctuation("::")));
+        } else {
+            index += code[index..].chars().next().unwrap().len_utf8();
+            tokens.push((start, Token::Punctuation(&code[start..index])));
+        }
+    }
+
+    tokens
+}
+
+/// Finds the index just past a string literal starting at the given index.
+fn end_of_string(bytes: &[u8], mut index: usize) -> usize {
+    if bytes[index] == b'r' {
+        index += 1;
+        let hashes = bytes[index..].iter().take_while(|&&b| b == b'#').count();
+        index += hashes + 1;
+
+        while index < bytes.len() {
+            if bytes[index] == b'"' && bytes[index + 1..].iter().take(hashes).filter(|&&b| b == b'#').count() == hashes {
+                return index + 1 + hashes;
+            }
+            index += 1;
+        }
+        return bytes.len();
+    }
+
+    index += 1;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'\\' => index += 2,
+            b'"' => return index + 1,
+            _ => index += 1,
+        }
+    }
+
+    bytes.len()
+}
+
+fn is_identifier_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_' || byte >= 0x80
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn names(source: &str) -> Vec<String> {
+        let ast = parse::parse_str(source).unwrap();
+        referenced_variables(&ast).into_iter().map(|v| v.name).collect()
+    }
+
+    #[test]
+    fn finds_variables_in_expressions() {
+        assert_eq!(names("<%= user.name %> <%= format!(\"{}\", count) %> <%= Some(x) %>"),
+                   vec!["user", "count", "x"]);
+    }
+
+    #[test]
+    fn ignores_literals_paths_and_fields() {
+        assert_eq!(names("<%= \"a b\".len() + 'c'.len_utf8() + std::u8::MAX + r#\"d\"# %>"),
+                   Vec::<String>::new());
+    }
+
+    #[test]
+    fn ignores_names_bound_by_the_template() {
+        assert_eq!(names("<% let total = 1; %><% for (i, item) in items %><%= i + item + total %><% end %>\
+                          <% macro link(url: &str) %><%= url %><% end %><%= link(\"x\") %>\
+                          <% capture c %><%= y %><% end %><%= c %>\
+                          <%= values.iter().map(|v| v * 2).count() %>"),
+                   vec!["items", "y", "values"]);
+    }
+
+    #[test]
+    fn scopes_bindings_to_blocks() {
+        assert_eq!(names("<% for x in xs %><% end %><%= x %>"), vec!["xs", "x"]);
+        assert_eq!(names("<% with user = order.customer %><%= user.name %><% end %><%= user %>"), vec!["order", "user"]);
+        assert_eq!(names("<% set total = price * qty %><% if total > 0 %><% set tax = total %><% end %><%= total + tax %>"),
+                   vec!["price", "qty", "tax"]);
+    }
+
+    #[test]
+    fn finds_the_spans_of_variables() {
+        let source = "ab<%= x + yz %><% if w %><% end %>";
+        let ast = parse::parse_str(source).unwrap();
+
+        assert_eq!(referenced_variables(&ast), vec![
+            VariableRef { name: "x".to_owned(), span: 6..7 },
+            VariableRef { name: "yz".to_owned(), span: 10..12 },
+            VariableRef { name: "w".to_owned(), span: 15..34 },
+        ]);
+    }
+
+    #[test]
+    fn finds_function_calls() {
+        let code = "fetch(a) + api::fetch(b) + x.fetch(c) + fetch!(d) + \"fetch(e)\"; fn fetch() {}";
+        assert_eq!(function_calls(code, "fetch"), vec![0..5, 11..21]);
+        assert_eq!(function_calls("::api::fetch (1)", "fetch"), vec![0..12]);
+    }
+
+    #[test]
+    fn limits_bindings_to_names_and_simple_types() {
+        assert_eq!(disallowed_binding("(i, mut item)"), None);
+        assert_eq!(disallowed_binding("url: &'a std::collections::HashMap<String, Vec<u8>>"), None);
+        assert_eq!(disallowed_binding("x: u8| exit(7); let g = |y: u8"), Some("'|'".to_owned()));
+        assert_eq!(disallowed_binding("_ in[0u8]{}exit(9);for _"), Some("'in'".to_owned()));
+        assert_eq!(disallowed_binding("Some(\"a\")"), Some("a literal".to_owned()));
+    }
+}
@@ -1,33 +1,121 @@
-#[derive(Clone, Debug, PartialEq)]
-pub struct Ast
-{
-    pub items: Vec<Item>,
-}
+//! The syntax tree of templates.
+//!
+//! The types live in the `tempo-ast` crate, so that tools can build on
+//! them without the rest of tempo, and are re-exported here. Methods
+//! which need the rest of tempo are added by `AstExt`.
+
+pub use tempo_ast::*;
+
+use analysis;
 
-#[derive(Clone, Debug, PartialEq)]
-pub struct Item
+/// Methods of an AST which need tempo's analysis.
+pub trait AstExt
 {
-    pub kind: ItemKind,
+    /// Finds the variables referenced by the template but not defined by
+    /// it. See `analysis::referenced_variables`.
+    fn referenced_variables(&self) -> Vec<analysis::VariableRef>;
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum ItemKind
+impl AstExt for Ast
 {
-    /// A normal piece of text.
-    Text(String),
-    /// A block of code.
-    Code {
-        /// The source code.
-        source: String,
-        /// Whether the result should be printed.
-        print_result: bool,
+    fn referenced_variables(&self) -> Vec<analysis::VariableRef> {
+        analysis::referenced_variables(self)
     }
 }
 
-impl From<Vec<Item>> for Ast
-{
-    fn from(items: Vec<Item>) -> Ast {
-        Ast { items: items }
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ops::Range;
+
+    fn text(text: &str) -> Item {
+        Item::new(ItemKind::Text(text.to_owned()))
+    }
+
+    #[test]
+    fn builds_asts_which_parse_the_same() {
+        let ast = AstBuilder::new()
+            .comment("generated")
+            .default_value("title", "\"Home\"")
+            .if_else_block("user.is_admin", |b| b.text("admin"), |b| b.print_expr("user.name"))
+            .macro_block("link", &["href: &str"], |b| b.print_expr("href"))
+            .capture_block("footer", |b| b.include("footer.trs"))
+            .assert("!title.is_empty()", Some("no title"))
+            .code("let x = 1;")
+            .build();
+
+        assert_eq!(::parse::parse_str(&ast.to_string()).unwrap(), ast);
+    }
+
+    #[test]
+    fn finds_referenced_variables() {
+        let ast = ::parse::parse_str("<% for x in xs %><%= x + y %><% end %>").unwrap();
+        let names: Vec<_> = ast.referenced_variables().into_iter().map(|v| v.name).collect();
+        assert_eq!(names, vec!["xs", "y"]);
+    }
+
+    fn spans(items: &[Item]) -> Vec<Range<usize>> {
+        items.iter().map(|item| item.span.clone()).collect()
     }
-}
 
+    #[test]
+    fn composes_asts_with_joined_spans() {
+        let parse = |source: &str| ::parse::parse_str(source).unwrap();
+
+        let mut ast = parse("a<%= x %>");
+        ast.append(parse("b"));
+        ast.extend(parse("<% if y %>c<% end %>").items);
+        let joined = parse("a<%= x %>b<% if y %>c<% end %>");
+        assert_eq!(ast, joined);
+        assert_eq!(spans(&ast.items), spans(&joined.items));
+        assert_eq!(spans(&ast.items[3].kind.children().into_iter().cloned().collect::<Vec<_>>()), vec![20..21]);
+
+        let removed = ast.splice(1..3, parse("<%# note %>"));
+        let spliced = parse("a<%# note %><% if y %>c<% end %>");
+        assert_eq!(removed, vec![parse("<%= x %>b").items[0].clone(), text("b")]);
+        assert_eq!(ast, spliced);
+        assert_eq!(spans(&ast.items), spans(&spliced.items));
+    }
+
+    #[test]
+    fn keeps_spans_of_named_templates() {
+        let mut header = ::parse::parse_str("head").unwrap();
+        header.set_origin("header");
+
+        let mut ast = ::parse::parse_str("body").unwrap();
+        ast.splice(0..0, header);
+        ast.append(::parse::parse_str("foot").unwrap());
+        assert_eq!(spans(&ast.items), vec![0..4, 0..4, 4..8]);
+    }
+
+    #[test]
+    fn hashes_the_meaning_of_templates() {
+        let hash = |source: &str| ::parse::parse_str(source).unwrap().content_hash();
+        let original = hash("a<%= x %>b<% if  ready %>c<% end %>");
+
+        assert_eq!(hash("a<%=x%><%# note %>b<% if ready %>c<% end %>"), original);
+        assert_ne!(hash("a<%= y %>b<% if ready %>c<% end %>"), original);
+        assert_ne!(hash("a<%= x %>b <% if ready %>c<% end %>"), original);
+        assert_ne!(hash("<%= \"a  b\" %>"), hash("<%= \"a b\" %>"));
+        assert_ne!(hash("<%= ('\"', \"a  b\") %>"), hash("<%= ('\"', \"a b\") %>"));
+        assert_ne!(hash("<%= r#\"a\"  b\"# %>"), hash("<%= r#\"a\" b\"# %>"));
+        assert_ne!(hash("<%= br\"a  b\" %>"), hash("<%= br\"a b\" %>"));
+        assert_eq!(hash("<%= f::<'a>(  x ) %>"), hash("<%= f::<'a>( x ) %>"));
+    }
+
+    #[test]
+    fn finds_named_blocks() {
+        let ast = ::parse::parse_str("<% block a %><% if x %><% block b %>1<% end %><% end %><% end %>\
+                                      <% block c %>2<% end %>").unwrap();
+        assert_eq!(ast.block_names(), vec!["a", "b", "c"]);
+        assert_eq!(ast.block("b").unwrap().span, 23..46);
+        assert!(ast.block("d").is_none());
+    }
+
+    #[test]
+    fn finds_features() {
+        let ast = ::parse::parse_str("<% for x in xs %><%= x %><% include \"a\" %><% end %><%= y %>").unwrap();
+        assert_eq!(ast.features(), vec![Feature::Print, Feature::Loop, Feature::Include]);
+        assert_eq!(::parse::parse_str("a<% if x %>b<% else %>c<% end %>").unwrap().features(), vec![Feature::Condition]);
+    }
+}
@@ -0,0 +1,171 @@
+//! Storage for the fragments rendered by `cache` blocks.
+//!
+//! Templates translated with `trans::Config::fragment_cache` look up
+//! the output of their `cache` blocks through a function given to their
+//! render function. A `CacheStore`, such as an `LruCache`, gives one
+//! with `lookup`.
+//!
+//! ```text
+//! render(&mut output, &cache.lookup())
+//! ```
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// A function fetching a fragment by key, rendering it with the given
+/// function if it is not stored.
+pub type FragmentLookup<'a> = Box<dyn Fn(&str, &mut dyn FnMut() -> String) -> String + 'a>;
+
+/// Stores rendered fragments by key.
+///
+/// Stores are shared by every render, so they are changed through
+/// shared references. An application can implement this to keep
+/// fragments in a service such as Redis.
+pub trait CacheStore
+{
+    /// Gets the fragment stored under a key.
+    fn get(&self, key: &str) -> Option<String>;
+
+    /// Stores a fragment under a key.
+    fn put(&self, key: &str, fragment: String);
+
+    /// Removes the fragment stored under a key, so that it is
+    /// rendered again when it is next used.
+    fn invalidate(&self, key: &str);
+
+    /// Gets the fragment stored under a key, rendering and storing
+    /// it if there is none.
+    fn fetch(&self, key: &str, render: &mut dyn FnMut() -> String) -> String {
+        if let Some(fragment) = self.get(key) {
+            return fragment;
+        }
+
+        let fragment = render();
+        self.put(key, fragment.clone());
+        fragment
+    }
+
+    /// Gets a function which fetches fragments, as taken by
+    /// render functions.
+    fn lookup<'a>(&'a self) -> FragmentLookup<'a>
+        where Self: Sized {
+        Box::new(move |key, render| self.fetch(key, render))
+    }
+}
+
+/// Fragments kept in memory, up to a number of them.
+///
+/// When the cache is full, the fragment which was used least
+/// recently is removed.
+#[derive(Debug)]
+pub struct LruCache
+{
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+/// The fragments of an `LruCache`.
+#[derive(Debug, Default)]
+struct Entries
+{
+    /// Each fragment, with the time it was last used.
+    fragments: HashMap<String, (String, u64)>,
+    /// The number of times fragments have been used, which is
+    /// the time of the next use.
+    uses: u64,
+}
+
+impl LruCache
+{
+    /// Creates an empty cache which holds up to a number of fragments.
+    pub fn new(capacity: usize) -> Self {
+        LruCache { capacity: capacity, entries: Mutex::new(Entries::default()) }
+    }
+
+    /// Gets the number of fragments in the cache.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().fragments.len()
+    }
+
+    /// Checks whether the cache has no fragments.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes every fragment.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().fragments.clear();
+    }
+}
+
+impl CacheStore for LruCache
+{
+    fn get(&self, key: &str) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        let time = entries.uses;
+
+        match entries.fragments.get_mut(key) {
+            Some(&mut (ref fragment, ref mut last_used)) => {
+                *last_used = time;
+                let fragment = fragment.clone();
+                entries.uses += 1;
+                Some(fragment)
+            },
+            None => None,
+        }
+    }
+
+    fn put(&self, key: &str, fragment: String) {
+        let mut entries = self.entries.lock().unwrap();
+        let time = entries.uses;
+        entries.uses += 1;
+        entries.fragments.insert(key.to_owned(), (fragment, time));
+
+        if entries.fragments.len() > self.capacity {
+            let oldest = entries.fragments.iter()
+                .min_by_key(|&(_, &(_, last_used))| last_used)
+                .map(|(key, _)| key.clone()).unwrap();
+            entries.fragments.remove(&oldest);
+        }
+    }
+
+    fn invalidate(&self, key: &str) {
+        self.entries.lock().unwrap().fragments.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renders_fragments_once() {
+        let cache = LruCache::new(10);
+        let mut renders = 0;
+
+        for _ in 0..3 {
+            let fragment = cache.fetch("sidebar", &mut || { renders += 1; "links".to_owned() });
+            assert_eq!(fragment, "links");
+        }
+        assert_eq!(renders, 1);
+
+        cache.invalidate("sidebar");
+        assert_eq!(cache.lookup()("sidebar", &mut || "new links".to_owned()), "new links");
+    }
+
+    #[test]
+    fn removes_least_recently_used_fragments() {
+        let cache = LruCache::new(2);
+        cache.put("a", "1".to_owned());
+        cache.put("b", "2".to_owned());
+        cache.get("a");
+        cache.put("c", "3".to_owned());
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("a"), Some("1".to_owned()));
+        assert_eq!(cache.get("b"), None);
+
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
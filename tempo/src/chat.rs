@@ -0,0 +1,150 @@
+//! Formatting for chat notifications, such as Slack and Discord messages.
+//!
+//! These are for use by the code in templates, so that data can be
+//! written into a message without being read as formatting.
+//!
+//! ```text
+//! <%= tempo::chat::escape_mrkdwn(&user.name) %> signed up.
+//! ```
+//!
+//! Slack's Block Kit payloads can be built with `Blocks`, which gives
+//! the JSON of the `blocks` field of a message.
+
+use value::json_string;
+
+use std::fmt;
+
+/// Escapes text for Slack's mrkdwn.
+///
+/// Slack only needs `&`, `<` and `>` escaped, so that text is not read
+/// as a link or a mention.
+pub fn escape_mrkdwn(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Escapes text for Discord's Markdown.
+///
+/// Formatting characters are escaped with a backslash, and mentions
+/// of `@everyone` and `@here` are broken so that they do not notify.
+pub fn escape_discord(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        if "\\*_~`|>#-[]<".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    // A zero width space after the @ keeps the text but not the mention.
+    escaped.replace("@everyone", "@\u{200b}everyone").replace("@here", "@\u{200b}here")
+}
+
+/// The blocks of a Slack message, in order.
+///
+/// Text given to sections and context is mrkdwn, and must already be
+/// escaped where it holds data. Header text is plain.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Blocks
+{
+    /// The JSON of each block.
+    blocks: Vec<String>,
+}
+
+impl Blocks
+{
+    /// Creates a message with no blocks.
+    pub fn new() -> Self {
+        Blocks::default()
+    }
+
+    /// Adds a header in large, plain text.
+    pub fn header(mut self, text: &str) -> Self {
+        self.blocks.push(format!("{{\"type\":\"header\",\"text\":{}}}", plain_text(text)));
+        self
+    }
+
+    /// Adds a section of mrkdwn text.
+    pub fn section(mut self, text: &str) -> Self {
+        self.blocks.push(format!("{{\"type\":\"section\",\"text\":{}}}", mrkdwn_text(text)));
+        self
+    }
+
+    /// Adds a section of mrkdwn fields, shown in two columns.
+    pub fn fields(mut self, fields: &[&str]) -> Self {
+        let fields: Vec<_> = fields.iter().map(|f| mrkdwn_text(f)).collect();
+        self.blocks.push(format!("{{\"type\":\"section\",\"fields\":[{}]}}", fields.join(",")));
+        self
+    }
+
+    /// Adds a line between blocks.
+    pub fn divider(mut self) -> Self {
+        self.blocks.push("{\"type\":\"divider\"}".to_owned());
+        self
+    }
+
+    /// Adds small, grey mrkdwn text, such as a footnote.
+    pub fn context(mut self, elements: &[&str]) -> Self {
+        let elements: Vec<_> = elements.iter().map(|e| mrkdwn_text(e)).collect();
+        self.blocks.push(format!("{{\"type\":\"context\",\"elements\":[{}]}}", elements.join(",")));
+        self
+    }
+
+    /// Adds a row of buttons, each linking to a URL.
+    pub fn buttons(mut self, buttons: &[(&str, &str)]) -> Self {
+        let elements: Vec<_> = buttons.iter().map(|&(text, url)| {
+            format!("{{\"type\":\"button\",\"text\":{},\"url\":{}}}", plain_text(text), json_string(url))
+        }).collect();
+        self.blocks.push(format!("{{\"type\":\"actions\",\"elements\":[{}]}}", elements.join(",")));
+        self
+    }
+
+    /// Gets the JSON array of the blocks.
+    pub fn to_json(&self) -> String {
+        format!("[{}]", self.blocks.join(","))
+    }
+}
+
+impl fmt::Display for Blocks
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.to_json())
+    }
+}
+
+/// Gets the JSON of a plain text object.
+fn plain_text(text: &str) -> String {
+    format!("{{\"type\":\"plain_text\",\"text\":{}}}", json_string(text))
+}
+
+/// Gets the JSON of a mrkdwn text object.
+fn mrkdwn_text(text: &str) -> String {
+    format!("{{\"type\":\"mrkdwn\",\"text\":{}}}", json_string(text))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn escapes_text() {
+        assert_eq!(escape_mrkdwn("<@U1> & co"), "&lt;@U1&gt; &amp; co");
+        assert_eq!(escape_discord("*bold* @everyone"), "\\*bold\\* @\u{200b}everyone");
+    }
+
+    #[test]
+    fn builds_blocks() {
+        let blocks = Blocks::new()
+            .header("Order shipped")
+            .section(&format!("*{}* is on its way", escape_mrkdwn("Tea & cake")))
+            .divider()
+            .buttons(&[("Track", "https://example.com/track")]);
+
+        assert_eq!(blocks.to_json(), "[\
+            {\"type\":\"header\",\"text\":{\"type\":\"plain_text\",\"text\":\"Order shipped\"}},\
+            {\"type\":\"section\",\"text\":{\"type\":\"mrkdwn\",\"text\":\"*Tea &amp; cake* is on its way\"}},\
+            {\"type\":\"divider\"},\
+            {\"type\":\"actions\",\"elements\":[{\"type\":\"button\",\"text\":\
+            {\"type\":\"plain_text\",\"text\":\"Track\"},\"url\":\"https://example.com/track\"}]}]");
+    }
+}
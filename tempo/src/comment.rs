@@ -0,0 +1,94 @@
+//! Comment syntax of output languages.
+
+use std::path::Path;
+
+/// The syntax of comments in a language.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Syntax
+{
+    /// Comments starting with a prefix and ending at the end of the line.
+    Line(&'static str),
+    /// Comments between an opening and closing delimiter.
+    Block(&'static str, &'static str),
+}
+
+impl Syntax
+{
+    /// Gets the comment syntax of files with the given extension.
+    pub fn for_extension(extension: &str) -> Option<Self> {
+        match &extension.to_lowercase()[..] {
+            "rs" | "c" | "h" | "cc" | "cpp" | "hpp" | "cs" | "java" | "kt" | "scala" |
+            "go" | "swift" | "js" | "ts" | "jsx" | "tsx" | "dart" | "proto" => Some(Syntax::Line("//")),
+            "py" | "rb" | "sh" | "bash" | "zsh" | "pl" | "r" | "yml" | "yaml" | "toml" |
+            "conf" | "cfg" | "nix" | "tf" | "dockerfile" | "mk" => Some(Syntax::Line("#")),
+            "sql" | "lua" | "hs" | "elm" => Some(Syntax::Line("--")),
+            "ini" | "asm" | "s" | "lisp" | "clj" | "el" => Some(Syntax::Line(";")),
+            "tex" | "erl" => Some(Syntax::Line("%")),
+            "vim" => Some(Syntax::Line("\"")),
+            "html" | "htm" | "xml" | "svg" | "md" | "vue" => Some(Syntax::Block("<!--", "-->")),
+            "css" | "scss" | "less" => Some(Syntax::Block("/*", "*/")),
+            "ml" | "mli" => Some(Syntax::Block("(*", "*)")),
+            _ => None,
+        }
+    }
+
+    /// Gets the comment syntax of a file from its extension.
+    pub fn for_path<P>(path: P) -> Option<Self>
+        where P: AsRef<Path> {
+        path.as_ref().extension().and_then(|e| e.to_str()).and_then(Syntax::for_extension)
+    }
+
+    /// Gets the comment syntax of the output of a template from the
+    /// template's path, ignoring a `.trs` extension.
+    pub fn for_template_path<P>(path: P) -> Option<Self>
+        where P: AsRef<Path> {
+        let path = path.as_ref();
+
+        if path.extension().map_or(false, |e| e == "trs") {
+            Syntax::for_path(path.with_extension(""))
+        } else {
+            Syntax::for_path(path)
+        }
+    }
+
+    /// Wraps text in a comment, including a trailing new line.
+    ///
+    /// Line comments are repeated for each line of the text.
+    pub fn comment(&self, text: &str) -> String {
+        match *self {
+            Syntax::Line(prefix) => text.lines().map(|line| {
+                if line.is_empty() {
+                    format!("{}\n", prefix)
+                } else {
+                    format!("{} {}\n", prefix, line)
+                }
+            }).collect(),
+            Syntax::Block(open, close) => format!("{} {} {}\n", open, text, close),
+        }
+    }
+}
+
+/// Builds a notice stating that a file was generated from a template.
+pub fn provenance_notice(template_path: &str, generated_at: &str) -> String {
+    format!("Generated by tempo from {} at {}. Do not edit.", template_path, generated_at)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn infers_syntax_from_paths() {
+        assert_eq!(Syntax::for_path("src/main.rs"), Some(Syntax::Line("//")));
+        assert_eq!(Syntax::for_path("config.YAML"), Some(Syntax::Line("#")));
+        assert_eq!(Syntax::for_path("index.html"), Some(Syntax::Block("<!--", "-->")));
+        assert_eq!(Syntax::for_path("README"), None);
+        assert_eq!(Syntax::for_template_path("page.css.trs"), Some(Syntax::Block("/*", "*/")));
+    }
+
+    #[test]
+    fn comments_each_line() {
+        assert_eq!(Syntax::Line("#").comment("a\nb"), "# a\n# b\n");
+        assert_eq!(Syntax::Block("/*", "*/").comment("a"), "/* a */\n");
+    }
+}
@@ -0,0 +1,661 @@
+//! Tables of data given to templates.
+//!
+//! Tables are read from CSV or JSON, with the types of their columns
+//! inferred from their values, and become a vector of structs in the
+//! generated code so that templates can use `row.column` as they would
+//! with their own types.
+
+use {Error, ErrorKind};
+
+use {analysis, position};
+use value::Value;
+
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+/// The deepest arrays and objects may be nested in JSON values.
+const MAX_JSON_DEPTH: usize = 128;
+
+/// The Rust type of a column.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ColumnType
+{
+    /// Whole numbers, as `i64`.
+    Int,
+    /// Finite numbers, as `f64`.
+    Float,
+    /// `true` or `false`, as `bool`.
+    Bool,
+    /// Anything else, as `&'static str`.
+    Text,
+}
+
+/// A column of a table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Column
+{
+    /// The name of the field holding the column, derived from its header.
+    pub name: String,
+    pub ty: ColumnType,
+}
+
+/// A table of data.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Table
+{
+    pub columns: Vec<Column>,
+    /// The values of each row, in the order of the columns.
+    pub rows: Vec<Vec<String>>,
+}
+
+impl ColumnType
+{
+    /// Gets the Rust type of values in the column.
+    pub fn rust_type(&self) -> &'static str {
+        match *self {
+            ColumnType::Int => "i64",
+            ColumnType::Float => "f64",
+            ColumnType::Bool => "bool",
+            ColumnType::Text => "&'static str",
+        }
+    }
+
+    /// Infers the type of a column from its values.
+    fn infer(values: &[&str]) -> Self {
+        if values.iter().all(|v| v.parse::<i64>().is_ok()) {
+            ColumnType::Int
+        } else if values.iter().all(|v| v.parse::<f64>().map(|f| f.is_finite()).unwrap_or(false)) {
+            ColumnType::Float
+        } else if values.iter().all(|v| *v == "true" || *v == "false") {
+            ColumnType::Bool
+        } else {
+            ColumnType::Text
+        }
+    }
+}
+
+/// Parses a table from CSV with a header row.
+///
+/// Fields may be quoted with `"`, in which case they can hold commas,
+/// new lines and `""` for a quote. Field names are the headers in
+/// lower case, with anything which is not a letter, digit or `_`
+/// replaced by `_`.
+pub fn parse_csv(text: &str) -> Result<Table, Error> {
+    let mut records = csv_records(text)?.into_iter();
+
+    let headers = match records.next() {
+        Some(headers) => headers,
+        None => return Err(ErrorKind::InvalidCsv(1, "there is no header row".to_owned()).into()),
+    };
+    let rows: Vec<Vec<String>> = records.collect();
+
+    for (index, row) in rows.iter().enumerate() {
+        if row.len() != headers.len() {
+            let reason = format!("expected {} fields but found {}", headers.len(), row.len());
+            return Err(ErrorKind::InvalidCsv(index + 2, reason).into());
+        }
+    }
+
+    let mut columns: Vec<Column> = Vec::new();
+    for (index, name) in column_names(&headers).into_iter().enumerate() {
+        if columns.iter().any(|c| c.name == name) {
+            let reason = format!("more than one column is named '{}'", name);
+            return Err(ErrorKind::InvalidCsv(1, reason).into());
+        }
+
+        let values: Vec<_> = rows.iter().map(|row| &row[index][..]).collect();
+        columns.push(Column { name: name, ty: ColumnType::infer(&values) });
+    }
+
+    Ok(Table { columns: columns, rows: rows })
+}
+
+/// Parses a table from a JSON array of objects, such as
+/// `[{"id": 1, "name": "Alice"}]`.
+///
+/// There is a column for each key of any of the objects. The values
+/// must be strings, numbers, booleans or `null`, which is an empty
+/// string, as are missing keys.
+pub fn parse_json(text: &str) -> Result<Table, Error> {
+    let mut parser = JsonParser { text: text, chars: text.char_indices().peekable() };
+    let objects = parser.rows()?;
+
+    let mut headers: Vec<String> = Vec::new();
+    for object in objects.iter() {
+        for &(ref key, _) in object.iter() {
+            if !headers.contains(key) {
+                headers.push(key.clone());
+            }
+        }
+    }
+
+    let mut columns: Vec<Column> = Vec::new();
+    for (header, name) in headers.iter().zip(column_names(&headers)) {
+        if columns.iter().any(|c| c.name == name) {
+            let reason = format!("more than one key is named '{}'", name);
+            return Err(ErrorKind::InvalidJson(1, reason).into());
+        }
+
+        let values: Vec<_> = objects.iter().map(|object| {
+            object.iter().find(|&&(ref key, _)| key == header).map(|&(_, ref value)| value)
+        }).collect();
+
+        let is_number = |v: &Option<&JsonScalar>| match *v { Some(&JsonScalar::Number(..)) => true, _ => false };
+        let ty = if values.iter().all(|v| is_number(v)) {
+            let texts: Vec<&str> = values.iter().map(|v| v.unwrap().text()).collect();
+            match ColumnType::infer(&texts) {
+                ColumnType::Int => ColumnType::Int,
+                _ => ColumnType::Float,
+            }
+        } else if values.iter().all(|v| match *v { Some(&JsonScalar::Bool(..)) => true, _ => false }) {
+            ColumnType::Bool
+        } else {
+            ColumnType::Text
+        };
+
+        columns.push(Column { name: name, ty: ty });
+    }
+
+    let rows = objects.iter().map(|object| {
+        headers.iter().map(|header| {
+            object.iter().find(|&&(ref key, _)| key == header)
+                .map(|&(_, ref value)| value.text().to_owned())
+                .unwrap_or_default()
+        }).collect()
+    }).collect();
+
+    Ok(Table { columns: columns, rows: rows })
+}
+
+/// Parses any JSON, such as the response of an API, into a value.
+///
+/// Numbers are integers if they fit in an `i64` and are written without
+/// a fraction or exponent, and floats otherwise. When an object has a key
+/// more than once, the last value is kept.
+pub fn parse_json_value(text: &str) -> Result<Value, Error> {
+    let mut parser = JsonParser { text: text, chars: text.char_indices().peekable() };
+    let value = parser.value(0)?;
+
+    parser.skip_whitespace();
+    match parser.chars.peek() {
+        Some(..) => Err(parser.error("expected the end of the text")),
+        None => Ok(value),
+    }
+}
+
+/// A value in a JSON object.
+enum JsonScalar
+{
+    Null,
+    Bool(String),
+    Number(String),
+    Text(String),
+}
+
+impl JsonScalar
+{
+    /// Gets the value as it is given to templates.
+    fn text(&self) -> &str {
+        match *self {
+            JsonScalar::Null => "",
+            JsonScalar::Bool(ref text) |
+                JsonScalar::Number(ref text) |
+                JsonScalar::Text(ref text) => text,
+        }
+    }
+}
+
+/// Reads the objects of a JSON array.
+struct JsonParser<'a>
+{
+    text: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> JsonParser<'a>
+{
+    /// Reads the whole text as an array of objects.
+    fn rows(&mut self) -> Result<Vec<Vec<(String, JsonScalar)>>, Error> {
+        let mut rows = Vec::new();
+
+        self.expect('[')?;
+        if !self.next_is(']') {
+            loop {
+                rows.push(self.object()?);
+                if !self.next_is(',') {
+                    break;
+                }
+            }
+            self.expect(']')?;
+        }
+
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some(..) => Err(self.error("expected the end of the text")),
+            None => Ok(rows),
+        }
+    }
+
+    fn object(&mut self) -> Result<Vec<(String, JsonScalar)>, Error> {
+        let mut fields = Vec::new();
+
+        self.expect('{')?;
+        if !self.next_is('}') {
+            loop {
+                self.expect('"')?;
+                let key = self.string()?;
+                self.expect(':')?;
+                fields.push((key, self.scalar()?));
+                if !self.next_is(',') {
+                    break;
+                }
+            }
+            self.expect('}')?;
+        }
+
+        Ok(fields)
+    }
+
+    /// Reads a value of any kind, including arrays and objects, inside
+    /// a number of others.
+    fn value(&mut self, depth: usize) -> Result<Value, Error> {
+        if depth == MAX_JSON_DEPTH {
+            return Err(self.error("arrays and objects are nested too deeply"));
+        }
+
+        if self.next_is('[') {
+            let mut list = Vec::new();
+            if !self.next_is(']') {
+                loop {
+                    list.push(self.value(depth + 1)?);
+                    if !self.next_is(',') {
+                        break;
+                    }
+                }
+                self.expect(']')?;
+            }
+            return Ok(Value::List(list));
+        }
+
+        if self.next_is('{') {
+            let mut map = BTreeMap::new();
+            if !self.next_is('}') {
+                loop {
+                    self.expect('"')?;
+                    let key = self.string()?;
+                    self.expect(':')?;
+                    map.insert(key, self.value(depth + 1)?);
+                    if !self.next_is(',') {
+                        break;
+                    }
+                }
+                self.expect('}')?;
+            }
+            return Ok(Value::Map(map));
+        }
+
+        Ok(match self.scalar()? {
+            JsonScalar::Null => Value::Null,
+            JsonScalar::Bool(text) => Value::Bool(text == "true"),
+            JsonScalar::Number(text) => match text.parse::<i64>() {
+                Ok(i) => Value::Int(i),
+                Err(..) => Value::Float(text.parse().unwrap()),
+            },
+            JsonScalar::Text(text) => Value::String(text),
+        })
+    }
+
+    fn scalar(&mut self) -> Result<JsonScalar, Error> {
+        self.skip_whitespace();
+        let start = self.offset();
+
+        match self.chars.peek().map(|&(_, c)| c) {
+            Some('"') => {
+                self.chars.next();
+                Ok(JsonScalar::Text(self.string()?))
+            },
+            Some('[') | Some('{') => Err(self.error("nested arrays and objects are not supported")),
+            Some(..) => {
+                while self.chars.peek().map_or(false, |&(_, c)| c.is_alphanumeric() || "+-.".contains(c)) {
+                    self.chars.next();
+                }
+
+                let word = &self.text[start..self.offset()];
+                match word {
+                    "null" => Ok(JsonScalar::Null),
+                    "true" | "false" => Ok(JsonScalar::Bool(word.to_owned())),
+                    _ if is_json_number(word) && word.parse::<f64>().map(|f| f.is_finite()).unwrap_or(false) => {
+                        Ok(JsonScalar::Number(word.to_owned()))
+                    },
+                    _ => Err(self.error_at(start, "expected a value")),
+                }
+            },
+            None => Err(self.error("expected a value")),
+        }
+    }
+
+    /// Reads the rest of a string, after its opening quote.
+    fn string(&mut self) -> Result<String, Error> {
+        let mut string = String::new();
+
+        loop {
+            match self.chars.next().map(|(_, c)| c) {
+                Some('"') => return Ok(string),
+                Some('\\') => {
+                    let c = match self.chars.next().map(|(_, c)| c) {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('u') => self.unicode_escape()?,
+                        Some(c @ '"') | Some(c @ '\\') | Some(c @ '/') => c,
+                        _ => return Err(self.error("invalid escape in string")),
+                    };
+                    string.push(c);
+                },
+                Some(c) => string.push(c),
+                None => return Err(self.error("a string is never closed")),
+            }
+        }
+    }
+
+    /// Reads the code of a `\\u` escape, and the low surrogate after it
+    /// if there is one.
+    fn unicode_escape(&mut self) -> Result<char, Error> {
+        let high = self.hex_code()?;
+
+        let code = if (0xd800..0xdc00).contains(&high) {
+            if !(self.next_char() == Some('\\') && self.next_char() == Some('u')) {
+                return Err(self.error("expected the low surrogate of a character"));
+            }
+            let low = self.hex_code()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err(self.error("expected the low surrogate of a character"));
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+
+        ::std::char::from_u32(code).ok_or_else(|| self.error("invalid character in string"))
+    }
+
+    fn hex_code(&mut self) -> Result<u32, Error> {
+        let digits: String = (0..4).filter_map(|_| self.next_char()).collect();
+        u32::from_str_radix(&digits, 16).map_err(|_| self.error("invalid escape in string"))
+    }
+
+    fn next_char(&mut self) -> Option<char> {
+        self.chars.next().map(|(_, c)| c)
+    }
+
+    /// Skips a character if it is next, after any whitespace.
+    fn next_is(&mut self, expected: char) -> bool {
+        self.skip_whitespace();
+        if self.chars.peek().map(|&(_, c)| c) == Some(expected) {
+            self.chars.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), Error> {
+        if self.next_is(expected) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", expected)))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().map_or(false, |&(_, c)| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn offset(&mut self) -> usize {
+        let length = self.text.len();
+        self.chars.peek().map_or(length, |&(i, _)| i)
+    }
+
+    fn error(&mut self, reason: &str) -> Error {
+        let offset = self.offset();
+        self.error_at(offset, reason)
+    }
+
+    fn error_at(&self, offset: usize, reason: &str) -> Error {
+        let (line, _) = position::line_and_column(self.text, offset);
+        ErrorKind::InvalidJson(line, reason.to_owned()).into()
+    }
+}
+
+/// Splits CSV into records of fields.
+fn csv_records(text: &str) -> Result<Vec<Vec<String>>, Error> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if field.is_empty() => {
+                let start_line = line;
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        },
+                        Some('"') => break,
+                        Some(c) => {
+                            if c == '\n' { line += 1; }
+                            field.push(c);
+                        },
+                        None => {
+                            let reason = "a quoted field is never closed".to_owned();
+                            return Err(ErrorKind::InvalidCsv(start_line, reason).into());
+                        },
+                    }
+                }
+            },
+            ',' => record.push(::std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => (),
+            '\n' => {
+                record.push(::std::mem::take(&mut field));
+                records.push(::std::mem::take(&mut record));
+                line += 1;
+            },
+            c => field.push(c),
+        }
+    }
+
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+/// Checks whether a word is a number in JSON's grammar, such as `-1.5e3`.
+///
+/// Unlike Rust, JSON does not allow a leading `+`, leading zeros, or a
+/// `.` without digits on both sides.
+fn is_json_number(word: &str) -> bool {
+    let bytes = word.as_bytes();
+    let digits_from = |index: usize| bytes[index..].iter().take_while(|b| b.is_ascii_digit()).count();
+
+    let mut index = if bytes.first() == Some(&b'-') { 1 } else { 0 };
+    match digits_from(index) {
+        0 => return false,
+        count if count > 1 && bytes[index] == b'0' => return false,
+        count => index += count,
+    }
+
+    if bytes.get(index) == Some(&b'.') {
+        match digits_from(index + 1) {
+            0 => return false,
+            count => index += 1 + count,
+        }
+    }
+
+    if bytes.get(index) == Some(&b'e') || bytes.get(index) == Some(&b'E') {
+        index += 1;
+        if bytes.get(index) == Some(&b'+') || bytes.get(index) == Some(&b'-') {
+            index += 1;
+        }
+        match digits_from(index) {
+            0 => return false,
+            count => index += count,
+        }
+    }
+
+    index == bytes.len()
+}
+
+/// Derives the name of a field from a header, or of a table from the
+/// name of its file, which can be used as a Rust identifier.
+///
+/// Characters which cannot be in an identifier become `_`, a leading
+/// digit is prefixed with `_` and keywords are suffixed with `_`. A
+/// header without any letters or digits, such as an empty one, has no
+/// name.
+pub fn field_name(header: &str) -> Option<String> {
+    if !header.chars().any(|c| c.is_alphanumeric()) {
+        return None;
+    }
+
+    let mut name: String = header.trim().to_lowercase().chars()
+        .map(|c| if c.is_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+
+    if name.chars().next().map_or(true, |c| c.is_numeric()) {
+        name.insert(0, '_');
+    }
+    if analysis::is_keyword(&name) {
+        name.push('_');
+    }
+
+    Some(name)
+}
+
+/// Derives the names of the columns with the given headers.
+///
+/// Columns whose headers have no name, such as the unnamed index column
+/// of a spreadsheet, are named by position, as in `column_1`, suffixed
+/// with `_` if another column already has that name.
+fn column_names(headers: &[String]) -> Vec<String> {
+    let names: Vec<Option<String>> = headers.iter().map(|h| field_name(h)).collect();
+
+    names.iter().enumerate().map(|(index, name)| match *name {
+        Some(ref name) => name.clone(),
+        None => {
+            let mut name = format!("column_{}", index + 1);
+            while names.iter().any(|n| n.as_ref() == Some(&name)) {
+                name.push('_');
+            }
+            name
+        },
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn derives_field_names() {
+        assert_eq!(field_name("sales-2024"), Some("sales_2024".to_owned()));
+        assert_eq!(field_name("2024 sales"), Some("_2024_sales".to_owned()));
+        assert_eq!(field_name("type"), Some("type_".to_owned()));
+        assert_eq!(field_name(""), None);
+        assert_eq!(field_name(" - "), None);
+    }
+
+    #[test]
+    fn names_columns_without_headers_by_position() {
+        let table = parse_csv(",a,column_3,#\n1,2,3,4\n").unwrap();
+        let names: Vec<_> = table.columns.iter().map(|c| &c.name[..]).collect();
+        assert_eq!(names, vec!["column_1", "a", "column_3", "column_4"]);
+
+        let table = parse_csv("column_2,\n1,2\n").unwrap();
+        assert_eq!(table.columns[1].name, "column_2_");
+    }
+
+    #[test]
+    fn parses_csv_with_types() {
+        let table = parse_csv("Name,Age,Price ($),type\r\n\"Smith, J\",42,1.5,true\nO\"\"Neil,7,2,false\n").unwrap();
+
+        assert_eq!(table.columns, vec![
+            Column { name: "name".to_owned(), ty: ColumnType::Text },
+            Column { name: "age".to_owned(), ty: ColumnType::Int },
+            Column { name: "price____".to_owned(), ty: ColumnType::Float },
+            Column { name: "type_".to_owned(), ty: ColumnType::Bool },
+        ]);
+        assert_eq!(table.rows[0], vec!["Smith, J", "42", "1.5", "true"]);
+        assert_eq!(table.rows[1][0], "O\"\"Neil");
+    }
+
+    #[test]
+    fn parses_quoted_fields() {
+        let table = parse_csv("a,b\n\"x\"\"y\",\"1\n2\"").unwrap();
+        assert_eq!(table.rows, vec![vec!["x\"y".to_owned(), "1\n2".to_owned()]]);
+    }
+
+    #[test]
+    fn parses_json_with_types() {
+        let table = parse_json(r#"[
+            {"id": 1, "name": "Al\"ice\u00e9", "price": 2, "paid": true},
+            {"id": -2, "price": 1.5e1, "paid": false, "note": null}
+        ]"#).unwrap();
+
+        assert_eq!(table.columns, vec![
+            Column { name: "id".to_owned(), ty: ColumnType::Int },
+            Column { name: "name".to_owned(), ty: ColumnType::Text },
+            Column { name: "price".to_owned(), ty: ColumnType::Float },
+            Column { name: "paid".to_owned(), ty: ColumnType::Bool },
+            Column { name: "note".to_owned(), ty: ColumnType::Text },
+        ]);
+        assert_eq!(table.rows[0], vec!["1", "Al\"ice\u{e9}", "2", "true", ""]);
+        assert_eq!(table.rows[1], vec!["-2", "", "1.5e1", "false", ""]);
+    }
+
+    #[test]
+    fn errors_on_malformed_json() {
+        assert!(parse_json("").is_err());
+        assert!(parse_json("{}").is_err());
+        assert!(parse_json("[{\"a\": [1]}]").is_err());
+        assert!(parse_json("[{\"a\": 1},]").is_err());
+        assert!(parse_json("[{\"a\": \"x}]").is_err());
+        assert!(parse_json("[] []").is_err());
+    }
+
+    #[test]
+    fn parses_json_values() {
+        let value = parse_json_value(r#" {"b": [1, 2.5, "x\ny"], "a": {"c": null, "d": true}, "e": 1e2} "#).unwrap();
+        assert_eq!(value.get("b").get("0"), &Value::Int(1));
+        assert_eq!(value.get("e"), &Value::Float(100.0));
+        assert_eq!(value.to_json(), r#"{"a":{"c":null,"d":true},"b":[1,2.5,"x\ny"],"e":100.0}"#);
+
+        assert!(parse_json_value("[1,]").is_err());
+        for number in &["+1", ".5", "1.", "01", "1e", "-"] {
+            assert!(parse_json_value(number).is_err(), "{}", number);
+        }
+        assert_eq!(parse_json_value("-0.5E+2").unwrap(), Value::Float(-50.0));
+        assert_eq!(parse_json_value(r#""\ud83d\ude00""#).unwrap(), Value::String("\u{1f600}".to_owned()));
+        assert!(parse_json_value(r#""\ud83d\u0041""#).is_err());
+        assert!(parse_json_value(&"[".repeat(1000)).is_err());
+    }
+
+    #[test]
+    fn errors_on_malformed_csv() {
+        assert!(parse_csv("").is_err());
+        assert!(parse_csv("a,b\n1\n").is_err());
+        assert!(parse_csv("a,A\n1,2\n").is_err());
+        assert!(parse_csv("a\n\"1\n").is_err());
+    }
+}
@@ -0,0 +1,40 @@
+//! Conversion of times into calendar dates, for formatting them.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Gets the year, month, day and seconds into the day of a time in UTC.
+///
+/// Months and days count from 1. Times before 1970 are supported.
+pub fn civil(time: SystemTime) -> (i64, i64, i64, i64) {
+    let seconds = match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    };
+    let (days, seconds) = (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+
+    // Convert days since the epoch into a civil date.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day, seconds)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn converts_times_into_dates() {
+        assert_eq!(civil(UNIX_EPOCH), (1970, 1, 1, 0));
+        assert_eq!(civil(UNIX_EPOCH + Duration::from_secs(951825600)), (2000, 2, 29, 43200));
+        assert_eq!(civil(UNIX_EPOCH - Duration::from_secs(1)), (1969, 12, 31, 86399));
+    }
+}
@@ -0,0 +1,130 @@
+//! Decoding of template files.
+//!
+//! Templates are UTF-8, but template trees migrated from other
+//! systems are often partly Latin-1 or otherwise invalid. Loaders can
+//! choose whether such files are errors or are decoded anyway.
+
+use {Error, ErrorKind};
+
+use std::str;
+
+/// What to do with text which is not valid UTF-8.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Fallback
+{
+    /// Give an error.
+    Error,
+    /// Decode the text as Latin-1.
+    Latin1,
+    /// Replace invalid sequences with U+FFFD.
+    Lossy,
+}
+
+/// The way a piece of text was decoded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Encoding
+{
+    /// The text was valid UTF-8.
+    Utf8,
+    /// The text was decoded as Latin-1.
+    Latin1,
+    /// The text was UTF-8 with the given number of invalid
+    /// sequences replaced.
+    Lossy(usize),
+}
+
+impl Fallback
+{
+    /// Gets a fallback from its name on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(Fallback::Error),
+            "latin1" => Some(Fallback::Latin1),
+            "lossy" => Some(Fallback::Lossy),
+            _ => None,
+        }
+    }
+}
+
+impl Encoding
+{
+    /// Gets a warning describing a decoding which may have changed
+    /// the text, if any.
+    pub fn warning(&self) -> Option<String> {
+        match *self {
+            Encoding::Utf8 => None,
+            Encoding::Latin1 => Some("text is not valid UTF-8, so it was decoded as Latin-1".to_owned()),
+            Encoding::Lossy(count) => {
+                Some(format!("replaced {} invalid UTF-8 sequence(s) with U+FFFD", count))
+            },
+        }
+    }
+}
+
+/// Decodes text, falling back as given if it is not valid UTF-8.
+pub fn decode(bytes: Vec<u8>, fallback: Fallback) -> Result<(String, Encoding), Error> {
+    let error = match String::from_utf8(bytes) {
+        Ok(text) => return Ok((text, Encoding::Utf8)),
+        Err(e) => e,
+    };
+
+    match fallback {
+        Fallback::Error => {
+            Err(ErrorKind::InvalidUtf8(error.utf8_error().valid_up_to()).into())
+        },
+        Fallback::Latin1 => {
+            let text = error.as_bytes().iter().map(|&b| b as char).collect();
+            Ok((text, Encoding::Latin1))
+        },
+        Fallback::Lossy => {
+            let count = count_invalid_sequences(error.as_bytes());
+            let text = String::from_utf8_lossy(error.as_bytes()).into_owned();
+            Ok((text, Encoding::Lossy(count)))
+        },
+    }
+}
+
+/// Counts the invalid sequences in UTF-8 text.
+fn count_invalid_sequences(mut bytes: &[u8]) -> usize {
+    let mut count = 0;
+
+    while let Err(e) = str::from_utf8(bytes) {
+        count += 1;
+
+        match e.error_len() {
+            Some(length) => bytes = &bytes[e.valid_up_to() + length..],
+            // The text ends in the middle of a sequence.
+            None => break,
+        }
+    }
+
+    count
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decodes_utf8() {
+        assert_eq!(decode("héllo".as_bytes().to_vec(), Fallback::Error).unwrap(),
+                   ("héllo".to_owned(), Encoding::Utf8));
+    }
+
+    #[test]
+    fn errors_on_invalid_utf8() {
+        assert!(decode(b"h\xe9llo".to_vec(), Fallback::Error).is_err());
+    }
+
+    #[test]
+    fn falls_back_to_latin1() {
+        assert_eq!(decode(b"h\xe9llo".to_vec(), Fallback::Latin1).unwrap(),
+                   ("héllo".to_owned(), Encoding::Latin1));
+    }
+
+    #[test]
+    fn decodes_lossily() {
+        assert_eq!(decode(b"h\xe9llo\xff\xe2\x82".to_vec(), Fallback::Lossy).unwrap(),
+                   ("h\u{fffd}llo\u{fffd}\u{fffd}".to_owned(), Encoding::Lossy(3)));
+    }
+}
@@ -0,0 +1,1927 @@
+use {Error, ErrorKind};
+use {ast, comment, encoding, parse, tag, trans};
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::prelude::*;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+use std::{fmt, fs};
+
+/// A function giving the source of a template.
+type SourceProvider = Arc<dyn Fn() -> Result<String, Error> + Send + Sync>;
+
+/// A function converting source into an AST.
+type Converter = Arc<dyn Fn(&str) -> Result<ast::Ast, Error> + Send + Sync>;
+
+/// A set of named templates.
+///
+/// Templates are parsed once when they are added, and the
+/// resulting ASTs are reused for every translation.
+///
+/// An engine is `Send` and `Sync`, and translating only needs a shared
+/// reference, so one engine in an `Arc` can serve every worker thread
+/// of a server without a lock. To change templates while others
+/// render, change a `fork` and swap it in.
+#[derive(Clone, Debug)]
+pub struct Engine
+{
+    /// The parsed templates, keyed by name.
+    templates: Arc<HashMap<String, Template>>,
+    /// The templates which are parsed when they are first used.
+    lazy_templates: Arc<HashMap<String, LazyTemplate>>,
+    /// The earlier versions of templates, keyed by name and then label.
+    versions: Arc<HashMap<String, BTreeMap<String, Arc<ast::Ast>>>>,
+    /// Whether templates loaded from files should be re-parsed
+    /// when the file changes on disk.
+    auto_reload: bool,
+    /// Whether `load_glob` skips templates marked as drafts.
+    skip_drafts: bool,
+    /// The options used to parse templates.
+    parse_config: parse::Config,
+    /// What to do with template files which are not valid UTF-8.
+    encoding_fallback: encoding::Fallback,
+    /// Limits on rendering, applied to all code the engine generates.
+    limits: trans::Limits,
+    /// How deeply includes may be nested.
+    max_include_depth: Option<usize>,
+    /// What to do with includes of templates which do not exist.
+    missing_include: MissingInclude,
+    /// The block tags defined by the application.
+    tags: tag::Tags,
+    /// Called with each template whose output may have changed.
+    invalidation_handler: Option<InvalidationHandler>,
+    /// The functions converting templates from other syntaxes, keyed
+    /// by extension.
+    delegates: HashMap<String, Delegate>,
+}
+
+/// A cached template.
+#[derive(Clone, Debug)]
+struct Template
+{
+    /// The AST, shared with forks of the engine until it is replaced.
+    ast: Arc<ast::Ast>,
+    /// How the source of the template was decoded.
+    encoding: encoding::Encoding,
+    /// The file the template was loaded from, if any.
+    file: Option<TemplateFile>,
+}
+
+/// A template whose source is only read and parsed when it is first used.
+#[derive(Clone)]
+struct LazyTemplate
+{
+    provider: SourceProvider,
+    ast: OnceLock<Arc<ast::Ast>>,
+}
+
+/// A function called with the name of a template whose output may
+/// have changed.
+#[derive(Clone)]
+struct InvalidationHandler(Arc<dyn Fn(&str) + Send + Sync>);
+
+/// A function converting the source of a template written in another
+/// syntax into an AST.
+#[derive(Clone)]
+struct Delegate(Converter);
+
+/// What to do when a template includes one which does not exist, and
+/// none of its fallbacks do either.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MissingInclude
+{
+    /// Translating the template fails.
+    Error,
+    /// The include renders nothing.
+    Empty,
+    /// The include renders nothing, and a warning is given by
+    /// `Engine::warnings`.
+    Warn,
+}
+
+/// A problem with a template which does not stop it being translated.
+#[derive(Debug)]
+pub struct Warning
+{
+    /// The template the problem is in.
+    pub template: Option<String>,
+    /// The error which would otherwise have been given.
+    pub error: Error,
+    /// The byte range of the template's source the problem is in.
+    pub span: Range<usize>,
+}
+
+/// A template file on disk.
+#[derive(Clone, Debug)]
+struct TemplateFile
+{
+    path: PathBuf,
+    /// The modification time of the file when it was last parsed.
+    modified: Option<SystemTime>,
+    /// The template as it was re-parsed when it was used with
+    /// auto-reloading, which only has a shared reference to the engine.
+    reloaded: Arc<Mutex<Option<Reloaded>>>,
+}
+
+/// A template file re-parsed because it changed on disk.
+#[derive(Clone, Debug)]
+struct Reloaded
+{
+    ast: Arc<ast::Ast>,
+    encoding: encoding::Encoding,
+    /// The modification time of the file when it was re-parsed.
+    modified: Option<SystemTime>,
+}
+
+impl Engine
+{
+    /// Creates a new engine with no templates.
+    pub fn new() -> Self {
+        Engine {
+            templates: Arc::new(HashMap::new()),
+            lazy_templates: Arc::new(HashMap::new()),
+            versions: Arc::new(HashMap::new()),
+            auto_reload: false,
+            skip_drafts: false,
+            parse_config: parse::Config::default(),
+            encoding_fallback: encoding::Fallback::Error,
+            limits: trans::Limits::default(),
+            max_include_depth: None,
+            missing_include: MissingInclude::Error,
+            tags: tag::Tags::new(),
+            invalidation_handler: None,
+            delegates: HashMap::new(),
+        }
+    }
+
+    /// Creates a copy of the engine which can be changed independently.
+    ///
+    /// The parsed templates are shared, and adding, removing or reloading
+    /// one in either engine only copies the table of their names, so this
+    /// is cheap however many templates there are. Settings such as limits
+    /// are copied.
+    pub fn fork(&self) -> Self {
+        self.clone()
+    }
+
+    /// Calls a function with an engine where a template is replaced.
+    ///
+    /// The replacement only exists within the call, and this engine is
+    /// left as it was. Tests can use this to stub out a heavy template
+    /// which is included by the one they check.
+    pub fn with_template_override<F, R>(&self, name: &str, source: &str, f: F) -> Result<R, Error>
+        where F: FnOnce(&Engine) -> R {
+        let mut engine = self.fork();
+        engine.invalidation_handler = None;
+        engine.add_template(name, source)?;
+        Ok(f(&engine))
+    }
+
+    /// Sets the options used to parse templates added from now on.
+    ///
+    /// Tags added to the engine are kept.
+    pub fn set_parse_config(&mut self, config: parse::Config) {
+        self.parse_config = config;
+        self.parse_config.tags.extend(&self.tags);
+    }
+
+    /// Adds a block tag defined by the application.
+    ///
+    /// The tag is used when parsing templates added from now on, and
+    /// when translating any template.
+    pub fn add_tag<T>(&mut self, handler: T)
+        where T: tag::TagHandler + 'static {
+        let handler: Arc<dyn tag::TagHandler> = Arc::new(handler);
+        self.tags.add(handler.clone());
+        self.parse_config.tags.add(handler);
+    }
+
+    /// Sets what happens when a template file is not valid UTF-8.
+    ///
+    /// By default, it is an error. Use `encoding` to find out how
+    /// each template was decoded.
+    pub fn set_encoding_fallback(&mut self, fallback: encoding::Fallback) {
+        self.encoding_fallback = fallback;
+    }
+
+    /// Sets limits on the resources used by rendering.
+    ///
+    /// These take precedence over the limits in the configuration
+    /// passed to `rust_code`.
+    pub fn set_limits(&mut self, limits: trans::Limits) {
+        self.limits = limits;
+    }
+
+    /// Sets how deeply includes may be nested.
+    ///
+    /// A template which includes another is one deep, and so on.
+    pub fn set_max_include_depth(&mut self, depth: Option<usize>) {
+        self.max_include_depth = depth;
+    }
+
+    /// Sets a function to call whenever the output of a template may
+    /// have changed.
+    ///
+    /// When a template is added, removed or reloaded, the function is
+    /// called with its name and the name of every template which includes
+    /// it, directly or through other templates. Applications can use this
+    /// to drop code built from those templates, and their fragments in a
+    /// `cache::CacheStore`. Lazy templates which have not been used yet
+    /// are not passed, as nothing has been built from them.
+    ///
+    /// Forks of the engine share the function, but the engines used by
+    /// `with_template_override` do not call it.
+    pub fn set_invalidation_handler<F>(&mut self, handler: F)
+        where F: Fn(&str) + Send + Sync + 'static {
+        self.invalidation_handler = Some(InvalidationHandler(Arc::new(handler)));
+    }
+
+    /// Sets what to do when a template includes one which does not exist.
+    ///
+    /// By default, it is an error. Themes whose templates are optional
+    /// overrides can render nothing instead.
+    pub fn set_missing_include(&mut self, missing_include: MissingInclude) {
+        self.missing_include = missing_include;
+    }
+
+    /// Sets whether file templates are re-parsed when they change on disk.
+    ///
+    /// This is useful during development. A changed file is re-parsed
+    /// when the template is next used, and the invalidation handler is
+    /// called as if it were reloaded. The re-parsed AST is used from
+    /// then on, but is only given by `template` once `reload_changed`
+    /// is called. When disabled, the cached AST is always used.
+    pub fn set_auto_reload(&mut self, auto_reload: bool) {
+        self.auto_reload = auto_reload;
+    }
+
+    /// Sets whether `load_glob` skips templates whose front matter marks
+    /// them as drafts, with `draft: true`.
+    ///
+    /// Production builds can skip drafts, while staging previews render
+    /// them. Drafts are loaded by default.
+    pub fn set_skip_drafts(&mut self, skip_drafts: bool) {
+        self.skip_drafts = skip_drafts;
+    }
+
+    /// Registers a function converting templates with an extension, such
+    /// as `.md`, from another syntax.
+    ///
+    /// Templates whose names have the extension are given to the delegate
+    /// instead of being parsed, whenever they are loaded or reloaded. It
+    /// gives their AST, such as a single text item holding the HTML of a
+    /// Markdown document, or a Handlebars template converted into tempo's
+    /// items. They are included like any other template, so a tree of
+    /// templates can be moved to tempo one at a time. Templates which
+    /// were already added are not converted.
+    pub fn add_delegate<F>(&mut self, extension: &str, delegate: F)
+        where F: Fn(&str) -> Result<ast::Ast, Error> + Send + Sync + 'static {
+        self.delegates.insert(extension.trim_start_matches('.').to_owned(), Delegate(Arc::new(delegate)));
+    }
+
+    /// Parses a template and registers it under a name.
+    ///
+    /// If a template with the same name already exists, it is replaced.
+    pub fn add_template(&mut self, name: &str, source: &str) -> Result<(), Error> {
+        let ast = self.parse_source(name, source)?;
+
+        self.take_template(name);
+        Arc::make_mut(&mut self.templates).insert(name.to_owned(), Template {
+            ast: Arc::new(ast),
+            encoding: encoding::Encoding::Utf8,
+            file: None,
+        });
+        self.invalidate(name);
+        Ok(())
+    }
+
+    /// Loads a template from a file and registers it under a name.
+    pub fn add_template_file<P>(&mut self, name: &str, path: P) -> Result<(), Error>
+        where P: AsRef<Path> {
+        let template = self.load_file(name, path.as_ref())?;
+        self.insert_file(name, template);
+        Ok(())
+    }
+
+    /// Loads a template from a file, without registering it.
+    fn load_file(&self, name: &str, path: &Path) -> Result<Template, Error> {
+        let modified = modification_time(path);
+        let (ast, encoding) = parse_file(path, name, &self.parse_config, self.encoding_fallback, self.delegate(name))?;
+
+        Ok(Template {
+            ast: Arc::new(ast),
+            encoding: encoding,
+            file: Some(TemplateFile { path: path.to_owned(), modified: modified, reloaded: Arc::new(Mutex::new(None)) }),
+        })
+    }
+
+    /// Registers a loaded template file under a name.
+    fn insert_file(&mut self, name: &str, template: Template) {
+        self.take_template(name);
+        Arc::make_mut(&mut self.templates).insert(name.to_owned(), template);
+        self.invalidate(name);
+    }
+
+    /// Loads every template file matching a glob, such as
+    /// `templates/**/*.trs`.
+    ///
+    /// Templates are named by their paths relative to the directory the
+    /// pattern starts in, with `/` between components, so
+    /// `templates/mail/welcome.trs` is named `mail/welcome.trs`. `*` and
+    /// `?` match within a component, and `**` matches any number of
+    /// directories.
+    ///
+    /// Every matching file is loaded even if some fail, and the error
+    /// lists each file which failed, including those whose names are not
+    /// valid UTF-8. Otherwise, the names of the loaded templates are
+    /// returned in sorted order. Drafts are not loaded if the engine
+    /// skips them.
+    pub fn load_glob(&mut self, pattern: &str) -> Result<Vec<String>, Error> {
+        let components: Vec<_> = pattern.split('/').collect();
+        let literal = components.iter().take_while(|c| !c.contains(|ch| ch == '*' || ch == '?')).count();
+        // The last component is matched against files even if it is literal.
+        let literal = ::std::cmp::min(literal, components.len() - 1);
+
+        let root: PathBuf = match components[..literal].join("/") {
+            // A pattern such as `/*.trs` starts from the root directory.
+            ref root if root.is_empty() && pattern.starts_with('/') => PathBuf::from("/"),
+            ref root if root.is_empty() => PathBuf::from("."),
+            root => PathBuf::from(root),
+        };
+
+        let mut files = Vec::new();
+        find_files(&root, &mut Vec::new(), &mut files)?;
+
+        let mut loaded = Vec::new();
+        let mut failures = Vec::new();
+        for file in files {
+            let (relative, is_valid) = match file {
+                Ok(relative) => (relative, true),
+                Err(relative) => (relative, false),
+            };
+
+            let names: Vec<_> = relative.split('/').collect();
+            if !matches_glob(&components[literal..], &names) {
+                continue;
+            }
+
+            let path = root.join(&relative);
+            if !is_valid {
+                failures.push((path.display().to_string(), ErrorKind::InvalidPath(relative).into()));
+                continue;
+            }
+
+            match self.load_file(&relative, &path) {
+                Ok(ref template) if self.skip_drafts && template.ast.is_draft() => (),
+                Ok(template) => {
+                    self.insert_file(&relative, template);
+                    loaded.push(relative);
+                },
+                Err(e) => failures.push((path.display().to_string(), e)),
+            }
+        }
+
+        if failures.is_empty() {
+            Ok(loaded)
+        } else {
+            Err(ErrorKind::TemplatesFailedToLoad(failures).into())
+        }
+    }
+
+    /// Registers a template under a name, to be parsed when it is
+    /// first used.
+    ///
+    /// The provider is called for the template's source then, and again
+    /// if it fails. This keeps startup fast for applications with many
+    /// templates which are rarely used.
+    pub fn add_lazy_template<F>(&mut self, name: &str, provider: F)
+        where F: Fn() -> Result<String, Error> + Send + Sync + 'static {
+        self.take_template(name);
+        Arc::make_mut(&mut self.lazy_templates).insert(name.to_owned(), LazyTemplate {
+            provider: Arc::new(provider),
+            ast: OnceLock::new(),
+        });
+        self.invalidate(name);
+    }
+
+    /// Parses a version of a template and registers it under a label,
+    /// such as `v3`.
+    ///
+    /// Versions are kept alongside the current template, and are only
+    /// used by `rust_code_versioned`. A version with the same label is
+    /// replaced.
+    pub fn add_template_version(&mut self, name: &str, version: &str, source: &str) -> Result<(), Error> {
+        let ast = self.parse_source(name, source)?;
+
+        Arc::make_mut(&mut self.versions).entry(name.to_owned()).or_insert_with(BTreeMap::new)
+            .insert(version.to_owned(), Arc::new(ast));
+        Ok(())
+    }
+
+    /// Gets the labels of the versions of a template, in sorted order.
+    pub fn template_versions(&self, name: &str) -> Vec<&str> {
+        match self.versions.get(name) {
+            Some(versions) => versions.keys().map(|v| &v[..]).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Re-parses all file templates which have changed on disk.
+    ///
+    /// Returns the names of the templates that were reloaded. If any
+    /// fails to parse, none are reloaded. Templates already re-parsed
+    /// by auto-reloading are not re-parsed again, nor returned.
+    pub fn reload_changed(&mut self) -> Result<Vec<String>, Error> {
+        let mut reloads = Vec::new();
+        let mut changed = Vec::new();
+
+        for (name, template) in self.templates.iter() {
+            let file = match template.file {
+                Some(ref file) => file,
+                None => continue,
+            };
+
+            let reloaded = file.reloaded.lock().unwrap().clone();
+            let known = reloaded.as_ref().map_or(file.modified, |r| r.modified);
+            let modified = modification_time(&file.path);
+
+            if modified != known {
+                let (ast, encoding) = parse_file(&file.path, name, &self.parse_config, self.encoding_fallback,
+                                                 self.delegate(name))?;
+                let reload = Reloaded { ast: Arc::new(ast), encoding: encoding, modified: modified };
+                reloads.push((name.clone(), reload));
+                changed.push(name.clone());
+            } else if let Some(reload) = reloaded {
+                reloads.push((name.clone(), reload));
+            }
+        }
+
+        // Templates shared with a fork are only copied when one has changed.
+        for (name, reload) in reloads {
+            let template = Arc::make_mut(&mut self.templates).get_mut(&name).unwrap();
+            template.ast = reload.ast;
+            template.encoding = reload.encoding;
+            let file = template.file.as_mut().unwrap();
+            file.modified = reload.modified;
+            // Forks keep whatever they re-parsed themselves.
+            file.reloaded = Arc::new(Mutex::new(None));
+        }
+
+        changed.sort();
+        for name in changed.iter() {
+            self.invalidate(name);
+        }
+        Ok(changed)
+    }
+
+    /// Removes a template, returning its AST if it existed.
+    ///
+    /// Lazy templates only have an AST once they have been used.
+    pub fn remove_template(&mut self, name: &str) -> Option<ast::Ast> {
+        if self.lazy_templates.contains_key(name) || self.templates.contains_key(name) {
+            self.invalidate(name);
+        }
+        self.take_template(name)
+    }
+
+    /// Removes a template without calling the invalidation handler.
+    fn take_template(&mut self, name: &str) -> Option<ast::Ast> {
+        if self.lazy_templates.contains_key(name) {
+            Arc::make_mut(&mut self.lazy_templates).remove(name).and_then(|lazy| lazy.ast.into_inner()).map(unshare)
+        } else if self.templates.contains_key(name) {
+            Arc::make_mut(&mut self.templates).remove(name).map(|t| unshare(t.ast))
+        } else {
+            None
+        }
+    }
+
+    /// Parses the source of a template, or converts it with the delegate
+    /// for its extension.
+    fn parse_source(&self, name: &str, source: &str) -> Result<ast::Ast, Error> {
+        let mut ast = match self.delegate(name) {
+            Some(delegate) => (delegate.0)(source)?,
+            None => parse::parse_str_with_config(source, &self.parse_config)?,
+        };
+        ast.set_origin(name);
+        Ok(ast)
+    }
+
+    /// Gets the delegate for the extension of a template's name.
+    fn delegate(&self, name: &str) -> Option<&Delegate> {
+        Path::new(name).extension().and_then(|e| e.to_str()).and_then(|e| self.delegates.get(e))
+    }
+
+    /// Calls the invalidation handler for a template and every template
+    /// which includes it.
+    fn invalidate(&self, name: &str) {
+        let handler = match self.invalidation_handler {
+            Some(ref handler) => handler,
+            None => return,
+        };
+
+        let mut affected = vec![name.to_owned()];
+        let mut pending = vec![name.to_owned()];
+
+        while let Some(included) = pending.pop() {
+            for other in self.template_names() {
+                let includes = self.template(other).map_or(false, |ast| ast.dependencies().contains(&included));
+                if includes && !affected.iter().any(|a| a == other) {
+                    affected.push(other.to_owned());
+                    pending.push(other.to_owned());
+                }
+            }
+        }
+
+        affected.sort();
+        for name in affected.iter() {
+            (handler.0)(name);
+        }
+    }
+
+    /// Gets the cached AST of a template.
+    ///
+    /// Lazy templates are not parsed by this, so only have an AST once
+    /// they have been used.
+    pub fn template(&self, name: &str) -> Option<&ast::Ast> {
+        match self.lazy_templates.get(name) {
+            Some(lazy) => lazy.ast.get().map(|ast| &**ast),
+            None => self.templates.get(name).map(|t| &*t.ast),
+        }
+    }
+
+    /// Gets the path of the file a template was loaded from, if it was.
+    ///
+    /// The origins of a template's items are its name, so tools which
+    /// show their positions in the file can find it with this.
+    pub fn template_path(&self, name: &str) -> Option<&Path> {
+        self.templates.get(name).and_then(|t| t.file.as_ref()).map(|file| &*file.path)
+    }
+
+    /// Gets how the source of a template was decoded.
+    pub fn encoding(&self, name: &str) -> Option<encoding::Encoding> {
+        match self.lazy_templates.get(name) {
+            Some(lazy) => lazy.ast.get().map(|_| encoding::Encoding::Utf8),
+            None => self.templates.get(name).map(|t| t.encoding),
+        }
+    }
+
+    /// Gets the names of all the templates a template includes,
+    /// directly or through other templates, in sorted order.
+    ///
+    /// Build systems can use this to find which templates affect an output.
+    /// Templates which do not exist are listed, as adding them would change
+    /// the output, such as fallbacks which are not used yet.
+    pub fn dependencies(&self, name: &str) -> Result<Vec<String>, Error> {
+        let mut dependencies = Vec::new();
+        let mut pending = self.lookup(name)?.dependencies();
+
+        while let Some(dependency) = pending.pop() {
+            if dependencies.contains(&dependency) {
+                continue;
+            }
+
+            if self.has_template(&dependency) {
+                pending.extend(self.lookup(&dependency)?.dependencies());
+            }
+            dependencies.push(dependency);
+        }
+
+        dependencies.sort();
+        Ok(dependencies)
+    }
+
+    /// Gets the keys of the data a template and the templates it includes
+    /// declare they need, each once, with the template's own first.
+    ///
+    /// The application can resolve these before rendering, rather than
+    /// building the data each template might need. See
+    /// `Ast::required_data`.
+    pub fn required_data(&self, name: &str) -> Result<Vec<String>, Error> {
+        let mut keys = self.lookup(name)?.required_data();
+
+        for dependency in self.dependencies(name)? {
+            if self.has_template(&dependency) {
+                for key in self.lookup(&dependency)?.required_data() {
+                    if !keys.contains(&key) {
+                        keys.push(key);
+                    }
+                }
+            }
+        }
+
+        Ok(keys)
+    }
+
+    /// Hashes the meaning of a template and the templates it includes.
+    ///
+    /// The hash only changes when the output could, so it can be stored
+    /// to find which templates need rendering again. See
+    /// `Ast::content_hash`.
+    pub fn content_hash(&self, name: &str) -> Result<u64, Error> {
+        Ok(self.resolved_ast(name)?.content_hash())
+    }
+
+    /// Gets the names of the templates which include a template,
+    /// directly or through other templates, in sorted order.
+    ///
+    /// These are the templates whose output changes when it does.
+    /// Every lazy template is parsed to find them.
+    pub fn dependents(&self, name: &str) -> Result<Vec<String>, Error> {
+        let mut dependents = Vec::new();
+
+        for other in self.template_names() {
+            if other != name && self.dependencies(other)?.iter().any(|d| d == name) {
+                dependents.push(other.to_owned());
+            }
+        }
+
+        Ok(dependents)
+    }
+
+    /// Gets the names of all registered templates.
+    pub fn template_names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.templates.keys().chain(self.lazy_templates.keys())
+            .map(|n| &n[..]).collect();
+        names.sort();
+        names
+    }
+
+    /// Parses templates and the templates they include ahead of their
+    /// first use, spread across a thread for each CPU.
+    ///
+    /// Lazy templates are otherwise parsed by the first translation which
+    /// needs them, so services can warm them at startup rather than slow
+    /// down their first requests. Every template is warmed even if some
+    /// fail, and the error lists each one which failed.
+    pub fn warm(&self, names: &[&str]) -> Result<(), Error> {
+        let threads = ::std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let chunk_size = ::std::cmp::max(1, names.len().div_ceil(threads));
+
+        let failures: Vec<_> = ::std::thread::scope(|scope| {
+            let handles: Vec<_> = names.chunks(chunk_size).map(|chunk| {
+                scope.spawn(move || {
+                    chunk.iter().filter_map(|&name| match self.dependencies(name) {
+                        Ok(..) => None,
+                        Err(e) => Some((name.to_owned(), e)),
+                    }).collect::<Vec<_>>()
+                })
+            }).collect();
+
+            handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+        });
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(ErrorKind::TemplatesFailedToLoad(failures).into())
+        }
+    }
+
+    /// Parses every registered template ahead of its first use.
+    ///
+    /// See `warm`.
+    pub fn warm_all(&self) -> Result<(), Error> {
+        self.warm(&self.template_names())
+    }
+
+    /// Translates a named template into Rust source code.
+    ///
+    /// In auto-reload mode, a template file which has changed on disk is
+    /// re-parsed first.
+    ///
+    /// If the configuration has no comment syntax, it is inferred from
+    /// the extension of the template's name, ignoring any `.trs`.
+    ///
+    /// Included templates are looked up by name and inlined.
+    pub fn rust_code(&self, name: &str, config: &trans::Config) -> Result<String, Error> {
+        trans::rust_code(self.resolved_ast(name)?, &self.translation_config(name, config))
+    }
+
+    /// Translates only a named block of a template into Rust source code,
+    /// such as to re-render part of a page after it has changed.
+    ///
+    /// The block is translated with the template's front matter, defaults
+    /// and top-level macros. Variables bound around the block, such as by
+    /// an enclosing `for` loop or a `set` tag, are not in scope, so its
+    /// data must be given to it directly. Blocks of included templates
+    /// can be translated too.
+    pub fn rust_code_block(&self, name: &str, block: &str, config: &trans::Config) -> Result<String, Error> {
+        let ast = match self.resolved_ast(name)?.block_ast(block) {
+            Some(ast) => ast,
+            None => return Err(ErrorKind::UnknownBlock(name.to_owned(), block.to_owned()).into()),
+        };
+
+        trans::rust_code(ast, &self.translation_config(name, config))
+    }
+
+    /// Translates a version of a template into Rust source code.
+    ///
+    /// Included templates use their version with the same label, so every
+    /// template it includes must have one too. This lets a document be
+    /// rendered again exactly as it was first generated.
+    pub fn rust_code_versioned(&self, name: &str, version: &str, config: &trans::Config)
+        -> Result<String, Error> {
+        self.pinned(name, version)?.rust_code(name, config)
+    }
+
+    /// Gets an engine where every template with a version under a label
+    /// is replaced by it.
+    ///
+    /// Fails unless the template and every template it includes has a
+    /// version under the label.
+    fn pinned(&self, name: &str, version: &str) -> Result<Engine, Error> {
+        let has_version = |name: &str| self.versions.get(name).map_or(false, |versions| versions.contains_key(version));
+        if !has_version(name) {
+            return Err(ErrorKind::UnknownTemplateVersion(name.to_owned(), version.to_owned()).into());
+        }
+
+        let mut engine = self.fork();
+        engine.invalidation_handler = None;
+        for (name, versions) in self.versions.iter() {
+            if let Some(ast) = versions.get(version) {
+                engine.remove_template(name);
+                Arc::make_mut(&mut engine.templates).insert(name.clone(), Template {
+                    ast: ast.clone(),
+                    encoding: encoding::Encoding::Utf8,
+                    file: None,
+                });
+            }
+        }
+
+        // An include without a version would otherwise use its current source.
+        for dependency in engine.dependencies(name)? {
+            if engine.has_template(&dependency) && !has_version(&dependency) {
+                return Err(ErrorKind::UnknownTemplateVersion(dependency, version.to_owned()).into());
+            }
+        }
+        Ok(engine)
+    }
+
+    /// Translates the old and new versions of a template into a render
+    /// function which writes the output of the old version, and reports
+    /// when the new version's output differs from it.
+    ///
+    /// This lets a change to a template be rolled out safely, by checking
+    /// the new version against real data before it is used. See
+    /// `trans::rust_code_canary`.
+    pub fn rust_code_canary(&self, old: &str, new: &str, config: &trans::Config) -> Result<String, Error> {
+        trans::rust_code_canary(self.resolved_ast(old)?, self.resolved_ast(new)?, &self.translation_config(old, config))
+    }
+
+    /// Gets the problems found translating a template which do not
+    /// stop it being translated, such as missing includes when set to
+    /// `MissingInclude::Warn`.
+    pub fn warnings(&self, name: &str) -> Result<Vec<Warning>, Error> {
+        let mut warnings = Vec::new();
+        self.resolve_includes(unshare(self.lookup(name)?).items, &[name.to_owned()], &mut warnings)?;
+        Ok(warnings)
+    }
+
+    /// Gets the AST of a template with its includes inlined.
+    fn resolved_ast(&self, name: &str) -> Result<ast::Ast, Error> {
+        let items = self.resolve_includes(unshare(self.lookup(name)?).items, &[name.to_owned()], &mut Vec::new())?;
+        Ok(ast::Ast { items: items })
+    }
+
+    /// Checks whether a template with a name is registered.
+    fn has_template(&self, name: &str) -> bool {
+        self.templates.contains_key(name) || self.lazy_templates.contains_key(name)
+    }
+
+    /// Gets the options for translating a template, with the engine's
+    /// settings applied.
+    fn translation_config(&self, name: &str, config: &trans::Config) -> trans::Config {
+        let limits = trans::Limits {
+            max_render_time: self.limits.max_render_time.or(config.limits.max_render_time),
+            max_output_size: self.limits.max_output_size.or(config.limits.max_output_size),
+            max_loop_iterations: self.limits.max_loop_iterations.or(config.limits.max_loop_iterations),
+            max_calls: self.limits.max_calls.iter().chain(config.limits.max_calls.iter().filter(|&&(ref name, _)| {
+                !self.limits.max_calls.iter().any(|&(ref n, _)| n == name)
+            })).cloned().collect(),
+        };
+        let mut tags = config.tags.clone();
+        tags.extend(&self.tags);
+
+        trans::Config {
+            comment_syntax: config.comment_syntax.or_else(|| comment::Syntax::for_template_path(name)),
+            limits: limits,
+            tags: tags,
+            ..config.clone()
+        }
+    }
+
+    /// Translates many templates, spread across a thread for each CPU.
+    ///
+    /// The results are in the order of the jobs, each as `rust_code`
+    /// would give it. The engine's parsed templates are shared by every
+    /// thread, so no template is parsed again.
+    pub fn rust_code_batch(&self, jobs: &[(&str, trans::Config)]) -> Vec<Result<String, Error>> {
+        let threads = ::std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let chunk_size = ::std::cmp::max(1, jobs.len().div_ceil(threads));
+
+        ::std::thread::scope(|scope| {
+            let handles: Vec<_> = jobs.chunks(chunk_size).map(|chunk| {
+                scope.spawn(move || {
+                    chunk.iter().map(|&(name, ref config)| self.rust_code(name, config)).collect::<Vec<_>>()
+                })
+            }).collect();
+
+            handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+        })
+    }
+
+    /// Translates a named template whose body is a single expression into
+    /// Rust source code for a function returning its value.
+    ///
+    /// See `trans::rust_value_code`.
+    pub fn rust_value_code(&self, name: &str, ty: &str, config: &trans::Config)
+        -> Result<String, Error> {
+        trans::rust_value_code(self.resolved_ast(name)?, ty, &self.translation_config(name, config))
+    }
+
+    /// Replaces included templates with their contents, given the
+    /// chain of templates including the items, outermost first.
+    fn resolve_includes(&self, items: Vec<ast::Item>, chain: &[String], warnings: &mut Vec<Warning>)
+        -> Result<Vec<ast::Item>, Error> {
+        let mut resolved = Vec::new();
+
+        for item in items {
+            let (span, origin) = (item.span, item.origin);
+            let kind = match item.kind {
+                ast::ItemKind::Include { name, fallbacks } => {
+                    if let Some(max_include_depth) = self.max_include_depth {
+                        if chain.len() > max_include_depth {
+                            return Err(ErrorKind::IncludeTooDeep(max_include_depth).into());
+                        }
+                    }
+
+                    let name = match ::std::iter::once(&name).chain(fallbacks.iter()).find(|n| self.has_template(n)) {
+                        Some(name) => name.clone(),
+                        None => {
+                            let error = ErrorKind::UnknownTemplate(name).into();
+                            match self.missing_include {
+                                MissingInclude::Error => return Err(error),
+                                MissingInclude::Empty => (),
+                                MissingInclude::Warn => {
+                                    warnings.push(Warning { template: origin, error: error, span: span });
+                                },
+                            }
+                            continue;
+                        },
+                    };
+
+                    let mut chain = chain.to_vec();
+                    let is_cycle = chain.contains(&name);
+                    chain.push(name);
+
+                    if is_cycle {
+                        return Err(ErrorKind::IncludeCycle(chain).into());
+                    }
+
+                    let included = unshare(self.lookup(chain.last().unwrap())?);
+                    resolved.extend(self.resolve_includes(included.items, &chain, warnings)?);
+                    continue;
+                },
+                ast::ItemKind::Macro { name, parameters, body } => ast::ItemKind::Macro {
+                    name: name,
+                    parameters: parameters,
+                    body: self.resolve_includes(body, chain, warnings)?,
+                },
+                ast::ItemKind::Capture { name, body } => ast::ItemKind::Capture {
+                    name: name,
+                    body: self.resolve_includes(body, chain, warnings)?,
+                },
+                ast::ItemKind::If { condition, body, else_body } => ast::ItemKind::If {
+                    condition: condition,
+                    body: self.resolve_includes(body, chain, warnings)?,
+                    else_body: self.resolve_includes(else_body, chain, warnings)?,
+                },
+                ast::ItemKind::For { pattern, iterator, body } => ast::ItemKind::For {
+                    pattern: pattern,
+                    iterator: iterator,
+                    body: self.resolve_includes(body, chain, warnings)?,
+                },
+                ast::ItemKind::File { path, body } => ast::ItemKind::File {
+                    path: path,
+                    body: self.resolve_includes(body, chain, warnings)?,
+                },
+                ast::ItemKind::Cache { key, body } => ast::ItemKind::Cache {
+                    key: key,
+                    body: self.resolve_includes(body, chain, warnings)?,
+                },
+                ast::ItemKind::With { name, value, body } => ast::ItemKind::With {
+                    name: name,
+                    value: value,
+                    body: self.resolve_includes(body, chain, warnings)?,
+                },
+                ast::ItemKind::Block { name, limit, truncation, body } => ast::ItemKind::Block {
+                    name: name,
+                    limit: limit,
+                    truncation: truncation,
+                    body: self.resolve_includes(body, chain, warnings)?,
+                },
+                ast::ItemKind::Tag { name, arguments, body } => ast::ItemKind::Tag {
+                    name: name,
+                    arguments: arguments,
+                    body: self.resolve_includes(body, chain, warnings)?,
+                },
+                kind => kind,
+            };
+
+            resolved.push(ast::Item { kind: kind, span: span, origin: origin });
+        }
+
+        Ok(resolved)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<ast::Ast>, Error> {
+        if let Some(lazy) = self.lazy_templates.get(name) {
+            if let Some(ast) = lazy.ast.get() {
+                return Ok(ast.clone());
+            }
+
+            let ast = self.parse_source(name, &(lazy.provider)()?)?;
+            // Another thread may have parsed it first, giving the same AST.
+            return Ok(lazy.ast.get_or_init(|| Arc::new(ast)).clone());
+        }
+
+        let template = match self.templates.get(name) {
+            Some(template) => template,
+            None => return Err(ErrorKind::UnknownTemplate(name.to_owned()).into()),
+        };
+
+        let file = match template.file {
+            Some(ref file) if self.auto_reload => file,
+            _ => return Ok(template.ast.clone()),
+        };
+
+        let ast = {
+            // The lock is held while parsing so that other threads do not
+            // parse the same change.
+            let mut reloaded = file.reloaded.lock().unwrap();
+            let known = reloaded.as_ref().map_or(file.modified, |r| r.modified);
+            let modified = modification_time(&file.path);
+
+            if modified == known {
+                return Ok(reloaded.as_ref().map_or(&template.ast, |r| &r.ast).clone());
+            }
+
+            let (ast, encoding) = parse_file(&file.path, name, &self.parse_config, self.encoding_fallback,
+                                             self.delegate(name))?;
+            let ast = Arc::new(ast);
+            *reloaded = Some(Reloaded { ast: ast.clone(), encoding: encoding, modified: modified });
+            ast
+        };
+
+        // The handler may use the engine, so is called without the lock.
+        self.invalidate(name);
+        Ok(ast)
+    }
+}
+
+/// Takes an AST out of an `Arc`, copying it if a fork still shares it.
+fn unshare(ast: Arc<ast::Ast>) -> ast::Ast {
+    Arc::try_unwrap(ast).unwrap_or_else(|ast| (*ast).clone())
+}
+
+impl fmt::Debug for LazyTemplate
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("LazyTemplate").field("ast", &self.ast).finish()
+    }
+}
+
+impl fmt::Debug for InvalidationHandler
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("InvalidationHandler")
+    }
+}
+
+impl fmt::Debug for Delegate
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("Delegate")
+    }
+}
+
+impl Default for Engine
+{
+    fn default() -> Self {
+        Engine::new()
+    }
+}
+
+/// Parses a template file, or converts it with a delegate.
+///
+/// The items are given the template's name as their origin rather than
+/// the path, so that what is generated from them, such as the keys of
+/// cached fragments, does not depend on where the file is.
+fn parse_file(path: &Path, name: &str, config: &parse::Config, fallback: encoding::Fallback,
+              delegate: Option<&Delegate>) -> Result<(ast::Ast, encoding::Encoding), Error> {
+    let mut file = fs::File::open(path)?;
+    // Check the size before reading so that huge files are never loaded.
+    parse::check_size(file.metadata()?.len() as usize, config)?;
+
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+
+    let (mut ast, encoding) = match delegate {
+        Some(delegate) => {
+            let (source, encoding) = encoding::decode(bytes, fallback)?;
+            ((delegate.0)(&source)?, encoding)
+        },
+        None => parse::parse_bytes(&bytes, config, fallback)?,
+    };
+    ast.set_origin(name);
+
+    Ok((ast, encoding))
+}
+
+/// Finds the files under a directory, as paths relative to it, in
+/// sorted order.
+fn find_files(directory: &Path, prefix: &mut Vec<String>, files: &mut Vec<Result<String, String>>)
+    -> Result<(), Error> {
+    let mut entries = fs::read_dir(directory)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            // Entries which cannot be named are listed lossily, and not searched.
+            Err(name) => {
+                prefix.push(name.to_string_lossy().into_owned());
+                files.push(Err(prefix.join("/")));
+                prefix.pop();
+                continue;
+            },
+        };
+
+        prefix.push(name);
+        if entry.file_type()?.is_dir() {
+            find_files(&entry.path(), prefix, files)?;
+        } else {
+            files.push(Ok(prefix.join("/")));
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+/// Checks whether the components of a path match those of a glob.
+fn matches_glob(pattern: &[&str], path: &[&str]) -> bool {
+    match (pattern.first(), path.first()) {
+        (Some(&"**"), _) => {
+            matches_glob(&pattern[1..], path) || (!path.is_empty() && matches_glob(pattern, &path[1..]))
+        },
+        (Some(p), Some(name)) => matches_component(p.as_bytes(), name.as_bytes()) &&
+            matches_glob(&pattern[1..], &path[1..]),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Checks whether a file name matches a component of a glob, with
+/// `*` and `?` as wildcards.
+fn matches_component(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (Some(&b'*'), _) => {
+            matches_component(&pattern[1..], name) || (!name.is_empty() && matches_component(pattern, &name[1..]))
+        },
+        (Some(&b'?'), Some(_)) => {
+            // `?` matches a whole character, not a byte of one.
+            let length = ::std::str::from_utf8(name).ok()
+                .and_then(|n| n.chars().next()).map_or(1, |c| c.len_utf8());
+            matches_component(&pattern[1..], &name[length..])
+        },
+        (Some(p), Some(n)) => p == n && matches_component(&pattern[1..], &name[1..]),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+fn modification_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn translates_registered_templates() {
+        let mut engine = Engine::new();
+        engine.add_template("email", "hello <%= name %>").unwrap();
+
+        let code = engine.rust_code("email", &Default::default()).unwrap();
+        assert!(code.contains("pub fn render("));
+        assert!(code.contains("name"));
+    }
+
+    #[test]
+    fn overrides_templates_within_a_call() {
+        let mut engine = Engine::new();
+        engine.add_template("page", "<% include \"sidebar\" %>").unwrap();
+        engine.add_template("sidebar", "<%= expensive() %>").unwrap();
+
+        let code = engine.with_template_override("sidebar", "stub", |engine| {
+            engine.rust_code("page", &Default::default()).unwrap()
+        }).unwrap();
+        assert!(code.contains("stub") && !code.contains("expensive"));
+
+        let code = engine.rust_code("page", &Default::default()).unwrap();
+        assert!(code.contains("expensive"));
+    }
+
+    #[test]
+    fn translates_tags_defined_by_the_application() {
+        struct Repeat;
+
+        impl tag::TagHandler for Repeat {
+            fn name(&self) -> &str { "repeat" }
+
+            fn parse(&self, arguments: &str) -> Result<(), String> {
+                arguments.parse::<usize>().map(|_| ()).map_err(|_| "expected a count".to_owned())
+            }
+
+            fn render(&self, arguments: &str, body: &str) -> String {
+                format!("{}.repeat({})", body, arguments)
+            }
+        }
+
+        let mut engine = Engine::new();
+        engine.add_tag(Repeat);
+        engine.add_template("page", "<% repeat 3 %>ab<% end %>").unwrap();
+
+        let code = engine.rust_code("page", &Default::default()).unwrap();
+        assert!(code.contains("_body.repeat(3)"));
+
+        let error = engine.add_template("bad", "<% repeat many %><% end %>").unwrap_err();
+        assert_eq!(error.to_string(), "invalid 'repeat' tag: expected a count");
+
+        let ast = engine.template("page").unwrap().clone();
+        let error = trans::rust_code(ast, &Default::default()).unwrap_err();
+        assert_eq!(error.code(), "E0112");
+    }
+
+    #[test]
+    fn parses_lazy_templates_when_first_used() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let provider_calls = calls.clone();
+
+        let mut engine = Engine::new();
+        engine.add_template("page", "<% include \"footer\" %>").unwrap();
+        engine.add_lazy_template("footer", move || {
+            provider_calls.fetch_add(1, Ordering::SeqCst);
+            Ok("FOOTER".to_owned())
+        });
+        engine.add_lazy_template("broken", || Err("not found".into()));
+
+        assert_eq!(engine.template_names(), vec!["broken", "footer", "page"]);
+        assert!(engine.template("footer").is_none());
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        assert!(engine.rust_code("page", &Default::default()).unwrap().contains("FOOTER"));
+        assert!(engine.rust_code("footer", &Default::default()).is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(engine.template("footer").is_some());
+
+        assert!(engine.rust_code("broken", &Default::default()).is_err());
+    }
+
+    #[test]
+    fn warms_lazy_templates() {
+        let mut engine = Engine::new();
+        engine.add_template("page", "<% include \"header\" %>").unwrap();
+        engine.add_lazy_template("header", || Ok("HEADER".to_owned()));
+        engine.add_lazy_template("footer", || Ok("FOOTER".to_owned()));
+        engine.add_lazy_template("broken", || Err("not found".into()));
+
+        engine.warm(&["page"]).unwrap();
+        assert!(engine.template("header").is_some());
+        assert!(engine.template("footer").is_none());
+
+        let error = engine.warm_all().unwrap_err();
+        assert_eq!(error.to_string(), "1 template(s) failed to load: broken: not found");
+        assert!(engine.template("footer").is_some());
+
+        assert_eq!(engine.warm(&["missing"]).unwrap_err().code(), "E0117");
+    }
+
+    #[test]
+    fn converts_templates_with_delegates() {
+        let mut engine = Engine::new();
+        engine.add_delegate(".md", |source| match source.trim().starts_with("# ") {
+            true => Ok(ast::Ast { items: vec![ast::Item::new(ast::ItemKind::Text(format!("<h1>{}</h1>", &source[2..])))] }),
+            false => Err("expected a heading".into()),
+        });
+
+        engine.add_template("intro.md", "# <%= Hi %>").unwrap();
+        engine.add_lazy_template("notes.md", || Ok("no heading".to_owned()));
+        engine.add_template("page.html", "<% include \"intro.md\" %><% include \"notes.md\" %>").unwrap();
+
+        assert_eq!(engine.template("intro.md").unwrap().items[0].origin, Some("intro.md".to_owned()));
+        assert!(engine.template("page.html").unwrap().dependencies().contains(&"notes.md".to_owned()));
+        assert_eq!(engine.rust_code("page.html", &Default::default()).unwrap_err().to_string(), "expected a heading");
+
+        engine.add_lazy_template("notes.md", || Ok("# Notes".to_owned()));
+        let code = engine.rust_code("page.html", &Default::default()).unwrap();
+        assert!(code.contains("<h1><%= Hi %></h1>") && code.contains("<h1>Notes</h1>"));
+    }
+
+    #[test]
+    fn forks_share_templates_until_changed() {
+        let mut engine = Engine::new();
+        engine.add_template("page", "<%= 1 %>").unwrap();
+
+        let mut fork = engine.fork();
+        assert!(Arc::ptr_eq(&engine.templates, &fork.templates));
+
+        fork.add_template("extra", "b").unwrap();
+        assert!(Arc::ptr_eq(&engine.templates["page"].ast, &fork.templates["page"].ast));
+        fork.remove_template("page");
+        fork.set_max_include_depth(Some(1));
+
+        assert_eq!(engine.template_names(), vec!["page"]);
+        assert_eq!(fork.template_names(), vec!["extra"]);
+        assert_eq!(engine.max_include_depth, None);
+    }
+
+    #[test]
+    fn inlines_included_templates() {
+        let mut engine = Engine::new();
+        engine.add_template("header", "HEADER").unwrap();
+        engine.add_template("page", "<% if true %><% include \"header\" %><% end %>").unwrap();
+
+        let code = engine.rust_code("page", &Default::default()).unwrap();
+        assert!(code.contains("HEADER"));
+    }
+
+    #[test]
+    fn finds_dependencies_and_dependents() {
+        let mut engine = Engine::new();
+        engine.add_template("page", "<% include \"layout\" %>").unwrap();
+        engine.add_template("layout", "<% include \"header\" %><% include \"footer\" %>").unwrap();
+        engine.add_template("header", "").unwrap();
+        engine.add_template("footer", "<% include \"header\" %>").unwrap();
+
+        assert_eq!(engine.dependencies("page").unwrap(), vec!["footer", "header", "layout"]);
+        assert_eq!(engine.dependents("header").unwrap(), vec!["footer", "layout", "page"]);
+    }
+
+    #[test]
+    fn finds_required_data() {
+        let mut engine = Engine::new();
+        engine.add_template("page", "---\nrequired_data: user, recent_posts,\n---\n<% include \"sidebar\" %>").unwrap();
+        engine.add_template("sidebar", "+++\nrequired_data = \"team_stats, user\"\n+++\n<% include \"missing\" %>").unwrap();
+
+        assert_eq!(engine.required_data("page").unwrap(), vec!["user", "recent_posts", "team_stats"]);
+        assert_eq!(engine.required_data("sidebar").unwrap(), vec!["team_stats", "user"]);
+        assert!(engine.required_data("missing").is_err());
+    }
+
+    #[test]
+    fn translates_templates_into_values() {
+        let mut engine = Engine::new();
+        engine.add_template("ports", "<% default base = 8000 %>\n<%= vec![base, base + 1] %>\n").unwrap();
+        engine.add_template("text", "port <%= 80 %>").unwrap();
+
+        let code = engine.rust_value_code("ports", "Vec<u16>", &Default::default()).unwrap();
+        assert!(code.contains("pub fn value() -> Vec<u16> {"));
+        assert!(code.contains("(vec![base, base + 1])"));
+        assert!(engine.rust_value_code("text", "u16", &Default::default()).is_err());
+    }
+
+    #[test]
+    fn maps_output_to_included_templates() {
+        let mut engine = Engine::new();
+        engine.add_template("header", "HEADER").unwrap();
+        engine.add_template("page", "<% include \"header\" %>body").unwrap();
+
+        let config = trans::Config { source_map: true, ..Default::default() };
+        let code = engine.rust_code("page", &config).unwrap();
+        assert!(code.contains("_source_map.push((_start.._writer.count, \"header\", 0..6));"));
+        assert!(code.contains("_source_map.push((_start.._writer.count, \"page\", 22..26));"));
+    }
+
+    #[test]
+    fn limits_include_depth() {
+        let mut engine = Engine::new();
+        engine.add_template("a", "<% include \"b\" %>").unwrap();
+        engine.add_template("b", "<% if x %><% include \"c\" %><% end %>").unwrap();
+        engine.add_template("c", "c").unwrap();
+
+        engine.set_max_include_depth(Some(2));
+        assert!(engine.rust_code("a", &Default::default()).is_ok());
+
+        engine.set_max_include_depth(Some(1));
+        assert_eq!(engine.rust_code("a", &Default::default()).unwrap_err().code(), "E0105");
+    }
+
+    #[test]
+    fn applies_engine_limits() {
+        let mut engine = Engine::new();
+        engine.add_template("list", "<% for x in xs %><%= x %><% end %>").unwrap();
+        engine.set_limits(trans::Limits { max_loop_iterations: Some(10), ..Default::default() });
+
+        let config = trans::Config {
+            limits: trans::Limits { max_loop_iterations: Some(20), max_output_size: Some(5), ..Default::default() },
+            ..Default::default()
+        };
+        let code = engine.rust_code("list", &config).unwrap();
+        assert!(code.contains("if _iterations.get() > 10 {"));
+        assert!(code.contains("if self.count + bytes.len() > 5 {"));
+    }
+
+    #[test]
+    fn translates_values_with_the_engine_config() {
+        let mut engine = Engine::new();
+        engine.add_template("port.toml.trs", "<%= 8080 %>").unwrap();
+
+        let code = engine.rust_value_code("port.toml.trs", "u16", &Default::default()).unwrap();
+        assert!(code.contains("let comment = |text: &str| -> String {"));
+    }
+
+    #[test]
+    fn limits_calls_of_functions() {
+        let mut engine = Engine::new();
+        engine.add_template("feed", "<% for url in urls %><%= http_fetch(url) %><% end %>").unwrap();
+        engine.set_limits(trans::Limits { max_calls: vec![("http_fetch".to_owned(), 5)], ..Default::default() });
+
+        let code = engine.rust_code("feed", &Default::default()).unwrap();
+        assert!(code.contains("let _calls = [::std::cell::Cell::new(0usize)];"));
+        assert!(code.contains("if _calls[0].get() > 5 {"));
+        assert!(code.contains("was called more than the limit of 5 times [E0119]"));
+        assert!(code.contains(" http_fetch })(url)"));
+    }
+
+    #[test]
+    fn errors_on_include_cycles() {
+        let mut engine = Engine::new();
+        engine.add_template("a", "<% include \"b\" %>").unwrap();
+        engine.add_template("b", "<% for x in xs %><% include \"c\" %><% end %>").unwrap();
+        engine.add_template("c", "<% include \"b\" %>").unwrap();
+
+        let error = engine.rust_code("a", &Default::default()).unwrap_err();
+        assert_eq!(error.code(), "E0109");
+        assert_eq!(error.to_string(), "templates include each other: a -> b -> c -> b");
+        assert_eq!(engine.dependencies("a").unwrap(), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn translates_batches_in_order() {
+        let mut engine = Engine::new();
+        let names: Vec<String> = (0..20).map(|i| format!("page{}", i)).collect();
+        for name in names.iter() {
+            engine.add_template(name, &format!("<h1>{}</h1>", name)).unwrap();
+        }
+
+        let mut jobs: Vec<_> = names.iter().map(|n| (&n[..], trans::Config::default())).collect();
+        jobs.push(("missing", trans::Config::default()));
+        let results = engine.rust_code_batch(&jobs);
+
+        assert_eq!(results.len(), 21);
+        for (name, result) in names.iter().zip(results.iter()) {
+            assert!(result.as_ref().unwrap().contains(&format!("<h1>{}</h1>", name)));
+        }
+        assert!(results[20].is_err());
+    }
+
+    #[test]
+    fn translates_async_templates() {
+        let mut engine = Engine::new();
+        engine.add_template("row", "<%= fetch(id).await %>").unwrap();
+
+        let config = trans::Config { async_render: true, ..Default::default() };
+        assert!(engine.rust_code("row", &config).unwrap().starts_with("pub async fn render("));
+
+        let config = trans::Config { include_entry_point: true, ..config };
+        assert_eq!(engine.rust_code("row", &config).unwrap_err().code(), "E0110");
+    }
+
+    #[test]
+    fn rejects_awaiting_in_blocks_rendered_by_closures() {
+        let mut engine = Engine::new();
+        engine.add_template("capture", "<% capture c %><% if x %><%= fetch().await %><% end %><% end %>").unwrap();
+        engine.add_template("limited", "<% block b limit=10 %><%= fetch().await %><% end %>").unwrap();
+        engine.add_template("cached", "<% cache id %><%= fetch().await %><% end %>").unwrap();
+        engine.add_template("plain", "<% block b %><% with x = fetch().await %><%= x %><% end %><% end %>\
+                                      <%= \".await\" %>").unwrap();
+
+        let config = trans::Config { async_render: true, ..Default::default() };
+        let error = engine.rust_code("capture", &config).unwrap_err();
+        assert_eq!((error.code(), error.to_string()), ("E0122", "a capture block cannot use `.await`".to_owned()));
+        assert_eq!(engine.rust_code("limited", &config).unwrap_err().code(), "E0122");
+        assert!(engine.rust_code("cached", &config).is_ok());
+        assert!(engine.rust_code("plain", &config).is_ok());
+
+        let config = trans::Config { fragment_cache: true, block_fallback: Some("x".to_owned()), ..config };
+        assert_eq!(engine.rust_code("cached", &config).unwrap_err().code(), "E0122");
+        assert_eq!(engine.rust_code("plain", &config).unwrap_err().code(), "E0122");
+    }
+
+    #[test]
+    fn translates_async_templates_into_send_futures() {
+        let mut engine = Engine::new();
+        engine.add_template("row", "<%= fetch().await %><% block name %><%= t(\"hi\") %><% end %>").unwrap();
+
+        let config = trans::Config {
+            async_render: true,
+            archive_files: true,
+            translations: true,
+            fragment_cache: true,
+            usage: true,
+            block_fallback: Some("failed".to_owned()),
+            ..Default::default()
+        };
+        let mut code = engine.rust_code("row", &config).unwrap();
+        code.push_str("
+            async fn fetch() -> u32 { 1 }
+
+            pub fn check() {
+                fn assert_send<T: Send>(_: T) {}
+                let (mut output, mut archive) = (Vec::new(), Vec::new());
+                assert_send(render(&mut output, &mut archive, &|_, _| None, &|_, f| f(), &|_| (),
+                                   &|_, _| String::new()));
+            }
+        ");
+
+        assert_compiles("async_send", &code, "2018");
+    }
+
+    #[test]
+    fn translates_file_blocks_into_archives() {
+        let mut engine = Engine::new();
+        engine.add_template("models", "<% file \"a.rs\" %>struct A;<% end %>").unwrap();
+
+        let code = engine.rust_code("models", &trans::Config::default()).unwrap();
+        assert!(code.contains("::std::fs::File::create(&_path)?"));
+
+        let config = trans::Config { archive_files: true, ..Default::default() };
+        let code = engine.rust_code("models", &config).unwrap();
+        assert!(code.starts_with("pub fn render(_writer: &mut ::std::io::Write, _archive: &mut ::std::io::Write)"));
+        assert!(!code.contains("::std::fs::File"));
+    }
+
+    #[test]
+    fn translates_templates_with_messages() {
+        let mut engine = Engine::new();
+        engine.add_template("welcome", "<%= t(\"welcome.title\") %>").unwrap();
+
+        let config = trans::Config { translations: true, ..Default::default() };
+        let code = engine.rust_code("welcome", &config).unwrap();
+        assert!(code.starts_with("pub fn render(_writer: &mut ::std::io::Write, _translate: &Fn(&str, Option<i64>) -> Option<String>)"));
+        assert!(code.contains("let t = |key: &str| -> String {"));
+    }
+
+    #[test]
+    fn translates_cached_fragments() {
+        let mut engine = Engine::new();
+        engine.add_template("page", "<% cache id % 2 %><%= links() %><% end %>").unwrap();
+
+        let config = trans::Config { fragment_cache: true, ..Default::default() };
+        let code = engine.rust_code("page", &config).unwrap();
+        assert!(code.starts_with("pub fn render(_writer: &mut ::std::io::Write, _cache: &Fn(&str, &mut FnMut() -> String) -> String)"));
+        assert!(code.contains("let _key = format!(\"{}:{}\", \"page\", id % 2);"));
+        assert!(code.contains("let _fragment = _cache(&_key, &mut || {"));
+
+        let code = engine.rust_code("page", &Default::default()).unwrap();
+        assert!(!code.contains("_cache"));
+    }
+
+    #[test]
+    fn translates_templates_reporting_usage() {
+        let mut engine = Engine::new();
+        engine.add_template("page", "<%= title %><% if user.is_admin %><% include \"admin\" %><% end %>").unwrap();
+        engine.add_template("admin", "<%= user.name %>").unwrap();
+
+        let config = trans::Config { usage: true, ..Default::default() };
+        let code = engine.rust_code("page", &config).unwrap();
+        assert!(code.starts_with("pub fn render(_writer: &mut ::std::io::Write, _usage: &Fn(&'static str))"));
+
+        let keys: Vec<_> = code.lines().filter(|line| line.contains("_usage(\"")).map(|line| line.trim()).collect();
+        assert_eq!(keys, vec![
+            "_usage(\"template:page\");",
+            "_usage(\"variable:title\");",
+            "_usage(\"variable:user\");",
+            "_usage(\"block:page:if@12\");",
+            "_usage(\"template:admin\");",
+            "_usage(\"variable:user\");",
+        ]);
+    }
+
+    #[test]
+    fn invalidates_templates_including_changed_ones() {
+        let mut engine = Engine::new();
+        engine.add_template("header", "<% include \"logo\" %>").unwrap();
+        engine.add_template("logo", "tempo").unwrap();
+        engine.add_template("page", "<% include \"header\" %>body").unwrap();
+        engine.add_template("about", "about").unwrap();
+
+        let invalidated = Arc::new(Mutex::new(Vec::new()));
+        let handler_invalidated = invalidated.clone();
+        engine.set_invalidation_handler(move |name| handler_invalidated.lock().unwrap().push(name.to_owned()));
+
+        engine.add_template("logo", "tempo!").unwrap();
+        assert_eq!(*invalidated.lock().unwrap(), vec!["header", "logo", "page"]);
+
+        invalidated.lock().unwrap().clear();
+        engine.with_template_override("logo", "stub", |_| ()).unwrap();
+        engine.remove_template("about");
+        engine.remove_template("missing");
+        assert_eq!(*invalidated.lock().unwrap(), vec!["about"]);
+    }
+
+    /// Writes a file, making its modification time later than any before.
+    fn write_changed(path: &Path, contents: &str, generation: u64) {
+        fs::write(path, contents).unwrap();
+        let modified = SystemTime::now() + ::std::time::Duration::from_secs(60 * generation);
+        fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn reloads_changed_files_when_they_are_used() {
+        let dir = ::std::env::temp_dir().join(format!("tempo-auto-reload-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        write_changed(&dir.join("logo.trs"), "first logo", 0);
+
+        let mut engine = Engine::new();
+        engine.add_template_file("logo", dir.join("logo.trs")).unwrap();
+        engine.add_template("page", "<% include \"logo\" %>").unwrap();
+        engine.set_auto_reload(true);
+
+        let invalidated = Arc::new(Mutex::new(Vec::new()));
+        let handler_invalidated = invalidated.clone();
+        engine.set_invalidation_handler(move |name| handler_invalidated.lock().unwrap().push(name.to_owned()));
+
+        write_changed(&dir.join("logo.trs"), "second logo", 1);
+        assert!(engine.rust_code("page", &Default::default()).unwrap().contains("second logo"));
+        assert_eq!(*invalidated.lock().unwrap(), vec!["logo", "page"]);
+
+        // The re-parsed template is kept, so is not parsed again.
+        invalidated.lock().unwrap().clear();
+        assert!(engine.rust_code("page", &Default::default()).unwrap().contains("second logo"));
+        assert_eq!(engine.reload_changed().unwrap(), Vec::<String>::new());
+        assert!(invalidated.lock().unwrap().is_empty());
+        assert!(engine.rust_code("logo", &Default::default()).unwrap().contains("second logo"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reloads_changed_files_all_at_once() {
+        let dir = ::std::env::temp_dir().join(format!("tempo-reload-changed-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        write_changed(&dir.join("a.trs"), "a", 0);
+        write_changed(&dir.join("b.trs"), "b", 0);
+
+        let mut engine = Engine::new();
+        engine.add_template_file("a", dir.join("a.trs")).unwrap();
+        engine.add_template_file("b", dir.join("b.trs")).unwrap();
+        assert_eq!(engine.reload_changed().unwrap(), Vec::<String>::new());
+
+        let invalidated = Arc::new(Mutex::new(Vec::new()));
+        let handler_invalidated = invalidated.clone();
+        engine.set_invalidation_handler(move |name| handler_invalidated.lock().unwrap().push(name.to_owned()));
+
+        write_changed(&dir.join("a.trs"), "new a", 1);
+        write_changed(&dir.join("b.trs"), "<% end %>", 1);
+        assert_eq!(engine.reload_changed().unwrap_err().code(), "E0002");
+        assert!(engine.rust_code("a", &Default::default()).unwrap().contains("\"a\""));
+        assert!(invalidated.lock().unwrap().is_empty());
+
+        write_changed(&dir.join("b.trs"), "new b", 2);
+        assert_eq!(engine.reload_changed().unwrap(), vec!["a", "b"]);
+        assert!(engine.rust_code("a", &Default::default()).unwrap().contains("new a"));
+        assert_eq!(*invalidated.lock().unwrap(), vec!["a", "b"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn includes_fallbacks_and_missing_templates() {
+        let mut engine = Engine::new();
+        engine.add_template("page", "<% include \"theme/header\" or \"header\" %><% include \"theme/footer\" %>").unwrap();
+        engine.add_template("header", "default header").unwrap();
+
+        assert_eq!(engine.rust_code("page", &Default::default()).unwrap_err().code(), "E0101");
+        assert_eq!(engine.dependencies("page").unwrap(), vec!["header", "theme/footer", "theme/header"]);
+
+        engine.set_missing_include(MissingInclude::Warn);
+        assert!(engine.rust_code("page", &Default::default()).unwrap().contains("default header"));
+        let warnings = engine.warnings("page").unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!((warnings[0].template.as_ref().unwrap(), warnings[0].span.clone()), (&"page".to_owned(), 40..68));
+
+        engine.add_template("theme/header", "themed header").unwrap();
+        assert!(engine.rust_code("page", &Default::default()).unwrap().contains("themed header"));
+    }
+
+    #[test]
+    fn loads_templates_matching_globs() {
+        let root = ::std::env::temp_dir().join(format!("tempo-load-glob-{}", ::std::process::id()));
+        fs::create_dir_all(root.join("mail")).unwrap();
+        fs::write(root.join("page.trs"), "<% include \"mail/welcome.trs\" %>").unwrap();
+        fs::write(root.join("notes.txt"), "not a template").unwrap();
+        fs::write(root.join("mail/welcome.trs"), "Welcome").unwrap();
+        fs::write(root.join("mail/broken.trs"), "<% end %>").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            fs::write(root.join(::std::ffi::OsStr::from_bytes(b"bad\xff.trs")), "").unwrap();
+        }
+
+        let mut engine = Engine::new();
+        let pattern = format!("{}/**/*.trs", root.display());
+        let error = engine.load_glob(&pattern).unwrap_err();
+        match error.kind() {
+            &ErrorKind::TemplatesFailedToLoad(ref failures) => {
+                let codes: Vec<_> = failures.iter().map(|&(_, ref e)| e.code()).collect();
+                assert_eq!(codes, if cfg!(unix) { vec!["E0204", "E0002"] } else { vec!["E0002"] });
+            },
+            kind => panic!("unexpected error: {:?}", kind),
+        }
+        assert_eq!(engine.template_names(), vec!["mail/welcome.trs", "page.trs"]);
+        assert!(engine.rust_code("page.trs", &Default::default()).unwrap().contains("Welcome"));
+
+        let mut engine = Engine::new();
+        let loaded = engine.load_glob(&format!("{}/mail/w?lcome.*", root.display())).unwrap();
+        assert_eq!(loaded, vec!["welcome.trs"]);
+        assert_eq!(engine.template_path("welcome.trs"), Some(&*root.join("mail/welcome.trs")));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn skips_drafts_when_loading_globs() {
+        let root = ::std::env::temp_dir().join(format!("tempo-drafts-{}", ::std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("draft.trs"), "---\ndraft: true\n---\nSoon").unwrap();
+        fs::write(root.join("published.trs"), "---\ndraft: false\n---\nOut now").unwrap();
+        fs::write(root.join("plain.trs"), "Plain").unwrap();
+        let pattern = format!("{}/*.trs", root.display());
+
+        let mut engine = Engine::new();
+        assert_eq!(engine.load_glob(&pattern).unwrap(), vec!["draft.trs", "plain.trs", "published.trs"]);
+
+        let mut engine = Engine::new();
+        engine.set_skip_drafts(true);
+        assert_eq!(engine.load_glob(&pattern).unwrap(), vec!["plain.trs", "published.trs"]);
+        assert!(!engine.has_template("draft.trs"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn names_origins_of_files_independently_of_their_directory() {
+        let config = trans::Config {
+            fragment_cache: true,
+            usage: true,
+            annotate_origins: true,
+            comment_syntax: Some(comment::Syntax::Block("<!--", "-->")),
+            ..Default::default()
+        };
+
+        let codes: Vec<_> = ["a", "b/c"].iter().map(|dir| {
+            let root = ::std::env::temp_dir().join(format!("tempo-origins-{}/{}", ::std::process::id(), dir));
+            fs::create_dir_all(root.join("mail")).unwrap();
+            fs::write(root.join("page.trs"), "<% cache id %><% include \"mail/welcome.trs\" %><% end %>").unwrap();
+            fs::write(root.join("mail/welcome.trs"), "Welcome").unwrap();
+
+            let mut engine = Engine::new();
+            engine.load_glob(&format!("{}/**/*.trs", root.display())).unwrap();
+            assert_eq!(engine.template("page.trs").unwrap().items[0].origin, Some("page.trs".to_owned()));
+            engine.rust_code("page.trs", &config).unwrap()
+        }).collect();
+        fs::remove_dir_all(::std::env::temp_dir().join(format!("tempo-origins-{}", ::std::process::id()))).unwrap();
+
+        assert!(codes[0].contains("\"page.trs"));
+        assert_eq!(codes[0], codes[1]);
+    }
+
+    #[test]
+    fn translates_single_blocks() {
+        let mut engine = Engine::new();
+        engine.add_template("page", "<% default title = \"Home\" %><h1><%= title %></h1>\
+                                     <% include \"sidebar\" %>").unwrap();
+        engine.add_template("sidebar", "<% block sidebar %><%= unread %> unread<% end %>").unwrap();
+
+        let code = engine.rust_code_block("page", "sidebar", &trans::Config::default()).unwrap();
+        assert!(code.contains("unread") && code.contains("\"Home\"") && !code.contains("<h1>"));
+        assert_eq!(engine.rust_code_block("page", "footer", &trans::Config::default()).unwrap_err().code(), "E0118");
+    }
+
+    #[test]
+    fn translates_single_blocks_using_front_matter_and_macros() {
+        let mut engine = Engine::new();
+        engine.add_template("page", "---\ntitle: Home\n---\n<% macro bold(text) %><b><%= text %></b><% end %>\
+                                     <% block header %><%= bold(title) %><% end %>").unwrap();
+
+        let code = engine.rust_code_block("page", "header", &trans::Config::default()).unwrap();
+        assert_compiles("block_front_matter", &code, "2015");
+    }
+
+    #[test]
+    fn translates_blocks_with_limits() {
+        let mut engine = Engine::new();
+        engine.add_template("push", "<% block body limit=10 truncate=ellipsis %><%= message %><% end %>").unwrap();
+
+        let code = engine.rust_code("push", &trans::Config::default()).unwrap();
+        assert!(code.contains("if _buffer.len() > 10 {"));
+        assert!(code.contains("let mut _end = 7;"));
+    }
+
+    #[test]
+    fn renders_placeholders_for_missing_optional_data() {
+        let mut engine = Engine::new();
+        engine.add_template("page", "---\noptional_data: posts, stats\n---\n<% block feed %>\
+                                     <% for post in posts.iter() %><% block post %><%= post %><%= posts.len() %>\
+                                     <% end %><% end %><% end %><% block footer %>(c)<% end %>").unwrap();
+
+        let mut config = trans::Config::default();
+        let code = engine.rust_code("page", &config).unwrap();
+        assert_eq!(code.matches("if let (&Some(ref posts),) = (&posts,) {").count(), 1);
+        assert!(code.contains("if posts.is_none() {") && code.contains("[E0120]"));
+
+        config.placeholder = Some("<div data-fill=\"{name}\"></div>".to_owned());
+        let code = engine.rust_code("page", &config).unwrap();
+        assert!(code.contains("<div data-fill=\\\"feed\\\"></div>") && !code.contains("E0120"));
+    }
+
+    #[test]
+    fn translates_pinned_versions() {
+        let mut engine = Engine::new();
+        engine.add_template("receipt", "Total: <% include \"amount\" %>").unwrap();
+        engine.add_template("amount", "<%= total %>").unwrap();
+        engine.add_template_version("receipt", "v1", "Paid <% include \"amount\" %>").unwrap();
+        engine.add_template_version("amount", "v1", "<%= total_cents %>").unwrap();
+        engine.add_template_version("receipt", "v2", "Sum: <% include \"amount\" %>").unwrap();
+
+        assert_eq!(engine.template_versions("receipt"), vec!["v1", "v2"]);
+
+        let config = trans::Config::default();
+        let v1 = engine.rust_code_versioned("receipt", "v1", &config).unwrap();
+        assert!(v1.contains("Paid ") && v1.contains("total_cents"));
+        match engine.rust_code_versioned("receipt", "v2", &config).unwrap_err().kind() {
+            &ErrorKind::UnknownTemplateVersion(ref name, _) => assert_eq!(name, "amount"),
+            kind => panic!("unexpected error: {:?}", kind),
+        }
+        engine.add_template_version("amount", "v2", "<%= total %>").unwrap();
+        let v2 = engine.rust_code_versioned("receipt", "v2", &config).unwrap();
+        assert!(v2.contains("Sum: ") && !v2.contains("total_cents"));
+        assert!(engine.rust_code("receipt", &config).unwrap().contains("Total: "));
+
+        match engine.rust_code_versioned("receipt", "v3", &config).unwrap_err().kind() {
+            &ErrorKind::UnknownTemplateVersion(..) => (),
+            kind => panic!("unexpected error: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn translates_templates_measuring_renders() {
+        let mut engine = Engine::new();
+        engine.add_template("page", "<% for item in items %><%= item %><% end %>").unwrap();
+
+        let config = trans::Config { render_stats: true, ..Default::default() };
+        let code = engine.rust_code("page", &config).unwrap();
+        assert!(code.starts_with("#[derive(Clone, Debug, Default)]\npub struct RenderStats"));
+        assert!(code.contains("pub fn render(_writer: &mut ::std::io::Write, _stats: &mut RenderStats)"));
+        assert!(code.contains("*_stats = RenderStats {"));
+    }
+
+    #[test]
+    fn translates_blocks_with_fallbacks() {
+        let mut engine = Engine::new();
+        engine.add_template("page", "<% block feed %><%= feed()? %><% end %>").unwrap();
+
+        let fallback = Some("<!-- block {name} failed: {id} -->".to_owned());
+        let config = trans::Config { block_fallback: fallback, ..Default::default() };
+        let code = engine.rust_code("page", &config).unwrap();
+        assert!(code.contains("_block_failed: &Fn(&'static str, &::std::io::Error) -> String)"));
+        assert!(code.contains("let _id = _block_failed(\"feed\", _error);"));
+        assert!(code.contains("\"<!-- block feed failed: {id} -->\".replace(\"{id}\", &_id)"));
+    }
+
+    #[test]
+    fn translates_canary_renders() {
+        let mut engine = Engine::new();
+        engine.add_template("v1", "Hi <%= name %>").unwrap();
+        engine.add_template("v2", "Hello <%= name %>").unwrap();
+
+        let config = trans::Config { usage: true, ..Default::default() };
+        let code = engine.rust_code_canary("v1", "v2", &config).unwrap();
+        assert!(code.starts_with("mod _old {"));
+        assert!(code.contains("pub fn render(_writer: &mut ::std::io::Write, \
+            _report_diff: &Fn(&str, Result<&str, &::std::io::Error>), _usage: &Fn(&'static str))"));
+        assert!(code.contains("_old::render(&mut _old_output, _usage)?;"));
+        assert!(code.contains("_new::render(&mut _new_output, &|_| ());"));
+
+        let config = trans::Config { source_map: true, ..Default::default() };
+        assert_eq!(engine.rust_code_canary("v1", "v2", &config).unwrap_err().code(), "E0113");
+    }
+
+    #[test]
+    fn translates_canary_renders_with_owned_parameters() {
+        let mut engine = Engine::new();
+        engine.add_template("v1", "Hi <%= name %>").unwrap();
+        engine.add_template("v2", "Hello <%= name %>").unwrap();
+
+        let config = trans::Config {
+            parameters: vec![trans::Parameter { name: "name".to_owned(), ty: "String".to_owned() }],
+            ..Default::default()
+        };
+        let mut code = engine.rust_code_canary("v1", "v2", &config).unwrap();
+        assert!(code.contains("_old::render(&mut _old_output, ::std::clone::Clone::clone(&name))?;"));
+        assert!(code.contains("_new::render(&mut _new_output, name);"));
+
+        code.push_str("
+            pub fn check() {
+                render(&mut Vec::new(), &|_, _| (), \"Ada\".to_owned()).unwrap();
+            }
+        ");
+        assert_compiles("canary_owned", &code, "2015");
+    }
+
+    #[test]
+    fn annotates_output_with_origins() {
+        let mut engine = Engine::new();
+        engine.add_template("page.html", "a<% if x %><% include \"header.html\" %><% end %>b").unwrap();
+        engine.add_template("header.html", "---\nsize: 1\n---\n\n<% if y %>\nh<% end %>").unwrap();
+
+        let config = trans::Config { annotate_origins: true, ..Default::default() };
+        let code = engine.rust_code("page.html", &config).unwrap();
+        let texts: Vec<_> = code.lines().filter(|line| line.contains(".write_all(\"")).map(|line| line.trim()).collect();
+        assert_eq!(texts, vec![
+            "_writer.write_all(\"<!-- begin page.html:1 -->\\n\".as_bytes())?;",
+            "_writer.write_all(\"a\".as_bytes())?;",
+            "_writer.write_all(\"<!-- begin header.html:4 -->\\n\".as_bytes())?;",
+            "_writer.write_all(\"\\n\".as_bytes())?;",
+            "_writer.write_all(\"\\nh\".as_bytes())?;",
+            "_writer.write_all(\"<!-- end header.html -->\\n\".as_bytes())?;",
+            "_writer.write_all(\"b\".as_bytes())?;",
+            "_writer.write_all(\"<!-- end page.html -->\\n\".as_bytes())?;",
+        ]);
+
+        engine.add_template("page", "a").unwrap();
+        assert_eq!(engine.rust_code("page", &config).unwrap_err().code(), "E0114");
+    }
+
+    #[test]
+    fn errors_on_unknown_templates() {
+        let engine = Engine::new();
+        assert!(engine.rust_code("missing", &Default::default()).is_err());
+    }
+
+    #[test]
+    fn lists_template_names_in_order() {
+        let mut engine = Engine::new();
+        engine.add_template("b", "").unwrap();
+        engine.add_template("a", "").unwrap();
+        assert_eq!(engine.template_names(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn shares_engines_across_threads() {
+        use std::sync::Arc;
+
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Engine>();
+        assert_send_sync::<ast::Ast>();
+
+        let mut engine = Engine::new();
+        for i in 0..4 {
+            engine.add_template(&format!("page{}", i), &format!("page {} <%= name %>", i)).unwrap();
+        }
+
+        let engine = Arc::new(engine);
+        let threads: Vec<_> = (0..4).map(|i| {
+            let engine = engine.clone();
+            ::std::thread::spawn(move || engine.rust_code(&format!("page{}", i), &Default::default()).unwrap())
+        }).collect();
+
+        for (i, thread) in threads.into_iter().enumerate() {
+            let code = thread.join().unwrap();
+            assert_eq!(code, engine.rust_code(&format!("page{}", i), &Default::default()).unwrap());
+            assert!(code.contains(&format!("\"page {} \"", i)));
+        }
+    }
+
+    /// Checks that generated code compiles as a library.
+    fn assert_compiles(name: &str, code: &str, edition: &str) {
+        let dir = ::std::env::temp_dir().join(format!("tempo-{}-{}", name, ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.rs"), code).unwrap();
+        let output = ::std::process::Command::new("rustc")
+            .args(&["--edition", edition, "--crate-type", "lib", "--emit", "metadata", "-A", "warnings"])
+            .arg("--out-dir").arg(&dir)
+            .arg(dir.join("lib.rs"))
+            .output().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    }
+}
@@ -6,4 +6,987 @@ error_chain! {
     foreign_links {
         Io(::std::io::Error);
     }
+
+    errors {
+        UnterminatedBlock(kind: String, line: usize, column: usize) {
+            description("unterminated block")
+            display(e) -> ("{}", e.message())
+        }
+
+        UnexpectedEnd {
+            description("unexpected end of block")
+            display(e) -> ("{}", e.message())
+        }
+
+        UnexpectedElse {
+            description("unexpected else")
+            display(e) -> ("{}", e.message())
+        }
+
+        TemplateTooLarge(size: usize, limit: usize) {
+            description("template too large")
+            display(e) -> ("{}", e.message())
+        }
+
+        TooManyFragments(count: usize, limit: usize) {
+            description("template has too many fragments")
+            display(e) -> ("{}", e.message())
+        }
+
+        InvalidUtf8(index: usize) {
+            description("template is not valid UTF-8")
+            display(e) -> ("{}", e.message())
+        }
+
+        UnsupportedVersion(requirement: String) {
+            description("template requires a different version of tempo")
+            display(e) -> ("{}", e.message())
+        }
+
+        InvalidVersionRequirement(requirement: String) {
+            description("invalid version requirement")
+            display(e) -> ("{}", e.message())
+        }
+
+        InvalidPragma(pragma: String) {
+            description("invalid pragma")
+            display(e) -> ("{}", e.message())
+        }
+
+        InvalidFrontMatter(line: usize, reason: String) {
+            description("invalid front matter")
+            display(e) -> ("{}", e.message())
+        }
+
+        InvalidTag(name: String, reason: String) {
+            description("invalid tag")
+            display(e) -> ("{}", e.message())
+        }
+
+        InvalidBlockOption(option: String) {
+            description("invalid block option")
+            display(e) -> ("{}", e.message())
+        }
+
+        BlocksTooDeep(tag: String, limit: usize, line: usize, column: usize) {
+            description("blocks are nested too deeply")
+            display(e) -> ("{}", e.message())
+        }
+
+        UnknownTemplate(name: String) {
+            description("unknown template")
+            display(e) -> ("{}", e.message())
+        }
+
+        UnknownBlock(template: String, block: String) {
+            description("unknown block")
+            display(e) -> ("{}", e.message())
+        }
+
+        UnresolvedInclude(name: String) {
+            description("unresolved include")
+            display(e) -> ("{}", e.message())
+        }
+
+        NotAnExpression {
+            description("template is not a single expression")
+            display(e) -> ("{}", e.message())
+        }
+
+        MalformedSource(source: String) {
+            description("template contains malformed source")
+            display(e) -> ("{}", e.message())
+        }
+
+        InvalidQuantity(text: String) {
+            description("invalid quantity")
+            display(e) -> ("{}", e.message())
+        }
+
+        UnknownTag(name: String) {
+            description("unknown tag")
+            display(e) -> ("{}", e.message())
+        }
+
+        UnsupportedCanaryOption(option: String) {
+            description("option not supported by canary renders")
+            display(e) -> ("{}", e.message())
+        }
+
+        NoCommentSyntax {
+            description("no comment syntax")
+            display(e) -> ("{}", e.message())
+        }
+
+        UnknownTemplateVersion(name: String, version: String) {
+            description("unknown template version")
+            display(e) -> ("{}", e.message())
+        }
+
+        TemplatesFailedToLoad(failures: Vec<(String, Error)>) {
+            description("templates failed to load")
+            display(e) -> ("{}", e.message())
+        }
+
+        StageFailed(command: String) {
+            description("an output stage failed")
+            display(e) -> ("{}", e.message())
+        }
+
+        InvalidCsv(line: usize, reason: String) {
+            description("invalid CSV")
+            display(e) -> ("{}", e.message())
+        }
+
+        InvalidJson(line: usize, reason: String) {
+            description("invalid JSON")
+            display(e) -> ("{}", e.message())
+        }
+
+        InvalidCatalog(line: usize) {
+            description("invalid message catalog")
+            display(e) -> ("{}", e.message())
+        }
+
+        UnexpectedValueType(expected: &'static str, found: &'static str) {
+            description("unexpected value type")
+            display(e) -> ("{}", e.message())
+        }
+
+        HostNotAllowed(url: String) {
+            description("host not allowed")
+            display(e) -> ("{}", e.message())
+        }
+
+        HttpGetFailed(url: String, reason: String) {
+            description("HTTP request failed")
+            display(e) -> ("{}", e.message())
+        }
+
+        UnknownQuery(name: String) {
+            description("unknown GraphQL query")
+            display(e) -> ("{}", e.message())
+        }
+
+        QueryFailed(name: String, reason: String) {
+            description("GraphQL query failed")
+            display(e) -> ("{}", e.message())
+        }
+
+        IncludeTooDeep(limit: usize) {
+            description("includes are nested too deeply")
+            display(e) -> ("{}", e.message())
+        }
+
+        IncludeCycle(chain: Vec<String>) {
+            description("templates include each other")
+            display(e) -> ("{}", e.message())
+        }
+
+        AsyncEntryPoint {
+            description("async templates cannot have an entry point")
+            display(e) -> ("{}", e.message())
+        }
+
+        OutputLimitExceeded(limit: usize) {
+            description("output is too large")
+            display(e) -> ("{}", e.message())
+        }
+
+        BlockLimitExceeded(name: String, limit: u64) {
+            description("output of a block is too large")
+            display(e) -> ("{}", e.message())
+        }
+
+        CallLimitExceeded(function: String, limit: usize) {
+            description("a function was called too many times")
+            display(e) -> ("{}", e.message())
+        }
+
+        MissingOptionalData(block: String, key: String) {
+            description("optional data was not given")
+            display(e) -> ("{}", e.message())
+        }
+
+        UnknownItem(source: String) {
+            description("template contains an item which cannot be translated")
+            display(e) -> ("{}", e.message())
+        }
+
+        AwaitInClosure(block: String) {
+            description("a block of an async template awaits a value")
+            display(e) -> ("{}", e.message())
+        }
+
+        IterationLimitExceeded(limit: usize) {
+            description("loops ran too many times")
+            display(e) -> ("{}", e.message())
+        }
+
+        RenderTimeLimitExceeded(limit: ::std::time::Duration) {
+            description("rendering took too long")
+            display(e) -> ("{}", e.message())
+        }
+
+        InvalidParamDeclaration(line: usize, reason: String) {
+            description("invalid parameter declaration")
+            display(e) -> ("{}", e.message())
+        }
+
+        InvalidParamValue(name: String, value: String) {
+            description("invalid parameter value")
+            display(e) -> ("{}", e.message())
+        }
+
+        InvalidHeader(path: String, line: String) {
+            description("invalid template header")
+            display(e) -> ("{}", e.message())
+        }
+
+        InvalidPath(path: String) {
+            description("path is not valid UTF-8")
+            display(e) -> ("{}", e.message())
+        }
+
+        UnterminatedRegion(name: String) {
+            description("unterminated region")
+            display(e) -> ("{}", e.message())
+        }
+
+        UnexpectedRegionEnd {
+            description("unexpected end of region")
+            display(e) -> ("{}", e.message())
+        }
+
+        WrongParamCount(expected: usize, found: usize) {
+            description("wrong number of parameter values")
+            display(e) -> ("{}", e.message())
+        }
+    }
+}
+
+/// The version of the messages of errors.
+///
+/// Messages are written from the templates given by `message_template`,
+/// and this is incremented whenever one changes, so that tools which
+/// match on messages can find out. The code and arguments of an error
+/// are stable across versions, and are better to match on.
+pub const MESSAGES_VERSION: u32 = 1;
+
+/// Builds the arguments of an error's message from `name: value` pairs.
+macro_rules! arguments {
+    ($($name:ident : $value:expr),* $(,)*) => {
+        vec![$((stringify!($name), $value.to_string())),*]
+    };
+}
+
+impl ErrorKind
+{
+    /// Gets the stable code identifying this kind of error.
+    ///
+    /// Codes never change meaning between releases. Errors from
+    /// parsing templates are `E00xx`, from the engine `E01xx`, from
+    /// scaffolding `E02xx`, from helpers and data used by the code in
+    /// templates `E03xx`, and from the environment `E09xx`.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            ErrorKind::UnterminatedBlock(..) => "E0001",
+            ErrorKind::UnexpectedEnd => "E0002",
+            ErrorKind::InvalidPragma(..) => "E0003",
+            ErrorKind::UnsupportedVersion(..) => "E0004",
+            ErrorKind::InvalidVersionRequirement(..) => "E0005",
+            ErrorKind::UnexpectedElse => "E0006",
+            ErrorKind::TemplateTooLarge(..) => "E0007",
+            ErrorKind::TooManyFragments(..) => "E0008",
+            ErrorKind::InvalidUtf8(..) => "E0009",
+            ErrorKind::InvalidFrontMatter(..) => "E0010",
+            ErrorKind::InvalidTag(..) => "E0011",
+            ErrorKind::InvalidBlockOption(..) => "E0012",
+            ErrorKind::BlocksTooDeep(..) => "E0013",
+            ErrorKind::UnknownTemplate(..) => "E0101",
+            ErrorKind::UnresolvedInclude(..) => "E0102",
+            ErrorKind::NotAnExpression => "E0103",
+            ErrorKind::MalformedSource(..) => "E0104",
+            ErrorKind::IncludeTooDeep(..) => "E0105",
+            ErrorKind::OutputLimitExceeded(..) => "E0106",
+            ErrorKind::IterationLimitExceeded(..) => "E0107",
+            ErrorKind::RenderTimeLimitExceeded(..) => "E0108",
+            ErrorKind::IncludeCycle(..) => "E0109",
+            ErrorKind::AsyncEntryPoint => "E0110",
+            ErrorKind::StageFailed(..) => "E0111",
+            ErrorKind::UnknownTag(..) => "E0112",
+            ErrorKind::UnsupportedCanaryOption(..) => "E0113",
+            ErrorKind::NoCommentSyntax => "E0114",
+            ErrorKind::UnknownTemplateVersion(..) => "E0115",
+            ErrorKind::BlockLimitExceeded(..) => "E0116",
+            ErrorKind::TemplatesFailedToLoad(..) => "E0117",
+            ErrorKind::UnknownBlock(..) => "E0118",
+            ErrorKind::CallLimitExceeded(..) => "E0119",
+            ErrorKind::MissingOptionalData(..) => "E0120",
+            ErrorKind::UnknownItem(..) => "E0121",
+            ErrorKind::AwaitInClosure(..) => "E0122",
+            ErrorKind::InvalidParamDeclaration(..) => "E0201",
+            ErrorKind::InvalidParamValue(..) => "E0202",
+            ErrorKind::InvalidHeader(..) => "E0203",
+            ErrorKind::InvalidPath(..) => "E0204",
+            ErrorKind::UnterminatedRegion(..) => "E0205",
+            ErrorKind::UnexpectedRegionEnd => "E0206",
+            ErrorKind::WrongParamCount(..) => "E0207",
+            ErrorKind::InvalidQuantity(..) => "E0301",
+            ErrorKind::InvalidCsv(..) => "E0302",
+            ErrorKind::InvalidJson(..) => "E0303",
+            ErrorKind::InvalidCatalog(..) => "E0304",
+            ErrorKind::UnexpectedValueType(..) => "E0305",
+            ErrorKind::HostNotAllowed(..) => "E0306",
+            ErrorKind::HttpGetFailed(..) => "E0307",
+            ErrorKind::UnknownQuery(..) => "E0308",
+            ErrorKind::QueryFailed(..) => "E0309",
+            ErrorKind::Io(..) => "E0901",
+            ErrorKind::Msg(..) => "E0999",
+        }
+    }
+
+    /// Gets the parts of the error's message which vary, by the names
+    /// they have in the template of the message.
+    ///
+    /// The names for a code never change, so tools can read these rather
+    /// than parsing the message.
+    pub fn arguments(&self) -> Vec<(&'static str, String)> {
+        match *self {
+            ErrorKind::UnterminatedBlock(ref kind, ref line, ref column) => {
+                arguments![kind: kind, line: line, column: column]
+            },
+            ErrorKind::UnexpectedEnd => arguments![],
+            ErrorKind::InvalidPragma(ref pragma) => arguments![pragma: pragma],
+            ErrorKind::UnsupportedVersion(ref requirement) => arguments![requirement: requirement, version: ::VERSION],
+            ErrorKind::InvalidVersionRequirement(ref requirement) => arguments![requirement: requirement],
+            ErrorKind::UnexpectedElse => arguments![],
+            ErrorKind::TemplateTooLarge(ref size, ref limit) => arguments![size: size, limit: limit],
+            ErrorKind::TooManyFragments(ref count, ref limit) => arguments![count: count, limit: limit],
+            ErrorKind::InvalidUtf8(ref index) => arguments![index: index],
+            ErrorKind::InvalidFrontMatter(ref line, ref reason) => arguments![line: line, reason: reason],
+            ErrorKind::InvalidTag(ref name, ref reason) => arguments![name: name, reason: reason],
+            ErrorKind::InvalidBlockOption(ref option) => arguments![option: option],
+            ErrorKind::BlocksTooDeep(ref tag, ref limit, ref line, ref column) => {
+                arguments![tag: tag, limit: limit, line: line, column: column]
+            },
+            ErrorKind::UnknownTemplate(ref name) => arguments![name: name],
+            ErrorKind::UnresolvedInclude(ref name) => arguments![name: name],
+            ErrorKind::NotAnExpression => arguments![],
+            ErrorKind::MalformedSource(ref source) => arguments![source: source],
+            ErrorKind::IncludeTooDeep(ref limit) => arguments![limit: limit],
+            ErrorKind::OutputLimitExceeded(ref limit) => arguments![limit: limit],
+            ErrorKind::IterationLimitExceeded(ref limit) => arguments![limit: limit],
+            ErrorKind::RenderTimeLimitExceeded(ref limit) => arguments![limit: ::units::Duration(*limit)],
+            ErrorKind::IncludeCycle(ref chain) => arguments![chain: chain.join(" -> ")],
+            ErrorKind::AsyncEntryPoint => arguments![],
+            ErrorKind::StageFailed(ref command) => arguments![command: command],
+            ErrorKind::UnknownTag(ref name) => arguments![name: name],
+            ErrorKind::UnsupportedCanaryOption(ref option) => arguments![option: option],
+            ErrorKind::NoCommentSyntax => arguments![],
+            ErrorKind::UnknownTemplateVersion(ref name, ref version) => arguments![name: name, version: version],
+            ErrorKind::BlockLimitExceeded(ref name, ref limit) => arguments![name: name, limit: limit],
+            ErrorKind::TemplatesFailedToLoad(ref failures) => arguments![
+                count: failures.len(),
+                failures: failures.iter().map(|&(ref path, ref e)| format!("{}: {}", path, e))
+                    .collect::<Vec<_>>().join("; ")
+            ],
+            ErrorKind::UnknownBlock(ref template, ref block) => arguments![template: template, block: block],
+            ErrorKind::CallLimitExceeded(ref function, ref limit) => arguments![function: function, limit: limit],
+            ErrorKind::MissingOptionalData(ref block, ref key) => arguments![block: block, key: key],
+            ErrorKind::UnknownItem(ref source) => arguments![source: source],
+            ErrorKind::AwaitInClosure(ref block) => arguments![block: block],
+            ErrorKind::InvalidParamDeclaration(ref line, ref reason) => arguments![line: line, reason: reason],
+            ErrorKind::InvalidParamValue(ref name, ref value) => arguments![name: name, value: value],
+            ErrorKind::InvalidHeader(ref path, ref line) => arguments![path: path, line: line],
+            ErrorKind::InvalidPath(ref path) => arguments![path: path],
+            ErrorKind::UnterminatedRegion(ref name) => arguments![name: name],
+            ErrorKind::UnexpectedRegionEnd => arguments![],
+            ErrorKind::WrongParamCount(ref expected, ref found) => arguments![expected: expected, found: found],
+            ErrorKind::InvalidQuantity(ref text) => arguments![text: text],
+            ErrorKind::InvalidCsv(ref line, ref reason) => arguments![line: line, reason: reason],
+            ErrorKind::InvalidJson(ref line, ref reason) => arguments![line: line, reason: reason],
+            ErrorKind::InvalidCatalog(ref line) => arguments![line: line],
+            ErrorKind::UnexpectedValueType(ref expected, ref found) => arguments![expected: expected, found: found],
+            ErrorKind::HostNotAllowed(ref url) => arguments![url: url],
+            ErrorKind::HttpGetFailed(ref url, ref reason) => arguments![url: url, reason: reason],
+            ErrorKind::UnknownQuery(ref name) => arguments![name: name],
+            ErrorKind::QueryFailed(ref name, ref reason) => arguments![name: name, reason: reason],
+            ErrorKind::Io(ref error) => arguments![error: error],
+            ErrorKind::Msg(ref message) => arguments![message: message],
+        }
+    }
+
+    /// Gets the message of the error, written from its template.
+    pub fn message(&self) -> String {
+        let template = message_template(self.code()).unwrap();
+        fill_template(template, &self.arguments())
+    }
+}
+
+impl Error
+{
+    /// Gets the stable code identifying this error.
+    pub fn code(&self) -> &'static str {
+        self.kind().code()
+    }
+
+    /// Gets the parts of the error's message which vary, by name.
+    pub fn arguments(&self) -> Vec<(&'static str, String)> {
+        self.kind().arguments()
+    }
+}
+
+/// Gets the template the message of each error is written from, with
+/// the names of its arguments in braces.
+///
+/// Messages only change when these do, and `MESSAGES_VERSION` is then
+/// incremented.
+pub fn message_template(code: &str) -> Option<&'static str> {
+    let template = match code {
+        "E0001" => "unterminated '{kind}' block opened on line {line}, column {column}",
+        "E0002" => "found 'end' outside of any block",
+        "E0003" => "invalid pragma: '{pragma}'",
+        "E0004" => "template requires tempo {requirement} but this is version {version}",
+        "E0005" => "invalid version requirement: '{requirement}'",
+        "E0006" => "found 'else' outside of an 'if' block",
+        "E0007" => "template is {size} bytes, but the limit is {limit} bytes",
+        "E0008" => "template has {count} fragments of text and code, but the limit is {limit}",
+        "E0009" => "template is not valid UTF-8 (at byte {index})",
+        "E0010" => "invalid front matter on line {line}: {reason}",
+        "E0011" => "invalid '{name}' tag: {reason}",
+        "E0012" => "invalid block option: '{option}'",
+        "E0013" => "'{tag}' on line {line}, column {column} opens more than {limit} nested blocks",
+        "E0101" => "unknown template: '{name}'",
+        "E0102" => "cannot include '{name}' outside of an engine",
+        "E0103" => "template must be a single '<%= %>' expression, optionally preceded by code",
+        "E0104" => "cannot translate malformed template source: '{source}'",
+        "E0105" => "includes are nested more than {limit} deep",
+        "E0106" => "output is larger than the limit of {limit} bytes",
+        "E0107" => "loops ran more than the limit of {limit} iterations",
+        "E0108" => "rendering took longer than the limit of {limit}",
+        "E0109" => "templates include each other: {chain}",
+        "E0110" => "an entry point cannot be generated for an async render function",
+        "E0111" => "the output stage '{command}' failed",
+        "E0112" => "no handler is registered for the '{name}' tag",
+        "E0113" => "canary renders do not support the '{option}' option",
+        "E0114" => "annotating output needs the comment syntax of the output language",
+        "E0115" => "template '{name}' has no version '{version}'",
+        "E0116" => "the output of block '{name}' is larger than its limit of {limit} bytes",
+        "E0117" => "{count} template(s) failed to load: {failures}",
+        "E0118" => "template '{template}' has no block named '{block}'",
+        "E0119" => "'{function}' was called more than the limit of {limit} times",
+        "E0120" => "block '{block}' uses '{key}', which was not given",
+        "E0121" => "this version of tempo cannot translate '{source}'",
+        "E0122" => "a {block} block cannot use `.await`",
+        "E0201" => "invalid parameter declaration on line {line}: {reason}",
+        "E0202" => "invalid value for parameter '{name}': '{value}'",
+        "E0203" => "invalid header line in {path}: '{line}'",
+        "E0204" => "path is not valid UTF-8: {path}",
+        "E0205" => "region '{name}' is never ended",
+        "E0206" => "found the end of a region that was never begun",
+        "E0207" => "the skeleton has {expected} parameters, but {found} values were given",
+        "E0301" => "invalid duration or size: '{text}'",
+        "E0302" => "invalid CSV on line {line}: {reason}",
+        "E0303" => "invalid JSON on line {line}: {reason}",
+        "E0304" => "invalid message catalog on line {line}: expected 'key = message'",
+        "E0305" => "expected a value of type '{expected}', found '{found}'",
+        "E0306" => "the host of '{url}' is not allowed",
+        "E0307" => "could not fetch '{url}': {reason}",
+        "E0308" => "the query '{name}' is not registered",
+        "E0309" => "query '{name}' failed: {reason}",
+        "E0901" => "{error}",
+        "E0999" => "{message}",
+        _ => return None,
+    };
+
+    Some(template)
+}
+
+/// Writes a message from a template, replacing each `{name}` with the
+/// argument of that name.
+fn fill_template(template: &str, arguments: &[(&'static str, String)]) -> String {
+    let mut message = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        message.push_str(&rest[..start]);
+        let end = start + rest[start..].find('}').unwrap();
+        match arguments.iter().find(|&&(name, _)| name == &rest[start + 1..end]) {
+            Some(&(_, ref value)) => message.push_str(value),
+            None => message.push_str(&rest[start..end + 1]),
+        }
+        rest = &rest[end + 1..];
+    }
+
+    message.push_str(rest);
+    message
+}
+
+/// Gets an extended description of an error code, with examples
+/// of how to fix it.
+///
+/// This is what `tempo explain` prints.
+pub fn explain(code: &str) -> Option<&'static str> {
+    let explanation = match code {
+        "E0001" => r#"A block was opened but never closed.
+
+Blocks such as `macro`, `capture`, `if`, `for` and `raw` must be closed
+before the end of the template. The error gives the line and column
+of the tag which opened the block.
+
+    <% if user.is_admin %>
+      Admin
+
+Close the block with `end` (or `endraw` for raw blocks):
+
+    <% if user.is_admin %>
+      Admin
+    <% end %>
+"#,
+        "E0002" => r#"An `end` tag was found outside of any block.
+
+    hello
+    <% end %>
+
+Either remove the `end` tag or add the tag which opens the block.
+Blocks written as Rust code, such as `<% if x { %>`, are closed with
+`<% } %>` rather than `end`.
+"#,
+        "E0003" => r#"A `@tempo:` comment is not a valid pragma.
+
+Pragmas have the form `name = "value"`, and the only name is `requires`.
+
+    <%# @tempo: requires >=0.1 %>
+
+Add the missing `=`:
+
+    <%# @tempo: requires = ">=0.1" %>
+"#,
+        "E0004" => r#"The template requires a version of tempo which is not this one.
+
+    <%# @tempo: requires = ">=2.0" %>
+
+Upgrade tempo to a version satisfying the requirement, or relax the
+requirement if the template works with this version.
+"#,
+        "E0005" => r#"A version requirement could not be parsed.
+
+    <%# @tempo: requires = "latest" %>
+
+Requirements are a version, optionally preceded by one of `=`, `>`,
+`>=`, `<`, `<=`, `^` or `~`:
+
+    <%# @tempo: requires = "^0.1" %>
+"#,
+        "E0006" => r#"An `else` tag was found outside of an `if` block.
+
+    <% for x in xs %>a<% else %>b<% end %>
+
+`else` may only appear once, directly inside an `if` block:
+
+    <% if xs.is_empty() %>b<% else %>a<% end %>
+"#,
+        "E0007" => r#"A template is larger than the limit set by the engine.
+
+Limits are set with the `max_size` option of `parse::Config`, usually
+to protect services which accept templates from untrusted users. Split
+the template into smaller templates, or raise the limit.
+"#,
+        "E0008" => r#"A template has more fragments than the limit set by the engine.
+
+Every run of text and every `<% %>` tag is a fragment. Limits are set
+with the `max_fragments` option of `parse::Config`. Split the template
+into smaller templates, or raise the limit.
+"#,
+        "E0009" => r#"A template file is not valid UTF-8.
+
+Template trees migrated from other systems are often partly Latin-1.
+Convert the file to UTF-8, or choose a fallback with
+`Engine::set_encoding_fallback` (or `--encoding-fallback` on the
+command line) to decode such files as Latin-1 or lossily.
+"#,
+        "E0010" => r#"The front matter at the top of a template could not be parsed.
+
+    ---
+    tags: [news, release]
+    ---
+
+Front matter holds one `key: value` field per line between `---` lines,
+or `key = value` between `+++` lines. Keys are Rust identifiers, and
+values are strings, numbers or booleans. Lists and tables are not
+supported.
+"#,
+        "E0011" => r#"The arguments of a tag defined by the application were rejected.
+
+    <% cache %>
+
+The tag's handler checks the code after its name when the template is
+parsed, and the message gives its reason. See the documentation of the
+application for how the tag is written, such as:
+
+    <% cache "sidebar" %>
+"#,
+        "E0012" => r#"A named block has an option which is not valid.
+
+    <% block summary limit=2kb %>
+
+Blocks take a `limit`, which is a size such as `512`, `2kB` or `1KiB`,
+and a `truncate`, which is `cut`, `ellipsis` or `error`:
+
+    <% block summary limit=2kB truncate=ellipsis %>
+"#,
+        "E0013" => r#"Blocks are nested more deeply than the limit set by the engine.
+
+    <% if a %><% if b %><% for x in xs %>...
+
+Limits are set with the `max_depth` option of `parse::Config`, usually
+to protect services which accept templates from untrusted users. The
+error names the tag which opened one block too many. Move the innermost
+blocks into a macro or another template, or raise the limit.
+"#,
+        "E0101" => r#"A template was looked up by a name which was never registered.
+
+    engine.rust_code("emial.html.trs", &config)
+
+Check the spelling of the name, and that the template was added to the
+engine with `add_template` or `add_template_file`.
+"#,
+        "E0102" => r#"A template includes another template, but was translated on its own.
+
+Includes are resolved by name, so they need an `Engine` holding every
+template involved. Translate the template through `Engine::rust_code`
+rather than calling `trans::rust_code` directly.
+"#,
+        "E0103" => r#"A template translated into a value is not a single expression.
+
+Templates which give a value rather than text must consist of one
+`<%= %>` expression, optionally preceded by code, defaults and comments.
+
+    port = <%= 8080 %>
+
+Remove the text around the expression:
+
+    <% default base = 8000 %>
+    <%= base + 80 %>
+"#,
+        "E0104" => r#"A template parsed with recovery still has errors in it.
+
+`parse::parse_with_recovery` keeps going past malformed source, so that
+editors can work with the rest of the template, but the result cannot
+be translated until the problems it reports are fixed.
+"#,
+        "E0105" => r#"Templates include each other more deeply than the engine allows.
+
+The limit is set with `Engine::set_max_include_depth`, usually to
+protect services which accept templates from untrusted users. Flatten
+the chain of includes, or raise the limit.
+"#,
+        "E0106" => r#"A template rendered more output than the limit allows.
+
+The limit is the `max_output_size` of `trans::Limits`, set with
+`Engine::set_limits` or in the translation options. Rendering stops as
+soon as a write would go over it.
+"#,
+        "E0107" => r#"The `for` blocks of a template ran more times than the limit allows.
+
+The limit is the `max_loop_iterations` of `trans::Limits`, and counts
+the iterations of every `for` block in one render together. Loops
+written as Rust code, such as `<% for x in xs { %>`, are not counted.
+"#,
+        "E0108" => r#"A template took longer to render than the limit allows.
+
+The limit is the `max_render_time` of `trans::Limits`. It is checked at
+the start of each iteration of a `for` block, so a single slow
+expression is not interrupted.
+"#,
+        "E0109" => r#"Templates include each other, so including them would never end.
+
+    a.html.trs:  <% include "b.html.trs" %>
+    b.html.trs:  <% include "a.html.trs" %>
+
+The message lists the chain of includes, ending with the template
+included a second time. Move the shared part into a template of its
+own which both can include.
+"#,
+        "E0110" => r#"A template was translated with both `async_render` and `include_entry_point`.
+
+An async render function can only be run by an executor, which tempo
+does not provide. Translate the template without an entry point, and
+await `render` from your own async code.
+"#,
+        "E0111" => r#"A command run on the rendered output exited unsuccessfully.
+
+    tempo render invoice.html.trs --pipe "wkhtmltopdf - -"
+
+The command's own error output is shown before this error. Check that
+the command is installed, and that it reads from standard input and
+writes to standard output.
+"#,
+        "E0112" => r#"A template uses a tag which has no handler when it is translated.
+
+Tags are parsed and translated with the same `tag::Tags`. Register them
+on the engine with `Engine::add_tag`, which uses them for both, or set
+`tags` in both the parse and translation options.
+"#,
+        "E0113" => r#"A canary render was translated with an option it does not support.
+
+Canary renders run two versions of a template and compare their output,
+which does not work with source maps, async rendering or an entry point.
+Translate the canary without the option, and call its render function
+from your own code.
+"#,
+        "E0114" => r#"Output was annotated with its origins, but its comment syntax is unknown.
+
+The annotations are comments in the output language. It is inferred from
+the extension of the template's name, as in `page.html.trs`, or can be
+set with `comment_syntax` in the translation options. `tempo render`
+uses the extension of its output path, given with `-o`, if there is one.
+"#,
+        "E0115" => r#"A template was translated pinned to a version it, or a template it
+includes, does not have.
+
+Versions are added with `Engine::add_template_version`, under a label
+such as `v3`. Keep every version which documents were rendered with, so
+that they can be rendered again exactly as they were.
+"#,
+        "E0116" => r#"A named block rendered more output than its limit, and truncates with `error`.
+
+    <% block summary limit=160 truncate=error %>
+
+Blocks which truncate with `cut` or `ellipsis` drop the output over their
+limit instead of failing.
+"#,
+        "E0117" => r#"Some of the template files matched by a glob could not be loaded.
+
+Every matching file is loaded even if others fail, and each failure is
+listed with the path of its file. The templates which did load are kept.
+
+Templates warmed with `warm` or `warm_all` are listed by name instead,
+such as a lazy template whose source could not be read.
+"#,
+        "E0118" => r#"A block of a template was translated on its own, but it has no such block.
+
+    engine.rust_code_block("page.html.trs", "sidebar", &config)
+
+Named blocks are written with `<% block sidebar %>`. Blocks in included
+templates count as blocks of the template which includes them.
+"#,
+        "E0119" => r#"A template called a function more times in one render than its limit allows.
+
+Limits on calls are set with `max_calls` in the translation limits, such
+as for functions which fetch data over the network. Calls inside loops
+count each time they are made.
+"#,
+        "E0120" => r#"A named block uses optional data which was not given to the render.
+
+    ---
+    optional_data: recent_posts
+    ---
+    <% block sidebar %><%= recent_posts.len() %><% end %>
+
+Data listed in `optional_data` is given as an `Option`, and blocks which
+use it are only rendered when it is `Some`. Give the data, or set the
+`placeholder` of the translation config to render placeholder markup in
+place of the block, such as to fill it in later.
+"#,
+        "E0121" => r#"An AST has a kind of item which this version of tempo cannot translate.
+
+The AST types are in the `tempo-ast` crate, which may gain new kinds of
+items in a minor version. An AST built with a newer `tempo-ast` than the
+one tempo was released with can use them. Update tempo, or build the
+item from the kinds it supports.
+"#,
+        "E0122" => r#"A template translated with `async_render` awaits a value inside a block
+which is rendered by a closure.
+
+Closures cannot `.await`, so the bodies of macros, captures, custom
+tags, limited blocks, cached blocks, blocks with a fallback and files
+written into an archive cannot. Await the value before the block, and
+use its result inside it.
+
+    <% set user = fetch_user(id).await %>
+    <% cache user.id %><%= user.name %><% end %>
+"#,
+        "E0201" => r#"A line in a `tempo.params` file is not a valid parameter declaration.
+
+Each line has the form `name: kind = default`, where the kind and the
+default are optional, and the kind is one of `string`, `bool` or `int`.
+
+    project name: text
+
+Parameter names must be identifiers, and kinds must be known:
+
+    project_name: string
+"#,
+        "E0202" => r#"A value given for a scaffold parameter does not match its kind.
+
+    port: int = eighty
+
+Give a value of the declared kind, such as `true` or `false` for
+`bool` parameters, or a whole number for `int` parameters:
+
+    port: int = 80
+"#,
+        "E0203" => r#"A line in the `---` header of a skeleton file is not a valid setting.
+
+Header lines have the form `key: value`, with `emit_if` and
+`if_exists` as the only keys.
+
+    ---
+    emit_if license
+    ---
+
+Add the missing colon:
+
+    ---
+    emit_if: license
+    ---
+"#,
+        "E0204" => r#"A path in a skeleton directory is not valid UTF-8.
+
+Skeleton paths are templates themselves, so they must be valid UTF-8.
+Rename the file.
+"#,
+        "E0205" => r#"A region in a generated file is begun but never ended.
+
+    // tempo:begin imports
+    use std::io;
+
+End the region with a matching marker:
+
+    // tempo:begin imports
+    use std::io;
+    // tempo:end imports
+"#,
+        "E0206" => r#"A region in a generated file is ended but was never begun.
+
+Check that the `tempo:begin` marker for the region was not removed.
+"#,
+        "E0207" => r#"A skeleton was generated with a different number of values than it has parameters.
+
+`Skeleton::program` takes the value of each parameter declared in
+`tempo.params`, in order. Give a value for every parameter, using its
+default where the user did not give one.
+"#,
+        "E0301" => r#"A duration or size could not be parsed.
+
+    "5 minutes".parse::<tempo::units::Duration>()
+
+Durations are whole numbers of `w`, `d`, `h`, `m`, `s`, `ms`, `us`
+or `ns`, such as `1h30m`. Sizes are a number with an optional unit,
+such as `512`, `10kB` or `1.5GiB`.
+"#,
+        "E0302" => r#"A table of data could not be read from CSV.
+
+    name,age
+    Alice,30,extra
+
+The first row holds the names of the columns, and every other row must
+have a field for each of them. Fields holding commas, quotes or new
+lines must be quoted with `"`, writing a quote inside them as `""`.
+"#,
+        "E0303" => r#"A table of data, or another value, could not be read from JSON.
+
+    [{"name": "Alice", "tags": ["admin"]}]
+
+Tables are an array of objects, whose values are strings, numbers,
+booleans or `null`. Nested arrays and objects are not supported in
+tables, and other values, such as the responses of APIs, may only nest
+them 128 deep.
+"#,
+        "E0304" => r#"A catalog of translated messages could not be parsed.
+
+    welcome.title: Welcome!
+
+Each line of a catalog is `key = message`, or a `#` comment. Plural
+forms of a message are given as `key.one` and `key.other`.
+"#,
+        "E0305" => r#"A `value::Value` was converted to a Rust type it does not hold.
+
+    let name: String = Value::Int(1).try_into()?;
+
+Only integers are converted to floats. Other values must be converted
+explicitly, for example by stringifying them with `to_string`.
+"#,
+        "E0306" => r#"A template fetched a URL whose host the HTTP client does not allow.
+
+    let http = tempo::http::Client::new(&["api.internal", "*.status.internal"]);
+
+Only hosts given to the client can be fetched, and a wildcard only
+matches subdomains. Only `http` and `https` URLs are allowed.
+"#,
+        "E0307" => r#"A URL could not be fetched by the HTTP client.
+
+The request failed, its response was larger than the client's limit, or
+it took longer than the client's timeout. Redirects are not followed, so
+URLs must point at the data itself. Fetching needs `curl` to be installed.
+"#,
+        "E0308" => r#"A template ran a GraphQL query which was never registered.
+
+    graphql.query("team_stats", &[])?
+
+Templates may only run the queries given to `graphql::Queries` by the
+application. Check the spelling of the name, or register the query:
+
+    queries.register("team_stats", "query { team { openIssues } }");
+"#,
+        "E0309" => r#"A GraphQL query gave errors, or a response with no data.
+
+The message holds the errors given by the server, such as an unknown
+field or a variable of the wrong type. Check the document of the query
+against the schema of the API.
+"#,
+        "E0901" => r#"A file could not be read or written.
+
+The message gives the underlying error from the operating system, such
+as a missing file or a lack of permissions.
+"#,
+        "E0999" => r#"An error with no more specific code.
+
+The message describes what went wrong.
+"#,
+        _ => return None,
+    };
+
+    Some(explanation)
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    #[test]
+    fn codes_are_exposed_on_errors() {
+        let error: Error = ErrorKind::UnterminatedBlock("macro".to_owned(), 1, 1).into();
+        assert_eq!(error.code(), "E0001");
+
+        let error: Error = "something went wrong".into();
+        assert_eq!(error.code(), "E0999");
+    }
+
+    #[test]
+    fn explains_error_codes() {
+        assert!(explain(ErrorKind::UnexpectedEnd.code()).is_some());
+        assert!(explain("E9999").is_none());
+    }
+
+    #[test]
+    fn writes_messages_from_templates() {
+        let error: Error = ErrorKind::UnterminatedBlock("if".to_owned(), 3, 5).into();
+        assert_eq!(error.arguments(), vec![
+            ("kind", "if".to_owned()),
+            ("line", "3".to_owned()),
+            ("column", "5".to_owned()),
+        ]);
+        assert_eq!(error.to_string(), "unterminated 'if' block opened on line 3, column 5");
+
+        let error: Error = ErrorKind::InvalidJson(1, "unexpected '{line}'".to_owned()).into();
+        assert_eq!(error.to_string(), "invalid JSON on line 1: unexpected '{line}'");
+
+        for code in (0..10000).map(|n| format!("E{:04}", n)) {
+            assert_eq!(message_template(&code).is_some(), explain(&code).is_some(), "{}", code);
+        }
+    }
 }
@@ -0,0 +1,192 @@
+//! Formatting of template source.
+//!
+//! Formatting puts a single space inside the delimiters of each tag,
+//! indents tags which are on a line of their own by how deeply they
+//! are nested in blocks, and removes trailing whitespace.
+//!
+//! Because tags on a line of their own and trailing whitespace are
+//! part of the text of a template, formatting may change the
+//! whitespace of its output.
+
+use Error;
+use {ast, parse};
+
+/// Configuration options for formatting.
+#[derive(Clone, Debug)]
+pub struct Config
+{
+    /// The text used to indent each level of nesting.
+    pub indent: String,
+}
+
+/// Formats template source.
+pub fn format_str(source: &str, parse_config: &parse::Config, config: &Config)
+    -> Result<String, Error> {
+    let ast = parse::parse_str_with_config(source, parse_config)?;
+    Ok(format_ast(&ast, config))
+}
+
+/// Converts an AST into formatted template source.
+pub fn format_ast(ast: &ast::Ast, config: &Config) -> String {
+    let mut formatter = Formatter { output: String::new(), config: config };
+    formatter.items(&ast.items, 0);
+
+    let trimmed_length = formatter.output.trim_end_matches(|c| c == ' ' || c == '\t').len();
+    formatter.output.truncate(trimmed_length);
+    formatter.output
+}
+
+struct Formatter<'a>
+{
+    output: String,
+    config: &'a Config,
+}
+
+impl<'a> Formatter<'a>
+{
+    fn items(&mut self, items: &[ast::Item], depth: usize) {
+        for item in items {
+            self.item(item, depth);
+        }
+    }
+
+    fn item(&mut self, item: &ast::Item, depth: usize) {
+        match item.kind {
+            ast::ItemKind::Text(..) => self.text(&item.to_string()),
+            ast::ItemKind::FrontMatter { ref source, .. } => self.output.push_str(source),
+            ast::ItemKind::Comment(ref text) => {
+                self.tag(&format!("<%# {} %>", text.trim()), depth)
+            },
+            ast::ItemKind::Code(ref source) => {
+                self.tag(&format!("<% {} %>", source.trim()), depth)
+            },
+            ast::ItemKind::Print(ref source) => {
+                self.tag(&format!("<%= {} %>", source.trim()), depth)
+            },
+            ast::ItemKind::Macro { ref name, ref parameters, ref body } => {
+                self.tag(&format!("<% macro {}({}) %>", name, parameters.join(", ")), depth);
+                self.items(body, depth + 1);
+                self.tag("<% end %>", depth);
+            },
+            ast::ItemKind::Capture { ref name, ref body } => {
+                self.tag(&format!("<% capture {} %>", name), depth);
+                self.items(body, depth + 1);
+                self.tag("<% end %>", depth);
+            },
+            ast::ItemKind::If { ref condition, ref body, ref else_body } => {
+                self.tag(&format!("<% if {} %>", condition), depth);
+                self.items(body, depth + 1);
+                if !else_body.is_empty() {
+                    self.tag("<% else %>", depth);
+                    self.items(else_body, depth + 1);
+                }
+                self.tag("<% end %>", depth);
+            },
+            ast::ItemKind::For { ref pattern, ref iterator, ref body } => {
+                self.tag(&format!("<% for {} in {} %>", pattern, iterator), depth);
+                self.items(body, depth + 1);
+                self.tag("<% end %>", depth);
+            },
+            ast::ItemKind::File { ref path, ref body } => {
+                self.tag(&format!("<% file {} %>", path), depth);
+                self.items(body, depth + 1);
+                self.tag("<% end %>", depth);
+            },
+            ast::ItemKind::Cache { ref key, ref body } => {
+                self.tag(&format!("<% cache {} %>", key), depth);
+                self.items(body, depth + 1);
+                self.tag("<% end %>", depth);
+            },
+            ast::ItemKind::With { ref name, ref value, .. } if item.included_with().is_some() => {
+                self.tag(&format!("<% {} %>", ast::include_with_tag(item.included_with().unwrap(), name, value)), depth);
+            },
+            ast::ItemKind::With { ref name, ref value, ref body } => {
+                self.tag(&format!("<% with {} = {} %>", name, value), depth);
+                self.items(body, depth + 1);
+                self.tag("<% end %>", depth);
+            },
+            ast::ItemKind::Block { ref name, limit, truncation, ref body } => {
+                self.tag(&format!("<% {} %>", ast::block_tag(name, limit, truncation)), depth);
+                self.items(body, depth + 1);
+                self.tag("<% end %>", depth);
+            },
+            ast::ItemKind::Tag { ref name, ref arguments, ref body } => {
+                match arguments.is_empty() {
+                    true => self.tag(&format!("<% {} %>", name), depth),
+                    false => self.tag(&format!("<% {} {} %>", name, arguments), depth),
+                }
+                self.items(body, depth + 1);
+                self.tag("<% end %>", depth);
+            },
+            _ => self.tag(&item.to_string(), depth),
+        }
+    }
+
+    /// Writes text, removing whitespace from the ends of its lines.
+    fn text(&mut self, text: &str) {
+        let mut lines = text.split('\n').peekable();
+
+        while let Some(line) = lines.next() {
+            if lines.peek().is_some() {
+                self.output.push_str(line.trim_end_matches(|c| c == ' ' || c == '\t'));
+                self.output.push('\n');
+            } else {
+                self.output.push_str(line);
+            }
+        }
+    }
+
+    /// Writes a tag, indenting it if it begins a line.
+    fn tag(&mut self, tag: &str, depth: usize) {
+        let line_start = self.output.rfind('\n').map(|i| i + 1).unwrap_or(0);
+
+        if self.output[line_start..].chars().all(|c| c == ' ' || c == '\t') {
+            self.output.truncate(line_start);
+            for _ in 0..depth {
+                self.output.push_str(&self.config.indent);
+            }
+        }
+
+        self.output.push_str(tag);
+    }
+}
+
+impl Default for Config
+{
+    fn default() -> Self {
+        Config {
+            indent: "  ".to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn format(source: &str) -> String {
+        format_str(source, &Default::default(), &Default::default()).unwrap()
+    }
+
+    #[test]
+    fn spaces_tags_consistently() {
+        assert_eq!(format("<%=x%> <%y;%> <%#note   %>"), "<%= x %> <% y; %> <%# note %>");
+    }
+
+    #[test]
+    fn indents_nested_tags() {
+        assert_eq!(format("<% for x in xs %>\n<% if x %>\n<%= x %>\n    <% end %>\n<% end %>\n"),
+                   "<% for x in xs %>\n  <% if x %>\n    <%= x %>\n  <% end %>\n<% end %>\n");
+    }
+
+    #[test]
+    fn leaves_indentation_of_text_alone() {
+        assert_eq!(format("<% if x %>\n      a <%= b %>\n<% end %>"),
+                   "<% if x %>\n      a <%= b %>\n<% end %>");
+    }
+
+    #[test]
+    fn removes_trailing_whitespace() {
+        assert_eq!(format("a  \nb\t\n<%= c %>  "), "a\nb\n<%= c %>");
+    }
+}
@@ -0,0 +1,214 @@
+//! Metadata given at the top of a template.
+//!
+//! Front matter is written between `---` lines as YAML, or between
+//! `+++` lines as TOML. Only flat fields are supported, one per line.
+//!
+//! ```text
+//! ---
+//! title: "Release notes"
+//! draft: false
+//! ---
+//! ```
+
+use {Error, ErrorKind};
+use analysis;
+
+use regex::Regex;
+
+pub use tempo_ast::front_matter::Value;
+
+/// The fields of front matter, in the order they are written.
+pub type Fields = Vec<(String, Value)>;
+
+/// The regex matching the key of a field.
+const KEY_REGEX: &'static str = r"^[A-Za-z_][A-Za-z0-9_]*$";
+
+/// Parses the front matter at the start of a template, if it has any.
+///
+/// Gives the fields and the length of the front matter in bytes,
+/// including both of its delimiter lines.
+pub fn parse(input: &str) -> Option<(Result<Fields, Error>, usize)> {
+    let delimiter = if input.starts_with("---") {
+        "---"
+    } else if input.starts_with("+++") {
+        "+++"
+    } else {
+        return None;
+    };
+
+    let mut lines = input.split('\n');
+    if lines.next().unwrap().trim_end() != delimiter {
+        return None;
+    }
+
+    let mut length = delimiter.len();
+    let mut body = Vec::new();
+    let mut is_closed = false;
+
+    for line in lines {
+        length += 1 + line.len();
+
+        if line.trim_end() == delimiter {
+            is_closed = true;
+            break;
+        }
+        body.push(line);
+    }
+
+    // Without a closing delimiter, the text is not front matter.
+    if !is_closed {
+        return None;
+    }
+
+    // The new line ending the closing delimiter is part of it.
+    if length < input.len() {
+        length += 1;
+    }
+
+    let separator = if delimiter == "---" { ':' } else { '=' };
+    Some((parse_fields(&body, separator), length))
+}
+
+/// Parses lines of `key: value` or `key = value` fields.
+fn parse_fields(lines: &[&str], separator: char) -> Result<Vec<(String, Value)>, Error> {
+    let key_regex = Regex::new(KEY_REGEX).unwrap();
+    let mut fields = Vec::new();
+
+    for (index, line) in lines.iter().enumerate() {
+        // The opening delimiter is the first line.
+        let invalid = |reason: &str| -> Error { ErrorKind::InvalidFrontMatter(index + 2, reason.to_owned()).into() };
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let i = match line.find(separator) {
+            Some(i) => i,
+            None => return Err(invalid(&format!("expected '{}' after the key", separator))),
+        };
+
+        let key = line[..i].trim();
+        if !key_regex.is_match(key) {
+            return Err(invalid(&format!("'{}' is not a valid key", key)));
+        }
+        // Fields are variables in the template, so cannot be named by keywords.
+        if analysis::is_keyword(key) {
+            return Err(invalid(&format!("'{}' is a Rust keyword, so cannot be a key", key)));
+        }
+        if fields.iter().any(|&(ref k, _)| k == key) {
+            return Err(invalid(&format!("'{}' is given more than once", key)));
+        }
+
+        let value = parse_value(line[i + 1..].trim(), separator == ':').map_err(|reason| invalid(&reason))?;
+        fields.push((key.to_owned(), value));
+    }
+
+    Ok(fields)
+}
+
+/// Parses a value, which may be followed by a `#` comment.
+///
+/// Only YAML allows strings without quotes.
+fn parse_value(text: &str, allow_bare_strings: bool) -> Result<Value, String> {
+    if text.starts_with('"') || text.starts_with('\'') {
+        let quote = text.chars().next().unwrap();
+        let mut string = String::new();
+        let mut chars = text[1..].char_indices();
+
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' if quote == '"' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    Some(c @ '"') | Some(c @ '\\') => string.push(c),
+                    _ => return Err("invalid escape in string".to_owned()),
+                },
+                c if c == quote => {
+                    // YAML writes a single quote in single quotes as `''`.
+                    if quote == '\'' && text[1 + i + 1..].starts_with('\'') {
+                        chars.next();
+                        string.push('\'');
+                        continue;
+                    }
+
+                    let rest = text[1 + i + 1..].trim();
+                    if !rest.is_empty() && !rest.starts_with('#') {
+                        return Err("unexpected text after the string".to_owned());
+                    }
+                    return Ok(Value::String(string));
+                },
+                c => string.push(c),
+            }
+        }
+
+        return Err("a string is never closed".to_owned());
+    }
+
+    let text = match text.find(" #") {
+        Some(i) => text[..i].trim(),
+        None => text,
+    };
+
+    if text == "true" || text == "false" {
+        Ok(Value::Bool(text == "true"))
+    } else if let Ok(integer) = text.parse() {
+        Ok(Value::Int(integer))
+    } else if let Some(float) = text.parse::<f64>().ok().filter(|f| f.is_finite()) {
+        Ok(Value::Float(float))
+    } else if text.starts_with('[') || text.starts_with('{') || text.is_empty() {
+        Err("nested values are not supported".to_owned())
+    } else if allow_bare_strings {
+        Ok(Value::String(text.to_owned()))
+    } else {
+        Err(format!("'{}' is not a value", text))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_yaml_front_matter() {
+        let (fields, length) = parse("---\ntitle: \"A \\\"B\\\"\"\n# note\ncount: 3 # items\nratio: 0.5\ndraft: false\nlayout: post\n---\nbody").unwrap();
+
+        assert_eq!(fields.unwrap(), vec![
+            ("title".to_owned(), Value::String("A \"B\"".to_owned())),
+            ("count".to_owned(), Value::Int(3)),
+            ("ratio".to_owned(), Value::Float(0.5)),
+            ("draft".to_owned(), Value::Bool(false)),
+            ("layout".to_owned(), Value::String("post".to_owned())),
+        ]);
+        assert_eq!(length, 86);
+    }
+
+    #[test]
+    fn parses_toml_front_matter() {
+        let (fields, length) = parse("+++\ntitle = 'It''s'\ncount = 2\n+++").unwrap();
+        assert_eq!(fields.unwrap(), vec![
+            ("title".to_owned(), Value::String("It's".to_owned())),
+            ("count".to_owned(), Value::Int(2)),
+        ]);
+        assert_eq!(length, 33);
+
+        assert!(parse("+++\ntitle = post\n+++\n").unwrap().0.is_err());
+    }
+
+    #[test]
+    fn ignores_text_which_is_not_front_matter() {
+        assert!(parse("--- title\n---\n").is_none());
+        assert!(parse("---\ntitle: a\n").is_none());
+        assert!(parse("text\n---\n").is_none());
+    }
+
+    #[test]
+    fn errors_on_invalid_front_matter() {
+        assert!(parse("---\ntags: [a, b]\n---\n").unwrap().0.is_err());
+        assert!(parse("---\nog-title: a\n---\n").unwrap().0.is_err());
+        assert_eq!(parse("---\ntype: post\n---\n").unwrap().0.unwrap_err().to_string(),
+                   "invalid front matter on line 2: 'type' is a Rust keyword, so cannot be a key");
+        assert!(parse("---\na: 1\na: 2\n---\n").unwrap().0.is_err());
+        assert!(parse("---\ntitle: \"a\n---\n").unwrap().0.is_err());
+    }
+}
@@ -0,0 +1,127 @@
+//! Running GraphQL queries while rendering, for dashboards which show
+//! data from a GraphQL API without building a context for each one.
+//!
+//! Templates may only run the queries registered with `Queries` when it
+//! is created, by name, as with persisted queries on a server. So a
+//! template can choose the variables of a query, but not what it asks
+//! for. The `data` of the result is given back as a `Value`.
+//!
+//! ```text
+//! <% let stats = graphql.query("team_stats", &[("team", Value::from(team))])?; %>
+//! <%= stats.get("team").get("openIssues") %>
+//! ```
+
+use {Error, ErrorKind};
+use data;
+use http;
+use value::Value;
+
+use std::collections::BTreeMap;
+
+/// Runs the registered queries against a GraphQL endpoint.
+#[derive(Clone, Debug)]
+pub struct Queries
+{
+    client: http::Client,
+    endpoint: String,
+    /// The document of each query, by name.
+    queries: BTreeMap<String, String>,
+}
+
+impl Queries
+{
+    /// Creates a set of queries made to an endpoint with a client, which
+    /// must allow its host.
+    pub fn new(client: http::Client, endpoint: &str) -> Self {
+        Queries {
+            client: client,
+            endpoint: endpoint.to_owned(),
+            queries: BTreeMap::new(),
+        }
+    }
+
+    /// Registers the document of a query under a name, replacing any
+    /// query with the same name.
+    pub fn register(&mut self, name: &str, document: &str) {
+        self.queries.insert(name.to_owned(), document.to_owned());
+    }
+
+    /// Gets the names of the registered queries.
+    pub fn names(&self) -> Vec<&str> {
+        self.queries.keys().map(|name| &name[..]).collect()
+    }
+
+    /// Runs a registered query with variables, giving the `data` of
+    /// its result.
+    pub fn query(&self, name: &str, variables: &[(&str, Value)]) -> Result<Value, Error> {
+        let document = match self.queries.get(name) {
+            Some(document) => document,
+            None => return Err(ErrorKind::UnknownQuery(name.to_owned()).into()),
+        };
+
+        let body = request_body(document, variables);
+        let response = self.client.post(&self.endpoint, "application/json", body.as_bytes())?;
+        response_data(name, &response)
+    }
+}
+
+/// Gets the JSON body of a request for a query.
+fn request_body(document: &str, variables: &[(&str, Value)]) -> String {
+    let variables: BTreeMap<String, Value> = variables.iter()
+        .map(|&(name, ref value)| (name.to_owned(), value.clone()))
+        .collect();
+
+    let mut body = BTreeMap::new();
+    body.insert("query".to_owned(), Value::from(document));
+    body.insert("variables".to_owned(), Value::Map(variables));
+    Value::Map(body).to_json()
+}
+
+/// Gets the `data` of the response to a query, unless it has errors.
+fn response_data(name: &str, response: &str) -> Result<Value, Error> {
+    let mut response = match data::parse_json_value(response)? {
+        Value::Map(response) => response,
+        _ => return Err(ErrorKind::QueryFailed(name.to_owned(), "the response is not an object".to_owned()).into()),
+    };
+
+    if let Some(Value::List(errors)) = response.remove("errors") {
+        if !errors.is_empty() {
+            let messages: Vec<String> = errors.iter().map(|e| e.get("message").to_string()).collect();
+            return Err(ErrorKind::QueryFailed(name.to_owned(), messages.join("; ")).into());
+        }
+    }
+
+    match response.remove("data") {
+        Some(Value::Null) | None => {
+            Err(ErrorKind::QueryFailed(name.to_owned(), "the response has no data".to_owned()).into())
+        },
+        Some(data) => Ok(data),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn runs_only_registered_queries() {
+        let mut queries = Queries::new(http::Client::new(&["api.internal"]), "https://api.internal/graphql");
+        queries.register("team_stats", "query($team: String!) { team(name: $team) { openIssues } }");
+        assert_eq!(queries.names(), vec!["team_stats"]);
+        assert_eq!(queries.query("everything", &[]).unwrap_err().code(), "E0308");
+
+        assert_eq!(request_body("{ a }", &[("team", Value::from("core")), ("n", Value::from(2))]),
+                   r#"{"query":"{ a }","variables":{"n":2,"team":"core"}}"#);
+    }
+
+    #[test]
+    fn gives_the_data_of_responses() {
+        let data = response_data("q", r#"{"data": {"team": {"openIssues": 3}}}"#).unwrap();
+        assert_eq!(data.get("team").get("openIssues"), &Value::Int(3));
+
+        let error = response_data("q", r#"{"data": null, "errors": [{"message": "a"}, {"message": "b"}]}"#);
+        assert_eq!(error.unwrap_err().to_string(), "query 'q' failed: a; b");
+        assert_eq!(response_data("q", "[]").unwrap_err().code(), "E0309");
+        assert_eq!(response_data("q", "{").unwrap_err().code(), "E0303");
+    }
+}
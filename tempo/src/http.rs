@@ -0,0 +1,236 @@
+//! Fetching data over HTTP while rendering, for trusted templates which
+//! embed data from internal services.
+//!
+//! Requests are made with `curl`, and only to the hosts a `Client` is
+//! configured to allow, within limits on the size of the response and
+//! the time taken. Redirects are not followed, as they could lead to
+//! other hosts. The client is given to templates as a parameter.
+//!
+//! ```text
+//! <%= http.get("https://status.internal/summary")? %>
+//! ```
+//!
+//! Async templates can await `get_async` instead, which fetches on a
+//! thread of its own.
+
+use {Error, ErrorKind};
+use units;
+
+use std::future::Future;
+use std::io::prelude::*;
+use std::pin::Pin;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::Duration;
+
+/// The largest response fetched by default.
+const DEFAULT_MAX_SIZE: usize = 1024 * 1024;
+/// The longest a request may take by default.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Makes `GET` and `POST` requests to a set of allowed hosts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Client
+{
+    /// Host names, such as `api.internal`, or wildcards matching their
+    /// subdomains, such as `*.internal`.
+    allowed_hosts: Vec<String>,
+    max_size: usize,
+    timeout: Duration,
+}
+
+/// The response of an `HttpGet` once it has arrived, and the waker of the
+/// task waiting on it.
+type HttpGetState = Arc<Mutex<(Option<Result<String, Error>>, Option<Waker>)>>;
+
+/// The result of `Client::get_async`, once the request has finished.
+#[derive(Debug)]
+pub struct HttpGet
+{
+    state: HttpGetState,
+}
+
+impl Client
+{
+    /// Creates a client allowed to fetch from the given hosts, with
+    /// responses of up to 1 MiB taking up to 10 seconds.
+    pub fn new(allowed_hosts: &[&str]) -> Self {
+        Client {
+            allowed_hosts: allowed_hosts.iter().map(|h| h.to_lowercase()).collect(),
+            max_size: DEFAULT_MAX_SIZE,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Sets the largest response body which may be fetched, in bytes.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    /// Sets the longest a request may take, including connecting.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Checks whether a URL is on an allowed host.
+    pub fn is_allowed(&self, url: &str) -> bool {
+        let host = match host(url) {
+            Some(host) => host.to_lowercase(),
+            None => return false,
+        };
+
+        self.allowed_hosts.iter().any(|allowed| {
+            if allowed.starts_with("*.") {
+                host.ends_with(&allowed[1..])
+            } else {
+                host == *allowed
+            }
+        })
+    }
+
+    /// Fetches the body of a URL as text.
+    pub fn get(&self, url: &str) -> Result<String, Error> {
+        self.request(url, None)
+    }
+
+    /// Posts a body of a content type to a URL, giving the body of the
+    /// response as text.
+    pub fn post(&self, url: &str, content_type: &str, body: &[u8]) -> Result<String, Error> {
+        self.request(url, Some((content_type, body)))
+    }
+
+    /// Makes a `GET` request, or a `POST` request if there is a body.
+    fn request(&self, url: &str, body: Option<(&str, &[u8])>) -> Result<String, Error> {
+        if !self.is_allowed(url) {
+            return Err(ErrorKind::HostNotAllowed(url.to_owned()).into());
+        }
+        let failed = |reason: String| -> Error { ErrorKind::HttpGetFailed(url.to_owned(), reason).into() };
+
+        let mut command = process::Command::new("curl");
+        command.arg("--silent").arg("--show-error").arg("--fail")
+            .arg("--proto").arg("=http,https")
+            .arg("--max-redirs").arg("0")
+            .arg("--max-time").arg(self.timeout.as_secs_f64().to_string())
+            .arg("--max-filesize").arg(self.max_size.to_string());
+        // Bodies are given on standard input, so that they do not show
+        // up in the list of processes.
+        if let Some((content_type, _)) = body {
+            command.arg("--header").arg(format!("Content-Type: {}", content_type))
+                .arg("--data-binary").arg("@-");
+        }
+        let mut child = command.arg("--").arg(url)
+            .stdin(if body.is_some() { process::Stdio::piped() } else { process::Stdio::null() })
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
+            .spawn()?;
+
+        // curl reads the whole body before it makes the request.
+        if let Some((_, body)) = body {
+            child.stdin.take().unwrap().write_all(body)?;
+        }
+
+        // Responses without a length are only found to be too large
+        // while they are read.
+        let mut response = Vec::new();
+        child.stdout.take().unwrap().take(self.max_size as u64 + 1).read_to_end(&mut response)?;
+        if response.len() > self.max_size {
+            child.kill()?;
+            child.wait()?;
+            return Err(failed(format!("the response is larger than {}", units::ByteSize(self.max_size as u64))));
+        }
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(failed(String::from_utf8_lossy(&output.stderr).trim().trim_start_matches("curl: ").to_owned()));
+        }
+
+        String::from_utf8(response).map_err(|_| failed("the response is not valid UTF-8".to_owned()))
+    }
+
+    /// Fetches the body of a URL as text, without blocking the task
+    /// which awaits it.
+    pub fn get_async(&self, url: &str) -> HttpGet {
+        let state = Arc::new(Mutex::new((None, None::<Waker>)));
+        let (client, url, shared) = (self.clone(), url.to_owned(), state.clone());
+
+        thread::spawn(move || {
+            let result = client.get(&url);
+            let mut state = shared.lock().unwrap();
+            state.0 = Some(result);
+            if let Some(waker) = state.1.take() {
+                waker.wake();
+            }
+        });
+
+        HttpGet { state: state }
+    }
+}
+
+impl Future for HttpGet
+{
+    type Output = Result<String, Error>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        match state.0.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.1 = Some(context.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+/// Gets the host of an `http` or `https` URL, without its port.
+pub fn host(url: &str) -> Option<&str> {
+    let rest = url.strip_prefix("http://").or_else(|| url.strip_prefix("https://"))?;
+
+    let authority = rest.split(|c| c == '/' || c == '?' || c == '#').next().unwrap();
+    // Credentials come before an `@`, and could otherwise disguise the host.
+    let host = authority.rsplit('@').next().unwrap();
+    let host = if host.starts_with('[') {
+        host.split(']').next().map(|h| &h[1..])
+    } else {
+        host.split(':').next()
+    };
+
+    host.filter(|h| !h.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn allows_only_configured_hosts() {
+        assert_eq!(host("https://user@api.internal:8443/a?b"), Some("api.internal"));
+        assert_eq!(host("http://[::1]:80/"), Some("::1"));
+        assert_eq!(host("file:///etc/passwd"), None);
+
+        let client = Client::new(&["api.internal", "*.status.internal"]);
+        assert!(client.is_allowed("https://API.internal/x"));
+        assert!(client.is_allowed("http://eu.status.internal/"));
+        assert!(!client.is_allowed("http://status.internal/"));
+        assert!(!client.is_allowed("http://api.internal@example.com/"));
+        assert_eq!(client.get("https://example.com/").unwrap_err().code(), "E0306");
+    }
+
+    #[test]
+    fn fails_requests_which_cannot_be_made() {
+        let mut client = Client::new(&["127.0.0.1"]);
+        client.set_timeout(Duration::from_secs(5));
+
+        let mut request = client.get_async("http://127.0.0.1:1/");
+        let mut context = Context::from_waker(Waker::noop());
+        let result = loop {
+            match Pin::new(&mut request).poll(&mut context) {
+                Poll::Ready(result) => break result,
+                Poll::Pending => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        assert_eq!(result.unwrap_err().code(), "E0307");
+    }
+}
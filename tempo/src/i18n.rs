@@ -0,0 +1,139 @@
+//! Translation of the messages in templates.
+//!
+//! Templates translated with `trans::Config::translations` look up
+//! messages through a function given to their render function. A
+//! `Translator`, such as a `Catalog`, gives one with `lookup`.
+//!
+//! ```text
+//! render(&mut output, &catalog.lookup())
+//! ```
+
+use {Error, ErrorKind};
+
+use std::collections::HashMap;
+
+/// A function translating a message by key, given the number of things
+/// it is about.
+pub type MessageLookup<'a> = Box<dyn Fn(&str, Option<i64>) -> Option<String> + 'a>;
+
+/// Gives the messages of templates in a language.
+pub trait Translator
+{
+    /// Gets the message for a key, and the number of things it is
+    /// about if it is given.
+    fn translate(&self, key: &str, count: Option<i64>) -> Option<String>;
+
+    /// Gets a function which translates messages, as taken by
+    /// render functions.
+    fn lookup<'a>(&'a self) -> MessageLookup<'a>
+        where Self: Sized {
+        Box::new(move |key, count| self.translate(key, count))
+    }
+}
+
+/// The plural forms of messages, chosen by the number of things a
+/// message is about.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PluralRule
+{
+    /// `one` for 1, and `other` for every other number, as in English.
+    OneOther,
+    /// `one` for 0 and 1, and `other` for every other number, as in French.
+    ZeroOneOther,
+    /// `other` for every number, as in Japanese.
+    Other,
+}
+
+/// Messages read from a catalog of `key = message` lines.
+///
+/// Plural forms of a message are given as `key.one` and `key.other`,
+/// and `#` starts a comment line.
+///
+/// ```text
+/// welcome.title = Welcome!
+/// inbox.count.one = You have {count} message
+/// inbox.count.other = You have {count} messages
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Catalog
+{
+    messages: HashMap<String, String>,
+    plural_rule: PluralRule,
+}
+
+impl PluralRule
+{
+    /// Gets the plural form of a message about a number of things.
+    pub fn form(&self, count: i64) -> &'static str {
+        match *self {
+            PluralRule::OneOther if count == 1 => "one",
+            PluralRule::ZeroOneOther if count == 0 || count == 1 => "one",
+            _ => "other",
+        }
+    }
+}
+
+impl Catalog
+{
+    /// Parses a catalog.
+    pub fn parse(text: &str, plural_rule: PluralRule) -> Result<Self, Error> {
+        let mut messages = HashMap::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.find('=') {
+                Some(i) => {
+                    messages.insert(line[..i].trim().to_owned(), line[i + 1..].trim().to_owned());
+                },
+                None => return Err(ErrorKind::InvalidCatalog(index + 1).into()),
+            }
+        }
+
+        Ok(Catalog { messages: messages, plural_rule: plural_rule })
+    }
+}
+
+impl Translator for Catalog
+{
+    /// Gets the plural form of the message for the count if there is
+    /// one, or otherwise the message itself.
+    fn translate(&self, key: &str, count: Option<i64>) -> Option<String> {
+        let plural = count.and_then(|count| {
+            self.messages.get(&format!("{}.{}", key, self.plural_rule.form(count)))
+        });
+
+        plural.or_else(|| self.messages.get(key)).cloned()
+    }
+}
+
+impl<F> Translator for F
+    where F: Fn(&str, Option<i64>) -> Option<String>
+{
+    fn translate(&self, key: &str, count: Option<i64>) -> Option<String> {
+        self(key, count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn translates_messages_from_catalogs() {
+        let catalog = Catalog::parse("# greetings\nwelcome = Bienvenue\nitems.one = {count} article\nitems.other = {count} articles\n",
+                                     PluralRule::ZeroOneOther).unwrap();
+        let lookup = catalog.lookup();
+
+        assert_eq!(lookup("welcome", None), Some("Bienvenue".to_owned()));
+        assert_eq!(lookup("items", Some(0)), Some("{count} article".to_owned()));
+        assert_eq!(lookup("items", Some(2)), Some("{count} articles".to_owned()));
+        assert_eq!(lookup("goodbye", None), None);
+
+        assert_eq!(Catalog::parse("welcome", PluralRule::Other).unwrap_err().code(), "E0304");
+    }
+}
@@ -0,0 +1,128 @@
+//! Formatting for iCalendar (ICS) files, as in RFC 5545.
+//!
+//! These are for use by the code in templates which generate calendar
+//! invites, so that data can be written into properties and the file
+//! is valid.
+//!
+//! ```text
+//! SUMMARY:<%= tempo::ics::escape_text(&event.title) %>
+//! DTSTART:<%= tempo::ics::date_time(event.starts_at) %>
+//! ```
+//!
+//! Lines longer than 75 octets must be folded, which is done over the
+//! whole of the rendered output by `fold`.
+//!
+//! vCard files (RFC 6350) are escaped and folded in the same way, so
+//! these can be used for contact cards too.
+
+use date::civil;
+
+use std::time::SystemTime;
+
+/// The most octets a line may have, not counting the line break.
+const MAX_LINE_LENGTH: usize = 75;
+
+/// Escapes text for a `TEXT` property value.
+///
+/// Backslashes, semicolons and commas are escaped with a backslash,
+/// and line breaks are written as `\n`.
+pub fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.replace("\r\n", "\n").chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                escaped.push('\\');
+                escaped.push(c);
+            },
+            '\n' | '\r' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Folds a single content line so that no line is longer than 75 octets.
+///
+/// Each continuation line starts with a space, and lines are only
+/// broken between characters, never inside one.
+pub fn fold_line(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + line.len() / MAX_LINE_LENGTH * 3);
+    let mut length = 0;
+
+    for c in line.chars() {
+        if length + c.len_utf8() > MAX_LINE_LENGTH {
+            folded.push_str("\r\n ");
+            // The space counts towards the length of the new line.
+            length = 1;
+        }
+
+        folded.push(c);
+        length += c.len_utf8();
+    }
+
+    folded
+}
+
+/// Folds every line of an ICS file, and ends each line with CRLF.
+///
+/// Lines may end with either LF or CRLF in the input.
+pub fn fold(content: &str) -> String {
+    let mut folded = String::with_capacity(content.len());
+
+    for line in content.lines() {
+        folded.push_str(&fold_line(line));
+        folded.push_str("\r\n");
+    }
+
+    folded
+}
+
+/// Formats a `DATE` value, such as `20170704`.
+pub fn date(time: SystemTime) -> String {
+    let (year, month, day, _) = civil(time);
+    format!("{:04}{:02}{:02}", year, month, day)
+}
+
+/// Formats a `DATE-TIME` value in UTC, such as `20170704T093000Z`.
+pub fn date_time(time: SystemTime) -> String {
+    let (year, month, day, seconds) = civil(time);
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day,
+            seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn escapes_text() {
+        assert_eq!(escape_text("Tea, cake; a\\b\r\nnext"), "Tea\\, cake\\; a\\\\b\\nnext");
+    }
+
+    #[test]
+    fn folds_long_lines() {
+        let line = format!("DESCRIPTION:{}", "a".repeat(100));
+        let folded = fold_line(&line);
+        let lines: Vec<_> = folded.split("\r\n").collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].len(), 75);
+        assert_eq!(lines[1], format!(" {}", "a".repeat(37)));
+
+        // A character is never split across lines.
+        let folded = fold_line(&format!("{}é", "a".repeat(74)));
+        assert_eq!(folded, format!("{}\r\n é", "a".repeat(74)));
+
+        assert_eq!(fold("BEGIN:VCALENDAR\nEND:VCALENDAR\n"), "BEGIN:VCALENDAR\r\nEND:VCALENDAR\r\n");
+    }
+
+    #[test]
+    fn formats_dates() {
+        let time = UNIX_EPOCH + Duration::from_secs(1499160600);
+        assert_eq!(date(time), "20170704");
+        assert_eq!(date_time(time), "20170704T093000Z");
+        assert_eq!(date_time(UNIX_EPOCH - Duration::from_secs(1)), "19691231T235959Z");
+    }
+}
@@ -1,13 +1,52 @@
+#![recursion_limit = "256"]
+
 #[macro_use] extern crate error_chain;
 extern crate regex;
+extern crate tempo_ast;
 
 pub use self::errors::{Error, ErrorKind};
-pub use self::ast::Ast;
+pub use self::ast::{Ast, AstExt};
+pub use self::engine::Engine;
+
+#[macro_use]
+mod macros;
 
 pub mod ast;
 pub mod parse;
 pub mod trans;
 pub mod errors;
+pub mod engine;
+pub mod scaffold;
+pub mod region;
+pub mod comment;
+pub mod version;
+pub mod fmt;
+pub mod encoding;
+pub mod lint;
+pub mod validate;
+pub mod analysis;
+pub mod position;
+pub mod units;
+pub mod data;
+pub mod pipeline;
+pub mod front_matter;
+pub mod tag;
+pub mod cache;
+pub mod usage;
+pub mod value;
+pub mod scope;
+pub mod sms;
+pub mod date;
+pub mod ics;
+pub mod mime;
+#[cfg(feature = "i18n")]
+pub mod i18n;
+#[cfg(feature = "chat")]
+pub mod chat;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "graphql")]
+pub mod graphql;
 
 pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
@@ -0,0 +1,312 @@
+//! Checks for likely mistakes in templates.
+//!
+//! Linting never changes how a template is translated. Each warning
+//! points at the byte range of the source it is about.
+
+use Error;
+use {ast, parse};
+
+use regex::Regex;
+
+use std::fmt;
+use std::ops::Range;
+
+/// A kind of likely mistake.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Lint
+{
+    /// A `<%` with no matching `%>`, which is output as text.
+    UnterminatedTag,
+    /// A `%>` with no matching `<%`.
+    StrayDelimiter,
+    /// A tag with nothing inside it.
+    EmptyTag,
+    /// A branch which can never be rendered.
+    UnreachableBranch,
+    /// A capture whose variable is never used.
+    UnusedCapture,
+    /// A printed expression ending in `;`.
+    PrintedStatement,
+    /// Code starting with `=`, which was probably meant to be printed.
+    UnprintedExpression,
+}
+
+/// A warning about part of a template.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning
+{
+    /// The kind of mistake.
+    pub lint: Lint,
+    /// A description of the mistake.
+    pub message: String,
+    /// The byte range of the source the warning is about.
+    pub span: Range<usize>,
+}
+
+impl Lint
+{
+    /// Gets the name of the lint, as shown in warnings.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Lint::UnterminatedTag => "unterminated-tag",
+            Lint::StrayDelimiter => "stray-delimiter",
+            Lint::EmptyTag => "empty-tag",
+            Lint::UnreachableBranch => "unreachable-branch",
+            Lint::UnusedCapture => "unused-capture",
+            Lint::PrintedStatement => "printed-statement",
+            Lint::UnprintedExpression => "unprinted-expression",
+        }
+    }
+}
+
+impl fmt::Display for Warning
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} [{}]", self.message, self.lint.name())
+    }
+}
+
+/// Checks a template for likely mistakes.
+///
+/// Templates which fail to parse give an error rather than warnings.
+/// Warnings are sorted by their position in the source.
+pub fn lint_str(source: &str) -> Result<Vec<Warning>, Error> {
+    let ast = parse::parse_str(source)?;
+    let spans = parse::tag_spans(source)?;
+
+    let mut linter = Linter {
+        source: source,
+        tags: spans.iter().filter_map(|span| match *span {
+            parse::TagSpan::Code(ref span) => Some(span.clone()),
+            parse::TagSpan::Raw(..) => None,
+        }).collect(),
+        next_tag: 0,
+        captures: Vec::new(),
+        warnings: Vec::new(),
+    };
+
+    linter.items(&ast.items);
+    linter.check_captures();
+    linter.check_delimiters(&spans);
+
+    let mut warnings = linter.warnings;
+    warnings.sort_by_key(|w| w.span.start);
+    Ok(warnings)
+}
+
+pub use position::line_and_column;
+
+struct Linter<'a>
+{
+    source: &'a str,
+    /// The spans of the tags, in the order their items appear in the AST.
+    tags: Vec<Range<usize>>,
+    next_tag: usize,
+    /// The names and tag spans of the captures seen so far.
+    captures: Vec<(String, Range<usize>)>,
+    warnings: Vec<Warning>,
+}
+
+impl<'a> Linter<'a>
+{
+    fn items(&mut self, items: &[ast::Item]) {
+        for item in items {
+            self.item(item);
+        }
+    }
+
+    fn item(&mut self, item: &ast::Item) {
+        match item.kind {
+            ast::ItemKind::Text(..) |
+            ast::ItemKind::FrontMatter { .. } |
+            ast::ItemKind::Error(..) => (),
+            ast::ItemKind::Code(ref source) => {
+                let span = self.next_tag();
+
+                if source.trim().is_empty() {
+                    self.warn(Lint::EmptyTag, "empty code block", span);
+                } else if source.trim_start().starts_with('=') {
+                    self.warn(Lint::UnprintedExpression,
+                              "the result of this code is not printed; did you mean '<%='?", span);
+                }
+            },
+            ast::ItemKind::Print(ref source) => {
+                let span = self.next_tag();
+
+                if source.trim().is_empty() {
+                    self.warn(Lint::EmptyTag, "empty printed expression", span);
+                } else if source.trim().ends_with(';') {
+                    self.warn(Lint::PrintedStatement, "printed expression ends with ';'", span);
+                }
+            },
+            ast::ItemKind::Comment(..) |
+            ast::ItemKind::Directive { .. } |
+            ast::ItemKind::Default { .. } |
+            ast::ItemKind::Set { .. } |
+            ast::ItemKind::Include { .. } |
+            ast::ItemKind::Assert { .. } => {
+                self.next_tag();
+            },
+            ast::ItemKind::Macro { ref body, .. } |
+            ast::ItemKind::For { ref body, .. } |
+            ast::ItemKind::File { ref body, .. } |
+            ast::ItemKind::Cache { ref body, .. } |
+            ast::ItemKind::Block { ref body, .. } |
+            ast::ItemKind::Tag { ref body, .. } => {
+                self.next_tag();
+                self.items(body);
+                self.next_tag();
+            },
+            // Written as a single `include "name" with binding` tag.
+            ast::ItemKind::With { .. } if item.included_with().is_some() => {
+                self.next_tag();
+            },
+            ast::ItemKind::With { ref body, .. } => {
+                self.next_tag();
+                self.items(body);
+                self.next_tag();
+            },
+            ast::ItemKind::Capture { ref name, ref body } => {
+                let span = self.next_tag();
+                self.captures.push((name.clone(), span));
+                self.items(body);
+                self.next_tag();
+            },
+            ast::ItemKind::If { ref condition, ref body, ref else_body } => {
+                let span = self.next_tag();
+                self.items(body);
+
+                let else_span = if self.peek_tag_source().map(|s| s.trim() == "else").unwrap_or(false) {
+                    Some(self.next_tag())
+                } else {
+                    None
+                };
+
+                self.items(else_body);
+                self.next_tag();
+
+                match (condition.trim(), else_span) {
+                    ("true", Some(else_span)) => {
+                        self.warn(Lint::UnreachableBranch,
+                                  "the condition is always true, so this branch is never rendered",
+                                  else_span);
+                    },
+                    ("false", _) => {
+                        self.warn(Lint::UnreachableBranch,
+                                  "the condition is always false, so this block is never rendered",
+                                  span);
+                    },
+                    _ => (),
+                }
+            },
+            // Kinds this version does not know of are not linted.
+            _ => (),
+        }
+    }
+
+    /// Warns about captures whose variables are never mentioned in any other tag.
+    fn check_captures(&mut self) {
+        for (name, span) in ::std::mem::replace(&mut self.captures, Vec::new()) {
+            let usage = Regex::new(&format!(r"\b{}\b", name)).unwrap();
+            let used = self.tags.iter().filter(|tag| **tag != span)
+                .any(|tag| usage.is_match(tag_body(self.source, tag)));
+
+            if !used {
+                self.warn(Lint::UnusedCapture, &format!("'{}' is captured but never used", name), span);
+            }
+        }
+    }
+
+    /// Warns about delimiters outside of any tag or raw block.
+    fn check_delimiters(&mut self, spans: &[parse::TagSpan]) {
+        let is_inside_tag = |index: usize| spans.iter().any(|span| match *span {
+            parse::TagSpan::Code(ref span) | parse::TagSpan::Raw(ref span) => {
+                span.start <= index && index < span.end
+            },
+        });
+
+        let source = self.source;
+        for (index, _) in source.match_indices(parse::OPEN_DELIMITER).filter(|&(i, _)| !is_inside_tag(i)) {
+            self.warn(Lint::UnterminatedTag, "'<%' is never closed, so it is output as text",
+                      index..index + parse::OPEN_DELIMITER.len());
+        }
+
+        for (index, _) in source.match_indices(parse::CLOSE_DELIMITER).filter(|&(i, _)| !is_inside_tag(i)) {
+            self.warn(Lint::StrayDelimiter, "'%>' without a matching '<%'",
+                      index..index + parse::CLOSE_DELIMITER.len());
+        }
+    }
+
+    fn next_tag(&mut self) -> Range<usize> {
+        let span = self.tags[self.next_tag].clone();
+        self.next_tag += 1;
+        span
+    }
+
+    fn peek_tag_source(&self) -> Option<&'a str> {
+        let source = self.source;
+        self.tags.get(self.next_tag).map(|span| tag_body(source, span))
+    }
+
+    fn warn(&mut self, lint: Lint, message: &str, span: Range<usize>) {
+        self.warnings.push(Warning { lint: lint, message: message.to_owned(), span: span });
+    }
+}
+
+/// Gets the source inside the delimiters of a tag.
+fn tag_body<'a>(source: &'a str, tag: &Range<usize>) -> &'a str {
+    &source[(tag.start + parse::OPEN_DELIMITER.len())..(tag.end - parse::CLOSE_DELIMITER.len())]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lints(source: &str) -> Vec<(Lint, Range<usize>)> {
+        lint_str(source).unwrap().into_iter().map(|w| (w.lint, w.span)).collect()
+    }
+
+    #[test]
+    fn accepts_clean_templates() {
+        assert_eq!(lints("<% capture a %>x<% end %><% if y %><%= a %><% else %>z<% end %>"), vec![]);
+    }
+
+    #[test]
+    fn finds_unterminated_tags_and_stray_delimiters() {
+        assert_eq!(lints("a <%= b %> %> <% raw %><%<% endraw %>"), vec![
+            (Lint::StrayDelimiter, 11..13),
+        ]);
+        assert_eq!(lints("a <%= b"), vec![(Lint::UnterminatedTag, 2..4)]);
+    }
+
+    #[test]
+    fn finds_empty_tags() {
+        assert_eq!(lints("<% %><%= %>"), vec![(Lint::EmptyTag, 0..5), (Lint::EmptyTag, 5..11)]);
+    }
+
+    #[test]
+    fn finds_unreachable_branches() {
+        assert_eq!(lints("<% if true %>a<% else %>b<% end %>"), vec![
+            (Lint::UnreachableBranch, 14..24),
+        ]);
+        assert_eq!(lints("<% if false %>a<% end %>"), vec![(Lint::UnreachableBranch, 0..14)]);
+    }
+
+    #[test]
+    fn finds_unused_captures() {
+        assert_eq!(lints("x<% capture a %>b<% end %>"), vec![(Lint::UnusedCapture, 1..16)]);
+    }
+
+    #[test]
+    fn finds_suspicious_expressions() {
+        assert_eq!(lints("<%= x; %><% = y %>"), vec![
+            (Lint::PrintedStatement, 0..9),
+            (Lint::UnprintedExpression, 9..18),
+        ]);
+    }
+
+    #[test]
+    fn finds_mistakes_around_non_ascii_text() {
+        assert_eq!(lints("😀 <% %> 名前 %>"), vec![(Lint::EmptyTag, 5..10), (Lint::StrayDelimiter, 18..20)]);
+    }
+}
@@ -0,0 +1,34 @@
+/// Builds the string variables given to a template, for use as
+/// `trans::Config::variables`.
+///
+/// Each value is converted with `ToString`.
+///
+/// ```
+/// # #[macro_use] extern crate tempo;
+/// # fn main() {
+/// let config = tempo::trans::Config {
+///     variables: context! { name: "world", count: 3 },
+///     ..Default::default()
+/// };
+/// assert_eq!(config.variables[1], ("count".to_owned(), "3".to_owned()));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! context {
+    ($($name:ident : $value:expr),* $(,)*) => {
+        vec![$((stringify!($name).to_owned(), $value.to_string())),*]
+    };
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn builds_variables() {
+        let empty: Vec<(String, String)> = context! {};
+        assert_eq!(empty, vec![]);
+        assert_eq!(context! { a: "x", b: 2, }, vec![
+            ("a".to_owned(), "x".to_owned()),
+            ("b".to_owned(), "2".to_owned()),
+        ]);
+    }
+}
@@ -0,0 +1,190 @@
+//! Encoding for email messages, as in RFC 2045 and RFC 5322.
+//!
+//! These are for use by the code in templates which render whole
+//! messages, such as `multipart/alternative` emails with attachments.
+//!
+//! ```text
+//! <% let boundary = tempo::mime::boundary(&[&text, &html]); %>
+//! <%= tempo::mime::fold_header("Subject", &tempo::mime::encode_word(&subject)) %>
+//! Content-Type: multipart/alternative; boundary="<%= boundary %>"
+//!
+//! --<%= boundary %>
+//! Content-Type: text/plain; charset=utf-8
+//! Content-Transfer-Encoding: quoted-printable
+//!
+//! <%= tempo::mime::quoted_printable(&text) %>
+//! ```
+
+/// The most characters a header line should have, not counting the line break.
+const MAX_HEADER_LENGTH: usize = 78;
+/// The most characters a line of quoted-printable text may have.
+const MAX_ENCODED_LENGTH: usize = 76;
+/// The most characters an encoded word may have.
+const MAX_WORD_LENGTH: usize = 75;
+
+/// Gets a boundary which appears in none of the parts it separates.
+///
+/// The boundary is worked out from the parts, so the same message always
+/// gets the same boundary.
+pub fn boundary(parts: &[&str]) -> String {
+    let mut hash = parts.iter().flat_map(|part| part.bytes()).fold(0xcbf29ce484222325, fnv);
+
+    loop {
+        let boundary = format!("=_tempo_{:016x}", hash);
+        if !parts.iter().any(|part| part.contains(&boundary[..])) {
+            return boundary;
+        }
+        hash = fnv(hash, 0);
+    }
+}
+
+/// Folds a header so that its lines are no longer than 78 characters.
+///
+/// Lines are broken before spaces in the value, so a single word longer
+/// than a line is left whole. The header has no line break at its end.
+pub fn fold_header(name: &str, value: &str) -> String {
+    let mut folded = format!("{}:", name);
+    let mut length = folded.len();
+
+    for word in value.split(' ') {
+        if length + 1 + word.len() > MAX_HEADER_LENGTH && length > name.len() + 1 {
+            folded.push_str("\r\n");
+            length = 0;
+        }
+
+        folded.push(' ');
+        folded.push_str(word);
+        length += 1 + word.len();
+    }
+
+    folded
+}
+
+/// Encodes text for a header, such as a subject, as in RFC 2047.
+///
+/// ASCII text without line breaks is left as it is. Other text is
+/// written as UTF-8 encoded words, separated by spaces so that the
+/// header can be folded between them.
+pub fn encode_word(text: &str) -> String {
+    if text.bytes().all(|b| (0x20..0x7f).contains(&b)) {
+        return text.to_owned();
+    }
+
+    const PREFIX: &'static str = "=?UTF-8?Q?";
+    const SUFFIX: &'static str = "?=";
+
+    let mut words = Vec::new();
+    let mut word = String::new();
+
+    for c in text.chars() {
+        let mut encoded = String::new();
+        let mut bytes = [0; 4];
+        for &byte in c.encode_utf8(&mut bytes).as_bytes() {
+            match byte {
+                b' ' => encoded.push('_'),
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'!' | b'*' | b'+' | b'-' | b'/' => {
+                    encoded.push(byte as char)
+                },
+                _ => encoded.push_str(&format!("={:02X}", byte)),
+            }
+        }
+
+        // Characters are never split between words.
+        if PREFIX.len() + word.len() + encoded.len() + SUFFIX.len() > MAX_WORD_LENGTH {
+            words.push(format!("{}{}{}", PREFIX, word, SUFFIX));
+            word.clear();
+        }
+        word.push_str(&encoded);
+    }
+
+    words.push(format!("{}{}{}", PREFIX, word, SUFFIX));
+    words.join(" ")
+}
+
+/// Encodes text as quoted-printable, with CRLF line breaks.
+///
+/// Lines longer than 76 characters are broken with soft line breaks,
+/// which are removed when the text is decoded.
+pub fn quoted_printable(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+
+    for (index, line) in text.split('\n').enumerate() {
+        if index > 0 {
+            encoded.push_str("\r\n");
+        }
+
+        let line = line.trim_end_matches('\r').as_bytes();
+        let mut length = 0;
+
+        for (index, &byte) in line.iter().enumerate() {
+            let is_last = index + 1 == line.len();
+            let piece = match byte {
+                // Whitespace at the end of a line would be removed in transit.
+                b' ' | b'\t' if !is_last => (byte as char).to_string(),
+                b'=' => "=3D".to_owned(),
+                b'!'..=b'~' => (byte as char).to_string(),
+                _ => format!("={:02X}", byte),
+            };
+
+            // A soft line break needs room for its `=`, unless the line ends here.
+            let limit = if is_last { MAX_ENCODED_LENGTH } else { MAX_ENCODED_LENGTH - 1 };
+            if length + piece.len() > limit {
+                encoded.push_str("=\r\n");
+                length = 0;
+            }
+
+            encoded.push_str(&piece);
+            length += piece.len();
+        }
+    }
+
+    encoded
+}
+
+/// Adds a byte to an FNV-1a hash.
+fn fnv(hash: u64, byte: u8) -> u64 {
+    (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn makes_boundaries() {
+        let boundary = boundary(&["text", "<p>html</p>"]);
+        assert!(boundary.starts_with("=_tempo_"));
+        assert_eq!(super::boundary(&["text", "<p>html</p>"]), boundary);
+        assert!(super::boundary(&[&boundary]) != boundary);
+    }
+
+    #[test]
+    fn folds_headers() {
+        let folded = fold_header("Subject", &"word ".repeat(20).trim());
+        let lines: Vec<_> = folded.split("\r\n").collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].len(), 78);
+        assert!(lines[1].starts_with(" word"));
+        assert_eq!(fold_header("To", "a@example.com"), "To: a@example.com");
+    }
+
+    #[test]
+    fn encodes_words() {
+        assert_eq!(encode_word("Hello"), "Hello");
+        assert_eq!(encode_word("Café = ok"), "=?UTF-8?Q?Caf=C3=A9_=3D_ok?=");
+
+        let encoded = encode_word(&"é".repeat(30));
+        assert!(encoded.split(' ').all(|w| w.len() <= 75 && w.ends_with("?=")));
+        assert_eq!(encoded.split(' ').count(), 3);
+    }
+
+    #[test]
+    fn encodes_quoted_printable() {
+        assert_eq!(quoted_printable("a = b \r\nCafé\t\n"), "a =3D b=20\r\nCaf=C3=A9=09\r\n");
+
+        let encoded = quoted_printable(&"=".repeat(30));
+        let lines: Vec<_> = encoded.split("\r\n").collect();
+        assert_eq!(lines[0], format!("{}=", "=3D".repeat(25)));
+        assert_eq!(lines[1], "=3D".repeat(5));
+    }
+}
@@ -1,12 +1,242 @@
-use Error;
-use ast;
+use {Error, ErrorKind};
+use {ast, encoding, front_matter, position, tag, units, version};
 
 use regex::Regex;
 
+use std::ops::Range;
+use std::sync::OnceLock;
+
+/// The delimiter which opens a tag.
+pub const OPEN_DELIMITER: &'static str = "<%";
+/// The delimiter which closes a tag.
+pub const CLOSE_DELIMITER: &'static str = "%>";
+
 /// The regex used to denote code snippets.
 const CODE_BLOCK_REGEX: &'static str = "<%.*?%>";
+/// The regex matching the tag which begins a raw block.
+const RAW_TAG_REGEX: &'static str = r"^<%\s*raw\s*%>$";
+/// The regex matching the tag which ends a raw block.
+const END_RAW_TAG_REGEX: &'static str = r"<%\s*endraw\s*%>";
+/// The regex matching the tag which begins a macro definition.
+const MACRO_TAG_REGEX: &'static str = r"^\s*macro\s+([A-Za-z_][A-Za-z0-9_]*)\s*\((.*)\)\s*$";
+/// The regex matching the tag which begins a capture block.
+const CAPTURE_TAG_REGEX: &'static str = r"^\s*capture\s+([A-Za-z_][A-Za-z0-9_]*)\s*$";
+/// The regex matching the tag which begins an if block.
+///
+/// Code ending in `{` is left as ordinary Rust code.
+const IF_TAG_REGEX: &'static str = r"^\s*if\s+(.*[^{\s])\s*$";
+/// The regex matching the tag which begins the else branch of an if block.
+const ELSE_TAG_REGEX: &'static str = r"^\s*else\s*$";
+/// The regex matching the tag which begins a file block.
+///
+/// The path is a string literal or a `format!`, so that code such as
+/// `file = open()` is left as ordinary Rust code.
+const FILE_TAG_REGEX: &'static str = r#"^\s*file\s+("(?:[^"\\]|\\.)*"|format!\s*\(.*\))\s*$"#;
+/// The regex matching the tag which begins a cache block.
+///
+/// Code such as `cache += 1;` or ending in `{` is left as ordinary Rust code.
+const CACHE_TAG_REGEX: &'static str = r"^\s*cache\s+([^=+\-*/%&|^<>!.;{\s](?:.*[^;{\s])?)\s*$";
+/// The regex matching the tag which begins a named block, with its
+/// options such as `limit=2kB`.
+const BLOCK_TAG_REGEX: &'static str = r"^\s*block\s+([A-Za-z_][A-Za-z0-9_]*)((?:\s+[a-z]+\s*=\s*\S+)*)\s*$";
+/// The regex matching an option of a named block.
+const BLOCK_OPTION_REGEX: &'static str = r"([a-z]+)\s*=\s*(\S+)";
+/// The regex matching the tag which begins a for block.
+///
+/// Code ending in `{` is left as ordinary Rust code.
+const FOR_TAG_REGEX: &'static str = r"^\s*for\s+(.+?)\s+in\s+(.*[^{\s])\s*$";
+/// The regex matching an `include "name"` tag.
+///
+/// Fallbacks may follow as `or "other"`, and then `with name = value`,
+/// or `with name` for a variable of the same name.
+const INCLUDE_TAG_REGEX: &'static str =
+    r#"^\s*include\s+("[^"]*"(?:\s+or\s+"[^"]*")*)(?:\s+with\s+([A-Za-z_][A-Za-z0-9_]*)(?:\s*=([^=].*))?)?\s*$"#;
+/// The regex matching the tag which begins a with block.
+const WITH_TAG_REGEX: &'static str = r"^\s*with\s+([A-Za-z_][A-Za-z0-9_]*)\s*=([^=].*[^{\s])\s*$";
+/// The regex matching an `assert condition, "message"` tag.
+///
+/// The message is optional.
+const ASSERT_TAG_REGEX: &'static str = r#"^\s*assert\s+(.+?)(?:\s*,\s*"((?:[^"\\]|\\.)*)")?\s*$"#;
+/// The regex matching the tag which ends a block.
+const END_TAG_REGEX: &'static str = r"^\s*end\s*$";
+/// The regex matching a `default name = value` directive.
+const DEFAULT_DIRECTIVE_REGEX: &'static str = r"^\s*default\s+([A-Za-z_][A-Za-z0-9_]*)\s*=(.*)$";
+/// The regex matching a `set name = value` tag.
+///
+/// Comparisons such as `set == other` are left as ordinary Rust code.
+const SET_TAG_REGEX: &'static str = r"^\s*set\s+([A-Za-z_][A-Za-z0-9_]*)\s*=([^=].*)$";
+
+/// The prefix of a comment tag, after the opening delimiter.
+const COMMENT_PREFIX: &'static str = "#";
+/// The prefix of a comment holding directives for tempo itself.
+const PRAGMA_PREFIX: &'static str = "@tempo:";
+
+/// The byte order mark which may begin UTF-8 text.
+const BYTE_ORDER_MARK: char = '\u{feff}';
+
+/// The prefix of a line statement.
+const LINE_STATEMENT_PREFIX: &'static str = "%";
+/// The prefix of a line which begins with a literal `%`.
+const LINE_STATEMENT_ESCAPE: &'static str = "%%";
+
+/// The compiled regexes which find tags and tell them apart.
+///
+/// The delimiters are fixed, so the regexes are compiled once and
+/// shared by every parse, rather than on each one.
+struct Regexes
+{
+    code_block: Regex,
+    raw_tag: Regex,
+    end_raw_tag: Regex,
+    block_option: Regex,
+    default_directive: Regex,
+    set_tag: Regex,
+    macro_tag: Regex,
+    capture_tag: Regex,
+    if_tag: Regex,
+    else_tag: Regex,
+    for_tag: Regex,
+    file_tag: Regex,
+    cache_tag: Regex,
+    block_tag: Regex,
+    include_tag: Regex,
+    with_tag: Regex,
+    assert_tag: Regex,
+    end_tag: Regex,
+}
+
+/// Gets the regexes shared by every parse, compiling them the first time.
+fn regexes() -> &'static Regexes {
+    static REGEXES: OnceLock<Regexes> = OnceLock::new();
+
+    REGEXES.get_or_init(|| Regexes {
+        code_block: Regex::new(CODE_BLOCK_REGEX).unwrap(),
+        raw_tag: Regex::new(RAW_TAG_REGEX).unwrap(),
+        end_raw_tag: Regex::new(END_RAW_TAG_REGEX).unwrap(),
+        block_option: Regex::new(BLOCK_OPTION_REGEX).unwrap(),
+        default_directive: Regex::new(DEFAULT_DIRECTIVE_REGEX).unwrap(),
+        set_tag: Regex::new(SET_TAG_REGEX).unwrap(),
+        macro_tag: Regex::new(MACRO_TAG_REGEX).unwrap(),
+        capture_tag: Regex::new(CAPTURE_TAG_REGEX).unwrap(),
+        if_tag: Regex::new(IF_TAG_REGEX).unwrap(),
+        else_tag: Regex::new(ELSE_TAG_REGEX).unwrap(),
+        for_tag: Regex::new(FOR_TAG_REGEX).unwrap(),
+        file_tag: Regex::new(FILE_TAG_REGEX).unwrap(),
+        cache_tag: Regex::new(CACHE_TAG_REGEX).unwrap(),
+        block_tag: Regex::new(BLOCK_TAG_REGEX).unwrap(),
+        include_tag: Regex::new(INCLUDE_TAG_REGEX).unwrap(),
+        with_tag: Regex::new(WITH_TAG_REGEX).unwrap(),
+        assert_tag: Regex::new(ASSERT_TAG_REGEX).unwrap(),
+        end_tag: Regex::new(END_TAG_REGEX).unwrap(),
+    })
+}
+
+/// Configuration options for parsing.
+#[derive(Clone, Debug)]
+pub struct Config
+{
+    /// Whether lines starting with `%` are treated as code.
+    ///
+    /// A line such as `% for i in 0..10 {` is equivalent to
+    /// `<% for i in 0..10 { %>` without the trailing newline.
+    /// A line starting with `%%` produces a literal `%`.
+    pub line_statements: bool,
+    /// Whether `\r\n` line endings are converted into `\n`.
+    ///
+    /// By default, line endings are preserved as they are.
+    pub normalize_line_endings: bool,
+    /// Whether the first new line after a tag which is not a
+    /// printed expression is removed.
+    pub trim_blocks: bool,
+    /// Whether spaces and tabs between the start of a line and a tag
+    /// which is not a printed expression are removed.
+    ///
+    /// Together with `trim_blocks`, this lets tags sit on lines of
+    /// their own without leaving blank lines in the output.
+    pub lstrip_blocks: bool,
+    /// The largest template accepted, in bytes.
+    pub max_size: Option<usize>,
+    /// The largest number of fragments of text and code accepted
+    /// in a template.
+    pub max_fragments: Option<usize>,
+    /// The deepest blocks may be nested inside one another.
+    pub max_depth: Option<usize>,
+    /// The block tags defined by the application.
+    ///
+    /// The name of each tag is reserved, so a tag whose code begins
+    /// with it as a word opens the block.
+    pub tags: tag::Tags,
+}
+
+/// The style of line endings used by a template.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LineEnding
+{
+    /// Lines end with `\n`.
+    Lf,
+    /// Lines end with `\r\n`.
+    CrLf,
+    /// Both styles are used.
+    Mixed,
+}
+
+/// The location of a tag in the source of a template.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TagSpan
+{
+    /// A tag, including its delimiters.
+    Code(Range<usize>),
+    /// A raw block, from the start of its opening tag to the
+    /// end of its closing tag.
+    Raw(Range<usize>),
+}
+
+/// The kind of a token of template source.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TokenKind
+{
+    /// The delimiter which opens a tag, along with the `=` of a
+    /// printed expression or the `#` of a comment.
+    OpenTag,
+    /// Text outside of tags, including the contents of raw blocks.
+    Text,
+    /// The contents of a tag.
+    CodeBody,
+    /// The delimiter which closes a tag.
+    CloseTag,
+}
+
+/// A token of template source, as found by the scanner.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token
+{
+    pub kind: TokenKind,
+    /// The byte range of the token in the source.
+    pub span: Range<usize>,
+}
+
+/// A problem found while parsing a template with recovery.
+#[derive(Debug)]
+pub struct Diagnostic
+{
+    /// The error which parsing without recovery would have given.
+    pub error: Error,
+    /// The byte range of the source the problem is in.
+    pub span: Range<usize>,
+}
+
+/// A change to the source of a template.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Edit
+{
+    /// The byte range of the old source which was replaced.
+    pub range: Range<usize>,
+    /// The text it was replaced with.
+    pub replacement: String,
+}
 
 /// A range of characters in the text.
+#[derive(Debug)]
 struct Span {
     pub low_index: usize,
     pub high_index: usize,
@@ -16,6 +246,36 @@ struct Span {
 enum FragmentKind {
     Code,
     Text,
+    /// A raw block, holding the span of its contents.
+    Raw(Span),
+    /// A raw block which is never ended.
+    UnterminatedRaw,
+}
+
+/// A parsed fragment, before blocks are nested.
+///
+/// Tags which only make sense as part of a block hold an error
+/// item, used in their place if they turn out to be malformed.
+enum Piece {
+    /// A standalone item.
+    Item(ast::Item),
+    /// The start of a block with an empty body, and its tag.
+    Open(ast::Item, ast::Item),
+    /// The start of the else branch of the innermost block.
+    Else(ast::Item),
+    /// The end of the innermost block.
+    End(ast::Item),
+}
+
+/// A block which has been opened but not yet ended.
+struct OpenBlock {
+    item: ast::Item,
+    /// The error item for the tag which opened the block.
+    tag: ast::Item,
+    /// The items preceding the block in the enclosing block.
+    outer_items: Vec<ast::Item>,
+    /// The error item for the `else` tag, if the else branch has begun.
+    else_tag: Option<ast::Item>,
 }
 
 /// A fragment of the text.
@@ -26,20 +286,211 @@ struct Fragment {
 
 /// Parse an AST from a string.
 pub fn parse_str(input: &str) -> Result<ast::Ast, Error> {
-    let code_block_regex = Regex::new(CODE_BLOCK_REGEX).unwrap();
+    parse_str_with_config(input, &Config::default())
+}
+
+/// Parse an AST from a string with the given options.
+pub fn parse_str_with_config(input: &str, config: &Config) -> Result<ast::Ast, Error> {
+    let mut diagnostics = Vec::new();
+    let ast = parse(input, config, &mut diagnostics);
+
+    match diagnostics.into_iter().next() {
+        Some(diagnostic) => Err(diagnostic.error),
+        None => Ok(ast),
+    }
+}
+
+/// Parse an AST from bytes which may not be valid UTF-8.
+///
+/// Invalid UTF-8 is handled as the fallback says, either giving an
+/// error with the offset of the first invalid byte or decoding the
+/// text anyway. The way the text was decoded is given with the AST.
+pub fn parse_bytes(input: &[u8], config: &Config, fallback: encoding::Fallback)
+    -> Result<(ast::Ast, encoding::Encoding), Error> {
+    check_size(input.len(), config)?;
+
+    match ::std::str::from_utf8(input) {
+        Ok(text) => Ok((parse_str_with_config(text, config)?, encoding::Encoding::Utf8)),
+        Err(..) => {
+            let (text, encoding) = encoding::decode(input.to_vec(), fallback)?;
+            Ok((parse_str_with_config(&text, config)?, encoding))
+        },
+    }
+}
+
+/// Parse an AST from a string, recovering from errors.
+///
+/// This never fails, which makes it suitable for editors. Malformed
+/// parts of the template become `ItemKind::Error` items, with a
+/// diagnostic for each, and the rest of the template is parsed as usual.
+/// A block which is never ended is replaced by its opening tag, as an
+/// error item, followed by its contents.
+pub fn parse_with_recovery(input: &str, config: &Config) -> (ast::Ast, Vec<Diagnostic>) {
+    let mut diagnostics = Vec::new();
+    let ast = parse(input, config, &mut diagnostics);
+
+    (ast, diagnostics)
+}
+
+/// Updates the AST of a template after its source was edited.
+///
+/// `source` is the source after the edit, and `ast` must have been
+/// parsed from the source before it with the same options. Only the
+/// top-level items on the lines touched by the edit are parsed again,
+/// and the spans of the items after them are moved. The template is
+/// parsed in full if the edit could change the meaning of the rest of
+/// it, such as by opening a raw block, or if line statements, line
+/// ending normalization, whitespace stripping or fragment limits are
+/// in use.
+pub fn reparse(ast: ast::Ast, source: &str, edit: &Edit, config: &Config) -> Result<ast::Ast, Error> {
+    if config.line_statements || config.normalize_line_endings || config.trim_blocks ||
+        config.lstrip_blocks || config.max_fragments.is_some() {
+        return parse_str_with_config(source, config);
+    }
+    check_size(source.len(), config)?;
+
+    let added = edit.range.start + edit.replacement.len();
+    if added > source.len() {
+        return parse_str_with_config(source, config);
+    }
+
+    let old_length = source.len() + edit.range.len() - edit.replacement.len();
+    let bom_length = source.len() - source.trim_start_matches(BYTE_ORDER_MARK).len();
+
+    // The items must cover the whole of the old source for their
+    // spans to be trusted.
+    let tiles = ast.items.first().map_or(false, |item| item.span.start == bom_length) &&
+        ast.items.last().map_or(false, |item| item.span.end == old_length) &&
+        ast.items.windows(2).all(|pair| pair[0].span.end == pair[1].span.start);
+
+    if !tiles || edit.range.start < bom_length {
+        return parse_str_with_config(source, config);
+    }
+
+    // Edits to front matter, or which may have added it, change how
+    // the rest of the template is split.
+    let front_matter_end = match ast.items[0].kind {
+        ast::ItemKind::FrontMatter { .. } => ast.items[0].span.end,
+        _ if front_matter::parse(&source[bom_length..]).is_some() => source.len(),
+        _ => bom_length,
+    };
+    if edit.range.start < front_matter_end {
+        return parse_str_with_config(source, config);
+    }
+
+    // Tags never span lines, so only the lines touched by the edit can change.
+    let line_start = source[..edit.range.start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = source[added..].find('\n').map(|i| added + i).unwrap_or(source.len())
+        + edit.range.len() - edit.replacement.len();
+
+    let first = ast.items.iter().position(|item| item.span.end >= line_start).unwrap();
+    let last = ast.items.iter().rposition(|item| item.span.start <= line_end).unwrap();
+    let old_region = ast.items[first].span.start..ast.items[last].span.end;
+    let region = old_region.start..(old_region.end + edit.replacement.len() - edit.range.len());
+
+    // Parse everything if the region holds a `<%` which could pair with
+    // a `%>` after it, or a raw block which is never ended.
+    let region_source = &source[region.clone()];
+    let mut text_start = 0;
+    for fragment in find_code_fragments(region_source) {
+        let is_unterminated = match fragment.kind {
+            FragmentKind::UnterminatedRaw => true,
+            _ => false,
+        };
 
-    let code_spans: Vec<_> = code_block_regex.find_iter(input).map(|m| {
-        Span {
-            low_index: m.start(),
-            high_index: m.end(),
+        if is_unterminated || region_source[text_start..fragment.span.low_index].contains(OPEN_DELIMITER) {
+            return parse_str_with_config(source, config);
         }
-    }).collect();
+        text_start = fragment.span.high_index;
+    }
+    if region_source[text_start..].contains(OPEN_DELIMITER) {
+        return parse_str_with_config(source, config);
+    }
 
-    verify_no_overlapping_spans(&code_spans);
+    // The region is parsed on its own, so could be mistaken for the
+    // start of a template, such as by beginning with front matter.
+    if region_source.starts_with(BYTE_ORDER_MARK) || front_matter::parse(region_source).is_some() {
+        return parse_str_with_config(source, config);
+    }
 
-    let code_fragments: Vec<_> = code_spans.into_iter().map(|span| {
-        Fragment { span: span, kind: FragmentKind::Code }
-    }).collect();
+    let mut parsed = match parse_str_with_config(region_source, config) {
+        Ok(parsed) => parsed,
+        Err(..) => return parse_str_with_config(source, config),
+    };
+    ast::shift_spans(&mut parsed.items, region.start as isize);
+
+    let mut items = ast.items;
+    let origin = items[0].origin.clone();
+    if let Some(ref origin) = origin {
+        parsed.set_origin(origin);
+    }
+
+    let mut after = items.split_off(last + 1);
+    ast::shift_spans(&mut after, region.len() as isize - old_region.len() as isize);
+    items.truncate(first);
+    items.extend(parsed.items);
+    items.extend(after);
+
+    Ok(ast::Ast { items: items })
+}
+
+/// Parses an AST, recording each error found along the way.
+fn parse(input: &str, config: &Config, diagnostics: &mut Vec<Diagnostic>) -> ast::Ast {
+    if let Err(e) = check_size(input.len(), config) {
+        let item = ast::Item::with_span(ast::ItemKind::Error(input.to_owned()), 0..input.len());
+        return ast::Ast { items: vec![recover(e, item, diagnostics)] };
+    }
+
+    let source = input;
+
+    // Spans are offset by what comes before the rest of the input,
+    // such as a byte order mark or front matter.
+    let mut offset = input.len() - input.trim_start_matches(BYTE_ORDER_MARK).len();
+    let mut input = &input[offset..];
+
+    let mut front_matter_item = None;
+    if let Some((result, length)) = front_matter::parse(input) {
+        let source = input[..length].to_owned();
+        let span = offset..(offset + length);
+
+        front_matter_item = Some(match result {
+            Ok(fields) => ast::Item::with_span(ast::ItemKind::FrontMatter { source: source, fields: fields }, span),
+            Err(e) => recover(e, ast::Item::with_span(ast::ItemKind::Error(source), span), diagnostics),
+        });
+
+        offset += length;
+        input = &input[length..];
+    }
+
+    // The offsets of the line feeds whose carriage returns were removed,
+    // in the normalized input, so that spans can be mapped back.
+    let mut crlf_offsets = Vec::new();
+    let normalized_input;
+    let input = if config.normalize_line_endings && input.contains('\r') {
+        for (count, (index, _)) in input.match_indices("\r\n").enumerate() {
+            crlf_offsets.push(offset + index - count);
+        }
+        normalized_input = input.replace("\r\n", "\n");
+        &normalized_input[..]
+    } else {
+        input
+    };
+
+    let expanded_input;
+    let input = if config.line_statements {
+        expanded_input = expand_line_statements(input);
+        &expanded_input[..]
+    } else {
+        input
+    };
+
+    let lines_before = source[..offset].matches('\n').count();
+    let locate = |index: usize| {
+        let (line, column) = position::line_and_column(input, index - offset);
+        (line + lines_before, column)
+    };
+
+    let code_fragments = find_code_fragments(input);
 
     let fragments = if !code_fragments.is_empty() {
         // If we have code fragments, we can interpolate the text fragments between them.
@@ -51,34 +502,639 @@ pub fn parse_str(input: &str) -> Result<ast::Ast, Error> {
         }]
     };
 
+    if let Some(max_fragments) = config.max_fragments {
+        if fragments.len() > max_fragments {
+            let error = ErrorKind::TooManyFragments(fragments.len(), max_fragments).into();
+            let span = offset..(offset + input.len());
+            let item = ast::Item::with_span(ast::ItemKind::Error(input.to_owned()), span);
+            return ast::Ast { items: vec![recover(error, item, diagnostics)] };
+        }
+    }
+
+    let mut fragments = fragments;
+    strip_block_whitespace(input, &mut fragments, config);
+
+    let fragments = convert_raw_fragments(fragments);
     let mut fragments = remove_empty_fragments(fragments);
     trim_delimiters_from_code_frags(&mut fragments);
 
-    let items = fragments.into_iter().map(|frag| {
-        let mut frag_text = input[frag.span.low_index..frag.span.high_index].to_string();
+    let regexes = regexes();
+
+    let pieces = fragments.into_iter().map(|frag| {
+        // Only the items which keep their source copy it, so most tags
+        // allocate only for the parts they capture.
+        let mut frag_text = &input[frag.span.low_index..frag.span.high_index];
+
+        // Spans of code include the delimiters trimmed from them.
+        let span = match frag.kind {
+            FragmentKind::Code => {
+                (offset + frag.span.low_index - OPEN_DELIMITER.len())..
+                    (offset + frag.span.high_index + CLOSE_DELIMITER.len())
+            },
+            _ => (offset + frag.span.low_index)..(offset + frag.span.high_index),
+        };
+
+        // Malformed tags are kept as they were written.
+        let error_item = || {
+            let source = match frag.kind {
+                FragmentKind::Code => {
+                    &input[(frag.span.low_index - OPEN_DELIMITER.len())..(frag.span.high_index + CLOSE_DELIMITER.len())]
+                },
+                _ => &input[frag.span.low_index..frag.span.high_index],
+            };
+            ast::Item::with_span(ast::ItemKind::Error(source.to_owned()), span.clone())
+        };
 
-        let print_result = if frag_text.starts_with("=") {
-            frag_text = frag_text[1..].to_string();
-            true
+        let print_result = if let FragmentKind::Code = frag.kind {
+            frag_text.starts_with("=")
         } else {
             false
         };
 
+        if print_result {
+            frag_text = &frag_text[1..];
+        }
+
         let item_kind = match frag.kind {
-            FragmentKind::Text => ast::ItemKind::Text(frag_text),
-            FragmentKind::Code => ast::ItemKind::Code {
-                source: frag_text,
-                print_result: print_result,
+            FragmentKind::Text | FragmentKind::Raw(..) => ast::ItemKind::Text(frag_text.to_owned()),
+            FragmentKind::UnterminatedRaw => {
+                let (line, column) = locate(span.start);
+                let error = ErrorKind::UnterminatedBlock("raw".to_owned(), line, column).into();
+                return Piece::Item(recover(error, error_item(), diagnostics));
+            },
+            FragmentKind::Code if frag_text.starts_with(COMMENT_PREFIX) => {
+                let text = &frag_text[COMMENT_PREFIX.len()..];
+
+                match parse_pragma(text) {
+                    Ok(Some((name, value))) => ast::ItemKind::Directive { name: name, value: value },
+                    Ok(None) => ast::ItemKind::Comment(text.to_owned()),
+                    Err(e) => return Piece::Item(recover(e, error_item(), diagnostics)),
+                }
+            },
+            FragmentKind::Code if print_result => ast::ItemKind::Print(frag_text.to_owned()),
+            FragmentKind::Code if regexes.end_tag.is_match(frag_text) => {
+                return Piece::End(error_item());
+            },
+            FragmentKind::Code if regexes.else_tag.is_match(frag_text) => {
+                return Piece::Else(error_item());
+            },
+            FragmentKind::Code if regexes.macro_tag.is_match(frag_text) => {
+                let captures = regexes.macro_tag.captures(frag_text).unwrap();
+                return Piece::Open(ast::Item::with_span(ast::ItemKind::Macro {
+                    name: captures[1].to_owned(),
+                    parameters: split_parameters(&captures[2]),
+                    body: Vec::new(),
+                }, span.clone()), error_item());
+            },
+            FragmentKind::Code if regexes.capture_tag.is_match(frag_text) => {
+                let captures = regexes.capture_tag.captures(frag_text).unwrap();
+                return Piece::Open(ast::Item::with_span(ast::ItemKind::Capture {
+                    name: captures[1].to_owned(),
+                    body: Vec::new(),
+                }, span.clone()), error_item());
+            },
+            FragmentKind::Code if regexes.if_tag.is_match(frag_text) => {
+                let captures = regexes.if_tag.captures(frag_text).unwrap();
+                return Piece::Open(ast::Item::with_span(ast::ItemKind::If {
+                    condition: captures[1].to_owned(),
+                    body: Vec::new(),
+                    else_body: Vec::new(),
+                }, span.clone()), error_item());
+            },
+            FragmentKind::Code if regexes.for_tag.is_match(frag_text) => {
+                let captures = regexes.for_tag.captures(frag_text).unwrap();
+                return Piece::Open(ast::Item::with_span(ast::ItemKind::For {
+                    pattern: captures[1].to_owned(),
+                    iterator: captures[2].to_owned(),
+                    body: Vec::new(),
+                }, span.clone()), error_item());
+            },
+            FragmentKind::Code if regexes.file_tag.is_match(frag_text) => {
+                let captures = regexes.file_tag.captures(frag_text).unwrap();
+                return Piece::Open(ast::Item::with_span(ast::ItemKind::File {
+                    path: captures[1].to_owned(),
+                    body: Vec::new(),
+                }, span.clone()), error_item());
+            },
+            FragmentKind::Code if regexes.cache_tag.is_match(frag_text) => {
+                let captures = regexes.cache_tag.captures(frag_text).unwrap();
+                return Piece::Open(ast::Item::with_span(ast::ItemKind::Cache {
+                    key: captures[1].to_owned(),
+                    body: Vec::new(),
+                }, span.clone()), error_item());
+            },
+            FragmentKind::Code if regexes.block_tag.is_match(frag_text) => {
+                let captures = regexes.block_tag.captures(frag_text).unwrap();
+                let (limit, truncation) = match parse_block_options(&captures[2]) {
+                    Ok(options) => options,
+                    Err(e) => return Piece::Item(recover(e, error_item(), diagnostics)),
+                };
+                return Piece::Open(ast::Item::with_span(ast::ItemKind::Block {
+                    name: captures[1].to_owned(),
+                    limit: limit,
+                    truncation: truncation,
+                    body: Vec::new(),
+                }, span.clone()), error_item());
+            },
+            FragmentKind::Code if regexes.include_tag.is_match(frag_text) => {
+                let captures = regexes.include_tag.captures(frag_text).unwrap();
+                let mut names = captures[1].split('"').skip(1).step_by(2).map(|n| n.to_owned());
+                let include = ast::ItemKind::Include { name: names.next().unwrap(), fallbacks: names.collect() };
+
+                match captures.get(2) {
+                    Some(name) => ast::ItemKind::With {
+                        name: name.as_str().to_owned(),
+                        value: captures.get(3).map_or(name.as_str(), |v| v.as_str()).trim().to_owned(),
+                        body: vec![ast::Item::with_span(include, span.clone())],
+                    },
+                    None => include,
+                }
+            },
+            FragmentKind::Code if regexes.with_tag.is_match(frag_text) => {
+                let captures = regexes.with_tag.captures(frag_text).unwrap();
+                return Piece::Open(ast::Item::with_span(ast::ItemKind::With {
+                    name: captures[1].to_owned(),
+                    value: captures[2].trim().to_owned(),
+                    body: Vec::new(),
+                }, span.clone()), error_item());
+            },
+            FragmentKind::Code if regexes.assert_tag.is_match(frag_text) => {
+                let captures = regexes.assert_tag.captures(frag_text).unwrap();
+                ast::ItemKind::Assert {
+                    condition: captures[1].to_owned(),
+                    message: captures.get(2).map(|m| m.as_str().to_owned()),
+                }
+            },
+            FragmentKind::Code if regexes.default_directive.is_match(frag_text) => {
+                let captures = regexes.default_directive.captures(frag_text).unwrap();
+                ast::ItemKind::Default {
+                    name: captures[1].to_owned(),
+                    value: captures[2].trim().to_owned(),
+                }
             },
+            FragmentKind::Code if regexes.set_tag.is_match(frag_text) => {
+                let captures = regexes.set_tag.captures(frag_text).unwrap();
+                ast::ItemKind::Set {
+                    name: captures[1].to_owned(),
+                    value: captures[2].trim().to_owned(),
+                }
+            },
+            FragmentKind::Code if config.tags.get(tag_name(frag_text)).is_some() => {
+                let name = tag_name(frag_text);
+                let arguments = frag_text.trim_start()[name.len()..].trim();
+
+                if let Err(reason) = config.tags.get(name).unwrap().parse(arguments) {
+                    let error = ErrorKind::InvalidTag(name.to_owned(), reason).into();
+                    return Piece::Item(recover(error, error_item(), diagnostics));
+                }
+                return Piece::Open(ast::Item::with_span(ast::ItemKind::Tag {
+                    name: name.to_owned(),
+                    arguments: arguments.to_owned(),
+                    body: Vec::new(),
+                }, span.clone()), error_item());
+            },
+            FragmentKind::Code => ast::ItemKind::Code(frag_text.to_owned()),
         };
 
-        ast::Item { kind: item_kind }
-    }).collect();
+        Piece::Item(ast::Item::with_span(item_kind, span))
+    }).collect::<Vec<_>>();
 
-    Ok(ast::Ast { items: items })
+    let mut items = nest_blocks(pieces, config.max_depth, &locate, diagnostics);
+    check_pragmas(&mut items, diagnostics);
+    if !crlf_offsets.is_empty() {
+        restore_crlf_spans(&mut items, diagnostics, &crlf_offsets);
+    }
+    items.splice(0..0, front_matter_item);
+
+    ast::Ast { items: items }
+}
+
+/// Maps spans in input whose `\r\n` line endings were normalized back
+/// to the source, given the offsets of the line feeds which had a
+/// carriage return removed before them.
+fn restore_crlf_spans(items: &mut [ast::Item], diagnostics: &mut [Diagnostic], crlf_offsets: &[usize]) {
+    struct Restore<'a>(&'a [usize]);
+
+    impl<'a> Restore<'a>
+    {
+        fn restore(&self, span: &mut Range<usize>) {
+            span.start += self.0.partition_point(|&offset| offset < span.start);
+            span.end += self.0.partition_point(|&offset| offset < span.end);
+        }
+    }
+
+    impl<'a> ast::VisitorMut for Restore<'a> {
+        fn visit_item_mut(&mut self, item: &mut ast::Item) {
+            self.restore(&mut item.span);
+            ast::walk_item_mut(self, item);
+        }
+    }
+
+    let mut restore = Restore(crlf_offsets);
+    for item in items.iter_mut() {
+        ast::VisitorMut::visit_item_mut(&mut restore, item);
+    }
+    for diagnostic in diagnostics.iter_mut() {
+        restore.restore(&mut diagnostic.span);
+    }
+}
+
+/// Parses the `limit` and `truncate` options of a named block.
+fn parse_block_options(options: &str) -> Result<(Option<u64>, ast::Truncation), Error> {
+    let (mut limit, mut truncation) = (None, ast::Truncation::Cut);
+
+    for captures in regexes().block_option.captures_iter(options) {
+        let invalid = || ErrorKind::InvalidBlockOption(captures[0].trim().to_owned());
+
+        match &captures[1] {
+            "limit" => limit = Some(captures[2].parse::<units::ByteSize>().map_err(|_| invalid())?.0),
+            "truncate" => truncation = ast::Truncation::from_name(&captures[2]).ok_or_else(invalid)?,
+            _ => return Err(invalid().into()),
+        }
+    }
+
+    Ok((limit, truncation))
+}
+
+/// Detects the style of line endings used by a template.
+///
+/// Gives `None` if the template has no line endings.
+pub fn detect_line_ending(input: &str) -> Option<LineEnding> {
+    let line_count = input.matches('\n').count();
+    let crlf_count = input.matches("\r\n").count();
+
+    match (line_count, crlf_count) {
+        (0, _) => None,
+        (_, 0) => Some(LineEnding::Lf),
+        (lines, crlfs) if lines == crlfs => Some(LineEnding::CrLf),
+        _ => Some(LineEnding::Mixed),
+    }
+}
+
+/// Finds the byte ranges of the tags in a template, in order.
+///
+/// Line statements are not tags.
+pub fn tag_spans(input: &str) -> Result<Vec<TagSpan>, Error> {
+    let offset = input.len() - input.trim_start_matches(BYTE_ORDER_MARK).len();
+    let fragments = find_code_fragments(&input[offset..]);
+
+    fragments.into_iter().map(|frag| {
+        let span = (frag.span.low_index + offset)..(frag.span.high_index + offset);
+        match frag.kind {
+            FragmentKind::Raw(..) => Ok(TagSpan::Raw(span)),
+            FragmentKind::UnterminatedRaw => {
+                let (line, column) = position::line_and_column(input, span.start);
+                Err(ErrorKind::UnterminatedBlock("raw".to_owned(), line, column).into())
+            },
+            _ => Ok(TagSpan::Code(span)),
+        }
+    }).collect()
+}
+
+/// Splits a template into tokens, in order.
+///
+/// This never fails, so it can be used to highlight templates as they
+/// are typed. The tokens cover the whole source except for any byte
+/// order mark, and line statements are text.
+pub fn tokens(input: &str) -> Vec<Token> {
+    let offset = input.len() - input.trim_start_matches(BYTE_ORDER_MARK).len();
+    let mut scanner = Scanner { input: &input[offset..], offset: offset, tokens: Vec::new() };
+    let mut index = 0;
+
+    for fragment in find_code_fragments(scanner.input) {
+        scanner.push(TokenKind::Text, index..fragment.span.low_index);
+
+        match fragment.kind {
+            FragmentKind::Raw(contents) => {
+                scanner.push_tag(fragment.span.low_index..contents.low_index);
+                scanner.push(TokenKind::Text, contents.low_index..contents.high_index);
+                scanner.push_tag(contents.high_index..fragment.span.high_index);
+            },
+            FragmentKind::UnterminatedRaw => {
+                let tag_end = fragment.span.low_index +
+                    scanner.input[fragment.span.low_index..].find(CLOSE_DELIMITER).unwrap() + CLOSE_DELIMITER.len();
+                scanner.push_tag(fragment.span.low_index..tag_end);
+                scanner.push(TokenKind::Text, tag_end..fragment.span.high_index);
+            },
+            _ => scanner.push_tag(fragment.span.low_index..fragment.span.high_index),
+        }
+
+        index = fragment.span.high_index;
+    }
+
+    let end = scanner.input.len();
+    scanner.push(TokenKind::Text, index..end);
+    scanner.tokens
+}
+
+/// Collects the tokens of a template.
+struct Scanner<'a>
+{
+    input: &'a str,
+    /// The length of the byte order mark removed from the input.
+    offset: usize,
+    tokens: Vec<Token>,
+}
+
+impl<'a> Scanner<'a>
+{
+    fn push(&mut self, kind: TokenKind, span: Range<usize>) {
+        if span.start != span.end {
+            self.tokens.push(Token { kind: kind, span: (span.start + self.offset)..(span.end + self.offset) });
+        }
+    }
+
+    /// Pushes the tokens of a tag, including its delimiters.
+    fn push_tag(&mut self, span: Range<usize>) {
+        let body_end = span.end - CLOSE_DELIMITER.len();
+        let marker_length = match self.input[(span.start + OPEN_DELIMITER.len())..].chars().next() {
+            Some('=') | Some('#') => 1,
+            _ => 0,
+        };
+        let body_start = ::std::cmp::min(span.start + OPEN_DELIMITER.len() + marker_length, body_end);
+
+        self.push(TokenKind::OpenTag, span.start..body_start);
+        self.push(TokenKind::CodeBody, body_start..body_end);
+        self.push(TokenKind::CloseTag, body_end..span.end);
+    }
+}
+
+/// Checks that a template of the given size in bytes is within the limit.
+pub fn check_size(size: usize, config: &Config) -> Result<(), Error> {
+    match config.max_size {
+        Some(max_size) if size > max_size => {
+            Err(ErrorKind::TemplateTooLarge(size, max_size).into())
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Parses the name and value of a pragma from the text of a comment.
+///
+/// A pragma has the form `<%# @tempo: name = "value" %>`. Comments
+/// which are not pragmas give `None`.
+fn parse_pragma(comment: &str) -> Result<Option<(String, String)>, Error> {
+    let text = comment.trim();
+
+    if !text.starts_with(PRAGMA_PREFIX) {
+        return Ok(None);
+    }
+
+    let pragma = text[PRAGMA_PREFIX.len()..].trim();
+    match pragma.find('=') {
+        Some(i) => Ok(Some((pragma[..i].trim().to_owned(),
+                            pragma[i+1..].trim().trim_matches('"').to_owned()))),
+        None => Err(ErrorKind::InvalidPragma(pragma.to_owned()).into()),
+    }
+}
+
+/// Checks the directives at the top level of a template, replacing
+/// invalid directives with error items.
+fn check_pragmas(items: &mut [ast::Item], diagnostics: &mut Vec<Diagnostic>) {
+    for item in items.iter_mut() {
+        let result = match item.kind {
+            ast::ItemKind::Directive { ref name, ref value } => check_pragma(name, value),
+            _ => continue,
+        };
+
+        if let Err(e) = result {
+            let error_item = ast::Item::with_span(ast::ItemKind::Error(item.to_string()), item.span.clone());
+            *item = recover(e, error_item, diagnostics);
+        }
+    }
+}
+
+/// Checks a directive.
+///
+/// The only directive is `requires`, holding the versions of
+/// tempo the template works with.
+fn check_pragma(name: &str, value: &str) -> Result<(), Error> {
+    match name {
+        "requires" => {
+            if !version::satisfies(::VERSION, value)? {
+                return Err(ErrorKind::UnsupportedVersion(value.to_owned()).into());
+            }
+            Ok(())
+        },
+        _ => Err(ErrorKind::InvalidPragma(format!("{} = \"{}\"", name, value)).into()),
+    }
+}
+
+/// Records an error, giving back the error item which replaces
+/// the malformed source.
+fn recover(error: Error, item: ast::Item, diagnostics: &mut Vec<Diagnostic>) -> ast::Item {
+    diagnostics.push(Diagnostic { error: error, span: item.span.clone() });
+    item
+}
+
+/// Nests the items inside blocks into the items which open them.
+///
+/// Errors are located with the line and column of a byte index.
+fn nest_blocks(pieces: Vec<Piece>, max_depth: Option<usize>, locate: &dyn Fn(usize) -> (usize, usize),
+               diagnostics: &mut Vec<Diagnostic>) -> Vec<ast::Item> {
+    let mut open_blocks: Vec<OpenBlock> = Vec::new();
+    let mut items = Vec::new();
+    // Blocks nested too deeply are replaced by their tags and contents,
+    // like blocks which are never ended.
+    let mut too_deep = 0;
+
+    for piece in pieces {
+        match piece {
+            Piece::Item(item) => items.push(item),
+            Piece::Open(_, tag) if too_deep > 0 => {
+                items.push(tag);
+                too_deep += 1;
+            },
+            Piece::Else(tag) if too_deep > 0 => items.push(tag),
+            Piece::End(tag) if too_deep > 0 => {
+                items.push(tag);
+                too_deep -= 1;
+            },
+            Piece::Open(_, tag) if max_depth.map_or(false, |limit| open_blocks.len() >= limit) => {
+                let (line, column) = locate(tag.span.start);
+                let error = ErrorKind::BlocksTooDeep(tag.to_string(), max_depth.unwrap(), line, column).into();
+                items.push(recover(error, tag, diagnostics));
+                too_deep = 1;
+            },
+            Piece::Open(item, tag) => {
+                open_blocks.push(OpenBlock {
+                    item: item,
+                    tag: tag,
+                    outer_items: ::std::mem::replace(&mut items, Vec::new()),
+                    else_tag: None,
+                });
+            },
+            Piece::Else(tag) => {
+                match open_blocks.last_mut() {
+                    Some(&mut OpenBlock { item: ast::Item { kind: ast::ItemKind::If { ref mut body, .. }, .. },
+                                          ref mut else_tag, .. }) if else_tag.is_none() => {
+                        *body = ::std::mem::replace(&mut items, Vec::new());
+                        *else_tag = Some(tag);
+                    },
+                    _ => items.push(recover(ErrorKind::UnexpectedElse.into(), tag, diagnostics)),
+                }
+            },
+            Piece::End(tag) => {
+                let OpenBlock { mut item, outer_items, else_tag, .. } = match open_blocks.pop() {
+                    Some(block) => block,
+                    None => {
+                        items.push(recover(ErrorKind::UnexpectedEnd.into(), tag, diagnostics));
+                        continue;
+                    },
+                };
+
+                let body = ::std::mem::replace(&mut items, outer_items);
+                match item.kind {
+                    ast::ItemKind::If { ref mut else_body, .. } if else_tag.is_some() => *else_body = body,
+                    ast::ItemKind::Macro { body: ref mut block_body, .. } |
+                    ast::ItemKind::Capture { body: ref mut block_body, .. } |
+                    ast::ItemKind::If { body: ref mut block_body, .. } |
+                    ast::ItemKind::For { body: ref mut block_body, .. } |
+                    ast::ItemKind::File { body: ref mut block_body, .. } |
+                    ast::ItemKind::Cache { body: ref mut block_body, .. } |
+                    ast::ItemKind::Block { body: ref mut block_body, .. } |
+                    ast::ItemKind::With { body: ref mut block_body, .. } |
+                    ast::ItemKind::Tag { body: ref mut block_body, .. } => *block_body = body,
+                    _ => unreachable!(),
+                }
+
+                item.span.end = tag.span.end;
+                items.push(item);
+            },
+        }
+    }
+
+    // Blocks which are never ended are replaced by their tags and contents.
+    while let Some(OpenBlock { item, tag, outer_items, else_tag }) = open_blocks.pop() {
+        let kind = match item.kind {
+            ast::ItemKind::Tag { ref name, .. } => name.clone(),
+            ref kind => kind.block_name().to_owned(),
+        };
+        let (line, column) = locate(tag.span.start);
+        let error = ErrorKind::UnterminatedBlock(kind, line, column).into();
+        let contents = ::std::mem::replace(&mut items, outer_items);
+
+        items.push(recover(error, tag, diagnostics));
+        match else_tag {
+            Some(else_tag) => {
+                items.extend(item.kind.children().into_iter().cloned());
+                items.push(else_tag);
+            },
+            None => (),
+        }
+        items.extend(contents);
+    }
+
+    items
+}
+
+/// Gets the first word of the code in a tag, which may be the name
+/// of a tag defined by the application.
+fn tag_name(code: &str) -> &str {
+    code.split_whitespace().next().unwrap_or("")
+}
+
+/// Splits a comma-separated list of parameters, ignoring
+/// commas nested inside brackets.
+fn split_parameters(text: &str) -> Vec<String> {
+    let mut parameters = Vec::new();
+    let mut depth = 0;
+    let mut current = String::new();
+
+    for c in text.chars() {
+        match c {
+            '(' | '[' | '{' | '<' => depth += 1,
+            ')' | ']' | '}' | '>' => depth -= 1,
+            ',' if depth == 0 => {
+                parameters.push(current.trim().to_owned());
+                current.clear();
+                continue;
+            },
+            _ => (),
+        }
+        current.push(c);
+    }
+
+    if !current.trim().is_empty() {
+        parameters.push(current.trim().to_owned());
+    }
+
+    parameters
+}
+
+/// Rewrites line statements into ordinary code blocks.
+fn expand_line_statements(input: &str) -> String {
+    let mut output = String::new();
+
+    for line in input.split_terminator('\n') {
+        let trimmed = line.trim_start();
+
+        if trimmed.starts_with(LINE_STATEMENT_ESCAPE) {
+            let indentation = &line[..line.len() - trimmed.len()];
+            output.push_str(indentation);
+            output.push_str(&trimmed[1..]);
+            output.push('\n');
+        } else if let Some(statement) = trimmed.strip_prefix(LINE_STATEMENT_PREFIX) {
+            let statement = statement.trim_end_matches('\r');
+            output.push_str(&format!("<%{} %>", statement));
+        } else {
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+
+    // Preserve the lack of a trailing new line.
+    if !input.ends_with('\n') && output.ends_with('\n') {
+        output.pop();
+    }
+
+    output
 }
 
-fn verify_no_overlapping_spans(_spans: &[Span]) {
+/// Finds the code blocks and raw blocks in the input.
+fn find_code_fragments(input: &str) -> Vec<Fragment> {
+    let regexes = regexes();
+
+    let mut fragments = Vec::new();
+    let mut index = 0;
+
+    while let Some(m) = regexes.code_block.find(&input[index..]) {
+        let (low_index, high_index) = (index + m.start(), index + m.end());
+
+        if regexes.raw_tag.is_match(m.as_str()) {
+            // Everything up until the end tag is literal text.
+            let end = match regexes.end_raw_tag.find(&input[high_index..]) {
+                Some(end) => end,
+                None => {
+                    fragments.push(Fragment {
+                        kind: FragmentKind::UnterminatedRaw,
+                        span: Span { low_index: low_index, high_index: input.len() },
+                    });
+                    break;
+                },
+            };
+
+            fragments.push(Fragment {
+                kind: FragmentKind::Raw(Span { low_index: high_index, high_index: high_index + end.start() }),
+                span: Span { low_index: low_index, high_index: high_index + end.end() },
+            });
+            index = high_index + end.end();
+        } else {
+            fragments.push(Fragment {
+                kind: FragmentKind::Code,
+                span: Span { low_index: low_index, high_index: high_index },
+            });
+            index = high_index;
+        }
+    }
+
+    let spans: Vec<_> = fragments.iter().map(|f| &f.span).collect();
+    verify_no_overlapping_spans(&spans);
+
+    fragments
+}
+
+fn verify_no_overlapping_spans(_spans: &[&Span]) {
     // FIXME: verify that no code spans overlap.
 }
 
@@ -117,6 +1173,49 @@ fn fill_in_text_fragments(input: &str, code_fragments: Vec<Fragment>) -> Vec<Fra
     fragments
 }
 
+/// Removes whitespace around block tags, as set by the `trim_blocks`
+/// and `lstrip_blocks` options.
+fn strip_block_whitespace(input: &str, fragments: &mut [Fragment], config: &Config) {
+    let is_block = |fragment: &Fragment| match fragment.kind {
+        FragmentKind::Code => !input[fragment.span.low_index + OPEN_DELIMITER.len()..].starts_with('='),
+        FragmentKind::Raw(..) => true,
+        FragmentKind::Text | FragmentKind::UnterminatedRaw => false,
+    };
+
+    for i in 0..fragments.len() {
+        if let FragmentKind::Text = fragments[i].kind {
+            if config.trim_blocks && i > 0 && is_block(&fragments[i - 1]) {
+                let text = &input[fragments[i].span.low_index..fragments[i].span.high_index];
+                if text.starts_with("\r\n") {
+                    fragments[i].span.low_index += 2;
+                } else if text.starts_with('\n') {
+                    fragments[i].span.low_index += 1;
+                }
+            }
+
+            if config.lstrip_blocks && fragments.get(i + 1).map_or(false, |f| is_block(f)) {
+                let text = &input[fragments[i].span.low_index..fragments[i].span.high_index];
+                let stripped = text.trim_end_matches(|c| c == ' ' || c == '\t');
+                let before = &input[..fragments[i].span.low_index];
+                let line_start = stripped.ends_with('\n') ||
+                    (stripped.is_empty() && (before.is_empty() || before.ends_with('\n')));
+
+                if line_start {
+                    fragments[i].span.high_index = fragments[i].span.low_index + stripped.len();
+                }
+            }
+        }
+    }
+}
+
+/// Turns raw blocks into text fragments holding their contents.
+fn convert_raw_fragments(fragments: Vec<Fragment>) -> Vec<Fragment> {
+    fragments.into_iter().map(|frag| match frag.kind {
+        FragmentKind::Raw(contents) => Fragment { kind: FragmentKind::Text, span: contents },
+        kind => Fragment { kind: kind, span: frag.span },
+    }).collect()
+}
+
 fn remove_empty_fragments(fragments: Vec<Fragment>) -> Vec<Fragment> {
     fragments.into_iter().filter(|frag| frag.span.low_index != frag.span.high_index).collect()
 }
@@ -125,9 +1224,24 @@ fn remove_empty_fragments(fragments: Vec<Fragment>) -> Vec<Fragment> {
 fn trim_delimiters_from_code_frags(fragments: &mut Vec<Fragment>) {
     for frag in fragments.iter_mut() {
         if let FragmentKind::Code = frag.kind {
-            // Trim the '<%' and '%>'.
-            frag.span.low_index += 2;
-            frag.span.high_index -= 2;
+            frag.span.low_index += OPEN_DELIMITER.len();
+            frag.span.high_index -= CLOSE_DELIMITER.len();
+        }
+    }
+}
+
+impl Default for Config
+{
+    fn default() -> Self {
+        Config {
+            line_statements: false,
+            normalize_line_endings: false,
+            trim_blocks: false,
+            lstrip_blocks: false,
+            max_size: None,
+            max_fragments: None,
+            max_depth: None,
+            tags: tag::Tags::new(),
         }
     }
 }
@@ -139,43 +1253,560 @@ mod test {
 
     #[test]
     fn parses_empty_string() {
-        assert_eq!(parse("").unwrap(), vec![].into());
+        assert_eq!(parse_str("").unwrap(), vec![].into());
     }
 
     #[test]
     fn parses_standalone_new_lines() {
-        assert_eq!(parse("\n\n\n").unwrap(), vec![
-            Item { kind: ItemKind::Text("\n\n\n".to_owned()) },
+        assert_eq!(parse_str("\n\n\n").unwrap(), vec![
+            Item::new(ItemKind::Text("\n\n\n".to_owned())),
         ].into());
     }
 
     #[test]
     fn parses_standalone_text() {
-        assert_eq!(parse("hello world").unwrap(), vec![
-            Item { kind: ItemKind::Text("hello world".to_owned()) },
+        assert_eq!(parse_str("hello world").unwrap(), vec![
+            Item::new(ItemKind::Text("hello world".to_owned())),
         ].into());
     }
 
     #[test]
     fn parses_standalone_code() {
-        assert_eq!(parse("<% hello %>").unwrap(), vec![
-            Item { kind: ItemKind::Code { source: " hello ".to_owned(), print_result: false } },
+        assert_eq!(parse_str("<% hello %>").unwrap(), vec![
+            Item::new(ItemKind::Code(" hello ".to_owned())),
         ].into());
     }
 
     #[test]
     fn parses_two_adjacent_code() {
-        assert_eq!(parse("<% hello %><% world %>").unwrap(), vec![
-            Item { kind: ItemKind::Code { source: " hello ".to_owned(), print_result: false } },
-            Item { kind: ItemKind::Code { source: " world ".to_owned(), print_result: false } },
+        assert_eq!(parse_str("<% hello %><% world %>").unwrap(), vec![
+            Item::new(ItemKind::Code(" hello ".to_owned())),
+            Item::new(ItemKind::Code(" world ".to_owned())),
+        ].into());
+    }
+
+    #[test]
+    fn parses_default_directives() {
+        assert_eq!(parse_str("<% default port = 8080 %>").unwrap(), vec![
+            Item::new(ItemKind::Default { name: "port".to_owned(), value: "8080".to_owned() }),
+        ].into());
+    }
+
+    #[test]
+    fn parses_set_tags() {
+        assert_eq!(parse_str("<% set total = price * qty %><% set == other %>").unwrap(), vec![
+            Item::new(ItemKind::Set { name: "total".to_owned(), value: "price * qty".to_owned() }),
+            Item::new(ItemKind::Code(" set == other ".to_owned())),
+        ].into());
+    }
+
+    #[test]
+    fn parses_raw_blocks() {
+        assert_eq!(parse_str("a<% raw %><%= x %><% endraw %>b").unwrap(), vec![
+            Item::new(ItemKind::Text("a".to_owned())),
+            Item::new(ItemKind::Text("<%= x %>".to_owned())),
+            Item::new(ItemKind::Text("b".to_owned())),
+        ].into());
+    }
+
+    #[test]
+    fn errors_on_unterminated_raw_blocks() {
+        assert!(parse_str("<% raw %><% x %>").is_err());
+    }
+
+    #[test]
+    fn parses_line_statements() {
+        let config = Config { line_statements: true, ..Config::default() };
+
+        assert_eq!(parse_str_with_config("% if x {\n  a\n%% b\n% }\n", &config).unwrap(), vec![
+            Item::new(ItemKind::Code(" if x { ".to_owned())),
+            Item::new(ItemKind::Text("  a\n% b\n".to_owned())),
+            Item::new(ItemKind::Code(" } ".to_owned())),
+        ].into());
+    }
+
+    #[test]
+    fn ignores_line_statements_by_default() {
+        assert_eq!(parse_str("% x\n").unwrap(), vec![
+            Item::new(ItemKind::Text("% x\n".to_owned())),
+        ].into());
+    }
+
+    #[test]
+    fn parses_macros() {
+        assert_eq!(parse_str("<% macro link(label, url: &str) %><%= label %><% end %>").unwrap(), vec![
+            Item::new(ItemKind::Macro {
+                name: "link".to_owned(),
+                parameters: vec!["label".to_owned(), "url: &str".to_owned()],
+                body: vec![
+                    Item::new(ItemKind::Print(" label ".to_owned())),
+                ],
+            }),
+        ].into());
+    }
+
+    #[test]
+    fn parses_captures() {
+        assert_eq!(parse_str("<% capture sidebar %>links<% end %>").unwrap(), vec![
+            Item::new(ItemKind::Capture {
+                name: "sidebar".to_owned(),
+                body: vec![Item::new(ItemKind::Text("links".to_owned()))],
+            }),
+        ].into());
+    }
+
+    #[test]
+    fn parses_comments() {
+        assert_eq!(parse_str("<%# note %>").unwrap(), vec![
+            Item::new(ItemKind::Comment(" note ".to_owned())),
+        ].into());
+    }
+
+    #[test]
+    fn parses_if_blocks() {
+        assert_eq!(parse_str("<% if x > 1 %>a<% else %>b<% end %>").unwrap(), vec![
+            Item::new(ItemKind::If {
+                condition: "x > 1".to_owned(),
+                body: vec![Item::new(ItemKind::Text("a".to_owned()))],
+                else_body: vec![Item::new(ItemKind::Text("b".to_owned()))],
+            }),
+        ].into());
+    }
+
+    #[test]
+    fn leaves_rust_if_statements_as_code() {
+        assert_eq!(parse_str("<% if x { %>").unwrap(), vec![
+            Item::new(ItemKind::Code(" if x { ".to_owned())),
+        ].into());
+    }
+
+    #[test]
+    fn errors_on_unexpected_elses() {
+        assert!(parse_str("<% else %>").is_err());
+        assert!(parse_str("<% for x in y %><% else %><% end %>").is_err());
+        assert!(parse_str("<% if x %><% else %><% else %><% end %>").is_err());
+    }
+
+    #[test]
+    fn parses_for_blocks() {
+        assert_eq!(parse_str("<% for (i, x) in xs.iter().enumerate() %>a<% end %>").unwrap(), vec![
+            Item::new(ItemKind::For {
+                pattern: "(i, x)".to_owned(),
+                iterator: "xs.iter().enumerate()".to_owned(),
+                body: vec![Item::new(ItemKind::Text("a".to_owned()))],
+            }),
+        ].into());
+    }
+
+    #[test]
+    fn parses_front_matter() {
+        let ast = parse_str("---\ntitle: Home\n---\n<%= title %>").unwrap();
+
+        assert_eq!(ast.front_matter(), &[("title".to_owned(), ::front_matter::Value::String("Home".to_owned()))]);
+        assert_eq!(ast.items[1].kind, ItemKind::Print(" title ".to_owned()));
+        assert_eq!(ast.items[1].span, 20..32);
+        assert_eq!(ast.to_string(), "---\ntitle: Home\n---\n<%= title %>");
+
+        assert_eq!(parse_str("---\ntitle: [a]\n---\n").unwrap_err().code(), "E0010");
+    }
+
+    #[test]
+    fn parses_file_blocks() {
+        assert_eq!(parse_str("<% file format!(\"{}.rs\", name) %>a<% end %><% file = open() %>").unwrap(), vec![
+            Item::new(ItemKind::File {
+                path: "format!(\"{}.rs\", name)".to_owned(),
+                body: vec![Item::new(ItemKind::Text("a".to_owned()))],
+            }),
+            Item::new(ItemKind::Code(" file = open() ".to_owned())),
+        ].into());
+    }
+
+    #[test]
+    fn parses_cache_blocks() {
+        assert_eq!(parse_str("<% cache format!(\"user-{}\", id) %>a<% end %><% cache += 1; %><% cache = 0; %>").unwrap(), vec![
+            Item::new(ItemKind::Cache {
+                key: "format!(\"user-{}\", id)".to_owned(),
+                body: vec![Item::new(ItemKind::Text("a".to_owned()))],
+            }),
+            Item::new(ItemKind::Code(" cache += 1; ".to_owned())),
+            Item::new(ItemKind::Code(" cache = 0; ".to_owned())),
+        ].into());
+    }
+
+    #[test]
+    fn parses_named_blocks() {
+        let source = "<% block summary limit=2kB truncate=ellipsis %>a<% end %><% block footer %><% end %>";
+        let ast = parse_str(source).unwrap();
+        assert_eq!(ast, vec![
+            Item::new(ItemKind::Block {
+                name: "summary".to_owned(),
+                limit: Some(2000),
+                truncation: ast::Truncation::Ellipsis,
+                body: vec![Item::new(ItemKind::Text("a".to_owned()))],
+            }),
+            Item::new(ItemKind::Block {
+                name: "footer".to_owned(),
+                limit: None,
+                truncation: ast::Truncation::Cut,
+                body: Vec::new(),
+            }),
+        ].into());
+        assert_eq!(parse_str(&ast.to_source()).unwrap(), ast);
+
+        assert!(parse_str("<% block summary limit=2kb %><% end %>").is_err());
+        assert!(parse_str("<% block summary truncate=never %><% end %>").is_err());
+    }
+
+    #[test]
+    fn parses_includes() {
+        assert_eq!(parse_str("<% include \"header.trs\" %><% include \"theme/footer\" or \"footer\" %>").unwrap(), vec![
+            Item::new(ItemKind::Include { name: "header.trs".to_owned(), fallbacks: Vec::new() }),
+            Item::new(ItemKind::Include { name: "theme/footer".to_owned(), fallbacks: vec!["footer".to_owned()] }),
+        ].into());
+    }
+
+    #[test]
+    fn parses_with_blocks() {
+        let source = "<% with user = order.customer %>a<% end %><% include \"card\" with product %>";
+        let ast = parse_str(source).unwrap();
+        assert_eq!(ast, vec![
+            Item::new(ItemKind::With {
+                name: "user".to_owned(),
+                value: "order.customer".to_owned(),
+                body: vec![Item::new(ItemKind::Text("a".to_owned()))],
+            }),
+            Item::new(ItemKind::With {
+                name: "product".to_owned(),
+                value: "product".to_owned(),
+                body: vec![Item::new(ItemKind::Include { name: "card".to_owned(), fallbacks: Vec::new() })],
+            }),
+        ].into());
+        assert_eq!(ast.to_source(), source);
+    }
+
+    #[test]
+    fn parses_assertions() {
+        assert_eq!(parse_str("<% assert f(a, \"b\") > 0, \"too \\\"small\\\"\" %><% assert x %>").unwrap(), vec![
+            Item::new(ItemKind::Assert {
+                condition: "f(a, \"b\") > 0".to_owned(),
+                message: Some("too \\\"small\\\"".to_owned()),
+            }),
+            Item::new(ItemKind::Assert { condition: "x".to_owned(), message: None }),
+        ].into());
+    }
+
+    #[test]
+    fn parses_directives() {
+        assert_eq!(parse_str("<%# @tempo: requires = \">=0.1\" %>").unwrap(), vec![
+            Item::new(ItemKind::Directive { name: "requires".to_owned(), value: ">=0.1".to_owned() }),
         ].into());
     }
 
+    #[test]
+    fn checks_version_requirements() {
+        assert!(parse_str("<%# @tempo: requires = \">=0.1\" %>").is_ok());
+        assert!(parse_str("<%# @tempo: requires = \">=99.0\" %>").is_err());
+        assert!(parse_str("<%# @tempo: unknown = \"x\" %>").is_err());
+    }
+
+    #[test]
+    fn errors_on_unterminated_blocks() {
+        assert!(parse_str("<% macro a() %>").is_err());
+    }
+
+    #[test]
+    fn errors_on_unexpected_ends() {
+        assert!(parse_str("<% end %>").is_err());
+    }
+
+    #[test]
+    fn strips_byte_order_marks() {
+        assert_eq!(parse_str("\u{feff}hello").unwrap(), vec![
+            Item::new(ItemKind::Text("hello".to_owned())),
+        ].into());
+    }
+
+    #[test]
+    fn normalizes_line_endings_if_asked() {
+        assert_eq!(parse_str("a\r\nb").unwrap(), vec![
+            Item::new(ItemKind::Text("a\r\nb".to_owned())),
+        ].into());
+
+        let config = Config { normalize_line_endings: true, ..Config::default() };
+        assert_eq!(parse_str_with_config("a\r\nb", &config).unwrap(), vec![
+            Item::new(ItemKind::Text("a\nb".to_owned())),
+        ].into());
+    }
+
+    #[test]
+    fn keeps_source_spans_when_normalizing_line_endings() {
+        let config = Config { normalize_line_endings: true, ..Config::default() };
+        let ast = parse_str_with_config("a\r\nb\r\n<%= x %>\r\n<% if y %>\r\nc<% end %>", &config).unwrap();
+
+        let spans: Vec<_> = ast.items.iter().map(|item| item.span.clone()).collect();
+        assert_eq!(spans, vec![0..6, 6..14, 14..16, 16..38]);
+        assert_eq!(ast.items[3].kind.children()[0].span, 26..29);
+    }
+
+    #[test]
+    fn detects_line_endings() {
+        assert_eq!(detect_line_ending("a"), None);
+        assert_eq!(detect_line_ending("a\nb\n"), Some(LineEnding::Lf));
+        assert_eq!(detect_line_ending("a\r\nb\r\n"), Some(LineEnding::CrLf));
+        assert_eq!(detect_line_ending("a\r\nb\n"), Some(LineEnding::Mixed));
+    }
+
+    #[test]
+    fn enforces_size_limits() {
+        let config = Config { max_size: Some(4), ..Config::default() };
+        assert!(parse_str_with_config("abcd", &config).is_ok());
+        assert!(parse_str_with_config("abcde", &config).is_err());
+    }
+
+    #[test]
+    fn enforces_fragment_limits() {
+        let config = Config { max_fragments: Some(2), ..Config::default() };
+        assert!(parse_str_with_config("a<% b %>", &config).is_ok());
+        assert!(parse_str_with_config("a<% b %>c", &config).is_err());
+    }
+
+    #[test]
+    fn enforces_depth_limits() {
+        let config = Config { max_depth: Some(2), ..Config::default() };
+        assert!(parse_str_with_config("<% if a %><% for x in xs %><% end %><% end %>", &config).is_ok());
+
+        let source = "<% if a %>\n <% if b %><% with c = 1 %>\n<% if d %>e<% end %><% end %><% end %>f<% end %>";
+        let error = parse_str_with_config(source, &config).unwrap_err();
+        assert_eq!(error.to_string(), "'<% with c = 1 %>' on line 2, column 12 opens more than 2 nested blocks");
+
+        let (ast, diagnostics) = parse_with_recovery(source, &config);
+        assert_eq!(ast.to_source(), source);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].span, 22..38);
+        let inner = ast.items[0].kind.children()[1];
+        assert_eq!(inner.kind.children()[0].kind, ItemKind::Error("<% with c = 1 %>".to_owned()));
+    }
+
+    #[test]
+    fn locates_unterminated_blocks() {
+        let error = parse_str("---\ntitle: a\n---\n<% for x in xs %>\n  <% if x %>a<% end %>").unwrap_err();
+        assert_eq!(error.to_string(), "unterminated 'for' block opened on line 4, column 1");
+
+        let error = parse_str("a\r\n <% raw %>b").unwrap_err();
+        assert_eq!(error.to_string(), "unterminated 'raw' block opened on line 2, column 2");
+    }
+
+    #[test]
+    fn round_trips_through_source() {
+        let source = "<%# @tempo: requires = \">=0.1\" %><% default n = 2 %>\
+                      <% macro m(a, b: u32) %><%= a %><% end %>\
+                      <% capture c %><% raw %><%= x %><% endraw %><% end %>\
+                      <% if n > 1 %>a<% else %><%# note %><% end %>\
+                      <% for i in 0..n %><% let x = i; %><% end %>\
+                      <% include \"other\" %><% assert n > 0, \"n is \\\"0\\\"\" %><% assert true %>";
+        let ast = parse_str(source).unwrap();
+
+        assert_eq!(parse_str(&ast.to_source()).unwrap(), ast);
+    }
+
+    #[test]
+    fn records_the_spans_of_items() {
+        let ast = parse_str("\u{feff}a<%= b %><% if c %>d<% end %>").unwrap();
+        let spans: Vec<_> = ast.items.iter().map(|item| item.span.clone()).collect();
+
+        assert_eq!(spans, vec![3..4, 4..12, 12..32]);
+        assert_eq!(ast.items[2].kind.children()[0].span, 22..23);
+    }
+
     #[test]
     fn parses_trailing_text() {
-        assert_eq!(parse("<% hello %>\n world").unwrap(), vec![
-            Item { kind: ItemKind::Code { source: " hello ".to_owned(), print_result: false } },
-            Item { kind: ItemKind::Text("\n world".to_owned()) },
+        assert_eq!(parse_str("<% hello %>\n world").unwrap(), vec![
+            Item::new(ItemKind::Code(" hello ".to_owned())),
+            Item::new(ItemKind::Text("\n world".to_owned())),
+        ].into());
+    }
+
+    #[test]
+    fn recovers_from_malformed_tags() {
+        let (ast, diagnostics) = parse_with_recovery("a<% end %>b<% if x %>c<% else %><% else %>d<% end %>",
+                                                     &Config::default());
+
+        assert_eq!(ast, vec![
+            Item::new(ItemKind::Text("a".to_owned())),
+            Item::new(ItemKind::Error("<% end %>".to_owned())),
+            Item::new(ItemKind::Text("b".to_owned())),
+            Item::new(ItemKind::If {
+                condition: "x".to_owned(),
+                body: vec![Item::new(ItemKind::Text("c".to_owned()))],
+                else_body: vec![
+                    Item::new(ItemKind::Error("<% else %>".to_owned())),
+                    Item::new(ItemKind::Text("d".to_owned())),
+                ],
+            }),
+        ].into());
+
+        let errors: Vec<_> = diagnostics.iter().map(|d| (d.error.code(), d.span.clone())).collect();
+        assert_eq!(errors, vec![("E0002", 1..10), ("E0006", 32..42)]);
+    }
+
+    #[test]
+    fn recovers_from_unterminated_blocks() {
+        let source = "<% for x in xs %>a<%# @tempo: foo = \"bar\" %><% raw %>b";
+        let (ast, diagnostics) = parse_with_recovery(source, &Config::default());
+
+        assert_eq!(ast.to_source(), source);
+        assert_eq!(ast.items[0], Item::new(ItemKind::Error("<% for x in xs %>".to_owned())));
+
+        let errors: Vec<_> = diagnostics.iter().map(|d| (d.error.code(), d.span.clone())).collect();
+        assert_eq!(errors, vec![("E0001", 44..54), ("E0001", 0..17), ("E0003", 18..44)]);
+    }
+
+    /// Checks that reparsing after an edit gives the same items and
+    /// spans as parsing the edited source.
+    fn assert_reparses(source: &str, range: Range<usize>, replacement: &str) {
+        assert_reparses_with_config(source, range, replacement, &Config::default());
+    }
+
+    fn assert_reparses_with_config(source: &str, range: Range<usize>, replacement: &str, config: &Config) {
+        fn spans(items: &[Item]) -> Vec<Range<usize>> {
+            items.iter().flat_map(|item| {
+                let children: Vec<_> = item.kind.children().into_iter().cloned().collect();
+                Some(item.span.clone()).into_iter().chain(spans(&children))
+            }).collect()
+        }
+
+        let mut edited = source.to_owned();
+        edited.drain(range.clone());
+        edited.insert_str(range.start, replacement);
+
+        let edit = Edit { range: range, replacement: replacement.to_owned() };
+        let reparsed = reparse(parse_str_with_config(source, config).unwrap(), &edited, &edit, config);
+        let parsed = parse_str_with_config(&edited, config);
+
+        match (reparsed, parsed) {
+            (Ok(reparsed), Ok(parsed)) => {
+                assert_eq!(reparsed, parsed);
+                assert_eq!(spans(&reparsed.items), spans(&parsed.items));
+            },
+            (Err(..), Err(..)) => (),
+            (reparsed, parsed) => panic!("reparsed as {:?} but parsed as {:?}", reparsed, parsed),
+        }
+    }
+
+    #[test]
+    fn reparses_edited_lines() {
+        let source = "<% if a %>\n  <%= b %>\n<% end %>\nc <%= d %> e\n<% for f in g %>h<% end %>\n";
+
+        assert_reparses(source, 27..28, "dd");
+        assert_reparses(source, 24..24, "x<%= y %>z\n");
+        assert_reparses(source, 14..15, "");
+        assert_reparses(source, 0..0, "top\n");
+        assert_reparses(source, source.len()..source.len(), "<% end %>");
+    }
+
+    #[test]
+    fn reparses_edits_which_change_later_lines() {
+        let source = "a\n<%= b %>\nc <% endraw %> <%= d %>\n";
+
+        assert_reparses(source, 0..1, "<% raw %>");
+        assert_reparses(source, 2..4, "");
+        assert_reparses("a<%= b %>c<%= d %>", 2..2, "<%= e %><%");
+    }
+
+    #[test]
+    fn reparses_edits_to_front_matter() {
+        let source = "---\na: 1\n---\n<%= a %>\n";
+
+        assert_reparses(source, 8..8, "2");
+        assert_reparses(source, 14..14, "b\n");
+        assert_reparses("--\na: 1\n---\nb\n", 0..0, "-");
+        assert_reparses("---\na: 1\n--\nb\n", 12..12, "-");
+        assert_reparses("a\n<%= b %>---\nc: 1\n---\n", 17..18, "2");
+    }
+
+    #[test]
+    fn reparses_with_the_same_config() {
+        let config = Config { max_depth: Some(1), ..Config::default() };
+
+        assert_reparses_with_config("a\n<% if b %>c<% end %>\n", 12..13, "<% if d %>e<% end %>", &config);
+        assert_reparses_with_config("a\n<% if b %>c<% end %>\n", 12..13, "cc", &config);
+    }
+
+    #[test]
+    fn splits_templates_into_tokens() {
+        fn kinds(source: &str) -> Vec<(TokenKind, &str)> {
+            tokens(source).into_iter().map(|t| (t.kind, &source[t.span])).collect()
+        }
+
+        assert_eq!(kinds("a<%= b %><%#c%><%%><% raw %><%x<% endraw %>"), vec![
+            (TokenKind::Text, "a"),
+            (TokenKind::OpenTag, "<%="), (TokenKind::CodeBody, " b "), (TokenKind::CloseTag, "%>"),
+            (TokenKind::OpenTag, "<%#"), (TokenKind::CodeBody, "c"), (TokenKind::CloseTag, "%>"),
+            (TokenKind::OpenTag, "<%"), (TokenKind::CloseTag, "%>"),
+            (TokenKind::OpenTag, "<%"), (TokenKind::CodeBody, " raw "), (TokenKind::CloseTag, "%>"),
+            (TokenKind::Text, "<%x"),
+            (TokenKind::OpenTag, "<%"), (TokenKind::CodeBody, " endraw "), (TokenKind::CloseTag, "%>"),
+        ]);
+        assert_eq!(kinds("\u{feff}<% raw %>b"), vec![
+            (TokenKind::OpenTag, "<%"), (TokenKind::CodeBody, " raw "), (TokenKind::CloseTag, "%>"),
+            (TokenKind::Text, "b"),
+        ]);
+    }
+
+    #[test]
+    fn records_spans_around_non_ascii_text() {
+        let source = "😀<%= 名前 %>é<% if x %>日本<% end %>";
+        let ast = parse_str(source).unwrap();
+
+        let spans: Vec<_> = ast.items.iter().map(|item| &source[item.span.clone()]).collect();
+        assert_eq!(spans, vec!["😀", "<%= 名前 %>", "é", "<% if x %>日本<% end %>"]);
+        assert_eq!(&source[ast.items[3].kind.children()[0].span.clone()], "日本");
+    }
+
+    #[test]
+    fn parses_bytes_which_are_not_utf8() {
+        let input = b"caf\xe9 <%= x %>";
+
+        assert_eq!(parse_bytes(input, &Config::default(), encoding::Fallback::Error).unwrap_err().code(), "E0009");
+
+        let (ast, encoding) = parse_bytes(input, &Config::default(), encoding::Fallback::Lossy).unwrap();
+        assert_eq!(ast, vec![
+            Item::new(ItemKind::Text("caf\u{fffd} ".to_owned())),
+            Item::new(ItemKind::Print(" x ".to_owned())),
+        ].into());
+        assert_eq!(encoding, encoding::Encoding::Lossy(1));
+    }
+
+    #[test]
+    fn strips_whitespace_around_blocks_if_asked() {
+        let source = "<ul>\n  <% for x in xs %>\n  <li><%= x %></li>\n  <% end %>\n</ul>\n";
+        let config = Config { trim_blocks: true, lstrip_blocks: true, ..Config::default() };
+        let ast = parse_str_with_config(source, &config).unwrap();
+
+        assert_eq!(ast, vec![
+            Item::new(ItemKind::Text("<ul>\n".to_owned())),
+            Item::new(ItemKind::For {
+                pattern: "x".to_owned(),
+                iterator: "xs".to_owned(),
+                body: vec![
+                    Item::new(ItemKind::Text("  <li>".to_owned())),
+                    Item::new(ItemKind::Print(" x ".to_owned())),
+                    Item::new(ItemKind::Text("</li>\n".to_owned())),
+                ],
+            }),
+            Item::new(ItemKind::Text("</ul>\n".to_owned())),
+        ].into());
+        assert_eq!(&source[ast.items[2].span.clone()], "</ul>\n");
+    }
+
+    #[test]
+    fn only_strips_whitespace_at_the_start_of_lines() {
+        let config = Config { trim_blocks: false, lstrip_blocks: true, ..Config::default() };
+
+        assert_eq!(parse_str_with_config("a  <% b %>\n", &config).unwrap(), vec![
+            Item::new(ItemKind::Text("a  ".to_owned())),
+            Item::new(ItemKind::Code(" b ".to_owned())),
+            Item::new(ItemKind::Text("\n".to_owned())),
         ].into());
     }
 }
@@ -0,0 +1,281 @@
+//! Stages run on the output of templates after they are rendered,
+//! such as converting HTML to PDF.
+//!
+//! Post-processors, such as minifiers, can also be applied to output
+//! as it is rendered, by rendering into a `Processed` writer.
+
+use {Error, ErrorKind};
+
+use std::io;
+use std::io::prelude::*;
+use std::process;
+use std::thread;
+
+/// A transformation of rendered output.
+pub trait Stage
+{
+    /// Transforms the output of the previous stage.
+    fn run(&self, input: Vec<u8>) -> Result<Vec<u8>, Error>;
+}
+
+/// A transformation applied to each chunk of rendered output as it
+/// is written, so that the whole output is never held in memory.
+///
+/// A chunk may end part of the way through anything the processor looks
+/// for, so state carried between chunks belongs in the processor.
+pub trait PostProcessor
+{
+    /// Processes a chunk of output, writing the result.
+    fn process(&mut self, chunk: &[u8], output: &mut dyn Write) -> io::Result<()>;
+
+    /// Writes anything held back once all of the output is processed.
+    fn finish(&mut self, _output: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A writer which passes everything written to it through a
+/// post-processor before writing it on.
+///
+/// `finish` must be called once rendering is done.
+#[derive(Debug)]
+pub struct Processed<W: Write, P: PostProcessor>
+{
+    writer: W,
+    processor: P,
+}
+
+/// Collapses each run of whitespace into a single character.
+///
+/// A run becomes a newline if it holds one, and a space otherwise,
+/// so text keeps its line breaks. This suits HTML without `<pre>`
+/// elements, and plain text.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CollapseWhitespace
+{
+    /// The character the current run of whitespace collapses to.
+    pending: Option<u8>,
+}
+
+/// Removes all whitespace from JSON outside of strings.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MinifyJson
+{
+    in_string: bool,
+    escaped: bool,
+}
+
+impl<W: Write, P: PostProcessor> Processed<W, P>
+{
+    /// Creates a writer which processes output before writing it to `writer`.
+    pub fn new(writer: W, processor: P) -> Self {
+        Processed { writer: writer, processor: processor }
+    }
+
+    /// Writes anything the processor held back, and gives back the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.processor.finish(&mut self.writer)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write, P: PostProcessor> Write for Processed<W, P>
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.processor.process(buf, &mut self.writer)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl PostProcessor for CollapseWhitespace
+{
+    fn process(&mut self, chunk: &[u8], output: &mut dyn Write) -> io::Result<()> {
+        let mut processed = Vec::with_capacity(chunk.len());
+
+        for &byte in chunk {
+            if (byte as char).is_ascii_whitespace() {
+                self.pending = match (self.pending, byte) {
+                    (Some(b'\n'), _) | (_, b'\n') => Some(b'\n'),
+                    _ => Some(b' '),
+                };
+            } else {
+                processed.extend(self.pending.take());
+                processed.push(byte);
+            }
+        }
+
+        output.write_all(&processed)
+    }
+
+    fn finish(&mut self, output: &mut dyn Write) -> io::Result<()> {
+        match self.pending.take() {
+            Some(byte) => output.write_all(&[byte]),
+            None => Ok(()),
+        }
+    }
+}
+
+impl PostProcessor for MinifyJson
+{
+    fn process(&mut self, chunk: &[u8], output: &mut dyn Write) -> io::Result<()> {
+        let mut processed = Vec::with_capacity(chunk.len());
+
+        for &byte in chunk {
+            if self.in_string {
+                match (self.escaped, byte) {
+                    (true, _) => self.escaped = false,
+                    (false, b'\\') => self.escaped = true,
+                    (false, b'"') => self.in_string = false,
+                    _ => (),
+                }
+            } else if (byte as char).is_ascii_whitespace() {
+                continue;
+            } else if byte == b'"' {
+                self.in_string = true;
+            }
+
+            processed.push(byte);
+        }
+
+        output.write_all(&processed)
+    }
+}
+
+impl Stage for CollapseWhitespace
+{
+    fn run(&self, input: Vec<u8>) -> Result<Vec<u8>, Error> {
+        process_all(self.clone(), &input)
+    }
+}
+
+impl Stage for MinifyJson
+{
+    fn run(&self, input: Vec<u8>) -> Result<Vec<u8>, Error> {
+        process_all(self.clone(), &input)
+    }
+}
+
+/// A stage which pipes the output through an external command, such
+/// as `wkhtmltopdf - -` or `weasyprint - -`.
+///
+/// The command reads the output on its standard input and writes the
+/// result to its standard output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Command
+{
+    pub program: String,
+    pub arguments: Vec<String>,
+}
+
+impl Command
+{
+    /// Creates a stage from a command line, split on whitespace.
+    pub fn parse(command_line: &str) -> Option<Self> {
+        let mut words = command_line.split_whitespace().map(|w| w.to_owned());
+
+        words.next().map(|program| Command {
+            program: program,
+            arguments: words.collect(),
+        })
+    }
+
+    fn command_line(&self) -> String {
+        ::std::iter::once(&self.program).chain(self.arguments.iter())
+            .map(|w| &w[..]).collect::<Vec<_>>().join(" ")
+    }
+}
+
+impl Stage for Command
+{
+    fn run(&self, input: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut child = process::Command::new(&self.program)
+            .args(&self.arguments)
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::inherit())
+            .spawn()?;
+
+        // The input is written from another thread, as the command may
+        // not read all of it before its output fills the pipe.
+        let mut stdin = child.stdin.take().unwrap();
+        let writer = thread::spawn(move || stdin.write_all(&input));
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(ErrorKind::StageFailed(self.command_line()).into());
+        }
+
+        // Commands which succeed without reading all of their input,
+        // such as `head`, close the pipe early.
+        match writer.join().unwrap() {
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(output.stdout),
+            Err(e) => Err(e.into()),
+            Ok(()) => Ok(output.stdout),
+        }
+    }
+}
+
+/// Runs output through each stage in turn.
+pub fn run(stages: &[Box<dyn Stage>], output: Vec<u8>) -> Result<Vec<u8>, Error> {
+    stages.iter().try_fold(output, |output, stage| stage.run(output))
+}
+
+/// Runs all of the output through a post-processor at once.
+fn process_all<P: PostProcessor>(processor: P, input: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut processed = Processed::new(Vec::with_capacity(input.len()), processor);
+    processed.write_all(input)?;
+    Ok(processed.finish()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pipes_output_through_commands() {
+        let stages: Vec<Box<dyn Stage>> = vec![
+            Box::new(Command::parse("tr a-z A-Z").unwrap()),
+            Box::new(Command::parse("rev").unwrap()),
+        ];
+        assert_eq!(run(&stages, b"hello".to_vec()).unwrap(), b"OLLEH");
+    }
+
+    #[test]
+    fn errors_when_commands_fail() {
+        let stages: Vec<Box<dyn Stage>> = vec![Box::new(Command::parse("false").unwrap())];
+        assert_eq!(run(&stages, Vec::new()).unwrap_err().code(), "E0111");
+        assert_eq!(Command::parse("  "), None);
+    }
+
+    #[test]
+    fn runs_commands_which_do_not_read_all_of_their_input() {
+        // Larger than the buffer of a pipe.
+        let input = vec![b'a'; 1 << 20];
+
+        let stages: Vec<Box<dyn Stage>> = vec![Box::new(Command::parse("head -c 1").unwrap())];
+        assert_eq!(run(&stages, input.clone()).unwrap(), b"a");
+
+        let stages: Vec<Box<dyn Stage>> = vec![Box::new(Command::parse("false").unwrap())];
+        assert_eq!(run(&stages, input).unwrap_err().code(), "E0111");
+    }
+
+    #[test]
+    fn collapses_whitespace() {
+        let stages: Vec<Box<dyn Stage>> = vec![Box::new(CollapseWhitespace::default())];
+        assert_eq!(run(&stages, b"<p>\n    a  b\t</p>  \n\n".to_vec()).unwrap(), b"<p>\na b </p>\n");
+    }
+
+    #[test]
+    fn minifies_json_in_chunks() {
+        let mut processed = Processed::new(Vec::new(), MinifyJson::default());
+        for chunk in &["{ \"a b\": [1, ", "2], \"c\\", "\" \" : \"d\" }\n"] {
+            processed.write_all(chunk.as_bytes()).unwrap();
+        }
+        assert_eq!(processed.finish().unwrap(), b"{\"a b\":[1,2],\"c\\\" \":\"d\"}");
+    }
+}
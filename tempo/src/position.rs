@@ -0,0 +1,93 @@
+//! Conversions between the positions used in template source.
+//!
+//! Spans are byte ranges, but editors and error messages usually
+//! count characters, lines and columns. Byte indices which fall inside
+//! a multi-byte character are rounded down to the start of it, and
+//! indices past the end of the source are clamped, so none of these
+//! functions panic.
+
+use std::ops::Range;
+
+/// Rounds a byte index down to the start of the character it is in.
+pub fn char_boundary(source: &str, index: usize) -> usize {
+    let mut index = ::std::cmp::min(index, source.len());
+    while !source.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// Gets the number of characters before a byte index.
+pub fn char_index(source: &str, byte_index: usize) -> usize {
+    source[..char_boundary(source, byte_index)].chars().count()
+}
+
+/// Gets the byte index of the character at a character index.
+pub fn byte_index(source: &str, char_index: usize) -> usize {
+    source.char_indices().nth(char_index).map(|(i, _)| i).unwrap_or(source.len())
+}
+
+/// Converts a byte range into a range of characters.
+pub fn char_span(source: &str, span: Range<usize>) -> Range<usize> {
+    char_index(source, span.start)..char_index(source, span.end)
+}
+
+/// Gets the one-based line and column of a byte index.
+///
+/// Columns count characters rather than bytes.
+pub fn line_and_column(source: &str, byte_index: usize) -> (usize, usize) {
+    let before = &source[..char_boundary(source, byte_index)];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+
+    (line, column)
+}
+
+/// Gets the byte index of a one-based line and column.
+///
+/// Columns past the end of the line give the end of the line, and
+/// lines past the end of the source give the end of the source.
+pub fn byte_index_at(source: &str, line: usize, column: usize) -> usize {
+    let line_start = if line <= 1 {
+        0
+    } else {
+        match source.match_indices('\n').nth(line - 2) {
+            Some((i, _)) => i + 1,
+            None => return source.len(),
+        }
+    };
+
+    let line_text = source[line_start..].split('\n').next().unwrap();
+    line_start + byte_index(line_text, column.saturating_sub(1))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SOURCE: &'static str = "héllo 😀\n<%= 名前 %>";
+
+    #[test]
+    fn converts_between_bytes_and_characters() {
+        assert_eq!(char_index(SOURCE, 7), 6);
+        assert_eq!(byte_index(SOURCE, 6), 7);
+        assert_eq!(char_span(SOURCE, 7..11), 6..7);
+        assert_eq!(byte_index(SOURCE, 100), SOURCE.len());
+    }
+
+    #[test]
+    fn rounds_indices_inside_characters_down() {
+        assert_eq!(char_boundary(SOURCE, 9), 7);
+        assert_eq!(char_index(SOURCE, 2), 1);
+        assert_eq!(line_and_column(SOURCE, 100), (2, 10));
+    }
+
+    #[test]
+    fn converts_to_and_from_lines_and_columns() {
+        let index = SOURCE.find('名').unwrap();
+        assert_eq!(line_and_column(SOURCE, index), (2, 5));
+        assert_eq!(byte_index_at(SOURCE, 2, 5), index);
+        assert_eq!(byte_index_at(SOURCE, 1, 100), SOURCE.find('\n').unwrap());
+        assert_eq!(byte_index_at(SOURCE, 5, 1), SOURCE.len());
+    }
+}
@@ -0,0 +1,119 @@
+//! Managed regions inside generated files.
+//!
+//! A region is delimited by lines containing `tempo:begin <name>` and
+//! `tempo:end`, usually inside comments of the output language.
+//!
+//! ```text
+//! // tempo:begin imports
+//! use foo::bar;
+//! // tempo:end
+//! ```
+//!
+//! When a file is regenerated, only the contents of its regions are
+//! replaced, so hand-written code outside of them is kept.
+
+use {Error, ErrorKind};
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// The marker beginning a region.
+pub const BEGIN_MARKER: &'static str = "tempo:begin";
+/// The marker ending a region.
+pub const END_MARKER: &'static str = "tempo:end";
+
+/// A managed region in a piece of text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region
+{
+    /// The name of the region.
+    pub name: String,
+    /// The byte range of the contents, excluding the marker lines.
+    pub contents: Range<usize>,
+}
+
+/// Finds all managed regions in a piece of text.
+pub fn find_regions(text: &str) -> Result<Vec<Region>, Error> {
+    let mut regions = Vec::new();
+    let mut current: Option<(String, usize)> = None;
+    let mut line_start = 0;
+
+    for line in text.split_terminator('\n') {
+        let line_end = ::std::cmp::min(line_start + line.len() + 1, text.len());
+
+        if let Some(i) = line.find(BEGIN_MARKER) {
+            if let Some((name, _)) = current {
+                return Err(ErrorKind::UnterminatedRegion(name).into());
+            }
+
+            let name = line[i + BEGIN_MARKER.len()..].split_whitespace().next().unwrap_or("");
+            current = Some((name.to_owned(), line_end));
+        } else if line.contains(END_MARKER) {
+            match current.take() {
+                Some((name, start)) => regions.push(Region { name: name, contents: start..line_start }),
+                None => return Err(ErrorKind::UnexpectedRegionEnd.into()),
+            }
+        }
+
+        line_start = line_end;
+    }
+
+    match current {
+        Some((name, _)) => Err(ErrorKind::UnterminatedRegion(name).into()),
+        None => Ok(regions),
+    }
+}
+
+/// Replaces the contents of the regions in `existing` with the contents
+/// of the regions of the same name in `generated`.
+///
+/// Text outside of regions, and regions which were not generated,
+/// are left as they are.
+pub fn update_regions(existing: &str, generated: &str) -> Result<String, Error> {
+    let generated_regions: HashMap<_, _> = find_regions(generated)?.into_iter().map(|region| {
+        (region.name, &generated[region.contents])
+    }).collect();
+
+    let mut result = String::new();
+    let mut index = 0;
+
+    for region in find_regions(existing)? {
+        if let Some(contents) = generated_regions.get(&region.name) {
+            result.push_str(&existing[index..region.contents.start]);
+            result.push_str(contents);
+            index = region.contents.end;
+        }
+    }
+
+    result.push_str(&existing[index..]);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_regions() {
+        let text = "a\n// tempo:begin imports\nuse x;\n// tempo:end\nb\n";
+        let regions = find_regions(text).unwrap();
+
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].name, "imports");
+        assert_eq!(&text[regions[0].contents.clone()], "use x;\n");
+    }
+
+    #[test]
+    fn updates_only_region_contents() {
+        let existing = "hand written\n# tempo:begin a\nold\n# tempo:end\nmore\n";
+        let generated = "# tempo:begin a\nnew\nlines\n# tempo:end\n";
+
+        assert_eq!(update_regions(existing, generated).unwrap(),
+                   "hand written\n# tempo:begin a\nnew\nlines\n# tempo:end\nmore\n");
+    }
+
+    #[test]
+    fn rejects_unterminated_regions() {
+        assert!(find_regions("<!-- tempo:begin a -->\n").is_err());
+    }
+}
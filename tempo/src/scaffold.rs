@@ -0,0 +1,775 @@
+//! Generation of project skeletons from a directory of templates.
+//!
+//! A skeleton is a directory tree. Files ending in `.trs` are templates,
+//! and are rendered into a file of the same name without the extension.
+//! All other files are copied verbatim.
+//!
+//! The paths of files may also contain template tags, such as
+//! `src/<%= module_name %>.rs.trs`.
+//!
+//! A template may begin with a header delimited by `---` lines. The
+//! `emit_if` key gives a Rust expression over the parameters, and the
+//! file is only generated if it evaluates to `true`.
+//!
+//! The `if_exists` key sets what happens when the file already exists
+//! in the output directory, overriding the default strategy.
+//!
+//! ```text
+//! ---
+//! emit_if: use_database
+//! if_exists: skip
+//! ---
+//! ```
+//!
+//! The parameters of a skeleton are declared in a `tempo.params` file
+//! at its root, one per line.
+//!
+//! ```text
+//! # The name of the crate.
+//! project_name = my-project
+//! use_database: bool = false
+//! port: int = 8080
+//! author
+//! ```
+//!
+//! Every parameter is passed to every template's render function.
+//!
+//! Commands to run in the output directory once it has been generated,
+//! such as `cargo fmt`, may be declared one per line in a `tempo.hooks`
+//! file. They are run without a shell, and with an environment holding
+//! only `PATH` and `HOME`.
+
+use {Error, ErrorKind};
+use {analysis, ast, comment, parse, region, trans};
+
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::{env, fmt, fs, process};
+
+/// The name of the file declaring the parameters of a skeleton.
+pub const PARAMS_FILE_NAME: &'static str = "tempo.params";
+/// The name of the file declaring the post-generation hooks of a skeleton.
+pub const HOOKS_FILE_NAME: &'static str = "tempo.hooks";
+/// The environment variables passed through to hooks.
+const HOOK_ENVIRONMENT: &'static [&'static str] = &["PATH", "HOME"];
+/// The extension of template files within a skeleton.
+pub const TEMPLATE_EXTENSION: &'static str = "trs";
+/// The line delimiting the header of a template.
+const HEADER_DELIMITER: &'static str = "---";
+/// The function of a generated program which normalises a rendered path,
+/// and stops generation if the path would leave the output directory.
+const CHECK_PATH_FUNCTION: &'static str = r#"fn check_path(relative_path: &str) -> String {
+    let mut parts = Vec::new();
+    let mut inside = true;
+    for component in ::std::path::Path::new(relative_path).components() {
+        match component {
+            ::std::path::Component::Normal(part) => parts.push(part.to_string_lossy().into_owned()),
+            ::std::path::Component::CurDir => (),
+            _ => inside = false,
+        }
+    }
+    if !inside || parts.is_empty() {
+        eprintln!("error: '{}' is not a path within the output directory", relative_path);
+        ::std::process::exit(1);
+    }
+    parts.join("/")
+}
+"#;
+
+/// A parameter declared by a skeleton.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Param
+{
+    /// The name of the parameter.
+    pub name: String,
+    /// The kind of value the parameter holds.
+    pub kind: ParamKind,
+    /// The value used when none is given.
+    pub default: Option<String>,
+}
+
+/// The kind of value a parameter holds.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ParamKind
+{
+    String,
+    Bool,
+    Integer,
+}
+
+/// What to do when a generated file already exists.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Strategy
+{
+    /// Replace the existing file.
+    Overwrite,
+    /// Leave the existing file untouched.
+    Skip,
+    /// Append the generated contents to the existing file.
+    Append,
+    /// Write both versions separated by conflict markers.
+    MergeMarkers,
+    /// Replace only the managed regions of the existing file.
+    ///
+    /// See the `region` module.
+    Regions,
+}
+
+/// A file written by a generated program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GeneratedFile
+{
+    /// The path of the file, relative to the output directory.
+    pub path: PathBuf,
+    /// The strategy given in the template header, if any.
+    pub strategy: Option<Strategy>,
+}
+
+/// A directory of templates.
+#[derive(Clone, Debug)]
+pub struct Skeleton
+{
+    /// The parameters of the skeleton.
+    pub params: Vec<Param>,
+    /// The files in the skeleton.
+    pub files: Vec<SkeletonFile>,
+    /// The commands to run after generation.
+    pub hooks: Vec<Hook>,
+}
+
+/// A command run after a skeleton has been generated.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hook
+{
+    /// The program to run.
+    pub program: String,
+    /// The arguments to the program.
+    pub arguments: Vec<String>,
+}
+
+/// A file in a skeleton.
+#[derive(Clone, Debug)]
+pub struct SkeletonFile
+{
+    /// A template rendering the path of the file, relative to
+    /// the output directory.
+    pub output_path: ast::Ast,
+    /// A Rust expression which must be true for the file to be generated.
+    pub condition: Option<String>,
+    /// What to do if the file already exists.
+    pub strategy: Option<Strategy>,
+    /// The comment syntax of the file, inferred from its extension.
+    pub comment_syntax: Option<comment::Syntax>,
+    /// The contents of the file.
+    pub kind: SkeletonFileKind,
+}
+
+/// The contents of a file in a skeleton.
+#[derive(Clone, Debug)]
+pub enum SkeletonFileKind
+{
+    /// A template.
+    Template(ast::Ast),
+    /// A file which is copied verbatim from the given path.
+    Verbatim(PathBuf),
+}
+
+impl Param
+{
+    /// Validates a value for this parameter, converting it into a Rust literal.
+    pub fn literal(&self, value: &str) -> Result<String, Error> {
+        let invalid = || ErrorKind::InvalidParamValue(self.name.clone(), value.to_owned());
+
+        match self.kind {
+            ParamKind::String => Ok(format!("{:?}", value)),
+            ParamKind::Bool => match &value.trim().to_lowercase()[..] {
+                "true" | "yes" | "y" => Ok("true".to_owned()),
+                "false" | "no" | "n" => Ok("false".to_owned()),
+                _ => Err(invalid().into()),
+            },
+            ParamKind::Integer => match value.trim().parse::<i64>() {
+                Ok(i) => Ok(i.to_string()),
+                Err(..) => Err(invalid().into()),
+            },
+        }
+    }
+}
+
+impl ParamKind
+{
+    /// Gets the kind with a given name in a parameter declaration.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "string" => Some(ParamKind::String),
+            "bool" => Some(ParamKind::Bool),
+            "int" => Some(ParamKind::Integer),
+            _ => None,
+        }
+    }
+
+    /// Gets the Rust type that values of this kind are passed as.
+    pub fn rust_type(&self) -> &'static str {
+        match *self {
+            ParamKind::String => "&str",
+            ParamKind::Bool => "bool",
+            ParamKind::Integer => "i64",
+        }
+    }
+}
+
+impl Skeleton
+{
+    /// Loads a skeleton from a directory.
+    pub fn load<P>(dir: P) -> Result<Skeleton, Error>
+        where P: AsRef<Path> {
+        let dir = dir.as_ref().canonicalize()?;
+
+        let params_path = dir.join(PARAMS_FILE_NAME);
+        let params = if params_path.exists() {
+            parse_params(&read_file(&params_path)?)?
+        } else {
+            Vec::new()
+        };
+
+        let hooks_path = dir.join(HOOKS_FILE_NAME);
+        let hooks = if hooks_path.exists() {
+            parse_hooks(&read_file(&hooks_path)?)
+        } else {
+            Vec::new()
+        };
+
+        let mut paths = Vec::new();
+        collect_files(&dir, &mut paths)?;
+        paths.sort();
+
+        let mut files = Vec::new();
+        for path in paths {
+            if path == params_path || path == hooks_path {
+                continue;
+            }
+
+            let relative_path = path.strip_prefix(&dir).unwrap().to_owned();
+
+            if path.extension().map_or(false, |ext| ext == TEMPLATE_EXTENSION) {
+                let source = read_file(&path)?;
+                let (header, body) = split_header(&source);
+                let header = parse_header(header, &relative_path)?;
+
+                files.push(SkeletonFile {
+                    output_path: parse_path(&relative_path.with_extension(""))?,
+                    condition: header.condition,
+                    strategy: header.strategy,
+                    comment_syntax: comment::Syntax::for_template_path(&relative_path),
+                    kind: SkeletonFileKind::Template(parse::parse_str(body)?),
+                });
+            } else {
+                files.push(SkeletonFile {
+                    output_path: parse_path(&relative_path)?,
+                    condition: None,
+                    strategy: None,
+                    comment_syntax: None,
+                    kind: SkeletonFileKind::Verbatim(path),
+                });
+            }
+        }
+
+        Ok(Skeleton { params: params, files: files, hooks: hooks })
+    }
+
+    /// Generates a standalone Rust program which renders the skeleton.
+    ///
+    /// `values` holds the value of each parameter, in declaration order.
+    ///
+    /// The program prints a manifest of the files it writes, which can be
+    /// read with `parse_manifest`.
+    pub fn program(&self, values: &[String], output_dir: &Path) -> Result<String, Error> {
+        if values.len() != self.params.len() {
+            return Err(ErrorKind::WrongParamCount(self.params.len(), values.len()).into());
+        }
+
+        let trans_config = trans::Config {
+            parameters: self.params.iter().map(|param| trans::Parameter {
+                name: param.name.clone(),
+                ty: param.kind.rust_type().to_owned(),
+            }).collect(),
+            ..Default::default()
+        };
+
+        let mut program = String::new();
+        let mut main = String::new();
+
+        for (param, value) in self.params.iter().zip(values) {
+            main.push_str(&format!("    let {}: {} = {};\n",
+                param.name, param.kind.rust_type(), param.literal(value)?));
+        }
+
+        let arguments: String = self.params.iter().map(|p| format!(", {}", p.name)).collect();
+
+        for (index, file) in self.files.iter().enumerate() {
+            let module_name = format!("file_{}", index);
+            let path_code = trans::rust_code(file.output_path.clone(), &trans_config)?;
+
+            program.push_str(&format!("mod {} {{\n", module_name));
+            program.push_str(&format!("pub mod path {{\n{}}}\n", path_code));
+
+            match file.condition {
+                Some(ref condition) => main.push_str(&format!("    if {} {{\n", condition)),
+                None => main.push_str("    {\n"),
+            }
+            main.push_str("        let mut path = Vec::new();\n");
+            main.push_str(&format!("        {}::path::render(&mut path{}).unwrap();\n",
+                module_name, arguments));
+            main.push_str("        let relative_path = check_path(&String::from_utf8(path).unwrap());\n");
+            main.push_str(&format!("        let path = ::std::path::Path::new({:?}).join(&relative_path);\n",
+                output_dir));
+            main.push_str("        ::std::fs::create_dir_all(path.parent().unwrap()).unwrap();\n");
+            main.push_str("        let mut file = ::std::fs::File::create(path).unwrap();\n");
+            main.push_str(&format!("        println!(\"{{}}\\t{{}}\", {:?}, relative_path);\n",
+                file.strategy.map_or("-", |s| s.name())));
+
+            match file.kind {
+                SkeletonFileKind::Template(ref ast) => {
+                    let trans_config = trans::Config {
+                        comment_syntax: file.comment_syntax,
+                        ..trans_config.clone()
+                    };
+                    let code = trans::rust_code(ast.clone(), &trans_config)?;
+
+                    program.push_str(&format!("pub mod contents {{\n{}}}\n", code));
+                    main.push_str(&format!("        {}::contents::render(&mut file{}).unwrap();\n",
+                        module_name, arguments));
+                },
+                SkeletonFileKind::Verbatim(ref path) => {
+                    main.push_str(&format!(
+                        "        ::std::io::Write::write_all(&mut file, include_bytes!({:?})).unwrap();\n",
+                        path));
+                },
+            }
+
+            program.push_str("}\n\n");
+            main.push_str("    }\n");
+        }
+
+        program.push_str(CHECK_PATH_FUNCTION);
+        program.push_str(&format!("\nfn main() {{\n{}}}\n", main));
+        Ok(program)
+    }
+}
+
+impl Strategy
+{
+    /// Gets the strategy with a given name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "overwrite" => Some(Strategy::Overwrite),
+            "skip" => Some(Strategy::Skip),
+            "append" => Some(Strategy::Append),
+            "merge" => Some(Strategy::MergeMarkers),
+            "regions" => Some(Strategy::Regions),
+            _ => None,
+        }
+    }
+
+    /// Gets the name of the strategy.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Strategy::Overwrite => "overwrite",
+            Strategy::Skip => "skip",
+            Strategy::Append => "append",
+            Strategy::MergeMarkers => "merge",
+            Strategy::Regions => "regions",
+        }
+    }
+
+    /// Combines the existing contents of a file with newly generated contents.
+    ///
+    /// Returns `None` if the file should be left as it is.
+    pub fn merge(&self, existing: &[u8], generated: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        if existing == generated {
+            return Ok(None);
+        }
+
+        Ok(match *self {
+            Strategy::Overwrite => Some(generated.to_owned()),
+            Strategy::Skip => None,
+            Strategy::Append => Some([existing, generated].concat()),
+            Strategy::Regions => {
+                let existing = String::from_utf8_lossy(existing);
+                let generated = String::from_utf8_lossy(generated);
+                Some(region::update_regions(&existing, &generated)?.into_bytes())
+            },
+            Strategy::MergeMarkers => {
+                let mut merged = b"<<<<<<< existing\n".to_vec();
+                merged.extend(existing);
+                if !existing.ends_with(b"\n") { merged.push(b'\n'); }
+                merged.extend(b"=======\n");
+                merged.extend(generated);
+                if !generated.ends_with(b"\n") { merged.push(b'\n'); }
+                merged.extend(b">>>>>>> generated\n");
+                Some(merged)
+            },
+        })
+    }
+}
+
+impl Hook
+{
+    /// Builds the command to run the hook in a directory.
+    ///
+    /// The environment is cleared except for a few variables needed to
+    /// find and run programs.
+    pub fn command(&self, dir: &Path) -> process::Command {
+        let mut command = process::Command::new(&self.program);
+        command.args(&self.arguments)
+               .current_dir(dir)
+               .env_clear();
+
+        for name in HOOK_ENVIRONMENT {
+            if let Some(value) = env::var_os(name) {
+                command.env(name, value);
+            }
+        }
+
+        command
+    }
+}
+
+impl fmt::Display for Hook
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.program)?;
+        for argument in self.arguments.iter() {
+            write!(fmt, " {}", argument)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the manifest printed by a generated program.
+pub fn parse_manifest(output: &str) -> Vec<GeneratedFile> {
+    output.lines().filter_map(|line| {
+        let tab = match line.find('\t') {
+            Some(tab) => tab,
+            None => return None,
+        };
+
+        Some(GeneratedFile {
+            path: PathBuf::from(&line[tab+1..]),
+            strategy: Strategy::from_name(&line[..tab]),
+        })
+    }).collect()
+}
+
+/// Produces a line-based diff between two texts.
+///
+/// Lines only in `old` are prefixed by `-`, lines only in `new`
+/// by `+`, and common lines by a space.
+pub fn diff(old: &str, new: &str) -> String {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+
+    // lengths[i][j] is the length of the longest common
+    // subsequence of old[i..] and new[j..].
+    let mut lengths = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i+1][j+1] + 1
+            } else {
+                ::std::cmp::max(lengths[i+1][j], lengths[i][j+1])
+            };
+        }
+    }
+
+    let mut result = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            result.push_str(&format!(" {}\n", old[i]));
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || lengths[i][j+1] >= lengths[i+1][j]) {
+            result.push_str(&format!("+{}\n", new[j]));
+            j += 1;
+        } else {
+            result.push_str(&format!("-{}\n", old[i]));
+            i += 1;
+        }
+    }
+
+    result
+}
+
+/// Parses the post-generation hooks of a skeleton.
+pub fn parse_hooks(source: &str) -> Vec<Hook> {
+    source.lines().map(|line| line.trim()).filter(|line| {
+        !line.is_empty() && !line.starts_with("#")
+    }).map(|line| {
+        let mut words = line.split_whitespace().map(|w| w.to_owned());
+        Hook { program: words.next().unwrap(), arguments: words.collect() }
+    }).collect()
+}
+
+/// Parses the parameter declarations of a skeleton.
+pub fn parse_params(source: &str) -> Result<Vec<Param>, Error> {
+    let mut params = Vec::new();
+
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with("#") {
+            continue;
+        }
+
+        let (declaration, default) = match line.find('=') {
+            Some(i) => (&line[..i], Some(unquote(line[i+1..].trim()).to_owned())),
+            None => (line, None),
+        };
+
+        let (name, kind) = match declaration.find(':') {
+            Some(i) => {
+                let kind_name = declaration[i+1..].trim();
+                let kind = match ParamKind::from_name(kind_name) {
+                    Some(kind) => kind,
+                    None => return Err(ErrorKind::InvalidParamDeclaration(
+                        line_number, format!("unknown kind '{}'", kind_name)).into()),
+                };
+                (declaration[..i].trim(), kind)
+            },
+            None => (declaration.trim(), ParamKind::String),
+        };
+
+        if !is_identifier(name) {
+            return Err(ErrorKind::InvalidParamDeclaration(
+                line_number, format!("'{}' is not a valid name", name)).into());
+        }
+
+        let param = Param { name: name.to_owned(), kind: kind, default: default };
+
+        if let Some(ref default) = param.default {
+            param.literal(default)?;
+        }
+
+        params.push(param);
+    }
+
+    Ok(params)
+}
+
+/// Splits a template into its header and body.
+fn split_header(source: &str) -> (Option<&str>, &str) {
+    let first_line_end = source.find('\n').map(|i| i + 1).unwrap_or(source.len());
+    if source[..first_line_end].trim_end() != HEADER_DELIMITER {
+        return (None, source);
+    }
+
+    let mut line_start = first_line_end;
+    while line_start < source.len() {
+        let line_end = source[line_start..].find('\n')
+            .map(|i| line_start + i + 1).unwrap_or(source.len());
+
+        if source[line_start..line_end].trim_end() == HEADER_DELIMITER {
+            return (Some(&source[first_line_end..line_start]), &source[line_end..]);
+        }
+        line_start = line_end;
+    }
+
+    // An unterminated header is treated as part of the body.
+    (None, source)
+}
+
+/// The settings in the header of a template.
+struct Header
+{
+    condition: Option<String>,
+    strategy: Option<Strategy>,
+}
+
+/// Parses the header of a template.
+fn parse_header(header: Option<&str>, path: &Path) -> Result<Header, Error> {
+    let mut result = Header { condition: None, strategy: None };
+
+    for line in header.unwrap_or("").lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("#") {
+            continue;
+        }
+
+        let invalid = || ErrorKind::InvalidHeader(path.display().to_string(), line.to_owned());
+
+        let (key, value) = match line.find(':') {
+            Some(i) => (line[..i].trim(), line[i+1..].trim()),
+            None => return Err(invalid().into()),
+        };
+
+        match key {
+            "emit_if" => result.condition = Some(value.to_owned()),
+            "if_exists" => match Strategy::from_name(value) {
+                Some(strategy) => result.strategy = Some(strategy),
+                None => return Err(invalid().into()),
+            },
+            _ => return Err(invalid().into()),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Parses a path, which may contain template tags.
+fn parse_path(path: &Path) -> Result<ast::Ast, Error> {
+    match path.to_str() {
+        Some(path) => parse::parse_str(path),
+        None => Err(ErrorKind::InvalidPath(path.display().to_string()).into()),
+    }
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_alphanumeric() || c == '_') && !analysis::is_keyword(s)
+        },
+        _ => false,
+    }
+}
+
+fn unquote(s: &str) -> &str {
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        &s[1..s.len()-1]
+    } else {
+        s
+    }
+}
+
+fn collect_files(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            collect_files(&path, paths)?;
+        } else {
+            paths.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn read_file(path: &Path) -> Result<String, Error> {
+    let mut file = fs::File::open(path)?;
+    let mut source = String::new();
+    file.read_to_string(&mut source)?;
+    Ok(source)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_params() {
+        let params = parse_params("# comment\nname = \"demo\"\nport: int = 80\nauthor\n").unwrap();
+
+        assert_eq!(params, vec![
+            Param { name: "name".to_owned(), kind: ParamKind::String, default: Some("demo".to_owned()) },
+            Param { name: "port".to_owned(), kind: ParamKind::Integer, default: Some("80".to_owned()) },
+            Param { name: "author".to_owned(), kind: ParamKind::String, default: None },
+        ]);
+    }
+
+    #[test]
+    fn rejects_unknown_param_kinds() {
+        assert!(parse_params("name: float").is_err());
+    }
+
+    #[test]
+    fn rejects_keyword_param_names() {
+        assert!(parse_params("type").is_err());
+        assert!(parse_params("r#type").is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_numbers_of_values() {
+        let skeleton = Skeleton {
+            params: parse_params("name\nport: int").unwrap(),
+            files: Vec::new(),
+            hooks: Vec::new(),
+        };
+
+        match skeleton.program(&["demo".to_owned()], Path::new("out")) {
+            Err(Error(ErrorKind::WrongParamCount(2, 1), _)) => (),
+            other => panic!("expected a parameter count error, got {:?}", other.map(|_| ())),
+        }
+        assert!(skeleton.program(&["demo".to_owned(), "80".to_owned()], Path::new("out")).is_ok());
+    }
+
+    #[test]
+    fn checks_generated_paths() {
+        let skeleton = Skeleton {
+            params: Vec::new(),
+            files: vec![SkeletonFile {
+                output_path: parse::parse_str("../escape.rs").unwrap(),
+                condition: None,
+                strategy: None,
+                comment_syntax: None,
+                kind: SkeletonFileKind::Template(parse::parse_str("").unwrap()),
+            }],
+            hooks: Vec::new(),
+        };
+
+        let program = skeleton.program(&[], Path::new("out")).unwrap();
+        assert!(program.contains("let relative_path = check_path(&String::from_utf8(path).unwrap());"));
+        assert!(program.contains(CHECK_PATH_FUNCTION));
+    }
+
+    #[test]
+    fn rejects_invalid_defaults() {
+        assert!(parse_params("debug: bool = maybe").is_err());
+    }
+
+    #[test]
+    fn merges_with_strategies() {
+        assert_eq!(Strategy::Skip.merge(b"a", b"b").unwrap(), None);
+        assert_eq!(Strategy::Overwrite.merge(b"a", b"b").unwrap(), Some(b"b".to_vec()));
+        assert_eq!(Strategy::Append.merge(b"a\n", b"b\n").unwrap(), Some(b"a\nb\n".to_vec()));
+        assert_eq!(Strategy::MergeMarkers.merge(b"a", b"b").unwrap(),
+                   Some(b"<<<<<<< existing\na\n=======\nb\n>>>>>>> generated\n".to_vec()));
+        assert_eq!(Strategy::Overwrite.merge(b"a", b"a").unwrap(), None);
+    }
+
+    #[test]
+    fn parses_manifests() {
+        assert_eq!(parse_manifest("skip\tsrc/main.rs\n-\tREADME\n"), vec![
+            GeneratedFile { path: PathBuf::from("src/main.rs"), strategy: Some(Strategy::Skip) },
+            GeneratedFile { path: PathBuf::from("README"), strategy: None },
+        ]);
+    }
+
+    #[test]
+    fn diffs_lines() {
+        assert_eq!(diff("a\nb\nc\n", "a\nc\nd\n"), " a\n-b\n c\n+d\n");
+    }
+
+    #[test]
+    fn parses_hooks() {
+        assert_eq!(parse_hooks("# comment\n\ncargo fmt\n"), vec![
+            Hook { program: "cargo".to_owned(), arguments: vec!["fmt".to_owned()] },
+        ]);
+    }
+
+    #[test]
+    fn splits_headers() {
+        assert_eq!(split_header("---\nemit_if: x\n---\nbody"), (Some("emit_if: x\n"), "body"));
+        assert_eq!(split_header("---\nunterminated"), (None, "---\nunterminated"));
+        assert_eq!(split_header("body"), (None, "body"));
+    }
+
+    #[test]
+    fn validates_values() {
+        let param = Param { name: "debug".to_owned(), kind: ParamKind::Bool, default: None };
+        assert_eq!(param.literal("yes").unwrap(), "true");
+        assert!(param.literal("2").is_err());
+    }
+}
@@ -0,0 +1,124 @@
+//! Layered data for templates, such as site settings shared by every
+//! render with the data of each request on top.
+//!
+//! A scope looks names up in its own layer, then in its parent's, and
+//! so on. A global scope can be the parent of the scope of every
+//! request, and that the parent of a scope for each iteration of a
+//! loop, without any of them being cloned. Changes to a scope only
+//! affect its own layer.
+//!
+//! Scopes are given to templates as a parameter, for use in code. They
+//! hold data when rendering, unlike the `context!` macro, which builds
+//! the variables given when a template is translated.
+//!
+//! ```text
+//! <%= scope.get("site_name") %>
+//! ```
+
+use value::Value;
+
+use std::collections::BTreeMap;
+
+/// A layer of named values, over an optional parent.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Scope<'a>
+{
+    values: BTreeMap<String, Value>,
+    parent: Option<&'a Scope<'a>>,
+}
+
+impl<'a> Scope<'a>
+{
+    /// Creates an empty scope with no parent.
+    pub fn new() -> Self {
+        Scope::default()
+    }
+
+    /// Creates an empty scope whose lookups fall through to a parent.
+    pub fn with_parent(parent: &'a Scope<'a>) -> Self {
+        Scope { values: BTreeMap::new(), parent: Some(parent) }
+    }
+
+    /// Gets the parent of the scope, if it has one.
+    pub fn parent(&self) -> Option<&'a Scope<'a>> {
+        self.parent
+    }
+
+    /// Sets a value in this layer, hiding any value with the same name
+    /// in its parents.
+    ///
+    /// Returns the value this layer had before, if any.
+    pub fn insert<K, V>(&mut self, key: K, value: V) -> Option<Value>
+        where K: Into<String>, V: Into<Value> {
+        self.values.insert(key.into(), value.into())
+    }
+
+    /// Removes a value from this layer, so that any value with the same
+    /// name in its parents is found again.
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        self.values.remove(key)
+    }
+
+    /// Gets the value with a name from the nearest layer which has one,
+    /// giving `Null` if there is none.
+    pub fn get(&self, key: &str) -> &Value {
+        const NULL: &'static Value = &Value::Null;
+
+        let mut layer = Some(self);
+        while let Some(scope) = layer {
+            if let Some(value) = scope.values.get(key) {
+                return value;
+            }
+            layer = scope.parent;
+        }
+        NULL
+    }
+
+    /// Checks whether any layer has a value with a name.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.values.contains_key(key) || self.parent.map_or(false, |parent| parent.contains_key(key))
+    }
+
+    /// Gets every value the scope can see as a single map, with the
+    /// values of nearer layers taking precedence.
+    pub fn to_value(&self) -> Value {
+        let mut values = match self.parent.map(|parent| parent.to_value()) {
+            Some(Value::Map(values)) => values,
+            _ => BTreeMap::new(),
+        };
+        values.extend(self.values.iter().map(|(k, v)| (k.clone(), v.clone())));
+        Value::Map(values)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn looks_up_values_through_layers() {
+        let mut site = Scope::new();
+        site.insert("site_name", "Tempo");
+        site.insert("theme", "light");
+
+        let mut request = Scope::with_parent(&site);
+        request.insert("theme", "dark");
+        request.insert("user", "ann");
+
+        for item in 1..3 {
+            let mut iteration = Scope::with_parent(&request);
+            iteration.insert("item", item);
+            assert_eq!(iteration.get("item"), &Value::Int(item as i64));
+            assert_eq!(iteration.get("theme"), &Value::from("dark"));
+        }
+
+        assert_eq!(request.get("site_name"), &Value::from("Tempo"));
+        assert_eq!(request.get("item"), &Value::Null);
+        assert_eq!(request.to_value().to_string(), "site_name: Tempo, theme: dark, user: ann");
+
+        request.remove("theme");
+        assert_eq!(request.get("theme"), &Value::from("light"));
+        assert_eq!(site.get("user"), &Value::Null);
+        assert!(request.contains_key("site_name") && !site.contains_key("user"));
+    }
+}
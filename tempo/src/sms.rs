@@ -0,0 +1,109 @@
+//! Checks on rendered SMS messages.
+//!
+//! Messages are sent in segments. A message written only with the GSM-7
+//! alphabet fits 160 characters in one segment, or 153 in each segment
+//! of a longer message. Any other character makes the whole message
+//! UCS-2, which fits 70 characters, or 67 per segment.
+
+use std::fmt;
+
+/// The characters of the GSM-7 basic alphabet.
+const GSM7_BASIC: &'static str = "@£$¥èéùìòÇ\nØø\rÅåΔ_ΦΓΛΩΠΨΣΘΞ\u{1b}ÆæßÉ !\"#¤%&'()*+,-./0123456789:;<=>?\
+                                   ¡ABCDEFGHIJKLMNOPQRSTUVWXYZÄÖÑÜ§¿abcdefghijklmnopqrstuvwxyzäöñüà";
+/// The characters of the GSM-7 extension table, which each take two
+/// characters of a segment.
+const GSM7_EXTENSION: &'static str = "\u{c}^{}\\[~]|€";
+
+/// How a message is encoded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Charset
+{
+    Gsm7,
+    Ucs2,
+}
+
+/// The length of a rendered message, as it would be sent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SmsReport
+{
+    pub charset: Charset,
+    /// The number of characters the message takes in its charset.
+    ///
+    /// Characters of the GSM-7 extension table count twice, and
+    /// characters outside the Basic Multilingual Plane count twice
+    /// in UCS-2.
+    pub length: usize,
+    /// The number of segments the message is sent in.
+    pub segments: usize,
+    /// The characters not in the GSM-7 alphabet, with their byte offsets.
+    pub incompatible: Vec<(usize, char)>,
+}
+
+impl Charset
+{
+    /// Gets the number of characters which fit in a message of
+    /// one segment, and in each segment of a longer message.
+    pub fn segment_lengths(&self) -> (usize, usize) {
+        match *self {
+            Charset::Gsm7 => (160, 153),
+            Charset::Ucs2 => (70, 67),
+        }
+    }
+}
+
+/// Works out how a message would be sent.
+pub fn analyze(text: &str) -> SmsReport {
+    let incompatible: Vec<_> = text.char_indices()
+        .filter(|&(_, c)| !GSM7_BASIC.contains(c) && !GSM7_EXTENSION.contains(c))
+        .collect();
+
+    let (charset, length) = if incompatible.is_empty() {
+        (Charset::Gsm7, text.chars().map(|c| if GSM7_EXTENSION.contains(c) { 2 } else { 1 }).sum())
+    } else {
+        (Charset::Ucs2, text.encode_utf16().count())
+    };
+
+    let (single, multiple) = charset.segment_lengths();
+    let segments = match length {
+        0 => 0,
+        length if length <= single => 1,
+        length => length.div_ceil(multiple),
+    };
+
+    SmsReport { charset: charset, length: length, segments: segments, incompatible: incompatible }
+}
+
+impl fmt::Display for Charset
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Charset::Gsm7 => write!(fmt, "GSM-7"),
+            Charset::Ucs2 => write!(fmt, "UCS-2"),
+        }
+    }
+}
+
+impl fmt::Display for SmsReport
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} characters in {} segment(s) of {}", self.length, self.segments, self.charset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counts_segments() {
+        let report = analyze(&"a".repeat(160));
+        assert_eq!((report.charset, report.length, report.segments), (Charset::Gsm7, 160, 1));
+        assert_eq!(analyze(&"a".repeat(161)).segments, 2);
+        assert_eq!(analyze(&"€".repeat(80)).length, 160);
+
+        let report = analyze("Café ☕ at 5");
+        assert_eq!((report.charset, report.segments), (Charset::Ucs2, 1));
+        assert_eq!(report.incompatible, vec![(6, '☕')]);
+        assert_eq!(analyze(&"☕".repeat(71)).segments, 2);
+    }
+}
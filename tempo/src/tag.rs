@@ -0,0 +1,93 @@
+//! Block tags defined by applications.
+//!
+//! A tag handler adds a block such as `<% markdown %>...<% end %>` to
+//! the template language. It checks the arguments of the opening tag
+//! when the template is parsed, and gives the Rust code rendering the
+//! block when it is translated.
+
+use std::fmt;
+use std::sync::Arc;
+
+/// A block tag defined by an application.
+pub trait TagHandler : Send + Sync
+{
+    /// Gets the name of the tag, which is the first word of its opening tag.
+    fn name(&self) -> &str;
+
+    /// Checks the arguments of an opening tag, which are the code after
+    /// the name, giving the reason if they are invalid.
+    fn parse(&self, arguments: &str) -> Result<(), String> {
+        let _ = arguments;
+        Ok(())
+    }
+
+    /// Gives a Rust expression for the output of a block.
+    ///
+    /// `body` is the name of a `String` variable holding the rendered
+    /// body of the block. The expression is printed like `<%= %>`.
+    fn render(&self, arguments: &str, body: &str) -> String;
+}
+
+/// A set of tag handlers, each with a different name.
+#[derive(Clone, Default)]
+pub struct Tags
+{
+    handlers: Vec<Arc<dyn TagHandler>>,
+}
+
+impl Tags
+{
+    /// Creates an empty set of tags.
+    pub fn new() -> Self {
+        Tags::default()
+    }
+
+    /// Adds a handler, replacing any handler of the same name.
+    pub fn add(&mut self, handler: Arc<dyn TagHandler>) {
+        self.handlers.retain(|h| h.name() != handler.name());
+        self.handlers.push(handler);
+    }
+
+    /// Adds the handlers of another set, replacing those of the same name.
+    pub fn extend(&mut self, other: &Tags) {
+        for handler in other.handlers.iter() {
+            self.add(handler.clone());
+        }
+    }
+
+    /// Gets the handler of a tag.
+    pub fn get(&self, name: &str) -> Option<&dyn TagHandler> {
+        self.handlers.iter().find(|h| h.name() == name).map(|h| &**h)
+    }
+}
+
+impl fmt::Debug for Tags
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_list().entries(self.handlers.iter().map(|h| h.name())).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Upper(&'static str);
+
+    impl TagHandler for Upper {
+        fn name(&self) -> &str { self.0 }
+        fn render(&self, _: &str, body: &str) -> String { format!("{}.to_uppercase()", body) }
+    }
+
+    #[test]
+    fn replaces_handlers_of_the_same_name() {
+        let mut tags = Tags::new();
+        tags.add(Arc::new(Upper("upper")));
+        tags.add(Arc::new(Upper("shout")));
+        tags.add(Arc::new(Upper("upper")));
+
+        assert!(tags.get("upper").is_some());
+        assert!(tags.get("lower").is_none());
+        assert_eq!(format!("{:?}", tags), "[\"shout\", \"upper\"]");
+    }
+}
@@ -1,11 +1,72 @@
-use Error;
-use ast;
+use {Error, ErrorKind};
+use {analysis, ast, comment, data, tag};
+use ast::VisitorMut;
 
 use std::io::prelude::*;
+use std::collections::HashMap;
 use std::io::Cursor;
 use std::io;
+use std::time::Duration;
 
 const INTERNAL_WRITER_NAME: &'static str = "_writer";
+/// The name of the global holding the build environment.
+const ENV_GLOBAL_NAME: &'static str = "env";
+/// The name of the helper which wraps text in a comment.
+const COMMENT_HELPER_NAME: &'static str = "comment";
+/// The name of the variable holding the source map as it is built.
+const SOURCE_MAP_NAME: &'static str = "_source_map";
+/// The name of the variable counting the iterations of loops.
+const ITERATIONS_NAME: &'static str = "_iterations";
+/// The name of the variable counting the calls of functions with limits.
+const CALLS_NAME: &'static str = "_calls";
+/// The type of the parameters taking writers.
+const WRITER_TYPE: &'static str = "&mut ::std::io::Write";
+/// The name of the parameter taking the archive of files.
+const ARCHIVE_NAME: &'static str = "_archive";
+/// The name of the helper which writes a file into the archive.
+const ARCHIVE_ENTRY_HELPER_NAME: &'static str = "_archive_entry";
+/// The name of the parameter taking the function which translates messages.
+const TRANSLATE_NAME: &'static str = "_translate";
+/// The name of the helper which translates a message.
+const TRANSLATE_HELPER_NAME: &'static str = "t";
+/// The name of the helper which translates a message about a number of things.
+const TRANSLATE_PLURAL_HELPER_NAME: &'static str = "t_plural";
+/// The type of the function which translates messages.
+const TRANSLATE_TYPE: &'static str = "&Fn(&str, Option<i64>) -> Option<String>";
+/// The name of the parameter taking the function which fetches cached fragments.
+const CACHE_NAME: &'static str = "_cache";
+/// The type of the function which fetches cached fragments.
+const CACHE_TYPE: &'static str = "&Fn(&str, &mut FnMut() -> String) -> String";
+/// The name of the parameter taking the function which records usage.
+const USAGE_NAME: &'static str = "_usage";
+/// The type of the function which records usage.
+const USAGE_TYPE: &'static str = "&Fn(&'static str)";
+/// The name of the parameter taking the function which reports the
+/// differences found by a canary render.
+const REPORT_DIFF_NAME: &'static str = "_report_diff";
+/// The type of the function which reports the differences found by a canary render.
+const REPORT_DIFF_TYPE: &'static str = "&Fn(&str, Result<&str, &::std::io::Error>)";
+/// The name of the parameter taking the statistics of a render.
+const STATS_NAME: &'static str = "_stats";
+/// The name of the type holding the statistics of a render.
+const STATS_TYPE: &'static str = "RenderStats";
+/// The name of the parameter taking the function which records a failed block.
+const BLOCK_FAILED_NAME: &'static str = "_block_failed";
+/// The type of the function which records a failed block.
+const BLOCK_FAILED_TYPE: &'static str = "&Fn(&'static str, &::std::io::Error) -> String";
+/// The name of the variable counting the blocks evaluated.
+const BLOCKS_EVALUATED_NAME: &'static str = "_blocks_evaluated";
+/// The name of the variable counting the fragments found in the cache.
+const CACHE_HITS_NAME: &'static str = "_cache_hits";
+/// The name of the variable holding the time rendering began, for statistics.
+const STATS_START_TIME_NAME: &'static str = "_stats_start_time";
+/// The name of the variable holding the rendered body of a tag.
+const TAG_BODY_NAME: &'static str = "_body";
+/// The name of the variable holding the time rendering began.
+const START_TIME_NAME: &'static str = "_start_time";
+/// The type of a source map in generated code.
+const SOURCE_MAP_TYPE: &'static str =
+    "Vec<(::std::ops::Range<usize>, &'static str, ::std::ops::Range<usize>)>";
 
 /// Configuration options for translation.
 #[derive(Clone, Debug)]
@@ -13,48 +74,1156 @@ pub struct Config
 {
     /// Whether an entry point should be included.
     pub include_entry_point: bool,
+    /// The build environment (e.g. `"production"`), exposed to
+    /// templates as the `env` global.
+    pub env: Option<String>,
+    /// String variables bound at the start of the render function,
+    /// as `(name, value)` pairs.
+    pub variables: Vec<(String, String)>,
+    /// Tables bound at the start of the render function, as
+    /// `(name, table)` pairs.
+    ///
+    /// Each table is a `Vec` of structs with a field for each column,
+    /// so that templates can loop over it and use `row.column`.
+    pub tables: Vec<(String, data::Table)>,
+    /// The comment syntax of the output language.
+    ///
+    /// If set, templates can call `comment(text)` to get the
+    /// text wrapped in a comment.
+    pub comment_syntax: Option<comment::Syntax>,
+    /// Text output before the template, such as a notice that
+    /// the output was generated.
+    pub header: Option<String>,
+    /// Extra parameters taken by the generated render function.
+    pub parameters: Vec<Parameter>,
+    /// Whether the render function returns a source map.
+    ///
+    /// The source map holds an `(output, origin, span)` tuple for each
+    /// piece of text and printed expression, mapping the byte range
+    /// of the output to the byte range of the template it came from.
+    /// Output from macros and captures is not mapped.
+    ///
+    /// The entry point writes the map to the file given as its first
+    /// argument, one `output_start..output_end<TAB>origin<TAB>span_start..span_end`
+    /// line per entry.
+    pub source_map: bool,
+    /// Limits on the resources used by rendering.
+    pub limits: Limits,
+    /// Whether the render function is `async`.
+    ///
+    /// Code in the template can then `.await` values, such as a row
+    /// fetched from a database, except inside blocks whose bodies are
+    /// rendered by closures: macros, captures, custom tags, blocks with
+    /// a limit or a `block_fallback`, cached blocks with a fragment cache
+    /// and files written into an archive. Awaiting in those is an error.
+    /// The generated code needs the 2018 edition or later, and there
+    /// is no entry point, as running it needs an executor. The writer
+    /// must be `Send` and the functions given to it `Sync`, so that the
+    /// future is `Send`.
+    pub async_render: bool,
+    /// Whether `file` blocks are written into a tar archive rather than
+    /// to the filesystem.
+    ///
+    /// The render function takes the writer of the archive as a second
+    /// parameter, after the writer of the output. Paths in the archive
+    /// are limited to 100 bytes, and `file` blocks cannot be used in
+    /// macros. The entry point writes the archive to its output and
+    /// discards any text outside `file` blocks.
+    pub archive_files: bool,
+    /// Whether templates can translate messages, with `t(key)` and
+    /// `t_plural(key, count)`.
+    ///
+    /// The render function takes a function giving the message for a
+    /// key, and the number of things it is about if given, as a
+    /// parameter after its writers. This can look messages up in the
+    /// Fluent or gettext catalogs of the application, or in a
+    /// `tempo::i18n::Catalog`. `{count}` in messages from `t_plural`
+    /// is replaced by the number, and messages which are not found are
+    /// printed as their keys, as they are by the entry point.
+    pub translations: bool,
+    /// Whether the output of `cache` blocks is stored and reused.
+    ///
+    /// The render function takes a function giving the fragment stored
+    /// under a key, given a function rendering it if there is none, as
+    /// a parameter after the function translating messages. A
+    /// `tempo::cache::CacheStore`, such as an `LruCache`, gives one.
+    /// Keys are prefixed with the name of the template and a `:`.
+    /// Otherwise, `cache` blocks are rendered every time, as they are
+    /// by the entry point.
+    pub fragment_cache: bool,
+    /// Whether rendering reports the templates, blocks and variables
+    /// it uses.
+    ///
+    /// The render function takes a function called with a key for
+    /// each use, as its last parameter before the extra parameters.
+    /// Keys are `template:NAME` when an included or named template is
+    /// rendered, `variable:NAME` for each variable it references, and
+    /// `block:NAME:KIND@START` when the body of a block is rendered,
+    /// where `START` is the byte offset of the block in the template and
+    /// the `KIND` of an else branch is `else`. Keys are the same for
+    /// every render, so that a `tempo::usage::UsageCounts` can count
+    /// them across many renders, and templates or data which are never
+    /// used can be found.
+    pub usage: bool,
+    /// Whether the output from each template is wrapped in comments
+    /// naming the template and the line of its source it came from,
+    /// such as `header.html:12`.
+    ///
+    /// This helps to find which template produced a part of the output,
+    /// but changes the output, so is for debugging. It needs a comment
+    /// syntax.
+    pub annotate_origins: bool,
+    /// Whether rendering measures itself.
+    ///
+    /// A `RenderStats` struct is generated alongside the render function,
+    /// with the number of blocks whose bodies were evaluated, the bytes
+    /// written to the output, the fragments found in the cache and the
+    /// time rendering took. The render function takes a `&mut RenderStats`
+    /// as its last parameter before the extra parameters, which is filled
+    /// in when rendering succeeds.
+    pub render_stats: bool,
+    /// The markup rendered in place of a named block which uses optional
+    /// data that was not given, such as a spinner, or a comment marking
+    /// where to put the block once the data is ready. `{name}` in it is
+    /// replaced by the name of the block.
+    ///
+    /// Optional data is listed in the `optional_data` field of the
+    /// template's front matter, and given as an `Option`. Inside a named
+    /// block which uses it, it is a reference to the value instead.
+    /// Without a placeholder, rendering fails if such a block is reached
+    /// when the data was not given.
+    pub placeholder: Option<String>,
+    /// The markup rendered in place of a named block which fails, such
+    /// as `<!-- block {name} failed: {id} -->`, so that the rest of the
+    /// page still renders. `{name}` in it is replaced by the name of the
+    /// block, and `{id}` by the id of the error.
+    ///
+    /// The render function takes a function called with the name of each
+    /// block which fails and its error, giving the id of the error, as
+    /// a parameter after the statistics of the render. This can log the
+    /// error as a warning and give the id it was logged under. The output
+    /// of a block is held back until it has rendered, so a block which
+    /// fails leaves none of its output behind, and is not part of the
+    /// source map. Blocks cannot `.await` values in an `async` render.
+    pub block_fallback: Option<String>,
+    /// The block tags defined by the application.
+    ///
+    /// Every tag in the template needs a handler, which gives the code
+    /// rendering the block.
+    pub tags: tag::Tags,
+}
+
+/// Limits on the resources used by rendering, for templates
+/// from untrusted sources.
+///
+/// A render function which exceeds a limit returns an error naming it.
+/// Inside macros and captures, which cannot fail, it panics instead.
+///
+/// Limits only apply to what tempo itself generates. Rust code inside
+/// tags, such as a `<% loop {} %>`, is not limited, so templates which
+/// may contain arbitrary code still need to be reviewed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Limits
+{
+    /// The longest rendering may take, checked at each iteration of
+    /// a `for` block.
+    pub max_render_time: Option<Duration>,
+    /// The most bytes which may be output.
+    pub max_output_size: Option<usize>,
+    /// The most iterations of `for` blocks, in total.
+    pub max_loop_iterations: Option<usize>,
+    /// The most times each function may be called, by name, such as
+    /// functions which are expensive to run.
+    ///
+    /// Calls of the function by name or by path are counted, but
+    /// method calls are not. Calls inside closures written in a template
+    /// fail to compile, as the render cannot stop from inside them.
+    pub max_calls: Vec<(String, usize)>,
+}
+
+/// A parameter of the generated render function.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Parameter
+{
+    /// The name of the parameter.
+    pub name: String,
+    /// The Rust type of the parameter.
+    pub ty: String,
 }
 
 /// Translate an AST into source code.
 pub fn rust_code(ast: ast::Ast, config: &Config) -> Result<String, Error> {
+    if config.async_render && config.include_entry_point {
+        return Err(ErrorKind::AsyncEntryPoint.into());
+    }
+
+    let mut ast = ast;
+    if config.async_render {
+        let mut await_in_closure = AwaitInClosure { config: config, closure: None, found: None };
+        await_in_closure.visit_ast_mut(&mut ast);
+        if let Some(block) = await_in_closure.found {
+            return Err(ErrorKind::AwaitInClosure(block).into());
+        }
+    }
+
+    if !config.limits.max_calls.is_empty() {
+        CallLimits(&config.limits.max_calls).visit_ast_mut(&mut ast);
+    }
+
+    let optional_data = ast.optional_data();
+    if !optional_data.is_empty() {
+        let placeholder = config.placeholder.as_ref().map(|p| &p[..]);
+        OptionalData { keys: &optional_data, placeholder: placeholder, unwrapped: Vec::new() }.visit_ast_mut(&mut ast);
+    }
+
     let mut write = Cursor::new(Vec::new());
 
     if config.include_entry_point {
-        emit_entry_point(&mut write)?;
+        emit_entry_point(config, &mut write)?;
+    }
+
+    if config.render_stats {
+        emit_stats_type(&mut write)?;
+    }
+
+    emit_main_function_start(config, false, &mut write)?;
+
+    if config.source_map || config.limits.max_output_size.is_some() || config.archive_files {
+        // The output is written through a wrapper, or to the archive.
+        writeln!(write, "    #[allow(unused_imports)]")?;
+        writeln!(write, "    use ::std::io::Write;")?;
+    }
+
+    emit_limits_start(&config.limits, &mut write)?;
+
+    if config.render_stats {
+        emit_stats_start(&mut write)?;
+    }
+
+    if config.source_map {
+        emit_source_map_start(&mut write)?;
+    }
+
+    if config.archive_files {
+        emit_archive_entry_helper(&mut write)?;
+    }
+
+    emit_globals(config, &mut write)?;
+
+    if let Some(ref header) = config.header {
+        emit_text(header, &mut write)?;
+    }
+
+    let mut items = if config.usage { record_usage(ast.items, None) } else { ast.items };
+    if config.render_stats {
+        count_blocks(&mut items);
+    }
+    if config.annotate_origins {
+        match config.comment_syntax {
+            Some(syntax) => {
+                let line_breaks = line_breaks(&items);
+                items = annotate_origins(items, syntax, &line_breaks, None);
+            },
+            None => return Err(ErrorKind::NoCommentSyntax.into()),
+        }
+    }
+    emit_items(items, config, &mut write)?;
+
+    emit_main_function_end(config, &mut write)?;
+
+    Ok(String::from_utf8(write.into_inner()).unwrap())
+}
+
+/// Translates two versions of a template into a render function which
+/// renders both, for rolling out changes to templates safely.
+///
+/// The output of the old version is written as usual, and the output of
+/// the new version is only compared with it. When they differ, or the
+/// new version fails, a function given as a parameter after the writers
+/// is called with both outputs. It should return quickly, such as by
+/// sending them to a thread which logs the difference. Source maps,
+/// async rendering and entry points are not supported. Parameters are
+/// cloned for the old version, so their types must be `Clone`.
+pub fn rust_code_canary(old: ast::Ast, new: ast::Ast, config: &Config) -> Result<String, Error> {
+    let unsupported = if config.source_map {
+        Some("source_map")
+    } else if config.async_render {
+        Some("async_render")
+    } else if config.include_entry_point {
+        Some("include_entry_point")
+    } else if config.render_stats {
+        Some("render_stats")
+    } else {
+        None
+    };
+    if let Some(option) = unsupported {
+        return Err(ErrorKind::UnsupportedCanaryOption(option.to_owned()).into());
+    }
+
+    let mut write = Cursor::new(Vec::new());
+
+    for &(module, ref ast) in [("_old", old), ("_new", new)].iter() {
+        writeln!(write, "mod {} {{", module)?;
+        writeln!(write, "#[allow(unused_imports)]")?;
+        writeln!(write, "use super::*;")?;
+        write.write_all(rust_code(ast.clone(), config)?.as_bytes())?;
+        writeln!(write, "}}\n")?;
+    }
+
+    // The new version does not write files, share cached fragments
+    // with the old version, count as usage, or record failed blocks.
+    let new_arguments: Vec<_> = render_arguments(config).into_iter().map(|argument| match &argument[..] {
+        ARCHIVE_NAME => "&mut ::std::io::sink()".to_owned(),
+        CACHE_NAME => "&|_, render| render()".to_owned(),
+        USAGE_NAME => "&|_| ()".to_owned(),
+        BLOCK_FAILED_NAME => "&|_, _| String::new()".to_owned(),
+        _ => argument,
+    }).collect();
+
+    emit_main_function_start(config, true, &mut write)?;
+    writeln!(write, "    let mut _old_output: Vec<u8> = Vec::new();")?;
+    let parameter_count = config.parameters.len();
+    let mut old_arguments = render_arguments(config);
+    let first_parameter = old_arguments.len() - parameter_count;
+    for argument in old_arguments[first_parameter..].iter_mut() {
+        *argument = format!("::std::clone::Clone::clone(&{})", argument);
+    }
+    writeln!(write, "    _old::render(&mut _old_output{})?;", old_arguments.iter().map(|a| format!(", {}", a)).collect::<String>())?;
+    writeln!(write, "    let mut _new_output: Vec<u8> = Vec::new();")?;
+    writeln!(write, "    let _new_result = _new::render(&mut _new_output{});", new_arguments.iter().map(|a| format!(", {}", a)).collect::<String>())?;
+    writeln!(write, "    let _old_text = String::from_utf8_lossy(&_old_output);")?;
+    writeln!(write, "    let _new_text = String::from_utf8_lossy(&_new_output);")?;
+    writeln!(write, "    match _new_result {{")?;
+    writeln!(write, "        Ok(()) if _old_text == _new_text => (),")?;
+    writeln!(write, "        Ok(()) => {}(&_old_text, Ok(&_new_text)),", REPORT_DIFF_NAME)?;
+    writeln!(write, "        Err(ref e) => {}(&_old_text, Err(e)),", REPORT_DIFF_NAME)?;
+    writeln!(write, "    }}")?;
+    writeln!(write, "    {}.write_all(&_old_output)", INTERNAL_WRITER_NAME)?;
+    writeln!(write, "}}")?;
+
+    Ok(String::from_utf8(write.into_inner()).unwrap())
+}
+
+/// Gets the arguments after the writer of the output which pass the
+/// parameters of a render function on to another with the same options.
+fn render_arguments(config: &Config) -> Vec<String> {
+    let mut arguments = Vec::new();
+
+    if config.archive_files {
+        arguments.push(ARCHIVE_NAME.to_owned());
+    }
+    if config.translations {
+        arguments.push(TRANSLATE_NAME.to_owned());
+    }
+    if config.fragment_cache {
+        arguments.push(CACHE_NAME.to_owned());
+    }
+    if config.usage {
+        arguments.push(USAGE_NAME.to_owned());
+    }
+    if config.render_stats {
+        arguments.push(STATS_NAME.to_owned());
+    }
+    if config.block_fallback.is_some() {
+        arguments.push(BLOCK_FAILED_NAME.to_owned());
+    }
+
+    arguments.extend(config.parameters.iter().map(|p| p.name.clone()));
+    arguments
+}
+
+/// Translate an AST whose body is a single expression into source code
+/// for a function returning the value of the expression.
+///
+/// The function is named `value` and returns the given Rust type. Code,
+/// defaults and comments may come before the expression, and text
+/// around it must be whitespace. The entry point, if included, prints
+/// the value with `{:?}`.
+pub fn rust_value_code(ast: ast::Ast, ty: &str, config: &Config) -> Result<String, Error> {
+    let mut items = ast.items;
+
+    // Whitespace around the expression is not part of the value.
+    items.retain(|item| match item.kind {
+        ast::ItemKind::Text(ref text) => !text.trim().is_empty(),
+        ast::ItemKind::Comment(..) | ast::ItemKind::Directive { .. } => false,
+        _ => true,
+    });
+
+    let expression = match items.pop() {
+        Some(ast::Item { kind: ast::ItemKind::Print(source), .. }) => source,
+        _ => return Err(ErrorKind::NotAnExpression.into()),
+    };
+
+    let is_statement = |item: &ast::Item| match item.kind {
+        ast::ItemKind::Code(..) | ast::ItemKind::Default { .. } | ast::ItemKind::Set { .. } |
+            ast::ItemKind::FrontMatter { .. } => true,
+        _ => false,
+    };
+
+    if !items.iter().all(is_statement) {
+        return Err(ErrorKind::NotAnExpression.into());
+    }
+
+    let mut write = Cursor::new(Vec::new());
+
+    if config.include_entry_point {
+        writeln!(write, "fn main() {{")?;
+        writeln!(write, "    println!(\"{{:?}}\", value());")?;
+        writeln!(write, "}}\n")?;
+    }
+
+    write!(write, "pub fn value(")?;
+    let parameters: Vec<_> = config.parameters.iter().map(|p| format!("{}: {}", p.name, p.ty)).collect();
+    writeln!(write, "{}) -> {} {{", parameters.join(", "), ty)?;
+
+    emit_globals(config, &mut write)?;
+    emit_items(items, config, &mut write)?;
+
+    writeln!(write, "    ({})", expression.trim())?;
+    writeln!(write, "}}")?;
+
+    Ok(String::from_utf8(write.into_inner()).unwrap())
+}
+
+/// Adds code recording the use of templates, blocks and variables
+/// to items.
+///
+/// `enclosing_origin` is the template of the block holding the items,
+/// whose use is already recorded, or `None` at the top level.
+fn record_usage(items: Vec<ast::Item>, enclosing_origin: Option<Option<&str>>) -> Vec<ast::Item> {
+    // Records are part of the template whose use they record.
+    let record = |key: String, origin: &Option<String>| ast::Item {
+        origin: origin.clone(),
+        ..ast::Item::new(ast::ItemKind::Code(format!("{}({:?});", USAGE_NAME, key)))
+    };
+    let origin_of = |item: &ast::Item| item.origin.clone();
+
+    // Each run of items from a template is rendered from its start.
+    let mut recorded = Vec::new();
+    let mut run_records: Vec<Vec<ast::Item>> = Vec::new();
+
+    for (index, item) in items.iter().enumerate() {
+        let origin = origin_of(item);
+        let is_new_run = index == 0 || origin_of(&items[index - 1]) != origin;
+        let mut records = Vec::new();
+
+        if is_new_run && enclosing_origin != Some(origin.as_ref().map(|o| &o[..])) && !recorded.contains(&origin) {
+            let run: Vec<_> = items[index..].iter().take_while(|i| origin_of(i) == origin).cloned().collect();
+            let mut variables: Vec<_> = analysis::referenced_variables(&run.into())
+                .into_iter().map(|v| v.name).collect();
+            variables.sort();
+            variables.dedup();
+
+            if let Some(ref name) = origin {
+                records.push(record(format!("template:{}", name), &origin));
+            }
+            records.extend(variables.into_iter().map(|name| record(format!("variable:{}", name), &origin)));
+            recorded.push(origin);
+        }
+        run_records.push(records);
+    }
+
+    let mut instrumented = Vec::new();
+
+    for (item, records) in items.into_iter().zip(run_records) {
+        instrumented.extend(records);
+
+        let origin = item.origin.clone();
+        let start = item.span.start;
+        let body = |body: Vec<ast::Item>, kind: &str| -> Vec<ast::Item> {
+            let key = format!("block:{}:{}@{}", origin.as_ref().map_or("", |o| &o[..]), kind, start);
+            let mut items = vec![record(key, &origin)];
+            items.extend(record_usage(body, Some(origin.as_ref().map(|o| &o[..]))));
+            items
+        };
+        let block_name = item.kind.block_name();
+
+        let kind = match item.kind {
+            ast::ItemKind::Macro { name, parameters, body: b } => {
+                ast::ItemKind::Macro { name: name, parameters: parameters, body: body(b, block_name) }
+            },
+            ast::ItemKind::Capture { name, body: b } => ast::ItemKind::Capture { name: name, body: body(b, block_name) },
+            ast::ItemKind::If { condition, body: b, else_body } => ast::ItemKind::If {
+                condition: condition,
+                body: body(b, block_name),
+                else_body: if else_body.is_empty() { else_body } else { body(else_body, "else") },
+            },
+            ast::ItemKind::For { pattern, iterator, body: b } => {
+                ast::ItemKind::For { pattern: pattern, iterator: iterator, body: body(b, block_name) }
+            },
+            ast::ItemKind::File { path, body: b } => ast::ItemKind::File { path: path, body: body(b, block_name) },
+            ast::ItemKind::Cache { key, body: b } => ast::ItemKind::Cache { key: key, body: body(b, block_name) },
+            ast::ItemKind::With { name, value, body: b } => {
+                ast::ItemKind::With { name: name, value: value, body: body(b, block_name) }
+            },
+            ast::ItemKind::Block { name, limit, truncation, body: b } => {
+                ast::ItemKind::Block { name: name, limit: limit, truncation: truncation, body: body(b, block_name) }
+            },
+            ast::ItemKind::Tag { name, arguments, body: b } => {
+                let b = body(b, &name);
+                ast::ItemKind::Tag { name: name, arguments: arguments, body: b }
+            },
+            kind => kind,
+        };
+
+        instrumented.push(ast::Item { kind: kind, span: item.span, origin: item.origin });
+    }
+
+    instrumented
+}
+
+/// Gets the byte offsets of the line breaks in the text of each
+/// template, in order.
+///
+/// Tags never span lines, so the line an item begins on can be found
+/// from these.
+fn line_breaks(items: &[ast::Item]) -> HashMap<String, Vec<usize>> {
+    struct LineBreaks(HashMap<String, Vec<usize>>);
+
+    impl ast::Visitor for LineBreaks {
+        fn visit_item(&mut self, item: &ast::Item) {
+            let text = match item.kind {
+                ast::ItemKind::Text(ref text) => Some(text),
+                ast::ItemKind::FrontMatter { ref source, .. } => Some(source),
+                _ => None,
+            };
+            if let (Some(origin), Some(text)) = (item.origin.as_ref(), text) {
+                let offsets = self.0.entry(origin.clone()).or_insert_with(Vec::new);
+                offsets.extend(text.match_indices('\n').map(|(i, _)| item.span.start + i));
+            }
+            ast::walk_item(self, item);
+        }
+    }
+
+    let mut line_breaks = LineBreaks(HashMap::new());
+    for item in items {
+        ast::Visitor::visit_item(&mut line_breaks, item);
+    }
+
+    // Templates included more than once give the same offsets again.
+    for offsets in line_breaks.0.values_mut() {
+        offsets.sort();
+        offsets.dedup();
+    }
+    line_breaks.0
+}
+
+/// Wraps each run of items from a template, other than the template of
+/// the enclosing block, in comments naming it and the line the run
+/// begins on.
+fn annotate_origins(items: Vec<ast::Item>, syntax: comment::Syntax, line_breaks: &HashMap<String, Vec<usize>>,
+                    enclosing_origin: Option<&str>) -> Vec<ast::Item> {
+    let comment = |text: String, origin: &Option<String>| ast::Item {
+        origin: origin.clone(),
+        ..ast::Item::new(ast::ItemKind::Text(syntax.comment(&text)))
+    };
+
+    let mut annotated = Vec::new();
+    let mut items = items.into_iter().peekable();
+
+    while let Some(first) = items.next() {
+        let origin = first.origin.clone();
+        let mut run = vec![first];
+        while items.peek().map_or(false, |item| item.origin == origin) {
+            run.push(items.next().unwrap());
+        }
+
+        let run: Vec<_> = run.into_iter().map(|item| {
+            let kind = annotate_block_origins(item.kind, syntax, line_breaks, item.origin.as_ref().map(|o| &o[..]));
+            ast::Item { kind: kind, span: item.span, origin: item.origin }
+        }).collect();
+
+        match origin {
+            Some(ref name) if Some(&name[..]) != enclosing_origin => {
+                // Front matter writes nothing, so the output begins after it.
+                let start = run.iter().filter(|item| match item.kind {
+                    ast::ItemKind::FrontMatter { .. } => false,
+                    _ => true,
+                }).map(|item| item.span.start).min().unwrap_or(0);
+                let line = line_breaks.get(name).map_or(0, |offsets| offsets.partition_point(|&i| i < start)) + 1;
+
+                annotated.push(comment(format!("begin {}:{}", name, line), &origin));
+                annotated.extend(run);
+                annotated.push(comment(format!("end {}", name), &origin));
+            },
+            _ => annotated.extend(run),
+        }
     }
 
-    emit_main_function_start(&mut write)?;
+    annotated
+}
+
+/// Annotates the origins of the items in the bodies of a block.
+fn annotate_block_origins(kind: ast::ItemKind, syntax: comment::Syntax, line_breaks: &HashMap<String, Vec<usize>>,
+                          origin: Option<&str>) -> ast::ItemKind {
+    let annotate = |body| annotate_origins(body, syntax, line_breaks, origin);
+
+    match kind {
+        ast::ItemKind::Macro { name, parameters, body } => {
+            ast::ItemKind::Macro { name: name, parameters: parameters, body: annotate(body) }
+        },
+        ast::ItemKind::Capture { name, body } => ast::ItemKind::Capture { name: name, body: annotate(body) },
+        ast::ItemKind::If { condition, body, else_body } => ast::ItemKind::If {
+            condition: condition,
+            body: annotate(body),
+            else_body: annotate(else_body),
+        },
+        ast::ItemKind::For { pattern, iterator, body } => {
+            ast::ItemKind::For { pattern: pattern, iterator: iterator, body: annotate(body) }
+        },
+        ast::ItemKind::File { path, body } => ast::ItemKind::File { path: path, body: annotate(body) },
+        ast::ItemKind::Cache { key, body } => ast::ItemKind::Cache { key: key, body: annotate(body) },
+        ast::ItemKind::With { name, value, body } => {
+            ast::ItemKind::With { name: name, value: value, body: annotate(body) }
+        },
+        ast::ItemKind::Block { name, limit, truncation, body } => {
+            ast::ItemKind::Block { name: name, limit: limit, truncation: truncation, body: annotate(body) }
+        },
+        ast::ItemKind::Tag { name, arguments, body } => {
+            ast::ItemKind::Tag { name: name, arguments: arguments, body: annotate(body) }
+        },
+        kind => kind,
+    }
+}
+
+/// Finds the first block whose body is rendered by a closure and
+/// awaits a value, as closures cannot.
+struct AwaitInClosure<'a>
+{
+    config: &'a Config,
+    /// The name of the outermost enclosing block rendered by a closure.
+    closure: Option<String>,
+    found: Option<String>,
+}
+
+impl<'a> AwaitInClosure<'a>
+{
+    fn is_closure(&self, kind: &ast::ItemKind) -> bool {
+        match *kind {
+            ast::ItemKind::Macro { .. } | ast::ItemKind::Capture { .. } | ast::ItemKind::Tag { .. } => true,
+            ast::ItemKind::Block { limit, .. } => limit.is_some() || self.config.block_fallback.is_some(),
+            ast::ItemKind::Cache { .. } => self.config.fragment_cache,
+            ast::ItemKind::File { .. } => self.config.archive_files,
+            _ => false,
+        }
+    }
+}
+
+impl<'a> VisitorMut for AwaitInClosure<'a>
+{
+    fn visit_item_mut(&mut self, item: &mut ast::Item) {
+        if self.found.is_some() {
+            return;
+        }
+        if self.closure.is_some() && item.kind.code_mut().iter().any(|code| analysis::awaits(code)) {
+            self.found = self.closure.clone();
+            return;
+        }
+
+        let enclosing = self.closure.clone();
+        if enclosing.is_none() && self.is_closure(&item.kind) {
+            self.closure = Some(match item.kind {
+                ast::ItemKind::Tag { ref name, .. } => name.clone(),
+                ref kind => kind.block_name().to_owned(),
+            });
+        }
+        ast::walk_item_mut(self, item);
+        self.closure = enclosing;
+    }
+}
 
-    for item in ast.items {
+/// Emits the variables available to every template.
+fn emit_globals(config: &Config, write: &mut dyn Write) -> Result<(), io::Error> {
+    if let Some(ref env) = config.env {
+        emit_string_variable(ENV_GLOBAL_NAME, env, write)?;
+    }
+
+    for &(ref name, ref value) in config.variables.iter() {
+        emit_string_variable(name, value, write)?;
+    }
+
+    for &(ref name, ref table) in config.tables.iter() {
+        emit_table(name, table, write)?;
+    }
+
+    if let Some(syntax) = config.comment_syntax {
+        emit_comment_helper(syntax, write)?;
+    }
+
+    if config.translations {
+        emit_translation_helpers(write)?;
+    }
+
+    Ok(())
+}
+
+fn emit_items(items: Vec<ast::Item>, config: &Config, write: &mut dyn Write)
+    -> Result<(), Error> {
+    for item in items {
         match item.kind {
-            ast::ItemKind::Code { source, print_result } => {
-                emit_code(&source, print_result, &mut write)?;
+            ast::ItemKind::Default { name, value } => {
+                if !is_variable_given(&name, config) {
+                    emit_default(&name, &value, write)?;
+                }
+            },
+            ast::ItemKind::Set { name, value } => {
+                emit_set(&name, &value, write)?;
+            },
+            ast::ItemKind::Code(source) => {
+                emit_code(&source, false, write)?;
+            },
+            ast::ItemKind::Print(source) => {
+                if config.source_map { emit_mapping_start(write)?; }
+                emit_code(&source, true, write)?;
+                if config.source_map { emit_mapping_end(&item.span, &item.origin, write)?; }
             },
             ast::ItemKind::Text(text) => {
-                emit_text(&text, &mut write)?;
+                if config.source_map { emit_mapping_start(write)?; }
+                emit_text(&text, write)?;
+                if config.source_map { emit_mapping_end(&item.span, &item.origin, write)?; }
+            },
+            ast::ItemKind::Comment(..) |
+            ast::ItemKind::Directive { .. } => (),
+            ast::ItemKind::FrontMatter { fields, .. } => {
+                for (name, value) in fields {
+                    // Front matter only holds values with literals.
+                    if let (Some(ty), Some(literal)) = (value.rust_type(), value.rust_literal()) {
+                        if !is_variable_given(&name, config) {
+                            writeln!(write, "    #[allow(unused_variables)]")?;
+                            writeln!(write, "    let {}: {} = {};", name, ty, literal)?;
+                        }
+                    }
+                }
+            },
+            ast::ItemKind::Macro { name, parameters, body } => {
+                emit_macro(&name, &parameters, body, config, write)?;
+            },
+            ast::ItemKind::Capture { name, body } => {
+                emit_capture(&name, body, config, write)?;
+            },
+            ast::ItemKind::If { condition, body, else_body } => {
+                emit_if(&condition, body, else_body, config, write)?;
+            },
+            ast::ItemKind::For { pattern, iterator, body } => {
+                emit_for(&pattern, &iterator, body, config, write)?;
+            },
+            ast::ItemKind::File { path, body } => {
+                emit_file(&path, body, config, write)?;
+            },
+            ast::ItemKind::Cache { key, body } => {
+                emit_cache(&key, &item.origin, body, config, write)?;
+            },
+            ast::ItemKind::With { name, value, body } => {
+                emit_with(&name, &value, body, config, write)?;
+            },
+            ast::ItemKind::Block { name, limit, truncation, body } => {
+                match config.block_fallback {
+                    Some(ref fallback) => emit_fallible_block(&name, limit, truncation, body, fallback, config, write)?,
+                    None => emit_block(&name, limit, truncation, body, config, write)?,
+                }
+            },
+            ast::ItemKind::Tag { name, arguments, body } => {
+                if config.source_map { emit_mapping_start(write)?; }
+                emit_tag(&name, &arguments, body, config, write)?;
+                if config.source_map { emit_mapping_end(&item.span, &item.origin, write)?; }
+            },
+            ast::ItemKind::Include { name, .. } => {
+                return Err(ErrorKind::UnresolvedInclude(name).into());
+            },
+            ast::ItemKind::Assert { condition, message } => {
+                emit_assert(&condition, message.as_ref().map(|m| &m[..]), &item.span, &item.origin, write)?;
+            },
+            ast::ItemKind::Error(source) => {
+                return Err(ErrorKind::MalformedSource(source).into());
+            },
+            kind => {
+                return Err(ErrorKind::UnknownItem(ast::Item::new(kind).to_string()).into());
             },
         }
     }
 
-    emit_main_function_end(&mut write)?;
+    Ok(())
+}
 
-    Ok(String::from_utf8(write.into_inner()).unwrap())
+/// Emits the signature of the render function, with the parameter
+/// taking the function reporting differences if it is a canary.
+fn emit_main_function_start(config: &Config, is_canary: bool, write: &mut dyn Write)
+    -> Result<(), io::Error> {
+    let qualifier = if config.async_render { "async " } else { "" };
+    write!(write, "pub {}fn render({}: {}", qualifier, INTERNAL_WRITER_NAME, parameter_type(WRITER_TYPE, config))?;
+
+    if config.archive_files {
+        write!(write, ", {}: {}", ARCHIVE_NAME, parameter_type(WRITER_TYPE, config))?;
+    }
+
+    if is_canary {
+        write!(write, ", {}: {}", REPORT_DIFF_NAME, parameter_type(REPORT_DIFF_TYPE, config))?;
+    }
+
+    if config.translations {
+        write!(write, ", {}: {}", TRANSLATE_NAME, parameter_type(TRANSLATE_TYPE, config))?;
+    }
+
+    if config.fragment_cache {
+        write!(write, ", {}: {}", CACHE_NAME, parameter_type(CACHE_TYPE, config))?;
+    }
+
+    if config.usage {
+        write!(write, ", {}: {}", USAGE_NAME, parameter_type(USAGE_TYPE, config))?;
+    }
+
+    if config.render_stats {
+        write!(write, ", {}: &mut {}", STATS_NAME, STATS_TYPE)?;
+    }
+
+    if config.block_fallback.is_some() {
+        write!(write, ", {}: {}", BLOCK_FAILED_NAME, parameter_type(BLOCK_FAILED_TYPE, config))?;
+    }
+
+    for parameter in config.parameters.iter() {
+        write!(write, ", {}: {}", parameter.name, parameter.ty)?;
+    }
+
+    if config.source_map {
+        writeln!(write, ") -> Result<{}, ::std::io::Error> {{", SOURCE_MAP_TYPE)
+    } else {
+        writeln!(write, ") -> Result<(), ::std::io::Error> {{")
+    }
+}
+
+/// Gets the type of a parameter of the render function.
+///
+/// The writers and functions given to an async render function must be
+/// `Send` and `Sync`, so that its future can be sent between threads.
+fn parameter_type(ty: &str, config: &Config) -> String {
+    if !config.async_render {
+        ty.to_owned()
+    } else if let Some(rest) = ty.strip_prefix("&mut ") {
+        format!("&mut ({} + Send)", rest)
+    } else if let Some(rest) = ty.strip_prefix('&') {
+        format!("&({} + Sync)", rest)
+    } else {
+        ty.to_owned()
+    }
+}
+
+fn emit_main_function_end(config: &Config, write: &mut dyn Write) -> Result<(), io::Error> {
+    if config.archive_files {
+        // A tar archive ends with two empty blocks.
+        writeln!(write, "    {}.write_all(&[0; 1024])?;", ARCHIVE_NAME)?;
+    }
+
+    if config.render_stats {
+        emit_stats_end(write)?;
+    }
+
+    if config.source_map {
+        writeln!(write, "\n    ;Ok({})\n }}", SOURCE_MAP_NAME)
+    } else {
+        writeln!(write, "\n    ;Ok(())\n }}")
+    }
+}
+
+/// Emits the type holding the statistics of a render.
+fn emit_stats_type(write: &mut dyn Write) -> Result<(), io::Error> {
+    writeln!(write, "#[derive(Clone, Debug, Default)]")?;
+    writeln!(write, "pub struct {} {{", STATS_TYPE)?;
+    writeln!(write, "    pub blocks_evaluated: u64,")?;
+    writeln!(write, "    pub bytes_emitted: u64,")?;
+    writeln!(write, "    pub cache_hits: u64,")?;
+    writeln!(write, "    pub wall_time: ::std::time::Duration,")?;
+    writeln!(write, "}}\n")
+}
+
+/// Emits the counters of the statistics of a render, and a writer
+/// which counts the bytes written.
+fn emit_stats_start(write: &mut dyn Write) -> Result<(), io::Error> {
+    writeln!(write, "    let {} = ::std::time::Instant::now();", STATS_START_TIME_NAME)?;
+    writeln!(write, "    let {} = ::std::cell::Cell::new(0u64);", BLOCKS_EVALUATED_NAME)?;
+    writeln!(write, "    #[allow(unused_variables)]")?;
+    writeln!(write, "    let {} = ::std::cell::Cell::new(0u64);", CACHE_HITS_NAME)?;
+    writeln!(write, "    struct _Measured<'a> {{ inner: &'a mut ::std::io::Write, count: u64 }}")?;
+    writeln!(write, "    impl<'a> ::std::io::Write for _Measured<'a> {{")?;
+    writeln!(write, "        fn write(&mut self, bytes: &[u8]) -> ::std::io::Result<usize> {{")?;
+    writeln!(write, "            let count = self.inner.write(bytes)?;")?;
+    writeln!(write, "            self.count += count as u64;")?;
+    writeln!(write, "            Ok(count)")?;
+    writeln!(write, "        }}")?;
+    writeln!(write, "        fn flush(&mut self) -> ::std::io::Result<()> {{ self.inner.flush() }}")?;
+    writeln!(write, "    }}")?;
+    writeln!(write, "    let mut _measured = _Measured {{ inner: {}, count: 0 }};", INTERNAL_WRITER_NAME)?;
+    writeln!(write, "    let {} = &mut _measured;", INTERNAL_WRITER_NAME)
+}
+
+/// Emits the code filling in the statistics of a render.
+fn emit_stats_end(write: &mut dyn Write) -> Result<(), io::Error> {
+    writeln!(write, "    *{} = {} {{", STATS_NAME, STATS_TYPE)?;
+    writeln!(write, "        blocks_evaluated: {}.get(),", BLOCKS_EVALUATED_NAME)?;
+    writeln!(write, "        bytes_emitted: _measured.count,")?;
+    writeln!(write, "        cache_hits: {}.get(),", CACHE_HITS_NAME)?;
+    writeln!(write, "        wall_time: {}.elapsed(),", STATS_START_TIME_NAME)?;
+    writeln!(write, "    }};")
+}
+
+/// Adds code counting the blocks evaluated to the start of each body.
+fn count_blocks(items: &mut Vec<ast::Item>) {
+    struct CountBlocks;
+
+    impl ast::VisitorMut for CountBlocks {
+        fn visit_items_mut(&mut self, items: &mut Vec<ast::Item>) {
+            let code = format!("{0}.set({0}.get() + 1);", BLOCKS_EVALUATED_NAME);
+            items.insert(0, ast::Item::new(ast::ItemKind::Code(code)));
+            ast::walk_items_mut(self, items);
+        }
+    }
+
+    ast::walk_items_mut(&mut CountBlocks, items);
 }
 
-fn emit_main_function_start(write: &mut Write) -> Result<(), io::Error> {
-    writeln!(write, "pub fn render({}: &mut ::std::io::Write) -> Result<(), ::std::io::Error> {{",
-        INTERNAL_WRITER_NAME)
+/// Emits a writer which counts the bytes written, so that
+/// output can be mapped back to the template.
+fn emit_source_map_start(write: &mut dyn Write) -> Result<(), io::Error> {
+    writeln!(write, "    struct _Counted<'a> {{ inner: &'a mut ::std::io::Write, count: usize }}")?;
+    writeln!(write, "    impl<'a> ::std::io::Write for _Counted<'a> {{")?;
+    writeln!(write, "        fn write(&mut self, bytes: &[u8]) -> ::std::io::Result<usize> {{")?;
+    writeln!(write, "            let count = self.inner.write(bytes)?;")?;
+    writeln!(write, "            self.count += count;")?;
+    writeln!(write, "            Ok(count)")?;
+    writeln!(write, "        }}")?;
+    writeln!(write, "        fn flush(&mut self) -> ::std::io::Result<()> {{ self.inner.flush() }}")?;
+    writeln!(write, "    }}")?;
+    writeln!(write, "    let mut {}: {} = Vec::new();", SOURCE_MAP_NAME, SOURCE_MAP_TYPE)?;
+    writeln!(write, "    let mut _counted = _Counted {{ inner: {}, count: 0 }};", INTERNAL_WRITER_NAME)?;
+    writeln!(write, "    let {} = &mut _counted;", INTERNAL_WRITER_NAME)
 }
 
-fn emit_main_function_end(write: &mut Write) -> Result<(), io::Error> {
-    writeln!(write, "\n    ;Ok(())\n }}")
+/// Emits a function which writes a file into a tar archive.
+fn emit_archive_entry_helper(write: &mut dyn Write) -> Result<(), io::Error> {
+    writeln!(write, "    fn {}(archive: &mut ::std::io::Write, path: &str, contents: &[u8]) -> ::std::io::Result<()> {{",
+        ARCHIVE_ENTRY_HELPER_NAME)?;
+    writeln!(write, "        if path.len() > 100 {{")?;
+    writeln!(write, "            let message = format!(\"the path '{{}}' is too long for a tar archive\", path);")?;
+    writeln!(write, "            return Err(::std::io::Error::new(::std::io::ErrorKind::Other, message));")?;
+    writeln!(write, "        }}")?;
+    writeln!(write, "        let mut header = [0u8; 512];")?;
+    writeln!(write, "        let size = format!(\"{{:011o}}\", contents.len());")?;
+    writeln!(write, "        let fields: [(usize, &[u8]); 7] = [")?;
+    writeln!(write, "            (0, path.as_bytes()), (100, b\"0000644\"), (108, b\"0000000\"), (116, b\"0000000\"),")?;
+    writeln!(write, "            (124, size.as_bytes()), (136, b\"00000000000\"),")?;
+    writeln!(write, "            (257, b\"ustar\\x0000\"),")?;
+    writeln!(write, "        ];")?;
+    writeln!(write, "        for &(offset, field) in fields.iter() {{")?;
+    writeln!(write, "            header[offset..offset + field.len()].copy_from_slice(field);")?;
+    writeln!(write, "        }}")?;
+    writeln!(write, "        header[156] = b'0';")?;
+    writeln!(write, "        // The checksum is summed with its own field as spaces.")?;
+    writeln!(write, "        header[148..156].copy_from_slice(b\"        \");")?;
+    writeln!(write, "        let checksum: u32 = header.iter().map(|&b| b as u32).sum();")?;
+    writeln!(write, "        header[148..156].copy_from_slice(format!(\"{{:06o}}\\0 \", checksum).as_bytes());")?;
+    writeln!(write, "        archive.write_all(&header)?;")?;
+    writeln!(write, "        archive.write_all(contents)?;")?;
+    writeln!(write, "        archive.write_all(&[0; 512][..(512 - contents.len() % 512) % 512])")?;
+    writeln!(write, "    }}")
+}
+
+/// Emits the state needed to enforce limits, and a writer which
+/// fails when the output is too large.
+fn emit_limits_start(limits: &Limits, write: &mut dyn Write) -> Result<(), io::Error> {
+    if let Some(max_output_size) = limits.max_output_size {
+        let message = limit_message(ErrorKind::OutputLimitExceeded(max_output_size));
+
+        writeln!(write, "    struct _Limited<'a> {{ inner: &'a mut ::std::io::Write, count: usize }}")?;
+        writeln!(write, "    impl<'a> ::std::io::Write for _Limited<'a> {{")?;
+        writeln!(write, "        fn write(&mut self, bytes: &[u8]) -> ::std::io::Result<usize> {{")?;
+        writeln!(write, "            if self.count + bytes.len() > {} {{", max_output_size)?;
+        writeln!(write, "                return Err(::std::io::Error::new(::std::io::ErrorKind::Other, \"{}\"));",
+            message)?;
+        writeln!(write, "            }}")?;
+        writeln!(write, "            let count = self.inner.write(bytes)?;")?;
+        writeln!(write, "            self.count += count;")?;
+        writeln!(write, "            Ok(count)")?;
+        writeln!(write, "        }}")?;
+        writeln!(write, "        fn flush(&mut self) -> ::std::io::Result<()> {{ self.inner.flush() }}")?;
+        writeln!(write, "    }}")?;
+        writeln!(write, "    let mut _limited = _Limited {{ inner: {}, count: 0 }};", INTERNAL_WRITER_NAME)?;
+        writeln!(write, "    let {} = &mut _limited;", INTERNAL_WRITER_NAME)?;
+    }
+
+    if limits.max_loop_iterations.is_some() {
+        writeln!(write, "    let {} = ::std::cell::Cell::new(0usize);", ITERATIONS_NAME)?;
+    }
+
+    if limits.max_render_time.is_some() {
+        writeln!(write, "    let {} = ::std::time::Instant::now();", START_TIME_NAME)?;
+    }
+
+    if !limits.max_calls.is_empty() {
+        let counts = vec!["::std::cell::Cell::new(0usize)"; limits.max_calls.len()];
+        writeln!(write, "    let {} = [{}];", CALLS_NAME, counts.join(", "))?;
+    }
+
+    Ok(())
 }
 
-fn emit_code(source: &str, print_result: bool, write: &mut Write)
+/// Emits the checks made at the start of each iteration of a loop.
+fn emit_loop_limit_checks(limits: &Limits, write: &mut dyn Write) -> Result<(), io::Error> {
+    if let Some(max_loop_iterations) = limits.max_loop_iterations {
+        let message = limit_message(ErrorKind::IterationLimitExceeded(max_loop_iterations));
+
+        writeln!(write, "    {0}.set({0}.get() + 1);", ITERATIONS_NAME)?;
+        writeln!(write, "    if {}.get() > {} {{", ITERATIONS_NAME, max_loop_iterations)?;
+        writeln!(write, "        return Err(::std::io::Error::new(::std::io::ErrorKind::Other, \"{}\"));", message)?;
+        writeln!(write, "    }}")?;
+    }
+
+    if let Some(max_render_time) = limits.max_render_time {
+        let message = limit_message(ErrorKind::RenderTimeLimitExceeded(max_render_time));
+
+        writeln!(write, "    if {}.elapsed() > ::std::time::Duration::new({}, {}) {{", START_TIME_NAME,
+            max_render_time.as_secs(), max_render_time.subsec_nanos())?;
+        writeln!(write, "        return Err(::std::io::Error::new(::std::io::ErrorKind::Other, \"{}\"));", message)?;
+        writeln!(write, "    }}")?;
+    }
+
+    Ok(())
+}
+
+/// Rewrites calls of functions with limits so that each call is counted,
+/// failing the render once there are too many.
+struct CallLimits<'a>(&'a [(String, usize)]);
+
+impl<'a> VisitorMut for CallLimits<'a>
+{
+    fn visit_item_mut(&mut self, item: &mut ast::Item) {
+        for code in item.kind.code_mut() {
+            for (index, &(ref name, limit)) in self.0.iter().enumerate() {
+                let message = limit_message(ErrorKind::CallLimitExceeded(name.clone(), limit));
+
+                // Later calls are rewritten first, so the earlier ranges stay valid.
+                for call in analysis::function_calls(code, name).into_iter().rev() {
+                    let counted = format!("({{ {0}[{1}].set({0}[{1}].get() + 1); if {0}[{1}].get() > {2} {{ \
+                                           return Err(::std::io::Error::new(::std::io::ErrorKind::Other, \"{3}\")); \
+                                           }} {4} }})", CALLS_NAME, index, limit, message, &code[call.clone()]);
+                    code.replace_range(call, &counted);
+                }
+            }
+        }
+
+        ast::walk_item_mut(self, item)
+    }
+}
+
+/// Renders the named blocks which use optional data only when it is
+/// given, with the data unwrapped inside them.
+struct OptionalData<'a>
+{
+    keys: &'a [String],
+    placeholder: Option<&'a str>,
+    /// The keys unwrapped by the enclosing blocks.
+    unwrapped: Vec<String>,
+}
+
+impl<'a> VisitorMut for OptionalData<'a>
+{
+    fn visit_item_mut(&mut self, item: &mut ast::Item) {
+        let (name, keys) = match item.kind {
+            ast::ItemKind::Block { ref name, ref body, .. } => {
+                let uses = analysis::referenced_variables(&ast::Ast { items: body.clone() });
+                let keys: Vec<String> = self.keys.iter()
+                    .filter(|&key| !self.unwrapped.contains(key) && uses.iter().any(|v| v.name == *key))
+                    .cloned().collect();
+                (name.clone(), keys)
+            },
+            _ => return ast::walk_item_mut(self, item),
+        };
+
+        let depth = self.unwrapped.len();
+        self.unwrapped.extend(keys.iter().cloned());
+        ast::walk_item_mut(self, item);
+        self.unwrapped.truncate(depth);
+
+        if keys.is_empty() {
+            return;
+        }
+
+        let missing = match self.placeholder {
+            Some(placeholder) => ast::ItemKind::Text(placeholder.replace("{name}", &name)),
+            None => ast::ItemKind::Code(keys.iter().map(|key| {
+                let message = limit_message(ErrorKind::MissingOptionalData(name.clone(), key.clone()));
+                format!(" if {}.is_none() {{ return Err(::std::io::Error::new(::std::io::ErrorKind::Other, \"{}\")); }}",
+                        key, message)
+            }).collect()),
+        };
+
+        let patterns: Vec<_> = keys.iter().map(|key| format!("&Some(ref {})", key)).collect();
+        let values: Vec<_> = keys.iter().map(|key| format!("&{}", key)).collect();
+        let block = item.clone();
+        item.kind = ast::ItemKind::If {
+            condition: format!("let ({},) = ({},)", patterns.join(", "), values.join(", ")),
+            body: vec![block],
+            else_body: vec![ast::Item::with_span(missing, item.span.clone())],
+        };
+    }
+}
+
+/// Gets the message of the error given when a limit is exceeded,
+/// escaped for a string literal.
+fn limit_message(kind: ErrorKind) -> String {
+    let code = kind.code();
+    escape_string(&format!("{} [{}]", Error::from(kind), code))
+}
+
+fn emit_mapping_start(write: &mut dyn Write) -> Result<(), io::Error> {
+    writeln!(write, "    let _start = {}.count;", INTERNAL_WRITER_NAME)
+}
+
+fn emit_mapping_end(span: &::std::ops::Range<usize>, origin: &Option<String>, write: &mut dyn Write)
+    -> Result<(), io::Error> {
+    let origin = origin.as_ref().map(|o| &o[..]).unwrap_or("");
+    writeln!(write, "    {}.push((_start..{}.count, \"{}\", {}..{}));", SOURCE_MAP_NAME,
+        INTERNAL_WRITER_NAME, escape_string(origin), span.start, span.end)
+}
+
+fn emit_string_variable(name: &str, value: &str, write: &mut dyn Write)
+    -> Result<(), io::Error> {
+    writeln!(write, "    #[allow(unused_variables)]")?;
+    writeln!(write, "    let {}: &'static str = \"{}\";", name, escape_string(value))
+}
+
+fn emit_table(name: &str, table: &data::Table, write: &mut dyn Write)
+    -> Result<(), io::Error> {
+    let row_type = format!("_Row_{}", name);
+
+    writeln!(write, "    #[allow(non_camel_case_types, dead_code)]")?;
+    writeln!(write, "    struct {} {{", row_type)?;
+    for column in table.columns.iter() {
+        writeln!(write, "        {}: {},", column.name, column.ty.rust_type())?;
+    }
+    writeln!(write, "    }}")?;
+
+    writeln!(write, "    #[allow(unused_variables)]")?;
+    writeln!(write, "    let {}: Vec<{}> = vec![", name, row_type)?;
+    for row in table.rows.iter() {
+        write!(write, "        {} {{", row_type)?;
+        for (column, value) in table.columns.iter().zip(row) {
+            let value = match column.ty {
+                data::ColumnType::Float => format!("{:?}", value.parse::<f64>().unwrap()),
+                data::ColumnType::Text => format!("\"{}\"", escape_string(value)),
+                data::ColumnType::Int => value.parse::<i64>().unwrap().to_string(),
+                data::ColumnType::Bool => value.clone(),
+            };
+            write!(write, " {}: {},", column.name, value)?;
+        }
+        writeln!(write, " }},")?;
+    }
+    writeln!(write, "    ];")
+}
+
+fn emit_code(source: &str, print_result: bool, write: &mut dyn Write)
     -> Result<(), io::Error> {
     if print_result {
         writeln!(write, "    {{")?;
-        writeln!(write, "        let result = {};", source)?;
+        writeln!(write, "        let result = &({});", source)?;
         writeln!(write, "        write!({}, \"{{}}\", result)?;", INTERNAL_WRITER_NAME)?;
         writeln!(write, "    }}")?;
     } else {
@@ -64,14 +1233,390 @@ fn emit_code(source: &str, print_result: bool, write: &mut Write)
     Ok(())
 }
 
-fn emit_text(text: &str, write: &mut Write) -> Result<(), io::Error> {
-    writeln!(write, "    {}.write_all(b\"{}\")?;", INTERNAL_WRITER_NAME,
+fn emit_translation_helpers(write: &mut dyn Write) -> Result<(), io::Error> {
+    writeln!(write, "    #[allow(unused_variables)]")?;
+    writeln!(write, "    let {} = |key: &str| -> String {{", TRANSLATE_HELPER_NAME)?;
+    writeln!(write, "        {}(key, None).unwrap_or_else(|| key.to_owned())", TRANSLATE_NAME)?;
+    writeln!(write, "    }};")?;
+    writeln!(write, "    #[allow(unused_variables)]")?;
+    writeln!(write, "    let {} = |key: &str, count: i64| -> String {{", TRANSLATE_PLURAL_HELPER_NAME)?;
+    writeln!(write, "        {}(key, Some(count)).unwrap_or_else(|| key.to_owned())", TRANSLATE_NAME)?;
+    writeln!(write, "            .replace(\"{{count}}\", &count.to_string())")?;
+    writeln!(write, "    }};")
+}
+
+fn emit_comment_helper(syntax: comment::Syntax, write: &mut dyn Write)
+    -> Result<(), io::Error> {
+    writeln!(write, "    #[allow(unused_variables)]")?;
+    writeln!(write, "    let {} = |text: &str| -> String {{", COMMENT_HELPER_NAME)?;
+
+    match syntax {
+        comment::Syntax::Line(prefix) => {
+            let prefix = escape_string(prefix);
+            writeln!(write, "        text.lines().map(|line| if line.is_empty() {{")?;
+            writeln!(write, "            format!(\"{}\\n\")", prefix)?;
+            writeln!(write, "        }} else {{")?;
+            writeln!(write, "            format!(\"{} {{}}\\n\", line)", prefix)?;
+            writeln!(write, "        }}).collect()")?;
+        },
+        comment::Syntax::Block(open, close) => {
+            writeln!(write, "        format!(\"{} {{}} {}\\n\", text)",
+                escape_string(open), escape_string(close))?;
+        },
+    }
+
+    writeln!(write, "    }};")
+}
+
+/// Emits an if block as an `if` statement.
+fn emit_if(condition: &str, body: Vec<ast::Item>, else_body: Vec<ast::Item>,
+           config: &Config, write: &mut dyn Write) -> Result<(), Error> {
+    writeln!(write, "    if {} {{", condition)?;
+    emit_items(body, config, write)?;
+
+    if !else_body.is_empty() {
+        writeln!(write, "    }} else {{")?;
+        emit_items(else_body, config, write)?;
+    }
+
+    writeln!(write, "    }}")?;
+    Ok(())
+}
+
+/// Emits a for block as a `for` loop.
+fn emit_for(pattern: &str, iterator: &str, body: Vec<ast::Item>,
+            config: &Config, write: &mut dyn Write) -> Result<(), Error> {
+    writeln!(write, "    for {} in {} {{", pattern, iterator)?;
+    emit_loop_limit_checks(&config.limits, write)?;
+    emit_items(body, config, write)?;
+    writeln!(write, "    }}")?;
+    Ok(())
+}
+
+/// Emits a macro as a closure returning its rendered body.
+fn emit_macro(name: &str, parameters: &[String], body: Vec<ast::Item>,
+              config: &Config, write: &mut dyn Write) -> Result<(), Error> {
+    writeln!(write, "    #[allow(unused_variables)]")?;
+    writeln!(write, "    let {} = |{}| -> String {{", name, parameters.join(", "))?;
+    emit_rendered_string(body, config, write)?;
+    writeln!(write, "    }};")?;
+    Ok(())
+}
+
+/// Emits a capture as a variable holding its rendered body.
+fn emit_capture(name: &str, body: Vec<ast::Item>, config: &Config, write: &mut dyn Write)
+    -> Result<(), Error> {
+    writeln!(write, "    #[allow(unused_variables)]")?;
+    writeln!(write, "    let {}: String = {{", name)?;
+    emit_rendered_string(body, config, write)?;
+    writeln!(write, "    }};")?;
+    Ok(())
+}
+
+/// Emits a block whose output is fetched from the cache, and only
+/// rendered if it is not there.
+fn emit_cache(key: &str, origin: &Option<String>, body: Vec<ast::Item>, config: &Config, write: &mut dyn Write)
+    -> Result<(), Error> {
+    if !config.fragment_cache {
+        writeln!(write, "    {{")?;
+        emit_items(body, config, write)?;
+        writeln!(write, "    }}")?;
+        return Ok(());
+    }
+
+    // Cached output is not part of the source map.
+    let config = &Config { source_map: false, ..config.clone() };
+
+    writeln!(write, "    {{")?;
+    // Keys are prefixed with the template's name, so that templates
+    // sharing a store cannot use each other's fragments.
+    match *origin {
+        Some(ref origin) => writeln!(write, "    let _key = format!(\"{{}}:{{}}\", \"{}\", {});",
+                                     escape_string(origin), key)?,
+        None => writeln!(write, "    let _key = ::std::string::ToString::to_string(&({}));", key)?,
+    }
+    if config.render_stats {
+        // The fragment was found in the cache unless it was rendered.
+        writeln!(write, "    let mut _is_rendered = false;")?;
+        writeln!(write, "    let _fragment = {}(&_key, &mut || {{", CACHE_NAME)?;
+        writeln!(write, "    _is_rendered = true;")?;
+        emit_rendered_string(body, config, write)?;
+        writeln!(write, "    }});")?;
+        writeln!(write, "    if !_is_rendered {{ {0}.set({0}.get() + 1); }}", CACHE_HITS_NAME)?;
+    } else {
+        writeln!(write, "    let _fragment = {}(&_key, &mut || {{", CACHE_NAME)?;
+        emit_rendered_string(body, config, write)?;
+        writeln!(write, "    }});")?;
+    }
+    writeln!(write, "    {}.write_all(_fragment.as_bytes())?;", INTERNAL_WRITER_NAME)?;
+    writeln!(write, "    }}")?;
+    Ok(())
+}
+
+/// Emits a block with a variable referring to the value of an expression.
+fn emit_with(name: &str, value: &str, body: Vec<ast::Item>, config: &Config, write: &mut dyn Write)
+    -> Result<(), Error> {
+    writeln!(write, "    {{")?;
+    writeln!(write, "    #[allow(unused_variables)]")?;
+    writeln!(write, "    let {} = &({});", name, value)?;
+    emit_items(body, config, write)?;
+    writeln!(write, "    }}")?;
+    Ok(())
+}
+
+/// Emits a named block, whose output is rendered into a buffer and
+/// truncated if it has a limit.
+fn emit_block(name: &str, limit: Option<u64>, truncation: ast::Truncation, body: Vec<ast::Item>,
+              config: &Config, write: &mut dyn Write) -> Result<(), Error> {
+    let limit = match limit {
+        Some(limit) => limit,
+        None => {
+            writeln!(write, "    {{")?;
+            emit_items(body, config, write)?;
+            writeln!(write, "    }}")?;
+            return Ok(());
+        },
+    };
+
+    // Output which may be truncated is not part of the source map.
+    let config = &Config { source_map: false, ..config.clone() };
+
+    writeln!(write, "    {{")?;
+    writeln!(write, "    let mut _buffer: Vec<u8> = Vec::new();")?;
+    writeln!(write, "    (|{}: &mut ::std::io::Write| -> Result<(), ::std::io::Error> {{", INTERNAL_WRITER_NAME)?;
+    emit_items(body, config, write)?;
+    writeln!(write, "    Ok(())")?;
+    writeln!(write, "    }})(&mut _buffer)?;")?;
+    writeln!(write, "    if _buffer.len() > {} {{", limit)?;
+
+    match truncation {
+        ast::Truncation::Error => {
+            let message = limit_message(ErrorKind::BlockLimitExceeded(name.to_owned(), limit));
+            writeln!(write, "        return Err(::std::io::Error::new(::std::io::ErrorKind::Other, \"{}\"));", message)?;
+        },
+        ast::Truncation::Cut | ast::Truncation::Ellipsis => {
+            let ellipsis = "\u{2026}";
+            let end = match truncation {
+                ast::Truncation::Ellipsis => limit.saturating_sub(ellipsis.len() as u64),
+                _ => limit,
+            };
+
+            // Output is only cut between characters.
+            writeln!(write, "        let mut _end = {};", end)?;
+            writeln!(write, "        while _end > 0 && (_buffer[_end] & 0xc0) == 0x80 {{ _end -= 1; }}")?;
+            writeln!(write, "        _buffer.truncate(_end);")?;
+            if truncation == ast::Truncation::Ellipsis && limit >= ellipsis.len() as u64 {
+                writeln!(write, "        _buffer.extend_from_slice({:?}.as_bytes());", ellipsis)?;
+            }
+        },
+    }
+
+    writeln!(write, "    }}")?;
+    writeln!(write, "    {}.write_all(&_buffer)?;", INTERNAL_WRITER_NAME)?;
+    writeln!(write, "    }}")?;
+    Ok(())
+}
+
+/// Emits a named block whose output is replaced by the fallback if it
+/// fails, recording the failure.
+fn emit_fallible_block(name: &str, limit: Option<u64>, truncation: ast::Truncation, body: Vec<ast::Item>,
+                       fallback: &str, config: &Config, write: &mut dyn Write) -> Result<(), Error> {
+    // Output which may be replaced is not part of the source map.
+    let config = &Config { source_map: false, ..config.clone() };
+    let fallback = fallback.replace("{name}", name);
+
+    writeln!(write, "    {{")?;
+    writeln!(write, "    let mut _block_output: Vec<u8> = Vec::new();")?;
+    writeln!(write, "    let _result = (|{}: &mut ::std::io::Write| -> Result<(), ::std::io::Error> {{", INTERNAL_WRITER_NAME)?;
+    emit_block(name, limit, truncation, body, config, write)?;
+    writeln!(write, "    Ok(())")?;
+    writeln!(write, "    }})(&mut _block_output);")?;
+    writeln!(write, "    match _result {{")?;
+    writeln!(write, "        Ok(()) => {}.write_all(&_block_output)?,", INTERNAL_WRITER_NAME)?;
+    writeln!(write, "        Err(ref _error) => {{")?;
+    writeln!(write, "            let _id = {}(\"{}\", _error);", BLOCK_FAILED_NAME, escape_string(name))?;
+    writeln!(write, "            {}.write_all(\"{}\".replace(\"{{id}}\", &_id).as_bytes())?;",
+             INTERNAL_WRITER_NAME, escape_string(&fallback))?;
+    writeln!(write, "        }},")?;
+    writeln!(write, "    }}")?;
+    writeln!(write, "    }}")?;
+    Ok(())
+}
+
+/// Emits a block tag defined by the application, printing the
+/// expression its handler gives for the rendered body.
+fn emit_tag(name: &str, arguments: &str, body: Vec<ast::Item>, config: &Config, write: &mut dyn Write)
+    -> Result<(), Error> {
+    let expression = match config.tags.get(name) {
+        Some(handler) => handler.render(arguments, TAG_BODY_NAME),
+        None => return Err(ErrorKind::UnknownTag(name.to_owned()).into()),
+    };
+
+    writeln!(write, "    {{")?;
+    writeln!(write, "    #[allow(unused_variables)]")?;
+    writeln!(write, "    let {}: String = {{", TAG_BODY_NAME)?;
+    emit_rendered_string(body, config, write)?;
+    writeln!(write, "    }};")?;
+    emit_code(&expression, true, write)?;
+    writeln!(write, "    }}")?;
+    Ok(())
+}
+
+/// Emits a block which renders items into a file, creating the
+/// directories the file is in.
+fn emit_file(path: &str, body: Vec<ast::Item>, config: &Config, write: &mut dyn Write)
+    -> Result<(), Error> {
+    // Output written to other files is not part of the source map.
+    let config = &Config { source_map: false, ..config.clone() };
+
+    if config.archive_files {
+        writeln!(write, "    {{")?;
+        writeln!(write, "    let _path = ::std::string::ToString::to_string(&{});", path)?;
+        writeln!(write, "    let _contents = {{")?;
+        emit_rendered_string(body, config, write)?;
+        writeln!(write, "    }};")?;
+        writeln!(write, "    {}({}, &_path, _contents.as_bytes())?;", ARCHIVE_ENTRY_HELPER_NAME, ARCHIVE_NAME)?;
+        writeln!(write, "    }}")?;
+        return Ok(());
+    }
+
+    writeln!(write, "    {{")?;
+    writeln!(write, "    let _path = ::std::path::PathBuf::from({});", path)?;
+    writeln!(write, "    if let Some(parent) = _path.parent() {{ ::std::fs::create_dir_all(parent)?; }}")?;
+    writeln!(write, "    let mut _file = ::std::io::BufWriter::new(::std::fs::File::create(&_path)?);")?;
+    writeln!(write, "    {{")?;
+    writeln!(write, "    let {}: &mut ::std::io::Write = &mut _file;", INTERNAL_WRITER_NAME)?;
+    emit_items(body, config, write)?;
+    writeln!(write, "    }}")?;
+    writeln!(write, "    ::std::io::Write::flush(&mut _file)?;")?;
+    writeln!(write, "    }}")?;
+    Ok(())
+}
+
+/// Emits an expression which renders items into a `String`.
+fn emit_rendered_string(items: Vec<ast::Item>, config: &Config, write: &mut dyn Write)
+    -> Result<(), Error> {
+    // Rendered strings are written to a buffer rather than the output.
+    let config = &Config { source_map: false, ..config.clone() };
+
+    writeln!(write, "    let mut _buffer: Vec<u8> = Vec::new();")?;
+    writeln!(write, "    (|{}: &mut ::std::io::Write| -> Result<(), ::std::io::Error> {{",
+        INTERNAL_WRITER_NAME)?;
+
+    emit_items(items, config, write)?;
+
+    writeln!(write, "    Ok(())")?;
+    // Writing into a vector cannot fail.
+    writeln!(write, "    }})(&mut _buffer).unwrap();")?;
+    writeln!(write, "    String::from_utf8(_buffer).unwrap()")?;
+    Ok(())
+}
+
+/// Emits an assertion which makes rendering fail with an error
+/// if its condition does not hold.
+///
+/// Inside macros and captures, which cannot fail, a failed
+/// assertion panics with the error instead.
+fn emit_assert(condition: &str, message: Option<&str>, span: &::std::ops::Range<usize>,
+               origin: &Option<String>, write: &mut dyn Write) -> Result<(), io::Error> {
+    let message = match message {
+        Some(message) => message.to_owned(),
+        None => format!("assertion failed: {}", escape_string(condition.trim())),
+    };
+    let location = match *origin {
+        Some(ref origin) => format!("{}, bytes {}..{}", escape_string(origin), span.start, span.end),
+        None => format!("bytes {}..{}", span.start, span.end),
+    };
+
+    writeln!(write, "    if !({}) {{", condition)?;
+    writeln!(write, "        return Err(::std::io::Error::new(::std::io::ErrorKind::Other, \"{} (at {})\"));",
+        message, location)?;
+    writeln!(write, "    }}")
+}
+
+fn emit_default(name: &str, value: &str, write: &mut dyn Write)
+    -> Result<(), io::Error> {
+    writeln!(write, "    #[allow(unused_variables)]")?;
+    writeln!(write, "    let {} = {};", name, value)
+}
+
+/// Binds a variable until the end of the enclosing block, shadowing
+/// any variable with the same name.
+fn emit_set(name: &str, value: &str, write: &mut dyn Write)
+    -> Result<(), io::Error> {
+    writeln!(write, "    #[allow(unused_variables)]")?;
+    writeln!(write, "    let {} = {};", name, value)
+}
+
+/// Checks whether the configuration gives a value for a variable.
+fn is_variable_given(name: &str, config: &Config) -> bool {
+    (name == ENV_GLOBAL_NAME && config.env.is_some()) ||
+        config.variables.iter().any(|&(ref n, _)| n == name) ||
+        config.tables.iter().any(|&(ref n, _)| n == name) ||
+        config.parameters.iter().any(|p| p.name == name)
+}
+
+fn emit_text(text: &str, write: &mut dyn Write) -> Result<(), io::Error> {
+    writeln!(write, "    {}.write_all(\"{}\".as_bytes())?;", INTERNAL_WRITER_NAME,
         escape_string(text))
 }
 
-fn emit_entry_point(write: &mut Write) -> Result<(), io::Error> {
+fn emit_entry_point(config: &Config, write: &mut dyn Write) -> Result<(), io::Error> {
+    let mut arguments = if config.archive_files {
+        "&mut ::std::io::sink(), &mut ::std::io::stdout()".to_owned()
+    } else {
+        "&mut ::std::io::stdout()".to_owned()
+    };
+
+    if config.translations {
+        arguments.push_str(", &|_, _| None");
+    }
+
+    if config.fragment_cache {
+        arguments.push_str(", &|_, render| render()");
+    }
+
+    if config.usage {
+        arguments.push_str(", &|_| ()");
+    }
+
+    if config.render_stats {
+        arguments.push_str(&format!(", &mut {}::default()", STATS_TYPE));
+    }
+
+    if config.block_fallback.is_some() {
+        arguments.push_str(", &|name, e| { _failures.set(_failures.get() + 1); \
+                            eprintln!(\"warning: block '{}' failed with error {}: {}\", name, _failures.get(), e); \
+                            _failures.get().to_string() }");
+    }
+
     writeln!(write, "fn main() {{")?;
-    writeln!(write, "    render(&mut ::std::io::stdout()).unwrap()")?;
+
+    if config.block_fallback.is_some() {
+        // Failed blocks are numbered in the order they fail.
+        writeln!(write, "    let _failures = ::std::cell::Cell::new(0);")?;
+    }
+
+    if config.source_map {
+        writeln!(write, "    use std::io::Write;")?;
+        writeln!(write, "    let source_map = match render({}) {{", arguments)?;
+        writeln!(write, "        Ok(source_map) => source_map,")?;
+        writeln!(write, "        Err(e) => {{ eprintln!(\"error: {{}}\", e); ::std::process::exit(1) }},")?;
+        writeln!(write, "    }};")?;
+        writeln!(write, "    if let Some(path) = ::std::env::args().nth(1) {{")?;
+        writeln!(write, "        let mut file = ::std::fs::File::create(path).unwrap();")?;
+        writeln!(write, "        for (output, origin, span) in source_map {{")?;
+        writeln!(write, "            writeln!(file, \"{{}}..{{}}\\t{{}}\\t{{}}..{{}}\", output.start, output.end,")?;
+        writeln!(write, "                     origin, span.start, span.end).unwrap();")?;
+        writeln!(write, "        }}")?;
+        writeln!(write, "    }}")?;
+    } else {
+        writeln!(write, "    if let Err(e) = render({}) {{", arguments)?;
+        writeln!(write, "        eprintln!(\"error: {{}}\", e);")?;
+        writeln!(write, "        ::std::process::exit(1);")?;
+        writeln!(write, "    }}")?;
+    }
+
     writeln!(write, "}}\n")?;
 
     Ok(())
@@ -91,6 +1636,24 @@ impl Default for Config
     fn default() -> Self {
         Config {
             include_entry_point: false,
+            env: None,
+            variables: Vec::new(),
+            tables: Vec::new(),
+            comment_syntax: None,
+            header: None,
+            parameters: Vec::new(),
+            source_map: false,
+            limits: Limits::default(),
+            async_render: false,
+            archive_files: false,
+            translations: false,
+            fragment_cache: false,
+            usage: false,
+            annotate_origins: false,
+            render_stats: false,
+            placeholder: None,
+            block_fallback: None,
+            tags: tag::Tags::new(),
         }
     }
 }
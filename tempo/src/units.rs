@@ -0,0 +1,283 @@
+//! Durations and sizes, as written in configuration files.
+//!
+//! These are for use by the code in templates, so that values such as
+//! `"5m"` or `"2GiB"` can be parsed, added together and printed again
+//! in the same style.
+
+use {Error, ErrorKind};
+
+use std::{fmt, ops, time};
+use std::str::FromStr;
+
+/// The units of durations, longest first, with their lengths in nanoseconds.
+const DURATION_UNITS: &'static [(&'static str, u64)] = &[
+    ("w", 7 * 24 * 60 * 60 * 1_000_000_000),
+    ("d", 24 * 60 * 60 * 1_000_000_000),
+    ("h", 60 * 60 * 1_000_000_000),
+    ("m", 60 * 1_000_000_000),
+    ("s", 1_000_000_000),
+    ("ms", 1_000_000),
+    ("us", 1_000),
+    ("ns", 1),
+];
+
+/// The binary units of sizes, largest first.
+const BINARY_SIZE_UNITS: &'static [(&'static str, u64)] = &[
+    ("PiB", 1 << 50),
+    ("TiB", 1 << 40),
+    ("GiB", 1 << 30),
+    ("MiB", 1 << 20),
+    ("KiB", 1 << 10),
+];
+
+/// The decimal units of sizes.
+const DECIMAL_SIZE_UNITS: &'static [(&'static str, u64)] = &[
+    ("PB", 1_000_000_000_000_000),
+    ("TB", 1_000_000_000_000),
+    ("GB", 1_000_000_000),
+    ("MB", 1_000_000),
+    ("kB", 1_000),
+    ("KB", 1_000),
+    ("B", 1),
+];
+
+/// A length of time, such as `1h30m`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Duration(pub time::Duration);
+
+/// A number of bytes, such as `2GiB`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteSize(pub u64);
+
+impl Duration
+{
+    /// Gets the length of the duration in nanoseconds.
+    fn nanos(&self) -> u64 {
+        self.0.as_secs() * 1_000_000_000 + self.0.subsec_nanos() as u64
+    }
+}
+
+impl FromStr for Duration
+{
+    type Err = Error;
+
+    /// Parses a duration made of whole numbers of units, such as
+    /// `90s` or `1h30m`.
+    ///
+    /// The units are `w`, `d`, `h`, `m`, `s`, `ms`, `us` and `ns`.
+    fn from_str(text: &str) -> Result<Self, Error> {
+        let invalid = || -> Error { ErrorKind::InvalidQuantity(text.to_owned()).into() };
+        let mut rest = text.trim();
+        let mut nanos: u64 = 0;
+
+        if rest.is_empty() {
+            return Err(invalid());
+        }
+
+        while !rest.is_empty() {
+            let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+            let count: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+            rest = &rest[digits..];
+
+            let unit_length = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+            let length = DURATION_UNITS.iter().find(|&&(unit, _)| unit == rest[..unit_length].trim())
+                .map(|&(_, length)| length).ok_or_else(&invalid)?;
+            rest = &rest[unit_length..];
+
+            nanos = count.checked_mul(length).and_then(|n| nanos.checked_add(n)).ok_or_else(&invalid)?;
+        }
+
+        Ok(Duration(time::Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)))
+    }
+}
+
+impl fmt::Display for Duration
+{
+    /// Writes the duration in the largest units possible, such as `1h30m`.
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut nanos = self.nanos();
+
+        if nanos == 0 {
+            return write!(fmt, "0s");
+        }
+
+        for &(unit, length) in DURATION_UNITS {
+            if nanos >= length {
+                write!(fmt, "{}{}", nanos / length, unit)?;
+                nanos %= length;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for ByteSize
+{
+    type Err = Error;
+
+    /// Parses a size such as `512`, `10 kB` or `1.5GiB`.
+    ///
+    /// Binary units (`KiB`, `MiB`, `GiB`, `TiB`, `PiB`) are powers of
+    /// 1024, and decimal units (`kB`, `MB`, `GB`, `TB`, `PB`) are powers
+    /// of 1000. A number without a unit is in bytes.
+    fn from_str(text: &str) -> Result<Self, Error> {
+        let invalid = || -> Error { ErrorKind::InvalidQuantity(text.to_owned()).into() };
+        let text = text.trim();
+
+        let number_length = text.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(text.len());
+        let (number, unit) = text.split_at(number_length);
+        let unit = unit.trim();
+
+        let multiplier = if unit.is_empty() {
+            1
+        } else {
+            BINARY_SIZE_UNITS.iter().chain(DECIMAL_SIZE_UNITS)
+                .find(|&&(name, _)| name == unit)
+                .map(|&(_, multiplier)| multiplier).ok_or_else(&invalid)?
+        };
+
+        let mut parts = number.splitn(2, '.');
+        let whole: u64 = parts.next().unwrap().parse().map_err(|_| invalid())?;
+        let bytes = whole.checked_mul(multiplier).ok_or_else(&invalid)?;
+
+        // Fractions are exact, as long as they come to whole bytes.
+        let fraction_bytes = match parts.next() {
+            Some(fraction) if fraction.len() <= 9 => {
+                let numerator: u64 = fraction.parse().map_err(|_| invalid())?;
+                let denominator = 10u64.pow(fraction.len() as u32);
+                numerator.checked_mul(multiplier).ok_or_else(&invalid)? / denominator
+            },
+            Some(..) => return Err(invalid()),
+            None => 0,
+        };
+
+        bytes.checked_add(fraction_bytes).map(ByteSize).ok_or_else(&invalid)
+    }
+}
+
+impl fmt::Display for ByteSize
+{
+    /// Writes the size in the largest binary unit it is at least one
+    /// of, with at most one decimal place, such as `1.5 GiB`.
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let unit = BINARY_SIZE_UNITS.iter().find(|&&(_, multiplier)| self.0 >= multiplier);
+
+        match unit {
+            Some(&(name, multiplier)) => {
+                let amount = format!("{:.1}", self.0 as f64 / multiplier as f64);
+                write!(fmt, "{} {}", amount.trim_end_matches(".0"), name)
+            },
+            None => write!(fmt, "{} B", self.0),
+        }
+    }
+}
+
+impl ops::Add for Duration
+{
+    type Output = Duration;
+
+    fn add(self, other: Duration) -> Duration { Duration(self.0 + other.0) }
+}
+
+impl ops::Sub for Duration
+{
+    type Output = Duration;
+
+    fn sub(self, other: Duration) -> Duration { Duration(self.0 - other.0) }
+}
+
+impl ops::Mul<u32> for Duration
+{
+    type Output = Duration;
+
+    fn mul(self, factor: u32) -> Duration { Duration(self.0 * factor) }
+}
+
+impl ops::Div<u32> for Duration
+{
+    type Output = Duration;
+
+    fn div(self, divisor: u32) -> Duration { Duration(self.0 / divisor) }
+}
+
+impl ops::Add for ByteSize
+{
+    type Output = ByteSize;
+
+    fn add(self, other: ByteSize) -> ByteSize { ByteSize(self.0 + other.0) }
+}
+
+impl ops::Sub for ByteSize
+{
+    type Output = ByteSize;
+
+    fn sub(self, other: ByteSize) -> ByteSize { ByteSize(self.0 - other.0) }
+}
+
+impl ops::Mul<u64> for ByteSize
+{
+    type Output = ByteSize;
+
+    fn mul(self, factor: u64) -> ByteSize { ByteSize(self.0 * factor) }
+}
+
+impl ops::Div<u64> for ByteSize
+{
+    type Output = ByteSize;
+
+    fn div(self, divisor: u64) -> ByteSize { ByteSize(self.0 / divisor) }
+}
+
+impl From<time::Duration> for Duration
+{
+    fn from(duration: time::Duration) -> Self { Duration(duration) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn duration(text: &str) -> Duration {
+        text.parse().unwrap()
+    }
+
+    fn size(text: &str) -> ByteSize {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(duration("5m").0, time::Duration::from_secs(300));
+        assert_eq!(duration("1h30m").0, time::Duration::from_secs(5400));
+        assert_eq!(duration("1s250ms").0, time::Duration::from_millis(1250));
+        assert!("5".parse::<Duration>().is_err());
+        assert!("5y".parse::<Duration>().is_err());
+        assert!("".parse::<Duration>().is_err());
+    }
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(duration("90s").to_string(), "1m30s");
+        assert_eq!((duration("1h") * 2 + duration("1500ms")).to_string(), "2h1s500ms");
+        assert_eq!(Duration::default().to_string(), "0s");
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(size("512"), ByteSize(512));
+        assert_eq!(size("2GiB"), ByteSize(2 << 30));
+        assert_eq!(size("10 kB"), ByteSize(10_000));
+        assert_eq!(size("1.5KiB"), ByteSize(1536));
+        assert!("2 GiBs".parse::<ByteSize>().is_err());
+        assert!("99999999999PiB".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(size("1.5GiB").to_string(), "1.5 GiB");
+        assert_eq!((size("1GiB") * 2).to_string(), "2 GiB");
+        assert_eq!((size("1MB") - size("1kB")).to_string(), "975.6 KiB");
+        assert_eq!(size("100").to_string(), "100 B");
+    }
+}
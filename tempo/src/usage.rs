@@ -0,0 +1,81 @@
+//! Counting the use of templates in production.
+//!
+//! Templates translated with `trans::Config::usage` report the templates,
+//! blocks and variables each render uses through a function given to
+//! their render function. `UsageCounts` gives one with `recorder`, and
+//! counts the uses across every render.
+//!
+//! ```text
+//! render(&mut output, &counts.recorder())
+//! ```
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// The number of times each template, block and variable was used.
+#[derive(Debug, Default)]
+pub struct UsageCounts
+{
+    counts: Mutex<BTreeMap<String, u64>>,
+}
+
+impl UsageCounts
+{
+    /// Creates counts with nothing used.
+    pub fn new() -> Self {
+        UsageCounts::default()
+    }
+
+    /// Records a use of the template, block or variable with a key.
+    pub fn record(&self, key: &str) {
+        *self.counts.lock().unwrap().entry(key.to_owned()).or_insert(0) += 1;
+    }
+
+    /// Gets a function which records uses, as taken by render functions.
+    pub fn recorder<'a>(&'a self) -> Box<dyn Fn(&'static str) + 'a> {
+        Box::new(move |key| self.record(key))
+    }
+
+    /// Gets the number of times a key was used.
+    pub fn count(&self, key: &str) -> u64 {
+        self.counts.lock().unwrap().get(key).cloned().unwrap_or(0)
+    }
+
+    /// Gets the number of times each key was used, in order of the keys.
+    pub fn counts(&self) -> BTreeMap<String, u64> {
+        self.counts.lock().unwrap().clone()
+    }
+
+    /// Finds the templates which were never rendered, out of the names
+    /// of every template, such as from `Engine::template_names`.
+    pub fn unused_templates<'a>(&self, names: &[&'a str]) -> Vec<&'a str> {
+        let counts = self.counts.lock().unwrap();
+        names.iter().cloned().filter(|name| !counts.contains_key(&format!("template:{}", name))).collect()
+    }
+
+    /// Removes every count, such as after they have been reported.
+    pub fn clear(&self) {
+        self.counts.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counts_uses() {
+        let counts = UsageCounts::new();
+        {
+            let record = counts.recorder();
+            record("template:page");
+            record("variable:user");
+            record("template:page");
+        }
+
+        assert_eq!(counts.count("template:page"), 2);
+        assert_eq!(counts.count("template:footer"), 0);
+        assert_eq!(counts.unused_templates(&["page", "footer"]), vec!["footer"]);
+        assert_eq!(counts.counts().len(), 2);
+    }
+}
@@ -0,0 +1,390 @@
+//! Checks on templates submitted by untrusted users, such as the
+//! customers of a hosted product, before they are accepted.
+//!
+//! A template is parsed, its features and data checked against a
+//! policy, linted, and its size estimated, giving a single verdict.
+//!
+//! Templates are translated into Rust, so any expression in a template
+//! can call any function in scope where it is compiled. The default
+//! policy limits expressions to a simple grammar of variables, fields,
+//! literals and operators, calling only the template's macros and a list
+//! of methods, and the patterns of loops and parameters of macros to
+//! names and simple types. Code tags are not checked, so should not be
+//! allowed.
+
+use Error;
+use {analysis, ast, lint, parse};
+use ast::{AstExt, Feature, Visitor};
+
+use std::fmt;
+use std::ops::Range;
+
+/// What a template must satisfy to be accepted.
+#[derive(Clone, Debug)]
+pub struct Policy
+{
+    /// How the template is parsed, including its largest size and number
+    /// of fragments.
+    pub parse_config: parse::Config,
+    /// The language features the template may use.
+    pub allowed_features: Vec<Feature>,
+    /// The names of the data given to the template, if it may only use
+    /// those.
+    pub variables: Option<Vec<String>>,
+    /// Whether lint warnings reject the template, rather than being
+    /// reported alongside it.
+    pub deny_warnings: bool,
+    /// The most items the template may have, including those in blocks.
+    pub max_items: Option<usize>,
+    /// The most memory the parsed template may take up, in bytes.
+    pub max_memory: Option<usize>,
+    /// The methods expressions may call, if they are limited to the
+    /// grammar of `analysis::disallowed_construct`.
+    pub allowed_methods: Option<Vec<String>>,
+}
+
+/// A reason a template is rejected.
+#[derive(Debug)]
+pub enum Violation
+{
+    /// The template could not be parsed, or is over the parsing limits.
+    Invalid(Error),
+    /// The template uses a feature the policy does not allow.
+    Feature {
+        feature: Feature,
+        /// The byte range of the item using the feature.
+        span: Range<usize>,
+    },
+    /// An expression is outside of the grammar the policy allows.
+    Expression {
+        /// A description of the construct, such as `a path`.
+        construct: String,
+        /// The byte range of the item with the expression.
+        span: Range<usize>,
+    },
+    /// The pattern of a `for` block or a macro parameter is outside of
+    /// the grammar the policy allows.
+    Binding {
+        /// A description of the construct, such as `'|'`.
+        construct: String,
+        /// The byte range of the item with the binding.
+        span: Range<usize>,
+    },
+    /// The template uses data which it is not given.
+    UnknownVariable {
+        name: String,
+        /// The byte range of the use of the variable.
+        span: Range<usize>,
+    },
+    /// A lint warning, when warnings are denied.
+    Lint(lint::Warning),
+    /// The template has more items than the policy allows.
+    TooManyItems {
+        count: usize,
+        limit: usize,
+    },
+    /// The parsed template takes up more memory than the policy allows.
+    TooMuchMemory {
+        size: usize,
+        limit: usize,
+    },
+}
+
+/// The result of validating a template.
+#[derive(Debug, Default)]
+pub struct Verdict
+{
+    /// The reasons the template is rejected, in the order they were found.
+    pub violations: Vec<Violation>,
+    /// Lint warnings which do not reject the template.
+    pub warnings: Vec<lint::Warning>,
+    /// The features the template uses.
+    pub features: Vec<Feature>,
+    /// The number of items in the template.
+    pub item_count: usize,
+    /// An estimate of the memory the parsed template takes up, in bytes.
+    pub memory_size: usize,
+}
+
+impl Policy
+{
+    /// Creates a policy which allows the given features and no others,
+    /// with no limits.
+    pub fn allowing(features: &[Feature]) -> Self {
+        Policy {
+            parse_config: parse::Config::default(),
+            allowed_features: features.to_vec(),
+            variables: None,
+            deny_warnings: false,
+            max_items: None,
+            max_memory: None,
+            allowed_methods: None,
+        }
+    }
+}
+
+impl Default for Policy
+{
+    /// Allows printing data, conditions, loops and the blocks which only
+    /// change how output is rendered. Code tags, files, caches, includes,
+    /// custom tags and directives are not allowed.
+    ///
+    /// Expressions may only call common methods of strings, collections
+    /// and options which do not modify them.
+    fn default() -> Self {
+        let mut policy = Policy::allowing(&[
+            Feature::Print,
+            Feature::Condition,
+            Feature::Loop,
+            Feature::Macro,
+            Feature::Capture,
+            Feature::Set,
+            Feature::Default,
+            Feature::Block,
+            Feature::With,
+            Feature::FrontMatter,
+        ]);
+
+        let methods = [
+            "len", "is_empty", "contains", "starts_with", "ends_with", "to_string", "to_uppercase",
+            "to_lowercase", "trim", "iter", "first", "last", "get", "is_some", "is_none", "unwrap_or",
+        ];
+        policy.allowed_methods = Some(methods.iter().map(|&m| m.to_owned()).collect());
+        policy
+    }
+}
+
+impl Verdict
+{
+    /// Checks whether the template is accepted.
+    pub fn is_accepted(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl fmt::Display for Violation
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::Invalid(ref e) => write!(fmt, "{}", e),
+            Violation::Feature { feature, .. } => write!(fmt, "the '{}' feature is not allowed", feature.name()),
+            Violation::Expression { ref construct, .. } => write!(fmt, "{} is not allowed in expressions", construct),
+            Violation::Binding { ref construct, .. } => write!(fmt, "{} is not allowed in bindings", construct),
+            Violation::UnknownVariable { ref name, .. } => write!(fmt, "'{}' is not given to the template", name),
+            Violation::Lint(ref warning) => write!(fmt, "{}", warning),
+            Violation::TooManyItems { count, limit } => {
+                write!(fmt, "template has {} items, but the limit is {}", count, limit)
+            },
+            Violation::TooMuchMemory { size, limit } => {
+                write!(fmt, "template takes up {} bytes, but the limit is {} bytes", size, limit)
+            },
+        }
+    }
+}
+
+/// Validates a template submitted by an untrusted user against a policy.
+///
+/// A template which fails to parse has a single `Invalid` violation.
+pub fn validate_untrusted(source: &str, policy: &Policy) -> Verdict {
+    let mut verdict = Verdict::default();
+
+    let ast = match parse::parse_str_with_config(source, &policy.parse_config) {
+        Ok(ast) => ast,
+        Err(e) => {
+            verdict.violations.push(Violation::Invalid(e));
+            return verdict;
+        },
+    };
+
+    let mut uses = FeatureUses(Vec::new());
+    uses.visit_ast(&ast);
+    for (feature, span) in uses.0 {
+        if !policy.allowed_features.contains(&feature) {
+            verdict.violations.push(Violation::Feature { feature: feature, span: span });
+        }
+    }
+
+    if let Some(ref methods) = policy.allowed_methods {
+        let mut expressions = Expressions { expressions: Vec::new(), bindings: Vec::new(), macros: Vec::new() };
+        expressions.visit_ast(&ast);
+
+        for (code, span) in expressions.expressions {
+            if let Some(construct) = analysis::disallowed_construct(&code, &expressions.macros, methods) {
+                verdict.violations.push(Violation::Expression { construct: construct, span: span });
+            }
+        }
+        for (binding, span) in expressions.bindings {
+            if let Some(construct) = analysis::disallowed_binding(&binding) {
+                verdict.violations.push(Violation::Binding { construct: construct, span: span });
+            }
+        }
+    }
+
+    if let Some(ref variables) = policy.variables {
+        for variable in ast.referenced_variables() {
+            if !variables.contains(&variable.name) {
+                verdict.violations.push(Violation::UnknownVariable { name: variable.name, span: variable.span });
+            }
+        }
+    }
+
+    // Linting parses with the default options, so templates which only
+    // parse with the policy's options are not linted.
+    for warning in lint::lint_str(source).unwrap_or_default() {
+        if policy.deny_warnings {
+            verdict.violations.push(Violation::Lint(warning));
+        } else {
+            verdict.warnings.push(warning);
+        }
+    }
+
+    verdict.features = ast.features();
+    verdict.item_count = ast.item_count();
+    verdict.memory_size = ast.memory_size();
+
+    if let Some(limit) = policy.max_items.filter(|&limit| verdict.item_count > limit) {
+        verdict.violations.push(Violation::TooManyItems { count: verdict.item_count, limit: limit });
+    }
+    if let Some(limit) = policy.max_memory.filter(|&limit| verdict.memory_size > limit) {
+        verdict.violations.push(Violation::TooMuchMemory { size: verdict.memory_size, limit: limit });
+    }
+
+    verdict
+}
+
+/// Finds each item which uses a feature, in order.
+struct FeatureUses(Vec<(Feature, Range<usize>)>);
+
+impl Visitor for FeatureUses
+{
+    fn visit_item(&mut self, item: &ast::Item) {
+        if let Some(feature) = item.kind.feature() {
+            self.0.push((feature, item.span.clone()));
+        }
+        ast::walk_item(self, item);
+    }
+}
+
+/// Finds each expression and binding in order, and the names of the
+/// macros.
+///
+/// The arguments of custom tags are only known to their handlers, so
+/// are not expressions.
+struct Expressions
+{
+    expressions: Vec<(String, Range<usize>)>,
+    /// The patterns of `for` blocks and the parameters of macros.
+    bindings: Vec<(String, Range<usize>)>,
+    macros: Vec<String>,
+}
+
+impl Visitor for Expressions
+{
+    fn visit_item(&mut self, item: &ast::Item) {
+        match item.kind {
+            ast::ItemKind::Print(ref code) |
+                ast::ItemKind::Default { value: ref code, .. } |
+                ast::ItemKind::Set { value: ref code, .. } |
+                ast::ItemKind::With { value: ref code, .. } |
+                ast::ItemKind::If { condition: ref code, .. } |
+                ast::ItemKind::File { path: ref code, .. } |
+                ast::ItemKind::Cache { key: ref code, .. } |
+                ast::ItemKind::Assert { condition: ref code, .. } => {
+                self.expressions.push((code.clone(), item.span.clone()));
+            },
+            ast::ItemKind::For { ref pattern, ref iterator, .. } => {
+                self.bindings.push((pattern.clone(), item.span.clone()));
+                self.expressions.push((iterator.clone(), item.span.clone()));
+            },
+            ast::ItemKind::Macro { ref name, ref parameters, .. } => {
+                self.macros.push(name.clone());
+                self.bindings.extend(parameters.iter().map(|p| (p.clone(), item.span.clone())));
+            },
+            _ => (),
+        }
+        ast::walk_item(self, item);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn violations(source: &str, policy: &Policy) -> Vec<String> {
+        validate_untrusted(source, policy).violations.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn accepts_templates_within_the_policy() {
+        let verdict = validate_untrusted("<% for item in items %><%= item %><% end %>", &Policy::default());
+        assert!(verdict.is_accepted());
+        assert_eq!(verdict.features, vec![Feature::Print, Feature::Loop]);
+        assert_eq!(verdict.item_count, 2);
+    }
+
+    #[test]
+    fn rejects_templates_outside_the_policy() {
+        let mut policy = Policy::default();
+        policy.variables = Some(vec!["name".to_owned()]);
+        policy.max_items = Some(2);
+
+        assert_eq!(violations("<% let y = 1; %>Hi <%= name %> <%= secret %>", &policy), vec![
+            "the 'code' feature is not allowed",
+            "'secret' is not given to the template",
+            "template has 5 items, but the limit is 2",
+        ]);
+
+        let verdict = validate_untrusted("<% if x %>", &policy);
+        assert_eq!(verdict.violations.len(), 1);
+        assert!(match verdict.violations[0] { Violation::Invalid(..) => true, _ => false });
+    }
+
+    #[test]
+    fn limits_expressions_to_a_simple_grammar() {
+        let policy = Policy::default();
+        let accepted = "<% macro link(url) %><%= url %><% end %>\
+                        <% if !user.name.is_empty() && (count + 1) * 2 >= items[0] || flag %>\
+                        <%= link(user.name.trim()) %><%= \"a\".len() as u64 %><% end %>";
+        assert_eq!(violations(accepted, &policy), Vec::<String>::new());
+
+        assert_eq!(violations("<%= { ::std::fs::remove_file(\"a\") } %>", &policy),
+                   vec!["a block is not allowed in expressions"]);
+        assert_eq!(violations("<% if { true } %>x<% end %>", &policy), vec!["a block is not allowed in expressions"]);
+        assert_eq!(violations("<% if ::std::fs::remove_file(\"a\").is_ok() %>x<% end %>", &policy),
+                   vec!["a path is not allowed in expressions"]);
+        assert_eq!(violations("<%= format!(\"{}\", x) %>", &policy), vec!["a macro is not allowed in expressions"]);
+        assert_eq!(violations("<% for x in xs.map(|x| x) %><% end %>", &policy),
+                   vec!["the method 'map' is not allowed in expressions"]);
+        assert_eq!(violations("<% set f = || x %>", &policy), vec!["a closure is not allowed in expressions"]);
+        assert_eq!(violations("<%= user.delete() %>", &policy), vec!["the method 'delete' is not allowed in expressions"]);
+        assert_eq!(violations("<%= unsafe_op %><%= unsafe { x } %>", &policy),
+                   vec!["'unsafe' is not allowed in expressions"]);
+        assert_eq!(violations("<%= exit(1) %>", &policy), vec!["calling 'exit' is not allowed in expressions"]);
+
+        let policy = Policy::allowing(&[Feature::Print]);
+        assert!(validate_untrusted("<%= ::std::process::id() %>", &policy).is_accepted());
+    }
+
+    #[test]
+    fn limits_bindings_to_a_simple_grammar() {
+        let policy = Policy::default();
+        let accepted = "<% macro link(url: &str, (a, b): (u8, Vec<u8>)) %><%= url %><% end %>\
+                        <% for (i, item) in items %><%= i %><% end %>";
+        assert_eq!(violations(accepted, &policy), Vec::<String>::new());
+
+        let parameters = "<% macro f(x: u8| String::new(); ::std::process::exit(7); let g = |y: u8) %>hi<% end %>";
+        assert_eq!(violations(parameters, &policy), vec!["'|' is not allowed in bindings"]);
+
+        let pattern = "<% for _ in[0u8]{}::std::process::exit(9);for _ in xs %>x<% end %>";
+        assert_eq!(violations(pattern, &policy), vec!["'in' is not allowed in bindings"]);
+    }
+
+    #[test]
+    fn reports_or_denies_warnings() {
+        let mut policy = Policy::default();
+        assert_eq!(validate_untrusted("<%= %>", &policy).warnings.len(), 1);
+
+        policy.deny_warnings = true;
+        assert_eq!(violations("<%= %>", &policy), vec!["empty printed expression [empty-tag]"]);
+    }
+}
@@ -0,0 +1,39 @@
+//! Dynamically typed values, for data whose shape is not known when
+//! templates are translated.
+//!
+//! Templates are Rust code, so most data is given to them with its own
+//! types. `Value` is for the rest, such as fields read from JSON or front
+//! matter, and converts to and from common Rust types. Its coercions are:
+//!
+//! * Truthiness: `Null`, `false`, zero, `NaN` and empty strings, lists
+//!   and maps are false. Everything else is true.
+//! * Stringification: `Null` is empty, numbers and booleans are written as
+//!   Rust writes them, and lists and maps have their elements separated by
+//!   `", "`, with each entry of a map written as `key: value`.
+//! * Numeric promotion: an `Int` is a `Float` wherever one is needed, but a
+//!   `Float` is never truncated to an `Int`.
+
+use {Error, ErrorKind};
+
+pub use tempo_ast::value::{json_string, UnexpectedType, Value};
+
+impl From<UnexpectedType> for Error
+{
+    fn from(error: UnexpectedType) -> Self {
+        ErrorKind::UnexpectedValueType(error.expected, error.found).into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn rejects_conversions_to_other_types() {
+        match Error::from(String::try_from(Value::Int(1)).unwrap_err()).kind() {
+            &ErrorKind::UnexpectedValueType("string", "int") => (),
+            kind => panic!("unexpected error: {:?}", kind),
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Checking of version requirements declared by templates.
+
+use {Error, ErrorKind};
+
+use std::cmp::Ordering;
+
+/// A version, as `(major, minor, patch)`.
+pub type Version = (u64, u64, u64);
+
+/// Parses a version such as `0.4` or `1.2.3`.
+///
+/// Missing components are zero.
+pub fn parse_version(text: &str) -> Option<Version> {
+    let mut parts = text.trim().splitn(3, '.');
+    let mut next = || -> Option<u64> {
+        match parts.next() {
+            Some(part) => part.parse().ok(),
+            None => Some(0),
+        }
+    };
+
+    Some((next()?, next()?, next()?))
+}
+
+/// Checks whether a version satisfies a requirement.
+///
+/// A requirement is a comma-separated list of comparisons, each of
+/// which must hold, such as `>=0.4, <1.0`. The operators `=`, `>`,
+/// `>=`, `<`, `<=`, `^` and `~` are supported, with the same meaning
+/// as in Cargo. A bare version is treated as `^`.
+pub fn satisfies(version: &str, requirement: &str) -> Result<bool, Error> {
+    let invalid = || -> Error { ErrorKind::InvalidVersionRequirement(requirement.to_owned()).into() };
+    let version = parse_version(version).ok_or_else(&invalid)?;
+
+    for comparison in requirement.split(',') {
+        let comparison = comparison.trim();
+        let operator_length = comparison.find(|c: char| c.is_ascii_digit()).unwrap_or(comparison.len());
+        let (operator, bound_text) = comparison.split_at(operator_length);
+        let bound = parse_version(bound_text).ok_or_else(&invalid)?;
+
+        let ordering = version.cmp(&bound);
+        let holds = match operator.trim() {
+            "=" => ordering == Ordering::Equal,
+            ">" => ordering == Ordering::Greater,
+            ">=" => ordering != Ordering::Less,
+            "<" => ordering == Ordering::Less,
+            "<=" => ordering != Ordering::Greater,
+            "~" => ordering != Ordering::Less && version < tilde_upper_bound(bound, bound_text),
+            "^" | "" => ordering != Ordering::Less && version < caret_upper_bound(bound),
+            _ => return Err(invalid()),
+        };
+
+        if !holds {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Gets the first version that is incompatible with `bound` under `^`.
+fn caret_upper_bound(bound: Version) -> Version {
+    match bound {
+        (0, 0, patch) => (0, 0, patch + 1),
+        (0, minor, _) => (0, minor + 1, 0),
+        (major, _, _) => (major + 1, 0, 0),
+    }
+}
+
+/// Gets the first version that is incompatible with `bound` under `~`.
+fn tilde_upper_bound(bound: Version, text: &str) -> Version {
+    if text.trim().contains('.') {
+        (bound.0, bound.1 + 1, 0)
+    } else {
+        (bound.0 + 1, 0, 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_versions() {
+        assert_eq!(parse_version("0.4"), Some((0, 4, 0)));
+        assert_eq!(parse_version("1.2.3"), Some((1, 2, 3)));
+        assert_eq!(parse_version("x"), None);
+    }
+
+    #[test]
+    fn checks_comparisons() {
+        assert!(satisfies("0.4.1", ">=0.4").unwrap());
+        assert!(!satisfies("0.3.9", ">=0.4").unwrap());
+        assert!(satisfies("0.4.1", ">=0.4, <1.0").unwrap());
+        assert!(!satisfies("1.0.0", ">=0.4, <1.0").unwrap());
+    }
+
+    #[test]
+    fn checks_caret_and_tilde() {
+        assert!(satisfies("0.4.5", "0.4").unwrap());
+        assert!(!satisfies("0.5.0", "^0.4").unwrap());
+        assert!(satisfies("1.9.0", "^1.2").unwrap());
+        assert!(!satisfies("1.3.0", "~1.2").unwrap());
+    }
+
+    #[test]
+    fn rejects_invalid_requirements() {
+        assert!(satisfies("0.1.0", "!!0.1").is_err());
+    }
+}
@@ -1,11 +1,12 @@
 extern crate tempo;
 extern crate clap;
 
-use clap::{Arg, App};
+use clap::{Arg, App, AppSettings, ArgMatches, SubCommand};
 
 use std::io::prelude::*;
-use std::io::stderr;
-use std::{fs, process};
+use std::io::{stdin, stdout, stderr};
+use std::path::{Path, PathBuf};
+use std::{env, fs, process, time};
 
 struct Config<'a>
 {
@@ -15,6 +16,16 @@ struct Config<'a>
     input_file_path: &'a str,
     /// Whether a standalone program should be generated.
     standalone: bool,
+    /// The build environment exposed to templates as `env`.
+    env: Option<&'a str>,
+    /// Whether lines starting with `%` are treated as code.
+    line_statements: bool,
+    /// Whether the new line after each block tag is removed.
+    trim_blocks: bool,
+    /// Whether the indentation before each block tag is removed.
+    lstrip_blocks: bool,
+    /// How the template is decoded if it is not valid UTF-8.
+    encoding_fallback: tempo::encoding::Fallback,
 }
 
 enum OutputKind {
@@ -22,11 +33,19 @@ enum OutputKind {
     Ast,
 }
 
+/// How errors are reported on stderr.
+#[derive(Copy, Clone)]
+enum ErrorFormat {
+    Human,
+    Json,
+}
+
 fn main() {
     let matches = App::new("Tempo template engine")
                           .version(tempo::VERSION)
                           .author(env!("CARGO_PKG_AUTHORS"))
                           .about(env!("CARGO_PKG_DESCRIPTION"))
+                          .setting(AppSettings::SubcommandsNegateReqs)
                           .arg(Arg::with_name("INPUT")
                                .help("Sets the input file to use")
                                .required(true)
@@ -34,6 +53,25 @@ fn main() {
                           .arg(Arg::with_name("standalone")
                                .short("s")
                                .help("Output source code with a main() function"))
+                          .arg(Arg::with_name("env")
+                               .long("env")
+                               .takes_value(true)
+                               .help("Sets the build environment exposed to templates as `env`"))
+                          .arg(Arg::with_name("line-statements")
+                               .long("line-statements")
+                               .help("Treats lines starting with '%' as code"))
+                          .arg(Arg::with_name("trim-blocks")
+                               .long("trim-blocks")
+                               .help("Removes the first new line after each block tag"))
+                          .arg(Arg::with_name("lstrip-blocks")
+                               .long("lstrip-blocks")
+                               .help("Removes the indentation before each block tag"))
+                          .arg(Arg::with_name("encoding-fallback")
+                               .long("encoding-fallback")
+                               .takes_value(true)
+                               .possible_values(&["error", "latin1", "lossy"])
+                               .default_value("error")
+                               .help("Sets how templates which are not valid UTF-8 are decoded"))
                           .arg(Arg::with_name("print")
                                .long("print")
                                .takes_value(true)
@@ -43,8 +81,182 @@ fn main() {
                                .short("v")
                                .multiple(true)
                                .help("Sets the level of verbosity"))
+                          .arg(Arg::with_name("error-format")
+                               .long("error-format")
+                               .takes_value(true)
+                               .global(true)
+                               .possible_values(&["human", "json"])
+                               .default_value("human")
+                               .help("Sets how errors are reported"))
+                          .subcommand(with_sqlite_args(SubCommand::with_name("render")
+                               .about("Renders a template by compiling and running it")
+                               .arg(Arg::with_name("INPUT")
+                                    .help("Sets the input file to use")
+                                    .required(true)
+                                    .index(1))
+                               .arg(Arg::with_name("set")
+                                    .long("set")
+                                    .takes_value(true)
+                                    .multiple(true)
+                                    .number_of_values(1)
+                                    .value_name("KEY=VALUE")
+                                    .help("Binds a string variable for the template"))
+                               .arg(Arg::with_name("data")
+                                    .long("data")
+                                    .takes_value(true)
+                                    .multiple(true)
+                                    .number_of_values(1)
+                                    .value_name("[NAME=]PATH")
+                                    .help("Binds the rows of a CSV or JSON file as a vector for the template, \
+                                           named after the file unless a name is given"))
+                               .arg(Arg::with_name("data-url")
+                                    .long("data-url")
+                                    .takes_value(true)
+                                    .multiple(true)
+                                    .number_of_values(1)
+                                    .value_name("[NAME=]URL")
+                                    .help("Binds the rows of a JSON array fetched with curl as a vector for the template, \
+                                           named after the last part of the URL unless a name is given"))
+                               .arg(Arg::with_name("cache-for")
+                                    .long("cache-for")
+                                    .takes_value(true)
+                                    .default_value("5m")
+                                    .value_name("DURATION")
+                                    .help("Sets how long data fetched by --data-url is reused before it is fetched again"))
+                               .arg(Arg::with_name("no-net")
+                                    .long("no-net")
+                                    .help("Only uses cached data for --data-url, failing if there is none"))
+                               .arg(Arg::with_name("pipe")
+                                    .long("pipe")
+                                    .takes_value(true)
+                                    .multiple(true)
+                                    .number_of_values(1)
+                                    .value_name("COMMAND")
+                                    .help("Pipes the output through a command, such as \"wkhtmltopdf - -\""))
+                               .arg(Arg::with_name("minify")
+                                    .long("minify")
+                                    .takes_value(true)
+                                    .possible_values(&["whitespace", "json"])
+                                    .value_name("SYNTAX")
+                                    .help("Minifies the output, after any --pipe commands"))
+                               .arg(Arg::with_name("annotate-origins")
+                                    .long("annotate-origins")
+                                    .help("Wraps the output of each template in comments naming it, for debugging"))
+                               .arg(Arg::with_name("block")
+                                    .long("block")
+                                    .takes_value(true)
+                                    .value_name("NAME")
+                                    .help("Renders only a named block of the template"))
+                               .arg(Arg::with_name("sms")
+                                    .long("sms")
+                                    .help("Reports the SMS segments of the output, warning of characters outside GSM-7"))
+                               .arg(Arg::with_name("tar")
+                                    .long("tar")
+                                    .help("Writes the files of `file` blocks into a tar archive on the output"))
+                               .arg(Arg::with_name("env")
+                                    .long("env")
+                                    .takes_value(true)
+                                    .help("Sets the build environment exposed to templates as `env`"))
+                               .arg(Arg::with_name("line-statements")
+                                    .long("line-statements")
+                                    .help("Treats lines starting with '%' as code"))
+                               .arg(Arg::with_name("trim-blocks")
+                                    .long("trim-blocks")
+                                    .help("Removes the first new line after each block tag"))
+                               .arg(Arg::with_name("lstrip-blocks")
+                                    .long("lstrip-blocks")
+                                    .help("Removes the indentation before each block tag"))
+                               .arg(Arg::with_name("encoding-fallback")
+                                    .long("encoding-fallback")
+                                    .takes_value(true)
+                                    .possible_values(&["error", "latin1", "lossy"])
+                                    .default_value("error")
+                                    .help("Sets how templates which are not valid UTF-8 are decoded"))
+                               .arg(Arg::with_name("output")
+                                    .short("o")
+                                    .long("output")
+                                    .takes_value(true)
+                                    .help("Writes the output to a file instead of stdout"))
+                               .arg(Arg::with_name("provenance")
+                                    .long("provenance")
+                                    .requires("output")
+                                    .help("Starts the output with a comment saying it was generated"))
+                               .arg(Arg::with_name("source-map")
+                                    .long("source-map")
+                                    .takes_value(true)
+                                    .value_name("PATH")
+                                    .help("Writes which template line each range of the output came from"))))
+                          .subcommand(SubCommand::with_name("scaffold")
+                               .about("Generates a project from a directory of templates")
+                               .arg(Arg::with_name("TEMPLATE_DIR")
+                                    .help("Sets the template directory to use")
+                                    .required(true)
+                                    .index(1))
+                               .arg(Arg::with_name("output")
+                                    .short("o")
+                                    .long("output")
+                                    .takes_value(true)
+                                    .default_value(".")
+                                    .help("Sets the directory to generate the project in"))
+                               .arg(Arg::with_name("if-exists")
+                                    .long("if-exists")
+                                    .takes_value(true)
+                                    .possible_values(&["overwrite", "skip", "append", "merge", "regions"])
+                                    .default_value("overwrite")
+                                    .help("Sets what to do with files that already exist"))
+                               .arg(Arg::with_name("dry-run")
+                                    .long("dry-run")
+                                    .help("Prints the changes that would be made without making them"))
+                               .arg(Arg::with_name("allow-hooks")
+                                    .long("allow-hooks")
+                                    .help("Runs the post-generation hooks declared by the templates")))
+                          .subcommand(SubCommand::with_name("fmt")
+                               .about("Formats templates in place")
+                               .arg(Arg::with_name("INPUT")
+                                    .help("Sets the template files to format")
+                                    .required(true)
+                                    .multiple(true)
+                                    .index(1))
+                               .arg(Arg::with_name("check")
+                                    .long("check")
+                                    .help("Checks that the files are formatted without changing them")))
+                          .subcommand(SubCommand::with_name("lint")
+                               .about("Checks templates for likely mistakes")
+                               .arg(Arg::with_name("INPUT")
+                                    .help("Sets the template files to check")
+                                    .required(true)
+                                    .multiple(true)
+                                    .index(1)))
+                          .subcommand(SubCommand::with_name("size")
+                               .about("Reports the size of templates once parsed")
+                               .arg(Arg::with_name("INPUT")
+                                    .help("Sets the template files, or directories of `.trs` files, to measure")
+                                    .required(true)
+                                    .multiple(true)
+                                    .index(1)))
+                          .subcommand(SubCommand::with_name("explain")
+                               .about("Prints an extended description of an error code")
+                               .arg(Arg::with_name("CODE")
+                                    .help("Sets the error code to explain, such as E0001")
+                                    .required(true)
+                                    .index(1)))
                           .get_matches();
 
+    let error_format = match matches.value_of("error-format") {
+        Some("json") => ErrorFormat::Json,
+        _ => ErrorFormat::Human,
+    };
+
+    match matches.subcommand() {
+        ("render", Some(matches)) => return exit_on_error(render(matches), error_format),
+        ("scaffold", Some(matches)) => return exit_on_error(scaffold(matches), error_format),
+        ("fmt", Some(matches)) => return exit_on_error(format(matches), error_format),
+        ("lint", Some(matches)) => return exit_on_error(lint(matches), error_format),
+        ("size", Some(matches)) => return exit_on_error(size(matches), error_format),
+        ("explain", Some(matches)) => return exit_on_error(explain(matches), error_format),
+        _ => (),
+    }
+
     let output_kind = match matches.value_of("print") {
         None => OutputKind::Code,
         Some("ast") => OutputKind::Ast,
@@ -55,38 +267,175 @@ fn main() {
         output_kind: output_kind,
         input_file_path: matches.value_of("INPUT").unwrap(),
         standalone: matches.is_present("standalone"),
+        env: matches.value_of("env"),
+        line_statements: matches.is_present("line-statements"),
+        trim_blocks: matches.is_present("trim-blocks"),
+        lstrip_blocks: matches.is_present("lstrip-blocks"),
+        encoding_fallback: encoding_fallback(&matches),
     };
 
-    if let Err(e) = run(&config) {
-        writeln!(stderr(), "error: {}", e).unwrap();
+    exit_on_error(run(&config), error_format);
+}
+
+fn exit_on_error(result: Result<(), tempo::Error>, format: ErrorFormat) {
+    if let Err(e) = result {
+        match format {
+            ErrorFormat::Human => writeln!(stderr(), "error[{}]: {}", e.code(), e).unwrap(),
+            ErrorFormat::Json => {
+                let arguments: Vec<_> = e.arguments().iter()
+                    .map(|&(name, ref value)| format!("\"{}\": {}", name, tempo::value::json_string(value)))
+                    .collect();
+                writeln!(stderr(), "{{\"code\": \"{}\", \"message\": {}, \"arguments\": {{{}}}, \
+                                    \"messages_version\": {}}}",
+                         e.code(), tempo::value::json_string(&e.to_string()), arguments.join(", "),
+                         tempo::errors::MESSAGES_VERSION).unwrap()
+            },
+        }
         process::exit(1);
     }
 }
 
-fn run(config: &Config) -> Result<(), tempo::Error> {
-    let mut file = fs::File::open(config.input_file_path)?;
-    let mut source = String::new();
-    file.read_to_string(&mut source)?;
+fn format(matches: &ArgMatches) -> Result<(), tempo::Error> {
+    let mut unformatted = Vec::new();
+
+    for path in matches.values_of("INPUT").unwrap() {
+        let mut source = String::new();
+        fs::File::open(path)?.read_to_string(&mut source)?;
+
+        let formatted = tempo::fmt::format_str(&source, &Default::default(), &Default::default())?;
 
-    let ast = tempo::parse::parse_str(&source).unwrap();
+        if formatted == source {
+            continue;
+        }
+
+        if matches.is_present("check") {
+            unformatted.push(path);
+        } else {
+            fs::File::create(path)?.write_all(formatted.as_bytes())?;
+        }
+    }
+
+    if unformatted.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("not formatted: {}", unformatted.join(", ")).into())
+    }
+}
+
+fn lint(matches: &ArgMatches) -> Result<(), tempo::Error> {
+    let mut warning_count = 0;
+
+    for path in matches.values_of("INPUT").unwrap() {
+        let mut source = String::new();
+        fs::File::open(path)?.read_to_string(&mut source)?;
+
+        for warning in tempo::lint::lint_str(&source)? {
+            let (line, column) = tempo::position::line_and_column(&source, warning.span.start);
+            writeln!(stderr(), "{}:{}:{}: warning: {}", path, line, column, warning)?;
+            warning_count += 1;
+        }
+    }
+
+    if warning_count == 0 {
+        Ok(())
+    } else {
+        Err(format!("found {} warning(s)", warning_count).into())
+    }
+}
+
+/// Prints the number of items, bytes of text and estimated memory of
+/// each template, and their totals.
+fn size(matches: &ArgMatches) -> Result<(), tempo::Error> {
+    let mut paths = Vec::new();
+    for input in matches.values_of("INPUT").unwrap() {
+        collect_templates(Path::new(input), &mut paths)?;
+    }
+    paths.sort();
+
+    let mut rows = Vec::new();
+    let (mut total_items, mut total_text, mut total_memory) = (0, 0, 0);
+    for path in paths.iter() {
+        let ast = parse_file(&path.to_string_lossy(), &Default::default(), tempo::encoding::Fallback::Lossy)?;
+        let (items, text, memory) = (ast.item_count(), ast.text_len(), ast.memory_size());
+
+        rows.push((path.display().to_string(), items, text, memory));
+        total_items += items;
+        total_text += text;
+        total_memory += memory;
+    }
+    rows.push(("total".to_owned(), total_items, total_text, total_memory));
+
+    let width = rows.iter().map(|row| row.0.len()).max().unwrap_or(0);
+    println!("{:width$}  {:>8}  {:>10}  {:>10}", "template", "items", "text", "memory", width = width);
+    for (name, items, text, memory) in rows {
+        println!("{:width$}  {:>8}  {:>10}  {:>10}", name, items,
+                 tempo::units::ByteSize(text as u64).to_string(),
+                 tempo::units::ByteSize(memory as u64).to_string(), width = width);
+    }
+
+    Ok(())
+}
+
+/// Finds the templates at a path, which are every `.trs` file under a
+/// directory.
+fn collect_templates(path: &Path, paths: &mut Vec<std::path::PathBuf>) -> Result<(), tempo::Error> {
+    if !path.is_dir() {
+        paths.push(path.to_owned());
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            collect_templates(&path, paths)?;
+        } else if path.extension().map_or(false, |e| e == tempo::scaffold::TEMPLATE_EXTENSION) {
+            paths.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn explain(matches: &ArgMatches) -> Result<(), tempo::Error> {
+    let code = matches.value_of("CODE").unwrap().to_uppercase();
+
+    match tempo::errors::explain(&code) {
+        Some(explanation) => {
+            print!("{}", explanation);
+            Ok(())
+        },
+        None => Err(format!("no extended information for '{}'", code).into()),
+    }
+}
+
+fn run(config: &Config) -> Result<(), tempo::Error> {
+    let parse_config = tempo::parse::Config {
+        line_statements: config.line_statements,
+        trim_blocks: config.trim_blocks,
+        lstrip_blocks: config.lstrip_blocks,
+        ..Default::default()
+    };
 
     match config.output_kind {
-        OutputKind::Code => print_code(ast, config)?,
-        OutputKind::Ast => print_ast(ast)?,
+        OutputKind::Code => println!("{}", rust_code(config, &parse_config)?),
+        OutputKind::Ast => print_ast(parse_file(config.input_file_path, &parse_config, config.encoding_fallback)?)?,
     }
 
     Ok(())
 }
 
-fn print_code(ast: tempo::Ast, config: &Config) -> Result<(), tempo::Error> {
+/// Translates the input file, with the templates it includes from next
+/// to it.
+fn rust_code(config: &Config, parse_config: &tempo::parse::Config) -> Result<String, tempo::Error> {
     let trans_config = tempo::trans::Config {
         include_entry_point: config.standalone,
+        env: config.env.map(|e| e.to_owned()),
+        ..Default::default()
     };
 
-    let rust_code = tempo::trans::rust_code(ast, &trans_config).unwrap();
-    println!("{}", rust_code);
-
-    Ok(())
+    let engine = load_engine(config.input_file_path, parse_config, config.encoding_fallback)?;
+    engine.rust_code(config.input_file_path, &trans_config)
 }
 
 fn print_ast(ast: tempo::Ast) -> Result<(), tempo::Error> {
@@ -94,4 +443,669 @@ fn print_ast(ast: tempo::Ast) -> Result<(), tempo::Error> {
     Ok(())
 }
 
+fn render(matches: &ArgMatches) -> Result<(), tempo::Error> {
+    let mut variables = Vec::new();
+    for assignment in matches.values_of("set").into_iter().flat_map(|v| v) {
+        match assignment.find('=') {
+            Some(i) => {
+                let name = &assignment[..i];
+                if !is_identifier(name) {
+                    return Err(format!("'{}' cannot name a variable, in '{}'", name, assignment).into());
+                } else if tempo::analysis::is_keyword(name) {
+                    return Err(format!("'{}' is a keyword, so cannot name a variable", name).into());
+                }
+                variables.push((name.to_owned(), assignment[i+1..].to_owned()));
+            },
+            None => return Err(format!("expected KEY=VALUE but got '{}'", assignment).into()),
+        }
+    }
+
+    let mut tables = Vec::new();
+    for argument in matches.values_of("data").into_iter().flat_map(|v| v) {
+        let (name, path) = named_source(argument)?;
+        let name = match name {
+            Some(name) => name,
+            None => match Path::new(path).file_stem().and_then(|s| s.to_str()).and_then(tempo::data::field_name) {
+                Some(name) => name,
+                None => return Err(format!("cannot name the data in '{}', use NAME={}", path, path).into()),
+            },
+        };
+
+        let mut text = String::new();
+        fs::File::open(path)?.read_to_string(&mut text)?;
+        if path.ends_with(".json") {
+            tables.push((name, tempo::data::parse_json(&text)?));
+        } else {
+            tables.push((name, tempo::data::parse_csv(&text)?));
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    {
+        if let Some(database) = matches.value_of("sqlite") {
+            for argument in matches.values_of("query").unwrap() {
+                let (name, query) = match named_source(argument)? {
+                    (Some(name), query) => (name, query),
+                    (None, query) => return Err(format!("name the rows of '{}' with NAME=QUERY", query).into()),
+                };
+                tables.push((name, tempo::data::parse_csv(&sqlite_query(database, query)?)?));
+            }
+        }
+    }
+
+    let cache_for: tempo::units::Duration = matches.value_of("cache-for").unwrap().parse()?;
+    for argument in matches.values_of("data-url").into_iter().flat_map(|v| v) {
+        let (name, url) = named_source(argument)?;
+        let name = match name {
+            Some(name) => name,
+            None => match url.rsplit('/').next().and_then(|s| Path::new(s).file_stem())
+                .and_then(|s| s.to_str()).and_then(tempo::data::field_name) {
+                Some(name) => name,
+                None => return Err(format!("cannot name the data from '{}', use NAME={}", url, url).into()),
+            },
+        };
+
+        let text = fetch_url(url, cache_for.0, matches.is_present("no-net"))?;
+        tables.push((name, tempo::data::parse_json(&text)?));
+    }
+
+    let input_path = matches.value_of("INPUT").unwrap();
+
+    let header = if matches.is_present("provenance") {
+        let output_path = matches.value_of("output").unwrap();
+        let syntax = match tempo::comment::Syntax::for_path(output_path) {
+            Some(syntax) => syntax,
+            None => return Err(format!("unknown comment syntax for '{}'", output_path).into()),
+        };
+
+        let notice = tempo::comment::provenance_notice(input_path, &utc_timestamp());
+        Some(syntax.comment(&notice))
+    } else {
+        None
+    };
+
+    let comment_syntax = match matches.value_of("output") {
+        Some(output_path) => tempo::comment::Syntax::for_path(output_path),
+        None => tempo::comment::Syntax::for_template_path(input_path),
+    };
+
+    let trans_config = tempo::trans::Config {
+        include_entry_point: true,
+        env: matches.value_of("env").map(|e| e.to_owned()),
+        variables: variables,
+        tables: tables,
+        comment_syntax: comment_syntax,
+        header: header,
+        source_map: matches.is_present("source-map"),
+        archive_files: matches.is_present("tar"),
+        annotate_origins: matches.is_present("annotate-origins"),
+        ..Default::default()
+    };
+
+    let parse_config = tempo::parse::Config {
+        line_statements: matches.is_present("line-statements"),
+        trim_blocks: matches.is_present("trim-blocks"),
+        lstrip_blocks: matches.is_present("lstrip-blocks"),
+        ..Default::default()
+    };
+
+    let engine = load_engine(input_path, &parse_config, encoding_fallback(matches))?;
+    let program = match matches.value_of("block") {
+        Some(block) => engine.rust_code_block(input_path, block, &trans_config)?,
+        None => engine.rust_code(input_path, &trans_config)?,
+    };
+
+    let mut stages: Vec<Box<dyn tempo::pipeline::Stage>> = Vec::new();
+    for command_line in matches.values_of("pipe").into_iter().flat_map(|v| v) {
+        match tempo::pipeline::Command::parse(command_line) {
+            Some(command) => stages.push(Box::new(command)),
+            None => return Err("--pipe needs a command".into()),
+        }
+    }
+
+    match matches.value_of("minify") {
+        Some("whitespace") => stages.push(Box::new(tempo::pipeline::CollapseWhitespace::default())),
+        Some("json") => stages.push(Box::new(tempo::pipeline::MinifyJson::default())),
+        _ => (),
+    }
+
+    let arguments: Vec<_> = matches.value_of("source-map").into_iter().collect();
+    if stages.is_empty() && !matches.is_present("sms") {
+        let output = match matches.value_of("output") {
+            Some(path) => process::Stdio::from(fs::File::create(path)?),
+            None => process::Stdio::inherit(),
+        };
+        run_program(&program, output, &arguments)?;
+    } else {
+        let output = run_program(&program, process::Stdio::piped(), &arguments)?;
+        let output = tempo::pipeline::run(&stages, output)?;
+        if matches.is_present("sms") {
+            report_sms(&String::from_utf8_lossy(&output))?;
+        }
+        match matches.value_of("output") {
+            Some(path) => fs::File::create(path)?.write_all(&output)?,
+            None => stdout().write_all(&output)?,
+        }
+    }
+
+    if let Some(source_map_path) = matches.value_of("source-map") {
+        if !parse_config.line_statements {
+            add_line_numbers(source_map_path, &engine)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes how rendered output would be sent as an SMS to stderr.
+fn report_sms(text: &str) -> Result<(), tempo::Error> {
+    let report = tempo::sms::analyze(text);
+
+    for &(offset, c) in report.incompatible.iter() {
+        let (line, column) = tempo::position::line_and_column(text, offset);
+        writeln!(stderr(), "warning: {:?} at {}:{} is not in the GSM-7 alphabet", c, line, column)?;
+    }
+    writeln!(stderr(), "sms: {}", report)?;
+    Ok(())
+}
+
+/// Fetches the text at a URL with curl, reusing a cached copy fetched
+/// within the given duration.
+///
+/// Without the network, any cached copy is used however old it is.
+fn fetch_url(url: &str, cache_for: time::Duration, no_net: bool) -> Result<String, tempo::Error> {
+    // FNV-1a, as the standard library's hasher may change between builds.
+    let hash = url.bytes().fold(0xcbf29ce484222325, |hash: u64, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    let cache_dir = cache_dir()?;
+    let cache_path = cache_dir.join(format!("{:016x}", hash));
+
+    let metadata = fs::symlink_metadata(&cache_path).ok();
+    if metadata.as_ref().map_or(false, |m| !m.file_type().is_file()) {
+        return Err(format!("'{}' is not a regular file, so is not used as a cache", cache_path.display()).into());
+    }
+
+    let age = metadata.and_then(|m| m.modified().ok())
+        .map(|modified| modified.elapsed().unwrap_or_default());
+    match age {
+        Some(age) if no_net || age < cache_for => {
+            let mut text = String::new();
+            fs::File::open(&cache_path)?.read_to_string(&mut text)?;
+            return Ok(text);
+        },
+        _ if no_net => return Err(format!("'{}' is not cached, and --no-net is set", url).into()),
+        _ => (),
+    }
+
+    let result = process::Command::new("curl")
+        .arg("--silent").arg("--show-error").arg("--fail").arg("--location")
+        .arg("--").arg(url)
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !result.status.success() {
+        return Err(format!("could not fetch '{}'", url).into());
+    }
+
+    let text = match String::from_utf8(result.stdout) {
+        Ok(text) => text,
+        Err(..) => return Err(format!("the data at '{}' is not valid UTF-8", url).into()),
+    };
+
+    // The data is written to a new file and moved into place, so that
+    // readers never see part of it, and nothing is written through a link.
+    let temporary_path = cache_dir.join(format!("{:016x}.{}.tmp", hash, process::id()));
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let written = options.open(&temporary_path)
+        .and_then(|mut file| file.write_all(text.as_bytes()))
+        .and_then(|()| fs::rename(&temporary_path, &cache_path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temporary_path);
+        return Err(e.into());
+    }
+
+    Ok(text)
+}
+
+/// Gets the directory data fetched by `--data-url` is cached in, creating
+/// it so that only the user can read it.
+fn cache_dir() -> Result<PathBuf, tempo::Error> {
+    let base = match env::var_os("XDG_CACHE_HOME") {
+        Some(ref dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => match env::var_os("HOME") {
+            Some(ref home) if !home.is_empty() => Path::new(home).join(".cache"),
+            _ => return Err("set HOME or XDG_CACHE_HOME to cache data fetched by --data-url".into()),
+        },
+    };
+    let dir = base.join("tempo");
+
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(&dir)?;
+
+    if !fs::symlink_metadata(&dir)?.file_type().is_dir() {
+        return Err(format!("'{}' is not a directory, so is not used as a cache", dir.display()).into());
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+    }
+
+    Ok(dir)
+}
+
+/// Splits the name a data source is bound to from a `NAME=SOURCE`
+/// argument, if it begins with one.
+fn named_source(argument: &str) -> Result<(Option<String>, &str), tempo::Error> {
+    let name = match argument.find('=') {
+        Some(i) => &argument[..i],
+        None => return Ok((None, argument)),
+    };
+
+    if !is_identifier(name) {
+        Ok((None, argument))
+    } else if tempo::analysis::is_keyword(name) {
+        Err(format!("'{}' is a keyword, so cannot name data", name).into())
+    } else {
+        Ok((Some(name.to_owned()), &argument[name.len() + 1..]))
+    }
+}
+
+/// Checks whether a name given on the command line is an identifier,
+/// other than `_`.
+fn is_identifier(name: &str) -> bool {
+    name.chars().next().map_or(false, |c| c.is_alphabetic() || c == '_') &&
+        name.chars().all(|c| c.is_alphanumeric() || c == '_') && name != "_"
+}
+
+/// Adds the arguments binding the rows of SQLite queries to a command.
+#[cfg(feature = "sqlite")]
+fn with_sqlite_args<'a, 'b>(command: App<'a, 'b>) -> App<'a, 'b> {
+    command.arg(Arg::with_name("sqlite")
+                .long("sqlite")
+                .takes_value(true)
+                .requires("query")
+                .value_name("DATABASE")
+                .help("Sets the SQLite database read by --query, using the sqlite3 shell"))
+           .arg(Arg::with_name("query")
+                .long("query")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("sqlite")
+                .value_name("NAME=SQL")
+                .help("Binds the rows returned by a query as a vector for the template"))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn with_sqlite_args<'a, 'b>(command: App<'a, 'b>) -> App<'a, 'b> {
+    command
+}
+
+/// Runs a query against an SQLite database, giving the rows as CSV
+/// with a header row.
+#[cfg(feature = "sqlite")]
+fn sqlite_query(database: &str, query: &str) -> Result<String, tempo::Error> {
+    let result = process::Command::new("sqlite3")
+        .arg("-bail").arg("-csv").arg("-header")
+        .arg(database).arg(query)
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !result.status.success() {
+        return Err(format!("could not run query '{}'", query).into());
+    }
+
+    // The shell prints no header when there are no rows, leaving
+    // nothing to give the columns their names and types from.
+    match String::from_utf8(result.stdout) {
+        Ok(ref rows) if rows.is_empty() => Err(format!("'{}' returned no rows", query).into()),
+        Ok(rows) => Ok(rows),
+        Err(..) => Err(format!("the rows of '{}' are not valid UTF-8", query).into()),
+    }
+}
+
+/// Rewrites the template byte ranges in a source map as
+/// `path:line:column` positions, finding the files of the templates
+/// named in it with the engine.
+fn add_line_numbers(source_map_path: &str, engine: &tempo::Engine) -> Result<(), tempo::Error> {
+    let mut source_map = String::new();
+    fs::File::open(source_map_path)?.read_to_string(&mut source_map)?;
+
+    let mut sources = std::collections::HashMap::new();
+    let mut rewritten = String::new();
+
+    for line in source_map.lines() {
+        let malformed = || -> tempo::Error { format!("malformed source map line '{}'", line).into() };
+        let mut fields = line.splitn(3, '\t');
+        let output = fields.next().ok_or_else(malformed)?;
+        let origin = fields.next().ok_or_else(malformed)?;
+        let span = fields.next().ok_or_else(malformed)?;
+        let start: usize = span.split("..").next().unwrap_or(span).parse().map_err(|_| malformed())?;
+        let path = engine.template_path(origin).unwrap_or(Path::new(origin)).display().to_string();
+
+        if !sources.contains_key(&path) {
+            let mut source = String::new();
+            fs::File::open(&path)?.read_to_string(&mut source)?;
+            sources.insert(path.clone(), source);
+        }
+
+        let (line, column) = tempo::position::line_and_column(&sources[&path], start);
+        rewritten.push_str(&format!("{}\t{}:{}:{}\n", output, path, line, column));
+    }
+
+    fs::File::create(source_map_path)?.write_all(rewritten.as_bytes())?;
+    Ok(())
+}
+
+/// Formats the current time as an ISO 8601 UTC timestamp.
+fn utc_timestamp() -> String {
+    let (year, month, day, seconds) = tempo::date::civil(time::SystemTime::now());
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day,
+            seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+fn parse_file(path: &str, config: &tempo::parse::Config, fallback: tempo::encoding::Fallback)
+    -> Result<tempo::Ast, tempo::Error> {
+    let (source, encoding) = tempo::encoding::decode(read_bytes(Path::new(path))?, fallback)?;
+
+    if let Some(warning) = encoding.warning() {
+        writeln!(stderr(), "warning: {}: {}", path, warning)?;
+    }
+
+    tempo::parse::parse_str_with_config(&source, config)
+}
+
+/// Loads a template into an engine along with the templates it includes,
+/// which are named by their paths relative to its directory.
+fn load_engine(path: &str, config: &tempo::parse::Config, fallback: tempo::encoding::Fallback)
+    -> Result<tempo::Engine, tempo::Error> {
+    let mut engine = tempo::Engine::new();
+    engine.set_parse_config(config.clone());
+    engine.set_encoding_fallback(fallback);
+    engine.add_template_file(path, path)?;
+
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    loop {
+        let found: Vec<_> = engine.dependencies(path)?.into_iter()
+            .filter(|name| engine.template(name).is_none() && dir.join(name).is_file())
+            .collect();
+        if found.is_empty() {
+            break;
+        }
+
+        for name in found {
+            engine.add_template_file(&name, dir.join(&name))?;
+        }
+    }
+
+    for name in engine.template_names() {
+        if let Some(warning) = engine.encoding(name).and_then(|e| e.warning()) {
+            writeln!(stderr(), "warning: {}: {}", dir.join(name).display(), warning)?;
+        }
+    }
+
+    Ok(engine)
+}
+
+fn encoding_fallback(matches: &ArgMatches) -> tempo::encoding::Fallback {
+    let name = matches.value_of("encoding-fallback").unwrap();
+    tempo::encoding::Fallback::from_name(name).unwrap()
+}
+
+fn scaffold(matches: &ArgMatches) -> Result<(), tempo::Error> {
+    let skeleton = tempo::scaffold::Skeleton::load(matches.value_of("TEMPLATE_DIR").unwrap())?;
+
+    let mut values = Vec::new();
+    for param in skeleton.params.iter() {
+        values.push(prompt(param)?);
+    }
+
+    let default_strategy = tempo::scaffold::Strategy::from_name(
+        matches.value_of("if-exists").unwrap()).unwrap();
+    let dry_run = matches.is_present("dry-run");
+
+    let output_dir = env::current_dir()?.join(matches.value_of("output").unwrap());
+    let staging_dir = create_temp_dir("tempo-staging")?;
+    let program = skeleton.program(&values, &staging_dir)?;
+
+    let manifest = match run_program(&program, process::Stdio::piped(), &[]) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            fs::remove_dir_all(&staging_dir)?;
+            return Err(e);
+        },
+    };
+    let manifest = tempo::scaffold::parse_manifest(&String::from_utf8_lossy(&manifest));
+
+    let result = install_files(&manifest, &staging_dir, &output_dir, default_strategy, dry_run);
+    if staging_dir.exists() {
+        fs::remove_dir_all(&staging_dir)?;
+    }
+    result?;
+
+    if dry_run {
+        return Ok(());
+    }
+
+    run_hooks(&skeleton.hooks, &output_dir, matches.is_present("allow-hooks"))
+}
+
+/// Moves generated files into the output directory, taking into account
+/// any existing files.
+///
+/// In a dry run, the changes are printed as diffs instead.
+fn install_files(files: &[tempo::scaffold::GeneratedFile],
+                 staging_dir: &Path,
+                 output_dir: &Path,
+                 default_strategy: tempo::scaffold::Strategy,
+                 dry_run: bool) -> Result<(), tempo::Error> {
+    for file in files {
+        let generated = read_bytes(&staging_dir.join(&file.path))?;
+        let output_path = output_dir.join(&file.path);
+
+        let (existing, contents) = if output_path.exists() {
+            let existing = read_bytes(&output_path)?;
+            let strategy = file.strategy.unwrap_or(default_strategy);
+            let contents = strategy.merge(&existing, &generated)?;
+            (existing, contents)
+        } else {
+            (Vec::new(), Some(generated))
+        };
+
+        let contents = match contents {
+            Some(contents) => contents,
+            None => continue,
+        };
+
+        if dry_run {
+            println!("--- {}", output_path.display());
+            println!("+++ {}", output_path.display());
+            print!("{}", tempo::scaffold::diff(&String::from_utf8_lossy(&existing),
+                                               &String::from_utf8_lossy(&contents)));
+        } else {
+            if let Some(parent) = output_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::File::create(&output_path)?.write_all(&contents)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_bytes(path: &Path) -> Result<Vec<u8>, tempo::Error> {
+    let mut bytes = Vec::new();
+    fs::File::open(path)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn run_hooks(hooks: &[tempo::scaffold::Hook], dir: &Path, allowed: bool)
+    -> Result<(), tempo::Error> {
+    if !allowed {
+        for hook in hooks {
+            writeln!(stderr(), "warning: skipping hook '{}' (pass --allow-hooks to run it)", hook)?;
+        }
+        return Ok(());
+    }
+
+    for hook in hooks {
+        let status = hook.command(dir).status()?;
+        if !status.success() {
+            return Err(format!("hook '{}' failed", hook).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Asks the user for the value of a parameter until a valid one is given.
+fn prompt(param: &tempo::scaffold::Param) -> Result<String, tempo::Error> {
+    loop {
+        match param.default {
+            Some(ref default) => print!("{} [{}]: ", param.name, default),
+            None => print!("{}: ", param.name),
+        }
+        stdout().flush()?;
+
+        let mut line = String::new();
+        if stdin().read_line(&mut line)? == 0 {
+            return Err(format!("no value given for '{}'", param.name).into());
+        }
+
+        let value = match (line.trim(), param.default.as_ref()) {
+            ("", Some(default)) => default.clone(),
+            ("", None) => continue,
+            (value, _) => value.to_owned(),
+        };
+
+        match param.literal(&value) {
+            Ok(..) => return Ok(value),
+            Err(e) => writeln!(stderr(), "{}", e)?,
+        }
+    }
+}
+
+/// Compiles a standalone program with `rustc` and runs it.
+///
+/// Returns the standard output of the program, if `output` is piped.
+fn run_program(program: &str, output: process::Stdio, arguments: &[&str])
+    -> Result<Vec<u8>, tempo::Error> {
+    let build_dir = create_temp_dir("tempo")?;
 
+    let result = compile_and_run(program, output, arguments, &build_dir);
+    fs::remove_dir_all(&build_dir)?;
+    result
+}
+
+/// Creates a new directory in the system's temporary directory which
+/// only the user can access, named by a prefix and a random suffix.
+///
+/// The directory must not exist already, so another user cannot create
+/// it, or a link in its place, beforehand.
+fn create_temp_dir(prefix: &str) -> Result<PathBuf, tempo::Error> {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+
+    for attempt in 0..100u32 {
+        // Each `RandomState` is seeded randomly.
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(process::id());
+        hasher.write_u32(attempt);
+
+        let dir = env::temp_dir().join(format!("{}-{:016x}", prefix, hasher.finish()));
+        match builder.create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Err(format!("could not create a directory for '{}' in '{}'", prefix, env::temp_dir().display()).into())
+}
+
+fn compile_and_run(program: &str, output: process::Stdio, arguments: &[&str], build_dir: &Path)
+    -> Result<Vec<u8>, tempo::Error> {
+    let source_path = build_dir.join("main.rs");
+    let binary_path = build_dir.join("main");
+
+    fs::File::create(&source_path)?.write_all(program.as_bytes())?;
+
+    let status = process::Command::new("rustc")
+        .arg("-A").arg("warnings")
+        .arg("-o").arg(&binary_path)
+        .arg(&source_path)
+        .status()?;
+    if !status.success() {
+        return Err("could not compile the generated program".into());
+    }
+
+    let result = process::Command::new(&binary_path)
+        .args(arguments)
+        .stdout(output)
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !result.status.success() {
+        return Err("the generated program failed".into());
+    }
+
+    Ok(result.stdout)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn translates_templates_with_includes() {
+        let dir = env::temp_dir().join(format!("tempo-cli-includes-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.trs"), "a<% include \"b.trs\" %>").unwrap();
+        fs::write(dir.join("b.trs"), "included").unwrap();
+
+        let path = dir.join("a.trs").display().to_string();
+        let config = Config {
+            output_kind: OutputKind::Code,
+            input_file_path: &path,
+            standalone: true,
+            env: None,
+            line_statements: false,
+            trim_blocks: false,
+            lstrip_blocks: false,
+            encoding_fallback: tempo::encoding::Fallback::Error,
+        };
+        let code = rust_code(&config, &Default::default());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(code.unwrap().contains("included"));
+    }
+
+    #[test]
+    fn errors_on_malformed_source_maps() {
+        let path = env::temp_dir().join(format!("tempo-cli-source-map-{}", process::id()));
+        fs::write(&path, "0..5\ta.trs\n").unwrap();
+
+        let result = add_line_numbers(path.to_str().unwrap(), &tempo::Engine::new());
+        fs::remove_file(&path).unwrap();
+
+        assert!(result.unwrap_err().to_string().contains("malformed source map line '0..5\ta.trs'"));
+    }
+}